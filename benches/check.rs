@@ -0,0 +1,80 @@
+//! Per-request cost of `GovernorPolicy::check`
+//!
+//! Quotas are large enough that every check is allowed, so the numbers show
+//! the overhead of the check path rather than the cost of rejecting.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use rama_core::Context;
+use rama_core::layer::limit::policy::Policy;
+use rama_x_governor::GovernorPolicy;
+
+/// Concurrent tasks in the contended benchmarks
+const TASKS: u64 = 8;
+
+fn direct() -> GovernorPolicy {
+    GovernorPolicy::builder()
+        .per_second(u32::MAX)
+        .burst_size(u32::MAX)
+        .build()
+}
+
+fn keyed() -> GovernorPolicy {
+    GovernorPolicy::builder()
+        .per_second(u32::MAX)
+        .burst_size(u32::MAX)
+        .build_with_keyer(|key: &str| key.to_owned())
+}
+
+fn bench_single(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let _guard = rt.enter();
+
+    for (name, policy) in [("direct", direct()), ("keyed", keyed())] {
+        c.bench_function(&format!("check/{name}"), |b| {
+            b.to_async(&rt)
+                .iter(|| async { policy.check(Context::default(), ()).await.output })
+        });
+    }
+}
+
+fn bench_contended(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(TASKS as usize)
+        .enable_all()
+        .build()
+        .unwrap();
+    let _guard = rt.enter();
+
+    for (name, policy) in [("direct", direct()), ("keyed", keyed())] {
+        let policy = Arc::new(policy);
+        c.bench_function(&format!("check_contended/{name}"), |b| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let policy = policy.clone();
+                async move {
+                    let start = Instant::now();
+                    let tasks: Vec<_> = (0..TASKS)
+                        .map(|_| {
+                            let policy = policy.clone();
+                            tokio::spawn(async move {
+                                for _ in 0..iters.div_ceil(TASKS) {
+                                    let _ = policy.check(Context::default(), ()).await;
+                                }
+                            })
+                        })
+                        .collect();
+                    for task in tasks {
+                        task.await.unwrap();
+                    }
+                    // wall time for all checks, i.e. the inverse of throughput
+                    start.elapsed()
+                }
+            })
+        });
+    }
+}
+
+criterion_group!(benches, bench_single, bench_contended);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+//! Throughput of `RedisStore` with and without pipelined batching
+//!
+//! Needs a Redis server, taken from `REDIS_URL` (default
+//! `redis://127.0.0.1/`):
+//!
+//! ```sh
+//! cargo bench --bench redis_batching --features redis
+//! ```
+//!
+//! Many tasks check concurrently, so the batched store can fold their round
+//! trips into few pipelines.
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use rama_x_governor::{Quota, RateLimitStore, RedisStore};
+
+/// Concurrent tasks checking against the store
+const TASKS: u64 = 64;
+
+fn bench_batching(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_owned());
+    let store = rt
+        .block_on(RedisStore::connect(&url))
+        .expect("Redis server to benchmark against")
+        .prefix("governor-bench:");
+    let quota = Quota::per_second(NonZeroU32::MAX);
+
+    let stores = [
+        ("unbatched", store.clone()),
+        ("batched", store.batching(64, Duration::from_micros(500))),
+    ];
+    for (name, store) in stores {
+        let store = Arc::new(store);
+        c.bench_function(&format!("redis_check/{name}"), |b| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let store = store.clone();
+                async move {
+                    let start = Instant::now();
+                    let tasks: Vec<_> = (0..TASKS)
+                        .map(|task| {
+                            let store = store.clone();
+                            tokio::spawn(async move {
+                                let key = format!("key-{task}");
+                                for _ in 0..iters.div_ceil(TASKS) {
+                                    let _ = store.check_n(&key, quota, 1).await;
+                                }
+                            })
+                        })
+                        .collect();
+                    for task in tasks {
+                        task.await.unwrap();
+                    }
+                    // wall time for all checks, i.e. the inverse of throughput
+                    start.elapsed()
+                }
+            })
+        });
+    }
+}
+
+criterion_group!(benches, bench_batching);
+criterion_main!(benches);
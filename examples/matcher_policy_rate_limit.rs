@@ -30,7 +30,7 @@ use rama_http::{
 };
 use rama_http_backend::server::HttpServer;
 use rama_net::stream::matcher::SocketMatcher;
-use rama_x_governor::GovernorPolicy;
+use rama_x_governor::{GovernorError, GovernorPolicy};
 use std::{sync::Arc, time::Duration};
 
 use std::convert::Infallible;
@@ -53,8 +53,10 @@ async fn main() {
             (
                 MapResultLayer::new(|result: Result<Response, BoxError>| match result {
                     Ok(response) => Ok(response),
-                    Err(box_error) => {
-                        if box_error.downcast_ref::<LimitReached>().is_some() {
+                    Err(box_error) => match box_error.downcast::<GovernorError>() {
+                        // rendered from the policy's rejection response, 429 by default
+                        Ok(err) => Ok(err.into_response()),
+                        Err(box_error) if box_error.downcast_ref::<LimitReached>().is_some() => {
                             Ok((
                                 [(
                                     HeaderName::from_static("x-proxy-error"),
@@ -63,16 +65,15 @@ async fn main() {
                                 StatusCode::TOO_MANY_REQUESTS,
                             )
                                 .into_response())
-                        } else {
-                            Ok((
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                Json(json!({
-                                    "error": box_error.to_string(),
-                                })),
-                            )
-                                .into_response())
                         }
-                    }
+                        Err(box_error) => Ok((
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            Json(json!({
+                                "error": box_error.to_string(),
+                            })),
+                        )
+                            .into_response()),
+                    },
                 }),
                 TraceErrLayer::new(),
                 // using the [`Either`] combinator you can make tree-like structures,
@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rama_x_governor::fuzz::{self, HeaderInput};
+
+fuzz_target!(|input: HeaderInput<'_>| fuzz::header_key(input));
@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rama_x_governor::fuzz;
+
+fuzz_target!(|spec: &str| fuzz::policy_spec(spec));
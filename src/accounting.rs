@@ -0,0 +1,233 @@
+//! Usage accounting for metering and billing
+//!
+//! When enabled, every accepted request is counted against its key. The counts
+//! are aggregated per interval and handed to a [`UsageSink`] at the end of each
+//! interval.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use serde_json::json;
+#[cfg(feature = "tokio")]
+use tokio::sync::mpsc;
+
+use crate::runtime::{self, Runtime};
+use crate::sync::StartOnce;
+
+/// Key under which requests of a direct (non-keyed) policy are accounted
+pub const DIRECT_USAGE_KEY: &str = "*";
+
+/// Number of accepted requests for a single key within one accounting interval
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRecord {
+    /// The rate limiting key
+    pub key: String,
+    /// Accepted requests within the interval
+    pub count: u64,
+    /// Start of the interval
+    pub window_start: SystemTime,
+    /// End of the interval
+    pub window_end: SystemTime,
+}
+
+/// Destination for aggregated usage records
+///
+/// Implemented for closures taking `Vec<UsageRecord>`, for bounded tokio
+/// channels (with the `tokio` feature), and by [`FileUsageSink`].
+pub trait UsageSink: Send + Sync + 'static {
+    /// Receive the records of one finished interval. Never called with an empty batch.
+    fn flush(&self, records: Vec<UsageRecord>);
+}
+
+impl<F> UsageSink for F
+where
+    F: Fn(Vec<UsageRecord>) + Send + Sync + 'static,
+{
+    fn flush(&self, records: Vec<UsageRecord>) {
+        self(records)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl UsageSink for mpsc::Sender<Vec<UsageRecord>> {
+    fn flush(&self, records: Vec<UsageRecord>) {
+        if let Err(err) = self.try_send(records) {
+            tracing::warn!("Dropping usage records: {}", err);
+        }
+    }
+}
+
+/// Appends usage records to a file as JSON lines
+#[derive(Debug, Clone)]
+pub struct FileUsageSink {
+    path: PathBuf,
+}
+
+impl FileUsageSink {
+    /// Create a sink appending to the file at `path`, creating it if needed
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileUsageSink { path: path.into() }
+    }
+}
+
+impl UsageSink for FileUsageSink {
+    fn flush(&self, records: Vec<UsageRecord>) {
+        let unix_secs = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        };
+
+        let mut buf = Vec::new();
+        for record in &records {
+            let line = json!({
+                "key": record.key,
+                "count": record.count,
+                "window_start": unix_secs(record.window_start),
+                "window_end": unix_secs(record.window_end),
+            });
+            // writing into a Vec cannot fail
+            let _ = writeln!(buf, "{line}");
+        }
+
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&buf));
+        if let Err(err) = result {
+            tracing::error!(
+                "Failed to write usage records to {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Per-key counters of accepted requests, flushed periodically
+pub(crate) struct Accounting {
+    interval: Duration,
+    sink: Box<dyn UsageSink>,
+    /// Start of the current window, only touched by flushes
+    window_start: Mutex<SystemTime>,
+    /// Counts of the current window, sharded so accepted requests of
+    /// different keys don't contend
+    counts: DashMap<String, AtomicU64>,
+    started: StartOnce,
+}
+
+impl Accounting {
+    pub(crate) fn new(interval: Duration, sink: Box<dyn UsageSink>) -> Self {
+        Accounting {
+            interval,
+            sink,
+            window_start: Mutex::new(SystemTime::now()),
+            counts: DashMap::new(),
+            started: StartOnce::new(),
+        }
+    }
+
+    pub(crate) fn record(&self, key: &str) {
+        // known keys only take a read lock on their shard
+        match self.counts.get(key) {
+            Some(count) => count.fetch_add(1, Ordering::Relaxed),
+            None => self
+                .counts
+                .entry(key.to_owned())
+                .or_default()
+                .fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Close the current window and hand its records to the sink
+    pub(crate) fn flush(&self) {
+        let now = SystemTime::now();
+        let start = std::mem::replace(&mut *self.window_start.lock().unwrap(), now);
+        let mut records = Vec::new();
+        // keys idle for a whole window are dropped, the others keep their entry
+        self.counts.retain(|key, count| {
+            let count = count.swap(0, Ordering::Relaxed);
+            if count > 0 {
+                records.push(UsageRecord {
+                    key: key.clone(),
+                    count,
+                    window_start: start,
+                    window_end: now,
+                });
+            }
+            count > 0
+        });
+        if !records.is_empty() {
+            self.sink.flush(records);
+        }
+    }
+
+    /// Spawn the periodic flush task, once
+    pub(crate) fn start_if_needed(self: &Arc<Self>, runtime: &Arc<dyn Runtime>) {
+        self.started.call(|| {
+            let accounting: Weak<Self> = Arc::downgrade(self);
+            let interval = self.interval;
+            let timer = runtime.clone();
+            let mut shutdown = runtime.shutdown();
+            runtime.spawn(Box::pin(async move {
+                loop {
+                    let running = runtime::tick(&*timer, interval, &mut shutdown).await;
+                    let Some(accounting) = accounting.upgrade() else {
+                        return;
+                    };
+                    accounting.flush();
+                    if !running {
+                        return;
+                    }
+                }
+            }));
+        });
+    }
+}
+
+impl Drop for Accounting {
+    fn drop(&mut self) {
+        // don't lose the partial window when the policy goes away
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn test_accounting_flush() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let accounting = Accounting::new(Duration::from_secs(60), Box::new(tx));
+
+        accounting.record("alice");
+        accounting.record("bob");
+        accounting.record("alice");
+        accounting.flush();
+
+        let mut records = rx.try_recv().unwrap();
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].key.as_str(), records[0].count), ("alice", 2));
+        assert_eq!((records[1].key.as_str(), records[1].count), ("bob", 1));
+
+        // empty windows are not flushed
+        accounting.flush();
+        assert!(rx.try_recv().is_err());
+
+        // counts start over in every window
+        accounting.record("bob");
+        accounting.flush();
+        let records = rx.try_recv().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].key.as_str(), records[0].count), ("bob", 1));
+    }
+}
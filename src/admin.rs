@@ -0,0 +1,182 @@
+//! Admin HTTP API for runtime policy controls
+//!
+//! [`GovernorAdminService`] exposes the controls of a [`GovernorHandle`] over
+//! HTTP. It is always built behind an auth layer supplied by the application,
+//! so it can't be mounted unprotected by accident.
+//!
+//! Routes, answering `204 No Content` on success:
+//!
+//! - `POST /keys/{key}/reset`: forget the limiter state of `key`
+//! - `PUT /denylist/{key}` and `DELETE /denylist/{key}`: add or remove a denylist entry
+//! - `PUT /quota?replenish_interval_ms=..&burst_size=..`: replace the quota
+//! - `PUT /shadow` and `DELETE /shadow`: turn shadow mode on or off
+//!
+//! Keys are taken verbatim from the path segment, without percent-decoding.
+
+use std::convert::Infallible;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use governor::Quota;
+use rama_core::{Context, Layer, Service};
+use rama_http::{Body, Method, Request, Response, StatusCode};
+
+use crate::GovernorHandle;
+
+/// Service applying admin requests to a [`GovernorHandle`]
+#[derive(Debug, Clone)]
+pub struct GovernorAdminService {
+    handle: GovernorHandle,
+}
+
+/// Serve the admin API for `handle`, wrapped in the `auth` layer
+///
+/// `auth` decides who may change the policy, e.g. a bearer token check.
+pub fn governor_admin_service<L>(handle: GovernorHandle, auth: L) -> L::Service
+where
+    L: Layer<GovernorAdminService>,
+{
+    auth.layer(GovernorAdminService { handle })
+}
+
+impl GovernorAdminService {
+    fn route(&self, method: &Method, path: &str, query: &str) -> StatusCode {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (&Method::POST, ["keys", key, "reset"]) if !key.is_empty() => {
+                if self.handle.reset_key(key) {
+                    StatusCode::NO_CONTENT
+                } else {
+                    StatusCode::NOT_FOUND
+                }
+            }
+            (&Method::PUT, ["denylist", key]) if !key.is_empty() => {
+                self.handle.deny(*key);
+                StatusCode::NO_CONTENT
+            }
+            (&Method::DELETE, ["denylist", key]) => {
+                if self.handle.undeny(key) {
+                    StatusCode::NO_CONTENT
+                } else {
+                    StatusCode::NOT_FOUND
+                }
+            }
+            (&Method::PUT, ["quota"]) => match parse_quota(query) {
+                Some(quota) => {
+                    self.handle.set_quota(quota);
+                    StatusCode::NO_CONTENT
+                }
+                None => StatusCode::BAD_REQUEST,
+            },
+            (&Method::PUT, ["shadow"]) => {
+                self.handle.set_shadow(true);
+                StatusCode::NO_CONTENT
+            }
+            (&Method::DELETE, ["shadow"]) => {
+                self.handle.set_shadow(false);
+                StatusCode::NO_CONTENT
+            }
+            (_, ["keys", _, "reset"] | ["denylist", _] | ["quota"] | ["shadow"]) => {
+                StatusCode::METHOD_NOT_ALLOWED
+            }
+            _ => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+/// Parse `replenish_interval_ms` and `burst_size` (default 1) from a query string
+fn parse_quota(query: &str) -> Option<Quota> {
+    let mut interval = None;
+    let mut burst = NonZeroU32::MIN;
+    for pair in query.split('&') {
+        match pair.split_once('=')? {
+            ("replenish_interval_ms", ms) => interval = Some(ms.parse().ok()?),
+            ("burst_size", size) => burst = size.parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some(Quota::with_period(Duration::from_millis(interval?))?.allow_burst(burst))
+}
+
+impl<State, ReqBody> Service<State, Request<ReqBody>> for GovernorAdminService
+where
+    State: Clone + Send + Sync + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+
+    async fn serve(
+        &self,
+        _ctx: Context<State>,
+        req: Request<ReqBody>,
+    ) -> Result<Self::Response, Self::Error> {
+        let uri = req.uri();
+        let status = self.route(req.method(), uri.path(), uri.query().unwrap_or(""));
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GovernorPolicy;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+
+    struct NoAuth;
+
+    impl<S> Layer<S> for NoAuth {
+        type Service = S;
+
+        fn layer(&self, inner: S) -> S {
+            inner
+        }
+    }
+
+    async fn allowed(policy: &GovernorPolicy) -> bool {
+        let result = policy.check(Context::<()>::default(), ()).await;
+        matches!(result.output, PolicyOutput::Ready(_))
+    }
+
+    async fn call(service: &GovernorAdminService, method: Method, uri: &str) -> StatusCode {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        service
+            .serve(Context::default(), req)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_admin_service() {
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let service = governor_admin_service(policy.handle().clone(), NoAuth);
+
+        assert!(allowed(&policy).await);
+        assert!(!allowed(&policy).await);
+        let status = call(&service, Method::POST, "/keys/default/reset").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(allowed(&policy).await);
+
+        let status = call(&service, Method::PUT, "/shadow").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(allowed(&policy).await);
+
+        let status = call(&service, Method::PUT, "/denylist/default").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!allowed(&policy).await);
+
+        let status = call(&service, Method::PUT, "/quota?burst_size=2").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let status = call(&service, Method::GET, "/shadow").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,241 @@
+//! Audit trail of traffic-blocking decisions
+//!
+//! A policy built with [`audit`](crate::GovernorPolicyBuilder::audit) reports
+//! every rejected request and every change made through its
+//! [`GovernorHandle`](crate::GovernorHandle), like denylisting a key or
+//! engaging a lockdown, to an [`AuditSink`] as an [`AuditRecord`]. Unlike the
+//! sampled denial logs, every event is reported, so the sink can keep the
+//! trail compliance asks for.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+#[cfg(feature = "tokio")]
+use tokio::sync::mpsc;
+
+/// Why a request was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DenyReason {
+    /// The quota of the key was exhausted
+    RateLimit,
+    /// The lockdown clamp was exhausted
+    Lockdown,
+    /// The key is on the denylist
+    Denylist,
+    /// The instance is draining
+    Drain,
+    /// An upstream limited the key, see [`GovernorHandle::back_off`](crate::GovernorHandle::back_off)
+    Upstream,
+}
+
+/// A change of enforcement made through the handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideAction {
+    /// Enforcement was turned off
+    Disable,
+    /// Normal enforcement was restored, lifting any lockdown
+    Enable,
+    /// A lockdown clamp was engaged
+    Lockdown,
+    /// Draining traffic off the instance was started
+    Drain,
+    /// Shadow mode was turned on
+    ShadowOn,
+    /// Shadow mode was turned off
+    ShadowOff,
+    /// Mirror mode was turned on
+    MirrorOn,
+    /// Mirror mode was turned off
+    MirrorOff,
+    /// The quota was replaced
+    SetQuota,
+    /// The state of a key was reset
+    ResetKey,
+    /// Tracing of every decision on a key was turned on
+    TraceKey,
+    /// A quota override was set for a limited time
+    TemporaryQuota,
+    /// A temporary quota override lapsed or was cancelled, and the regular
+    /// quota applies again
+    TemporaryQuotaExpired,
+}
+
+/// What happened
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A request was rejected
+    Denied {
+        /// Why
+        reason: DenyReason,
+        /// When retrying may succeed, if known
+        retry_after: Option<Duration>,
+    },
+    /// The key was put on the denylist
+    Banned,
+    /// The key was taken off the denylist
+    Unbanned,
+    /// A request skipped all checks with the bypass token
+    Bypassed,
+    /// Enforcement was changed at runtime
+    Override {
+        /// The change
+        action: OverrideAction,
+    },
+}
+
+/// A single audited event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    /// When the event happened
+    pub time: SystemTime,
+    /// Name of the policy
+    pub policy: String,
+    /// The key concerned, for events about a single key
+    pub key: Option<String>,
+    /// The event
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// Destination for audit records
+///
+/// Called on the request path for denials, so implementations should hand
+/// records off rather than block. Implemented for closures taking an
+/// [`AuditRecord`], for bounded tokio channels (with the `tokio` feature),
+/// and by [`TracingAuditSink`].
+pub trait AuditSink: Send + Sync + 'static {
+    /// Receive one record
+    fn record(&self, record: AuditRecord);
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(AuditRecord) + Send + Sync + 'static,
+{
+    fn record(&self, record: AuditRecord) {
+        self(record)
+    }
+}
+
+/// Drops records while the channel is full, logging a warning, so a slow
+/// consumer can't stall requests
+#[cfg(feature = "tokio")]
+impl AuditSink for mpsc::Sender<AuditRecord> {
+    fn record(&self, record: AuditRecord) {
+        if let Err(err) = self.try_send(record) {
+            tracing::warn!("Dropping audit record: {}", err);
+        }
+    }
+}
+
+/// Logs records as JSON through `tracing`, under the `rama_x_governor::audit`
+/// target so they can be routed apart from debug logs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingAuditSink;
+
+impl AuditSink for TracingAuditSink {
+    fn record(&self, record: AuditRecord) {
+        match serde_json::to_string(&record) {
+            Ok(json) => tracing::info!(target: "rama_x_governor::audit", "{json}"),
+            Err(err) => tracing::error!("Failed to serialize audit record: {}", err),
+        }
+    }
+}
+
+/// Audit sink of a policy, tagging records with the policy name
+pub(crate) struct Auditor {
+    policy: Arc<str>,
+    sink: Arc<dyn AuditSink>,
+}
+
+impl Auditor {
+    pub(crate) fn new(policy: &str, sink: Arc<dyn AuditSink>) -> Self {
+        Auditor {
+            policy: policy.into(),
+            sink,
+        }
+    }
+
+    pub(crate) fn record(&self, key: Option<&str>, event: AuditEvent) {
+        self.sink.record(AuditRecord {
+            time: SystemTime::now(),
+            policy: self.policy.to_string(),
+            key: key.map(str::to_owned),
+            event,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use crate::{GovernorPolicy, Limiter};
+
+    #[tokio::test]
+    async fn test_audit_trail() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let limiter = Limiter::from(
+            GovernorPolicy::builder()
+                .per_minute(1)
+                .name("login")
+                .audit(move |record: AuditRecord| sink.lock().unwrap().push(record))
+                .build_with_keyer(|key: &str| key.to_owned()),
+        );
+        let handle = limiter.policy().handle();
+
+        handle.deny("mallory");
+        let _ = limiter.check_key("mallory").await;
+        limiter.check_key("alice").await.unwrap();
+        let _ = limiter.check_key("alice").await;
+        handle.disable();
+
+        let records = records.lock().unwrap();
+        let events: Vec<_> = records
+            .iter()
+            .map(|record| (record.key.as_deref(), record.event.clone()))
+            .collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], (Some("mallory"), AuditEvent::Banned));
+        assert_eq!(
+            events[1],
+            (
+                Some("mallory"),
+                AuditEvent::Denied {
+                    reason: DenyReason::Denylist,
+                    retry_after: None
+                }
+            )
+        );
+        assert!(matches!(
+            events[2],
+            (
+                Some("alice"),
+                AuditEvent::Denied {
+                    reason: DenyReason::RateLimit,
+                    retry_after: Some(_)
+                }
+            )
+        ));
+        assert_eq!(
+            events[3],
+            (
+                None,
+                AuditEvent::Override {
+                    action: OverrideAction::Disable
+                }
+            )
+        );
+        assert!(records.iter().all(|record| record.policy == "login"));
+
+        let json = serde_json::to_value(&records[1]).unwrap();
+        assert_eq!(json["event"], "denied");
+        assert_eq!(json["reason"], "denylist");
+    }
+}
@@ -0,0 +1,138 @@
+//! Rollover of unused quota into a burst bank
+//!
+//! A policy built with [`burst_bank`](crate::GovernorPolicyBuilder::burst_bank)
+//! keeps a credit account per key next to its regular quota. Capacity that
+//! would be lost because the regular bucket is already full is credited to
+//! the account instead, up to a cap, and a request the regular quota rejects
+//! is let through if it can be paid with a credit. A mostly idle client can
+//! so occasionally run a batch larger than the burst size.
+//!
+//! The regular bucket is tracked through the [`RateLimitInfo`] of allowed
+//! requests, so only in-memory policies have a bank.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use dashmap::DashMap;
+use governor::Quota;
+
+use crate::RateLimitInfo;
+
+/// Drop accounts whose bank is full again every this many new keys
+const GC_EVERY: u64 = 4096;
+
+#[derive(Debug, Clone, Copy)]
+struct Account {
+    /// Nanoseconds since the start of the bank at which the regular bucket
+    /// is full, or was last looked at while full
+    full_at: u64,
+    /// Overflow credited so far, in nanoseconds of replenish time
+    banked: u64,
+}
+
+/// Credit accounts per key
+#[derive(Debug)]
+pub struct BurstBank {
+    cap: u32,
+    accounts: DashMap<String, Account>,
+    inserts: AtomicU64,
+    start: Instant,
+}
+
+impl BurstBank {
+    pub(crate) fn new(cap: u32) -> Self {
+        BurstBank {
+            cap,
+            accounts: DashMap::new(),
+            inserts: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    /// Replenish interval and bank capacity of `quota`, in nanoseconds
+    fn limits(&self, quota: Quota) -> (u64, u64) {
+        let t = (quota.replenish_interval().as_nanos() as u64).max(1);
+        (t, t.saturating_mul(u64::from(self.cap)))
+    }
+
+    /// Run `f` on the account of `key` with the overflow up to `now` credited
+    ///
+    /// Keys without an account start with a full bank, like a new key starts
+    /// with a full burst, which lets accounts with a full bank be dropped.
+    fn with_account<T>(
+        &self,
+        key: &str,
+        quota: Quota,
+        f: impl FnOnce(&mut Account, u64, u64) -> T,
+    ) -> T {
+        let now = self.now();
+        let (t, capacity) = self.limits(quota);
+        let mut inserted = false;
+        let result = {
+            let mut account = self.accounts.entry(key.to_owned()).or_insert_with(|| {
+                inserted = true;
+                Account {
+                    full_at: now,
+                    banked: capacity,
+                }
+            });
+            if now > account.full_at {
+                account.banked = (account.banked + (now - account.full_at)).min(capacity);
+                account.full_at = now;
+            }
+            f(&mut account, now, t)
+        };
+
+        if inserted && self.inserts.fetch_add(1, Ordering::Relaxed) % GC_EVERY == GC_EVERY - 1 {
+            self.accounts.retain(|_, account| {
+                account.banked + now.saturating_sub(account.full_at) < capacity
+            });
+        }
+        result
+    }
+
+    /// Track the regular bucket of `key` after an allowed request
+    pub(crate) fn allowed(&self, key: &str, quota: Quota, info: &RateLimitInfo) {
+        self.with_account(key, quota, |account, now, t| {
+            account.full_at = now + t * u64::from(info.limit - info.remaining);
+        });
+    }
+
+    /// Pay a request the regular quota rejected with a credit, if there is one
+    pub(crate) fn withdraw(&self, key: &str, quota: Quota) -> bool {
+        self.with_account(key, quota, |account, _, t| {
+            let paid = account.banked >= t;
+            if paid {
+                account.banked -= t;
+            }
+            paid
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{GovernorError, GovernorPolicy, Limiter};
+
+    #[tokio::test]
+    async fn test_burst_bank() {
+        let limiter = Limiter::from(
+            GovernorPolicy::builder()
+                .per_minute(1)
+                .burst_bank(2)
+                .build_with_keyer(|key: &str| key.to_owned()),
+        );
+        // the regular burst of one, then two banked credits
+        for _ in 0..3 {
+            limiter.check_key("batch").await.unwrap();
+        }
+        assert!(matches!(
+            limiter.check_key("batch").await,
+            Err(GovernorError::RateLimited(_))
+        ));
+    }
+}
@@ -0,0 +1,83 @@
+//! Charging requests by the size of their body
+//!
+//! A policy built with [`max_body_cost`](crate::GovernorPolicyBuilder::max_body_cost)
+//! charges each request one cell per so many bytes its `Content-Length`
+//! declares, and at least one, so one quota limits both the request count
+//! and the ingress bytes of a key. The charge is made at admission, before
+//! the body is read.
+
+use std::any::Any;
+use std::num::{NonZeroU32, NonZeroU64};
+
+use crate::exempt::{declared_length, http_request};
+
+/// Cells charged per request, by declared body size
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BodyCost {
+    bytes_per_cell: NonZeroU64,
+}
+
+impl BodyCost {
+    pub(crate) fn new(bytes_per_cell: NonZeroU64) -> Self {
+        BodyCost { bytes_per_cell }
+    }
+
+    /// Cells to charge for `request`
+    ///
+    /// Requests without a declared length, e.g. chunked uploads, and requests
+    /// that aren't HTTP requests with rama's default body type cost one cell.
+    pub(crate) fn of(&self, request: &dyn Any) -> NonZeroU32 {
+        http_request(request)
+            .and_then(declared_length)
+            .map_or(NonZeroU32::MIN, |len| self.of_len(len))
+    }
+
+    /// Cells to charge for `len` bytes, at least one
+    pub(crate) fn of_len(&self, len: u64) -> NonZeroU32 {
+        let cells = len.div_ceil(self.bytes_per_cell.get());
+        NonZeroU32::new(u32::try_from(cells).unwrap_or(u32::MAX)).unwrap_or(NonZeroU32::MIN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+    use rama_http::{Body, Request, header};
+
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_body_cost() {
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .burst_size(10)
+            .max_body_cost(1024)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let upload = |len: u64| {
+            Request::builder()
+                .header(header::CONTENT_LENGTH, len)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let admitted = |len| {
+            let result = policy.check(Context::<()>::default(), upload(len));
+            async { matches!(result.await.output, PolicyOutput::Ready(_)) }
+        };
+
+        // 4 KiB and a tiny request, then the 6 cells of 5 KiB and a byte
+        // exceed the 5 left
+        assert!(admitted(4096).await);
+        assert!(admitted(10).await);
+        assert!(!admitted(5121).await);
+        assert!(admitted(4096).await);
+        // bodies beyond the burst are charged a full burst
+        let fresh = GovernorPolicy::builder()
+            .per_minute(1)
+            .burst_size(10)
+            .max_body_cost(1024)
+            .build();
+        let result = fresh.check(Context::<()>::default(), upload(1 << 30)).await;
+        assert!(matches!(result.output, PolicyOutput::Ready(_)));
+    }
+}
@@ -0,0 +1,176 @@
+//! Keying on a hash of the request body
+//!
+//! Spam campaigns send identical payloads from many addresses, so per-IP
+//! limits don't catch them. [`BodyHashLayer`] buffers small request bodies and
+//! stores their hash in the [`Context`] as [`BodyHash`], which [`BodyHashKey`]
+//! then turns into a limiter key. Mount the layer in front of the limit layer:
+//!
+//! ```ignore
+//! (
+//!     BodyHashLayer::new(64 * 1024),
+//!     LimitLayer::new(ExtractingPolicy::new(policy, BodyHashKey::new())),
+//! )
+//! ```
+
+use std::hash::Hasher;
+
+use crate::KeyExtractor;
+use crate::extract::client_ip;
+use crate::stable_hash::stable_hasher;
+use rama_core::error::BoxError;
+use rama_core::{Context, Layer, Service};
+use rama_http::dep::http_body_util::BodyExt;
+use rama_http::{Body, Request, header};
+
+/// Context extension holding the hash of the request body
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BodyHash(pub u64);
+
+/// Layer hashing request bodies of up to a maximum length
+///
+/// Only bodies with a `Content-Length` of at most the maximum are buffered and
+/// hashed; other requests pass through untouched and get no [`BodyHash`].
+#[derive(Debug, Clone, Copy)]
+pub struct BodyHashLayer {
+    max_len: u64,
+}
+
+impl BodyHashLayer {
+    /// Hash bodies of at most `max_len` bytes
+    pub fn new(max_len: u64) -> Self {
+        BodyHashLayer { max_len }
+    }
+}
+
+impl<S> Layer<S> for BodyHashLayer {
+    type Service = BodyHashService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BodyHashService {
+            inner,
+            max_len: self.max_len,
+        }
+    }
+}
+
+/// Service created by [`BodyHashLayer`]
+#[derive(Debug, Clone)]
+pub struct BodyHashService<S> {
+    inner: S,
+    max_len: u64,
+}
+
+impl<S, State> Service<State, Request> for BodyHashService<S>
+where
+    S: Service<State, Request, Error: Into<BoxError>>,
+    State: Clone + Send + Sync + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+
+    async fn serve(
+        &self,
+        mut ctx: Context<State>,
+        req: Request,
+    ) -> Result<Self::Response, Self::Error> {
+        let content_length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        if content_length.is_none_or(|len| len > self.max_len) {
+            return self.inner.serve(ctx, req).await.map_err(Into::into);
+        }
+
+        let (parts, body) = req.into_parts();
+        let bytes = body.collect().await?.to_bytes();
+        let mut hasher = stable_hasher();
+        hasher.write(&bytes);
+        ctx.insert(BodyHash(hasher.finish()));
+
+        let req = Request::from_parts(parts, Body::from(bytes));
+        self.inner.serve(ctx, req).await.map_err(Into::into)
+    }
+}
+
+/// Keys requests by their [`BodyHash`], optionally per client IP
+///
+/// Requests without a body hash have no key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BodyHashKey {
+    per_client_ip: bool,
+}
+
+impl BodyHashKey {
+    /// Key by body hash alone, limiting a payload across all senders
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Key by body hash and client IP, limiting each sender of a payload
+    pub fn per_client_ip(mut self) -> Self {
+        self.per_client_ip = true;
+        self
+    }
+}
+
+impl<State, ReqBody> KeyExtractor<State, Request<ReqBody>> for BodyHashKey {
+    fn extract(&self, ctx: &Context<State>, _req: &Request<ReqBody>) -> Option<String> {
+        let BodyHash(hash) = ctx.get::<BodyHash>()?;
+        if !self.per_client_ip {
+            return Some(format!("{hash:016x}"));
+        }
+        let ip = client_ip(ctx)?;
+        Some(format!("{ip}/{hash:016x}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rama_net::stream::SocketInfo;
+    use std::convert::Infallible;
+
+    /// Answers with the key the extractor derives
+    struct EchoKey(BodyHashKey);
+
+    impl Service<(), Request> for EchoKey {
+        type Response = Option<String>;
+        type Error = Infallible;
+
+        async fn serve(
+            &self,
+            ctx: Context<()>,
+            req: Request,
+        ) -> Result<Option<String>, Infallible> {
+            Ok(self.0.extract(&ctx, &req))
+        }
+    }
+
+    async fn key_of(extractor: BodyHashKey, body: &'static str, peer: &str) -> Option<String> {
+        let service = BodyHashLayer::new(16).layer(EchoKey(extractor));
+        let mut ctx = Context::default();
+        ctx.insert(SocketInfo::new(None, peer.parse().unwrap()));
+        let req = Request::builder()
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap();
+        service.serve(ctx, req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_body_hash_key() {
+        let by_body = BodyHashKey::new();
+        let a = key_of(by_body, "buy now", "10.0.0.1:1000").await;
+        let b = key_of(by_body, "buy now", "10.0.0.2:1000").await;
+        assert!(a.is_some() && a == b);
+        assert_eq!(
+            key_of(by_body, "a body over the limit", "10.0.0.1:1000").await,
+            None
+        );
+
+        let per_ip = BodyHashKey::new().per_client_ip();
+        let a = key_of(per_ip, "buy now", "10.0.0.1:1000").await;
+        let b = key_of(per_ip, "buy now", "10.0.0.2:1000").await;
+        assert_ne!(a, b);
+    }
+}
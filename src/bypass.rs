@@ -0,0 +1,101 @@
+//! Break-glass bypass of a policy for internal tooling
+//!
+//! A policy built with [`bypass_token`](crate::GovernorPolicyBuilder::bypass_token)
+//! lets HTTP requests carrying the secret in the [`BYPASS_TOKEN`] header
+//! through without any checks, including the denylist and lockdown. Unlike
+//! address allowlists, this keeps working when NAT ranges change. Every
+//! bypass is reported to the audit sink of the policy.
+
+use std::any::Any;
+use std::fmt;
+
+use rama_http::HeaderName;
+
+use crate::exempt::http_request;
+
+/// The `X-RateLimit-Bypass` header carrying the bypass token
+pub const BYPASS_TOKEN: HeaderName = HeaderName::from_static("x-ratelimit-bypass");
+
+/// Secret letting requests bypass a policy
+pub(crate) struct BypassToken {
+    secret: Box<[u8]>,
+}
+
+impl BypassToken {
+    pub(crate) fn new(secret: impl Into<String>) -> Self {
+        BypassToken {
+            secret: secret.into().into_bytes().into(),
+        }
+    }
+
+    /// Whether `request` is an HTTP request carrying the secret
+    pub(crate) fn matches(&self, request: &dyn Any) -> bool {
+        let Some(request) = http_request(request) else {
+            return false;
+        };
+        request
+            .headers()
+            .get_all(&BYPASS_TOKEN)
+            .iter()
+            .any(|token| constant_time_eq(token.as_bytes(), &self.secret))
+    }
+}
+
+impl fmt::Debug for BypassToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BypassToken").finish_non_exhaustive()
+    }
+}
+
+/// Compare without returning early, so the time taken doesn't tell how much
+/// of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+    use rama_http::{Body, Request};
+
+    use super::*;
+    use crate::{AuditEvent, AuditRecord, GovernorPolicy};
+
+    #[tokio::test]
+    async fn test_bypass_token() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .bypass_token("s3cret")
+            .audit(move |record: AuditRecord| sink.lock().unwrap().push(record))
+            .build();
+        let request = |token: Option<&str>| {
+            let mut builder = Request::builder();
+            if let Some(token) = token {
+                builder = builder.header(BYPASS_TOKEN, token);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let mut admitted = 0;
+        for token in [None, None, Some("s3cret"), Some("s3cret"), Some("guess!")] {
+            let result = policy.check(Context::default(), request(token)).await;
+            admitted += matches!(result.output, PolicyOutput::Ready(_)) as usize;
+        }
+        assert_eq!(admitted, 3);
+
+        let records = records.lock().unwrap();
+        let bypasses = records
+            .iter()
+            .filter(|record| record.event == AuditEvent::Bypassed)
+            .count();
+        assert_eq!(bypasses, 2);
+    }
+}
@@ -0,0 +1,182 @@
+//! Fault injection for resilience tests
+//!
+//! [`ChaosPolicy`] wraps a policy and, at configurable probabilities, delays
+//! requests, rejects them as rate limited or fails them like an unreachable
+//! store would. Put it in a test stack to check that clients handle 429s and
+//! that the application copes with limiter failures. Not meant for
+//! production traffic.
+
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use rama_core::Context;
+use rama_core::layer::limit::policy::{Policy, PolicyOutput, PolicyResult};
+
+use crate::runtime::{self, Runtime};
+use crate::{GovernorError, GovernorGuard, Rejected, RejectionResponse, StoreError};
+
+/// Name injected rejections are reported under
+const CHAOS_POLICY: &str = "chaos";
+
+/// Policy injecting latency, denials and failures into a wrapped policy
+pub struct ChaosPolicy<P> {
+    inner: P,
+    deny: f64,
+    error: f64,
+    latency: f64,
+    delay: Duration,
+    rng: AtomicU64,
+    runtime: Option<Arc<dyn Runtime>>,
+}
+
+impl<P> ChaosPolicy<P> {
+    /// Wrap `inner`, injecting nothing until probabilities are set
+    pub fn new(inner: P) -> Self {
+        ChaosPolicy {
+            inner,
+            deny: 0.0,
+            error: 0.0,
+            latency: 0.0,
+            delay: Duration::ZERO,
+            rng: AtomicU64::new(RandomState::new().hash_one(0u64)),
+            runtime: runtime::default_runtime(),
+        }
+    }
+
+    /// Reject requests as rate limited with probability `p`
+    pub fn deny_probability(mut self, p: f64) -> Self {
+        self.deny = p;
+        self
+    }
+
+    /// Fail requests with a store error with probability `p`
+    pub fn error_probability(mut self, p: f64) -> Self {
+        self.error = p;
+        self
+    }
+
+    /// Delay requests by `delay` with probability `p`
+    ///
+    /// Waits on the [`Runtime`]; without one no latency is added.
+    pub fn latency(mut self, p: f64, delay: Duration) -> Self {
+        self.latency = p;
+        self.delay = delay;
+        self
+    }
+
+    /// Make the injected faults reproducible
+    pub fn seed(self, seed: u64) -> Self {
+        self.rng.store(seed, Ordering::Relaxed);
+        self
+    }
+
+    /// Wait on `runtime` instead of tokio
+    pub fn runtime(mut self, runtime: impl Runtime) -> Self {
+        self.runtime = Some(Arc::new(runtime));
+        self
+    }
+
+    /// The wrapped policy
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Returns true with probability `p`
+    fn roll(&self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        // splitmix64
+        let mut z = self
+            .rng
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+impl<P> fmt::Debug for ChaosPolicy<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChaosPolicy")
+            .field("deny", &self.deny)
+            .field("error", &self.error)
+            .field("latency", &self.latency)
+            .field("delay", &self.delay)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P, State, Request> Policy<State, Request> for ChaosPolicy<P>
+where
+    P: Policy<State, Request, Guard = GovernorGuard, Error = GovernorError>,
+    State: Clone + Send + Sync + 'static,
+    Request: Send + Sync + 'static,
+{
+    type Guard = GovernorGuard;
+    type Error = GovernorError;
+
+    async fn check(
+        &self,
+        ctx: Context<State>,
+        request: Request,
+    ) -> PolicyResult<State, Request, Self::Guard, Self::Error> {
+        if let Some(runtime) = &self.runtime {
+            if self.roll(self.latency) {
+                runtime.sleep(self.delay).await;
+            }
+        }
+        let injected = if self.roll(self.deny) {
+            Some(GovernorError::RateLimited(Rejected::new(
+                CHAOS_POLICY.into(),
+                None,
+                Arc::new(RejectionResponse::default()),
+            )))
+        } else if self.roll(self.error) {
+            Some(GovernorError::StoreUnavailable(StoreError::new(
+                "injected store failure",
+            )))
+        } else {
+            None
+        };
+        match injected {
+            Some(err) => PolicyResult {
+                ctx,
+                request,
+                output: PolicyOutput::Abort(err),
+            },
+            None => self.inner.check(ctx, request).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_chaos_policy() {
+        let policy = ChaosPolicy::new(GovernorPolicy::builder().per_second(1000).build())
+            .deny_probability(0.3)
+            .error_probability(0.2)
+            .seed(7);
+
+        let (mut denied, mut failed) = (0, 0);
+        for _ in 0..1000 {
+            match policy.check(Context::default(), ()).await.output {
+                PolicyOutput::Abort(GovernorError::RateLimited(_)) => denied += 1,
+                PolicyOutput::Abort(GovernorError::StoreUnavailable(_)) => failed += 1,
+                _ => {}
+            }
+        }
+        // 30% denied, and 20% of the remaining 70% failed
+        assert!((250..350).contains(&denied), "{denied}");
+        assert!((100..180).contains(&failed), "{failed}");
+    }
+}
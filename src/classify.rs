@@ -0,0 +1,169 @@
+//! Client classification for bot tiers
+//!
+//! A [`ClientClassifier`] sorts requests into [`ClientClass`]es, and a
+//! [`ClassSelector`] checks each class against its own policy. This gives
+//! search engine crawlers an explicit crawl budget while unidentified
+//! automation gets squeezed.
+
+use std::collections::HashMap;
+
+use rama_core::Context;
+use rama_http::{Request, header};
+
+use crate::{GovernorPolicy, PolicySelector};
+
+/// Kind of client behind a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientClass {
+    /// A crawler whose identity was verified, e.g. through reverse DNS
+    VerifiedBot,
+    /// A regular web browser
+    Browser,
+    /// Scripts, libraries and self-declared bots
+    Automation,
+    /// Anything that couldn't be classified
+    Unknown,
+}
+
+/// Sorts requests into client classes
+pub trait ClientClassifier<Request>: Send + Sync + 'static {
+    /// The class of the client that sent `req`
+    fn classify(&self, req: &Request) -> ClientClass;
+}
+
+impl<Request, F> ClientClassifier<Request> for F
+where
+    F: Fn(&Request) -> ClientClass + Send + Sync + 'static,
+{
+    fn classify(&self, req: &Request) -> ClientClass {
+        self(req)
+    }
+}
+
+/// Lowercase `User-Agent` fragments of HTTP libraries, tools and bots
+const AUTOMATION_TOKENS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "curl",
+    "wget",
+    "python",
+    "go-http-client",
+    "java/",
+    "okhttp",
+    "libwww",
+    "headless",
+];
+
+/// Classifies HTTP requests by their `User-Agent` header
+///
+/// The header is trivially spoofed, so this never yields
+/// [`ClientClass::VerifiedBot`]; wrap it in a classifier that verifies
+/// crawler claims to hand out crawl budgets.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserAgentClassifier;
+
+impl<Body> ClientClassifier<Request<Body>> for UserAgentClassifier {
+    fn classify(&self, req: &Request<Body>) -> ClientClass {
+        let Some(agent) = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return ClientClass::Unknown;
+        };
+        let agent = agent.to_ascii_lowercase();
+        if AUTOMATION_TOKENS.iter().any(|token| agent.contains(token)) {
+            ClientClass::Automation
+        } else if agent.starts_with("mozilla/") {
+            ClientClass::Browser
+        } else {
+            ClientClass::Unknown
+        }
+    }
+}
+
+/// Selects the policy by the class of the client
+///
+/// Classes without a policy of their own use the default policy.
+pub struct ClassSelector<C> {
+    classifier: C,
+    policies: HashMap<ClientClass, GovernorPolicy>,
+    default: GovernorPolicy,
+}
+
+impl<C> ClassSelector<C> {
+    /// Classify clients with `classifier`, using `default` unless configured otherwise
+    pub fn new(classifier: C, default: GovernorPolicy) -> Self {
+        ClassSelector {
+            classifier,
+            policies: HashMap::new(),
+            default,
+        }
+    }
+
+    /// Use `policy` for clients of `class`
+    pub fn class(mut self, class: ClientClass, policy: GovernorPolicy) -> Self {
+        self.policies.insert(class, policy);
+        self
+    }
+}
+
+impl<C> std::fmt::Debug for ClassSelector<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClassSelector")
+            .field("classes", &self.policies.keys())
+            .finish()
+    }
+}
+
+impl<C, State, Request> PolicySelector<State, Request> for ClassSelector<C>
+where
+    C: ClientClassifier<Request>,
+{
+    fn select(&self, _ctx: &Context<State>, req: &Request) -> &GovernorPolicy {
+        let class = self.classifier.classify(req);
+        self.policies.get(&class).unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rama_http::Body;
+
+    fn request(agent: &str) -> Request {
+        Request::builder()
+            .header(header::USER_AGENT, agent)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_class_selector() {
+        let browser = request("Mozilla/5.0 (X11; Linux x86_64) Firefox/128.0");
+        let crawler = request("Mozilla/5.0 (compatible; Googlebot/2.1)");
+        let script = request("python-requests/2.32");
+        assert_eq!(UserAgentClassifier.classify(&browser), ClientClass::Browser);
+        assert_eq!(
+            UserAgentClassifier.classify(&crawler),
+            ClientClass::Automation
+        );
+        assert_eq!(
+            UserAgentClassifier.classify(&script),
+            ClientClass::Automation
+        );
+
+        let selector = ClassSelector::new(
+            UserAgentClassifier,
+            GovernorPolicy::builder().per_second(10).build(),
+        )
+        .class(
+            ClientClass::Automation,
+            GovernorPolicy::builder().per_second(1).build(),
+        );
+        let ctx = Context::default();
+        let interval = |req: &Request| selector.select(&ctx, req).status().replenish_interval_ms;
+        assert_eq!((interval(&browser), interval(&script)), (100, 1000));
+    }
+}
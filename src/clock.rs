@@ -0,0 +1,66 @@
+//! Clock selection and the direct limiter built on it
+//!
+//! With the `quanta` feature (on by default) limiters use governor's
+//! TSC-based clock. Without it they fall back to [`SystemClock`], which only
+//! needs `SystemTime` and so also works on wasm and edge runtimes.
+
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use governor::clock::Clock;
+use governor::middleware::{StateInformationMiddleware, StateSnapshot};
+use governor::state::{InMemoryState, NotKeyed, RateLimiter};
+use governor::{InsufficientCapacity, NotUntil, Quota};
+use once_cell::sync::Lazy;
+
+#[cfg(feature = "quanta")]
+use governor::clock::QuantaClock;
+#[cfg(not(feature = "quanta"))]
+use governor::clock::SystemClock;
+
+/// Clock used by all limiters of this crate
+#[cfg(feature = "quanta")]
+pub(crate) type LimiterClock = QuantaClock;
+/// Clock used by all limiters of this crate
+#[cfg(not(feature = "quanta"))]
+pub(crate) type LimiterClock = SystemClock;
+
+/// Point in time as reported by [`LimiterClock`]
+pub(crate) type LimiterInstant = <LimiterClock as Clock>::Instant;
+
+/// Direct (non-keyed) rate limiter on [`LimiterClock`]
+pub(crate) struct DirectLimiter {
+    limiter: RateLimiter<NotKeyed, InMemoryState, LimiterClock, StateInformationMiddleware>,
+    quota: Quota,
+}
+
+impl DirectLimiter {
+    pub(crate) fn new(quota: Quota) -> Self {
+        DirectLimiter {
+            limiter: RateLimiter::direct_with_clock(quota, &LimiterClock::default())
+                .with_middleware::<StateInformationMiddleware>(),
+            quota,
+        }
+    }
+
+    pub(crate) fn check(&self) -> Result<StateSnapshot, NotUntil<LimiterInstant>> {
+        self.limiter.check()
+    }
+
+    pub(crate) fn check_n(
+        &self,
+        n: NonZeroU32,
+    ) -> Result<Result<StateSnapshot, NotUntil<LimiterInstant>>, InsufficientCapacity> {
+        self.limiter.check_n(n)
+    }
+
+    pub(crate) fn quota(&self) -> Quota {
+        self.quota
+    }
+}
+
+/// Time until a rejected request could be retried
+pub(crate) fn wait_time(not_until: &NotUntil<LimiterInstant>) -> Duration {
+    static CLOCK: Lazy<LimiterClock> = Lazy::new(LimiterClock::default);
+    not_until.wait_time_from(CLOCK.now())
+}
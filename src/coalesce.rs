@@ -0,0 +1,328 @@
+//! Coalescing identical requests of over-limit clients
+//!
+//! Abusive pollers request the same resource over and over. Rejecting them
+//! is cheap, but they keep retrying; serving them a response someone already
+//! paid for is just as cheap and ends the retry loop. [`CoalesceLayer`] lets
+//! the `GET` requests a policy in mirror mode tagged with
+//! [`RateLimitExceeded`] share one response per resource: the first goes
+//! through, identical ones arriving while it is in flight wait for its
+//! response, and later ones get a copy of it for a short time to live. Mount
+//! it behind the limit layer:
+//!
+//! ```ignore
+//! (
+//!     LimitLayer::new(GovernorPolicy::builder().per_second(5).mirror(true).build()),
+//!     CoalesceLayer::new(Duration::from_secs(2)),
+//! )
+//! ```
+//!
+//! Requests are identical if they are for the same host, path and query,
+//! ignoring a trailing slash. Requests carrying credentials are never
+//! coalesced, and responses setting cookies or marked `private` or `no-store`
+//! are never shared, so one client can't receive another's private data.
+//! Only successful responses with a `Content-Length` of at most a limit are
+//! shared; others are passed through, and the waiting requests are served on
+//! their own.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use rama_core::error::BoxError;
+use rama_core::{Context, Layer, Service};
+use rama_http::{Request, Response};
+use tokio::sync::watch;
+
+use crate::RateLimitExceeded;
+use crate::stale::{CachedResponse, DEFAULT_MAX_BODY_LEN, is_keepable, resource};
+
+/// Drop expired entries every this many insertions
+const GC_EVERY: u64 = 256;
+
+#[derive(Debug)]
+enum Slot {
+    /// A request for the resource is being served
+    InFlight(watch::Receiver<Option<Arc<CachedResponse>>>),
+    /// The response to the resource, shared until it expires
+    Ready(Arc<CachedResponse>, Instant),
+}
+
+/// Layer coalescing identical `GET` requests of over-limit clients
+#[derive(Debug, Clone)]
+pub struct CoalesceLayer {
+    ttl: Duration,
+    max_body_len: u64,
+}
+
+impl CoalesceLayer {
+    /// Share responses with identical requests arriving within `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        CoalesceLayer {
+            ttl,
+            max_body_len: DEFAULT_MAX_BODY_LEN,
+        }
+    }
+
+    /// Share responses with bodies of at most `max_body_len` bytes, 64 KiB by default
+    pub fn max_body_len(mut self, max_body_len: u64) -> Self {
+        self.max_body_len = max_body_len;
+        self
+    }
+}
+
+impl<S> Layer<S> for CoalesceLayer {
+    type Service = CoalesceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CoalesceService {
+            inner,
+            ttl: self.ttl,
+            max_body_len: self.max_body_len,
+            slots: Arc::default(),
+            inserts: Arc::default(),
+        }
+    }
+}
+
+/// Service created by [`CoalesceLayer`]
+///
+/// Clones share their responses.
+#[derive(Debug, Clone)]
+pub struct CoalesceService<S> {
+    inner: S,
+    ttl: Duration,
+    max_body_len: u64,
+    slots: Arc<DashMap<String, Slot>>,
+    inserts: Arc<AtomicU64>,
+}
+
+impl<S> CoalesceService<S> {
+    /// Join the response to `resource`, or become the request serving it
+    fn join(&self, resource: &str) -> Result<Joined, watch::Sender<Option<Arc<CachedResponse>>>> {
+        let now = Instant::now();
+        let mut entry = match self.slots.entry(resource.to_owned()) {
+            Entry::Occupied(entry) => entry,
+            Entry::Vacant(entry) => {
+                let (sender, receiver) = watch::channel(None);
+                entry.insert(Slot::InFlight(receiver));
+                self.inserted(now);
+                return Err(sender);
+            }
+        };
+        match entry.get() {
+            Slot::Ready(shared, expires) if *expires > now => {
+                return Ok(Joined::Ready(shared.clone()));
+            }
+            Slot::InFlight(receiver) if receiver.has_changed().is_ok() => {
+                return Ok(Joined::InFlight(receiver.clone()));
+            }
+            // expired, or its request was dropped before completing
+            _ => {}
+        }
+        let (sender, receiver) = watch::channel(None);
+        entry.insert(Slot::InFlight(receiver));
+        Err(sender)
+    }
+
+    /// Give up serving `resource` to the requests waiting for `sender`
+    ///
+    /// Only the slot of `sender` is removed: once it expired or was dropped,
+    /// a newer request may be serving the resource in its place.
+    fn release(&self, resource: &str, sender: watch::Sender<Option<Arc<CachedResponse>>>) {
+        let own = sender.subscribe();
+        self.slots.remove_if(
+            resource,
+            |_, slot| matches!(slot, Slot::InFlight(receiver) if receiver.same_channel(&own)),
+        );
+        // dropping the sender lets the waiting requests serve themselves
+        drop(sender);
+    }
+
+    fn inserted(&self, now: Instant) {
+        if self.inserts.fetch_add(1, Ordering::Relaxed) % GC_EVERY == GC_EVERY - 1 {
+            self.slots.retain(|_, slot| match slot {
+                Slot::Ready(_, expires) => *expires > now,
+                Slot::InFlight(receiver) => receiver.has_changed().is_ok(),
+            });
+        }
+    }
+}
+
+enum Joined {
+    Ready(Arc<CachedResponse>),
+    InFlight(watch::Receiver<Option<Arc<CachedResponse>>>),
+}
+
+impl<S, State, ReqBody> Service<State, Request<ReqBody>> for CoalesceService<S>
+where
+    S: Service<State, Request<ReqBody>, Response = Response, Error: Into<BoxError>>,
+    State: Clone + Send + Sync + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = Response;
+    type Error = BoxError;
+
+    async fn serve(
+        &self,
+        ctx: Context<State>,
+        req: Request<ReqBody>,
+    ) -> Result<Self::Response, Self::Error> {
+        let resource = resource(&req).filter(|_| ctx.contains::<RateLimitExceeded>());
+        let Some(resource) = resource else {
+            return self.inner.serve(ctx, req).await.map_err(Into::into);
+        };
+
+        let sender = match self.join(&resource) {
+            Ok(Joined::Ready(shared)) => return Ok(shared.to_response()),
+            Ok(Joined::InFlight(mut receiver)) => {
+                let shared = receiver.wait_for(Option::is_some).await.ok();
+                if let Some(shared) = shared.and_then(|shared| shared.clone()) {
+                    tracing::debug!("Coalesced over-limit request for {}", resource);
+                    return Ok(shared.to_response());
+                }
+                // the request being served failed, serve this one on its own
+                return self.inner.serve(ctx, req).await.map_err(Into::into);
+            }
+            Err(sender) => sender,
+        };
+
+        let response = match self.inner.serve(ctx, req).await.map_err(Into::into) {
+            Ok(response) if is_keepable(&response, self.max_body_len) => response,
+            result => {
+                self.release(&resource, sender);
+                return result;
+            }
+        };
+        let shared = match CachedResponse::read(response).await {
+            Ok(shared) if shared.is_shareable() => shared,
+            Ok(shared) => {
+                self.release(&resource, sender);
+                return Ok(shared.to_response());
+            }
+            Err(err) => {
+                self.release(&resource, sender);
+                return Err(err);
+            }
+        };
+        let shared = Arc::new(shared);
+        let expires = Instant::now() + self.ttl;
+        self.slots
+            .insert(resource, Slot::Ready(shared.clone(), expires));
+        sender.send_replace(Some(shared.clone()));
+        Ok(shared.to_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::atomic::AtomicUsize;
+
+    use rama_core::service::service_fn;
+    use rama_http::dep::http_body_util::BodyExt;
+    use rama_http::{Body, StatusCode, header};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_coalesce_over_limit_polling() {
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+        let service = Arc::new(
+            CoalesceLayer::new(Duration::from_millis(100)).layer(service_fn(
+                move |_: Request<()>| {
+                    let counter = counter.clone();
+                    async move {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        let response = Response::builder()
+                            .header(header::CONTENT_LENGTH, 10)
+                            .body(Body::from("status: ok"))
+                            .unwrap();
+                        Ok::<_, Infallible>(response)
+                    }
+                },
+            )),
+        );
+        let poll = |path: &'static str, over_limit: bool| {
+            let service = service.clone();
+            async move {
+                let mut ctx = Context::<()>::default();
+                if over_limit {
+                    ctx.insert(RateLimitExceeded { retry_after: None });
+                }
+                let req = Request::builder().uri(path).body(()).unwrap();
+                let response = service.serve(ctx, req).await.unwrap();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                assert_eq!(body, "status: ok");
+            }
+        };
+
+        // in flight
+        let polls: Vec<_> = (0..3)
+            .map(|_| tokio::spawn(poll("http://api/status", true)))
+            .collect();
+        for polled in polls {
+            polled.await.unwrap();
+        }
+        assert_eq!(served.load(Ordering::Relaxed), 1);
+        // cached, and clients within their limit are served as usual
+        poll("http://api/status/", true).await;
+        poll("http://api/status", false).await;
+        assert_eq!(served.load(Ordering::Relaxed), 2);
+        // expired
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        poll("http://api/status", true).await;
+        assert_eq!(served.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_only_small_successes() {
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+        let service = Arc::new(
+            CoalesceLayer::new(Duration::from_secs(60))
+                .max_body_len(16)
+                .layer(service_fn(move |req: Request<()>| {
+                    let counter = counter.clone();
+                    async move {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        let (status, body) = match req.uri().path() {
+                            "/down" => (StatusCode::SERVICE_UNAVAILABLE, "try later"),
+                            _ => (StatusCode::OK, "a report longer than the limit"),
+                        };
+                        let response = Response::builder()
+                            .status(status)
+                            .header(header::CONTENT_LENGTH, body.len())
+                            .body(Body::from(body))
+                            .unwrap();
+                        Ok::<_, Infallible>(response)
+                    }
+                })),
+        );
+        let poll = |path: &'static str| {
+            let service = service.clone();
+            async move {
+                let mut ctx = Context::<()>::default();
+                ctx.insert(RateLimitExceeded { retry_after: None });
+                let req = Request::builder().uri(path).body(()).unwrap();
+                service.serve(ctx, req).await.unwrap().status()
+            }
+        };
+
+        for path in ["http://api/down", "http://api/report"] {
+            served.store(0, Ordering::Relaxed);
+            let polls: Vec<_> = (0..3).map(|_| tokio::spawn(poll(path))).collect();
+            for polled in polls {
+                polled.await.unwrap();
+            }
+            poll(path).await;
+            // every request was served on its own, and nothing is left behind
+            assert_eq!(served.load(Ordering::Relaxed), 4);
+            assert!(service.slots.is_empty());
+        }
+    }
+}
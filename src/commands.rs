@@ -0,0 +1,129 @@
+//! Limiting commands per connection for non-HTTP protocols
+//!
+//! Line protocols like SMTP, IMAP or custom TCP protocols run many commands
+//! over one long-lived connection, and it is the commands, not the
+//! connections, that cost. A [`CommandLimiter`] hands each accepted
+//! connection its own [`ConnectionCommands`], and the server signals each
+//! command it parses, so the policy limits commands per connection with the
+//! same controls, metrics, tracing and audit trail as HTTP requests:
+//!
+//! ```ignore
+//! let limiter = CommandLimiter::new(
+//!     GovernorPolicy::builder()
+//!         .per_second(5)
+//!         .burst_size(20)
+//!         .name("smtp")
+//!         .build_with_keyer(|key: &str| key.to_owned()),
+//! );
+//!
+//! // per accepted connection
+//! let commands = limiter.connection(peer);
+//! while let Some(line) = lines.next_line().await? {
+//!     if commands.command().await.is_err() {
+//!         writer.write_all(b"421 Too many commands, closing connection\r\n").await?;
+//!         break;
+//!     }
+//!     handle(line).await?;
+//! }
+//! ```
+//!
+//! Connections are keyed by their peer address and a sequence number, e.g.
+//! `203.0.113.7:51234#17`, so runtime controls and traces can target them.
+//! The state of closed connections is collected by GC like any other key.
+
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{GovernorError, GovernorPolicy};
+
+/// Hands out per-connection command limits of one policy
+///
+/// The policy should be keyed, a direct policy counts the commands of all
+/// connections together.
+#[derive(Debug, Clone)]
+pub struct CommandLimiter {
+    policy: Arc<GovernorPolicy>,
+    next_connection: Arc<AtomicU64>,
+}
+
+impl CommandLimiter {
+    /// Limit the commands of each connection with `policy`
+    pub fn new(policy: impl Into<Arc<GovernorPolicy>>) -> Self {
+        CommandLimiter {
+            policy: policy.into(),
+            next_connection: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The wrapped policy
+    pub fn policy(&self) -> &Arc<GovernorPolicy> {
+        &self.policy
+    }
+
+    /// Start counting the commands of a connection accepted from `peer`
+    pub fn connection(&self, peer: SocketAddr) -> ConnectionCommands {
+        let id = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        ConnectionCommands {
+            policy: self.policy.clone(),
+            key: format!("{peer}#{id}"),
+        }
+    }
+}
+
+/// Command limit of one connection
+#[derive(Debug, Clone)]
+pub struct ConnectionCommands {
+    policy: Arc<GovernorPolicy>,
+    key: String,
+}
+
+impl ConnectionCommands {
+    /// Key the commands of this connection are counted under
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Count one command, rejecting it once the connection is over its limit
+    pub async fn command(&self) -> Result<(), GovernorError> {
+        self.policy.decide(&self.key).await.map(|_| ())
+    }
+
+    /// Count `n` commands at once, e.g. a pipelined batch
+    ///
+    /// As for requests, a cost above the burst size is charged as a full
+    /// burst. Counting zero commands always succeeds.
+    pub async fn commands(&self, n: u32) -> Result<(), GovernorError> {
+        match NonZeroU32::new(n) {
+            Some(cost) => self.policy.decide_n(&self.key, cost).await.map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_commands_per_connection() {
+        let limiter = CommandLimiter::new(
+            GovernorPolicy::builder()
+                .per_minute(1)
+                .burst_size(3)
+                .build_with_keyer(|key: &str| key.to_owned()),
+        );
+        let peer: SocketAddr = "203.0.113.7:25".parse().unwrap();
+        let first = limiter.connection(peer);
+        let second = limiter.connection(peer);
+        assert_ne!(first.key(), second.key());
+
+        first.commands(2).await.unwrap();
+        first.command().await.unwrap();
+        assert!(first.command().await.is_err());
+        // a new connection of the same peer starts afresh
+        second.commands(3).await.unwrap();
+        assert_eq!(limiter.policy().len(), 2);
+    }
+}
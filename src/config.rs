@@ -0,0 +1,498 @@
+//! Declarative rule sets and their validation
+//!
+//! A [`GovernorConfig`] lists rules that each limit a set of path prefixes,
+//! typically deserialized from the configuration file of the application.
+//! [`GovernorConfig::validate`] reports mistakes in such a file before any
+//! policy is built, so they can fail a startup or a CI job instead of showing
+//! up as panics or quietly unlimited routes.
+//!
+//! Each rule picks its own [`Algorithm`], so e.g. billing routes can count
+//! exactly per window while everything else is smoothed by GCRA, with the
+//! same key extraction and rejection handling for all of them.
+//!
+//! A [`NamespaceConfig`] lists the quota tiers of a
+//! [`NamespacedPolicy`](crate::NamespacedPolicy): a default quota and
+//! overrides per namespace, to reload along with the rest of the file.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::num::NonZeroU32;
+
+use arc_swap::ArcSwap;
+use governor::Quota;
+use rama_core::Context;
+use rama_core::layer::limit::policy::{Policy, PolicyOutput, PolicyResult};
+use rama_http::Request;
+use serde::{Deserialize, Serialize};
+
+use crate::dsl::parse_rate;
+use crate::{
+    Algorithm, ExtractingPolicy, GovernorError, GovernorGuard, KeySource, NamespaceQuotas,
+    PolicyParseError, PolicySpec,
+};
+
+/// A set of rules, checked in order
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GovernorConfig {
+    /// The rules; a request is limited by the first rule matching its path
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+/// One rule of a [`GovernorConfig`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleConfig {
+    /// Name of the rule, used as policy name
+    pub name: String,
+    /// Rate like `10r/s`, see [`PolicySpec`]
+    pub rate: String,
+    /// Burst size, the count of the rate by default
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// `global` (default), `ip`, `header:NAME` or `header-or-ip:NAME`
+    #[serde(default)]
+    pub key: Option<String>,
+    /// `gcra` (default) or `fixed_window`
+    #[serde(default)]
+    pub algorithm: Option<Algorithm>,
+    /// Path prefixes the rule applies to; all paths if empty
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Path prefixes within `paths` that are not limited
+    #[serde(default)]
+    pub exempt: Vec<String>,
+}
+
+/// A rate and burst size
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    /// Rate like `10r/s`, see [`PolicySpec`]
+    pub rate: String,
+    /// Burst size, the count of the rate by default
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+impl QuotaConfig {
+    /// Parse the rate and burst size into a quota
+    pub fn quota(&self) -> Result<Quota, PolicyParseError> {
+        let quota = parse_rate(&self.rate)?;
+        match self.burst {
+            None => Ok(quota),
+            Some(burst) => NonZeroU32::new(burst)
+                .map(|burst| quota.allow_burst(burst))
+                .ok_or_else(|| PolicyParseError::InvalidValue {
+                    option: "burst".to_owned(),
+                    value: burst.to_string(),
+                }),
+        }
+    }
+}
+
+/// Quota tiers of a [`NamespacedPolicy`](crate::NamespacedPolicy)
+///
+/// ```json
+/// {
+///     "default": { "rate": "10r/s", "burst": 20 },
+///     "overrides": { "acme": { "rate": "100r/s", "burst": 200 } }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceConfig {
+    /// Quota of namespaces without an override
+    pub default: QuotaConfig,
+    /// Quota per namespace
+    #[serde(default)]
+    pub overrides: BTreeMap<String, QuotaConfig>,
+}
+
+impl NamespaceConfig {
+    /// Parse all quotas, to build a policy with or pass to
+    /// [`NamespacedPolicy::set_quotas`](crate::NamespacedPolicy::set_quotas)
+    pub fn quotas(&self) -> Result<NamespaceQuotas, PolicyParseError> {
+        self.overrides.iter().try_fold(
+            NamespaceQuotas::new(self.default.quota()?),
+            |quotas, (namespace, quota)| Ok(quotas.with_override(namespace, quota.quota()?)),
+        )
+    }
+}
+
+/// How serious a [`Diagnostic`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The rule works, but likely not as intended
+    Warning,
+    /// The rule can't be built
+    Error,
+}
+
+/// What a [`Diagnostic`] is about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// The rate allows no requests at all
+    ZeroRate,
+    /// The rate doesn't parse
+    InvalidRate(String),
+    /// The burst size is zero
+    ZeroBurst,
+    /// The key source doesn't parse
+    InvalidKey(String),
+    /// Another rule has the same name
+    DuplicateName,
+    /// An earlier rule matches every path of this rule, so it never applies
+    ShadowedBy(String),
+    /// The exemption lies outside the paths of the rule, so it never applies
+    UnreachableExemption(String),
+}
+
+/// A problem found by [`GovernorConfig::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Name of the rule the problem is in
+    pub rule: String,
+    /// How serious the problem is
+    pub severity: Severity,
+    /// What the problem is
+    pub kind: DiagnosticKind,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity}: rule {:?}: ", self.rule)?;
+        match &self.kind {
+            DiagnosticKind::ZeroRate => write!(f, "rate is zero, no request would ever pass"),
+            DiagnosticKind::InvalidRate(reason) => write!(f, "{reason}"),
+            DiagnosticKind::ZeroBurst => write!(f, "burst must be at least 1"),
+            DiagnosticKind::InvalidKey(reason) => write!(f, "{reason}"),
+            DiagnosticKind::DuplicateName => write!(f, "another rule has the same name"),
+            DiagnosticKind::ShadowedBy(rule) => {
+                write!(
+                    f,
+                    "never applies, rule {rule:?} matches all of its paths first"
+                )
+            }
+            DiagnosticKind::UnreachableExemption(path) => {
+                write!(f, "exemption {path:?} is outside the paths of the rule")
+            }
+        }
+    }
+}
+
+/// Returns true if every path matched by `inner` is matched by `outer`
+fn covers(outer: &[String], inner: &[String]) -> bool {
+    if outer.is_empty() {
+        return true;
+    }
+    !inner.is_empty()
+        && inner
+            .iter()
+            .all(|path| outer.iter().any(|prefix| path.starts_with(prefix.as_str())))
+}
+
+impl RuleConfig {
+    fn spec(&self) -> Result<PolicySpec, Vec<DiagnosticKind>> {
+        let mut problems = Vec::new();
+        let spec = match self.rate.parse::<PolicySpec>() {
+            Ok(spec) => Some(spec),
+            Err(_) if self.rate.trim().starts_with("0r/") => {
+                problems.push(DiagnosticKind::ZeroRate);
+                None
+            }
+            Err(err) => {
+                problems.push(DiagnosticKind::InvalidRate(err.to_string()));
+                None
+            }
+        };
+        if self.burst == Some(0) {
+            problems.push(DiagnosticKind::ZeroBurst);
+        }
+        let key = match self.key.as_deref().map(str::parse::<KeySource>) {
+            Some(Err(err)) => {
+                problems.push(DiagnosticKind::InvalidKey(err.to_string()));
+                None
+            }
+            Some(Ok(key)) => Some(key),
+            None => None,
+        };
+
+        match spec {
+            Some(mut spec) if problems.is_empty() => {
+                spec.builder = spec.builder.name(self.name.clone());
+                if let Some(burst) = self.burst {
+                    spec.builder = spec.builder.burst_size(burst);
+                }
+                if let Some(algorithm) = self.algorithm {
+                    spec.builder = spec.builder.algorithm(algorithm);
+                }
+                if let Some(key) = key {
+                    spec.key = key;
+                }
+                Ok(spec)
+            }
+            _ => Err(problems),
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.paths.is_empty()
+            || self
+                .paths
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+impl GovernorConfig {
+    /// Check the rules for mistakes, returning all problems found
+    ///
+    /// An empty list means the config builds and every rule and exemption
+    /// can apply to some request.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            let mut report = |severity, kind| {
+                diagnostics.push(Diagnostic {
+                    rule: rule.name.clone(),
+                    severity,
+                    kind,
+                })
+            };
+            if let Err(problems) = rule.spec() {
+                for problem in problems {
+                    report(Severity::Error, problem);
+                }
+            }
+            let earlier = &self.rules[..index];
+            if earlier.iter().any(|other| other.name == rule.name) {
+                report(Severity::Error, DiagnosticKind::DuplicateName);
+            }
+            if let Some(other) = earlier
+                .iter()
+                .find(|other| covers(&other.paths, &rule.paths))
+            {
+                report(
+                    Severity::Warning,
+                    DiagnosticKind::ShadowedBy(other.name.clone()),
+                );
+            }
+            for path in &rule.exempt {
+                if !rule.matches(path) {
+                    report(
+                        Severity::Warning,
+                        DiagnosticKind::UnreachableExemption(path.clone()),
+                    );
+                }
+            }
+        }
+        diagnostics
+    }
+
+    /// Fail on any error diagnostic, logging the warnings
+    pub(crate) fn check(&self) -> Result<(), Vec<Diagnostic>> {
+        let (errors, warnings): (Vec<_>, Vec<_>) = self
+            .validate()
+            .into_iter()
+            .partition(|diagnostic| diagnostic.severity == Severity::Error);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        for warning in warnings {
+            tracing::warn!("{}", warning);
+        }
+        Ok(())
+    }
+
+    /// Build a policy applying the rules, failing on any error diagnostic
+    ///
+    /// Warnings are logged.
+    pub fn build(&self) -> Result<ConfigPolicy, Vec<Diagnostic>> {
+        self.check()?;
+        let rules = self.rules.iter().map(ConfigRule::build).collect();
+        Ok(ConfigPolicy {
+            rules: ArcSwap::from_pointee(rules),
+        })
+    }
+}
+
+/// A rule of a [`ConfigPolicy`] and the policy enforcing it
+#[derive(Clone)]
+pub(crate) struct ConfigRule {
+    pub(crate) config: RuleConfig,
+    pub(crate) policy: ExtractingPolicy<KeySource>,
+}
+
+impl ConfigRule {
+    /// Build the policy of a validated rule
+    pub(crate) fn build(config: &RuleConfig) -> Self {
+        let spec = config.spec().expect("validated rule");
+        ConfigRule {
+            config: config.clone(),
+            policy: spec.build(),
+        }
+    }
+}
+
+/// Policy built from a [`GovernorConfig`]
+///
+/// Requests not matching any rule, or matching an exemption of the first
+/// rule they match, are not limited. The rules can be changed at runtime
+/// through [`plan`](Self::plan) and [`apply`](Self::apply).
+pub struct ConfigPolicy {
+    pub(crate) rules: ArcSwap<Vec<ConfigRule>>,
+}
+
+impl ConfigPolicy {
+    /// Policy of the rule named `name`
+    pub fn rule(&self, name: &str) -> Option<ExtractingPolicy<KeySource>> {
+        self.rules
+            .load()
+            .iter()
+            .find(|rule| rule.config.name == name)
+            .map(|rule| rule.policy.clone())
+    }
+
+    /// The rules in effect
+    pub fn config(&self) -> GovernorConfig {
+        GovernorConfig {
+            rules: self
+                .rules
+                .load()
+                .iter()
+                .map(|rule| rule.config.clone())
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Debug for ConfigPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.rules
+                    .load()
+                    .iter()
+                    .map(|rule| rule.config.name.clone()),
+            )
+            .finish()
+    }
+}
+
+impl<State, Body> Policy<State, Request<Body>> for ConfigPolicy
+where
+    State: Clone + Send + Sync + 'static,
+    Body: Send + Sync + 'static,
+{
+    type Guard = GovernorGuard;
+    type Error = GovernorError;
+
+    async fn check(
+        &self,
+        ctx: Context<State>,
+        request: Request<Body>,
+    ) -> PolicyResult<State, Request<Body>, Self::Guard, Self::Error> {
+        let path = request.uri().path();
+        let rules = self.rules.load();
+        let policy = rules
+            .iter()
+            .find(|rule| rule.config.matches(path))
+            .filter(|rule| {
+                !rule
+                    .config
+                    .exempt
+                    .iter()
+                    .any(|prefix| path.starts_with(prefix.as_str()))
+            })
+            .map(|rule| rule.policy.clone());
+        drop(rules);
+        match policy {
+            Some(policy) => policy.check(ctx, request).await,
+            None => PolicyResult {
+                ctx,
+                request,
+                output: PolicyOutput::Ready(GovernorGuard::unlimited()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, rate: &str, paths: &[&str], exempt: &[&str]) -> RuleConfig {
+        RuleConfig {
+            name: name.to_owned(),
+            rate: rate.to_owned(),
+            burst: None,
+            key: None,
+            algorithm: None,
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+            exempt: exempt.iter().map(|path| path.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_config() {
+        let config = GovernorConfig {
+            rules: vec![
+                rule("api", "10r/s", &["/api"], &["/api/health", "/static"]),
+                rule("users", "5r/s", &["/api/users"], &[]),
+                rule("login", "0r/m", &["/login"], &[]),
+                RuleConfig {
+                    burst: Some(0),
+                    key: Some("cookie".to_owned()),
+                    ..rule("search", "1r/s", &["/search"], &[])
+                },
+            ],
+        };
+        let kinds: Vec<_> = config
+            .validate()
+            .into_iter()
+            .map(|diagnostic| (diagnostic.rule, diagnostic.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (
+                    "api".to_owned(),
+                    DiagnosticKind::UnreachableExemption("/static".to_owned())
+                ),
+                (
+                    "users".to_owned(),
+                    DiagnosticKind::ShadowedBy("api".to_owned())
+                ),
+                ("login".to_owned(), DiagnosticKind::ZeroRate),
+                ("search".to_owned(), DiagnosticKind::ZeroBurst),
+                (
+                    "search".to_owned(),
+                    DiagnosticKind::InvalidKey("invalid value for key: \"cookie\"".to_owned())
+                ),
+            ]
+        );
+        assert!(config.build().is_err());
+
+        let config: GovernorConfig = serde_json::from_value(serde_json::json!({
+            "rules": [{
+                "name": "api",
+                "rate": "1r/m",
+                "algorithm": "fixed_window",
+                "paths": ["/api"],
+                "exempt": ["/api/health"],
+            }],
+        }))
+        .unwrap();
+        assert_eq!(config.rules[0].algorithm, Some(Algorithm::FixedWindow));
+        let policy = config.build().unwrap();
+        let mut admitted = 0;
+        for path in ["/api/a", "/api/b", "/api/health", "/other"] {
+            let req = Request::builder().uri(path).body(()).unwrap();
+            let result = policy.check(Context::<()>::default(), req).await;
+            admitted += matches!(result.output, PolicyOutput::Ready(_)) as usize;
+        }
+        assert_eq!(admitted, 3);
+    }
+}
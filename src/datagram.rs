@@ -0,0 +1,186 @@
+//! Rate limiting datagram services
+//!
+//! UDP servers, DNS resolvers and QUIC front ends never see an HTTP request,
+//! so the HTTP layer never limits them. [`DatagramPolicy`] checks datagrams
+//! per source address instead, charging a cell per packet or, through
+//! [`per_bytes`](DatagramPolicy::per_bytes), per so many bytes of payload,
+//! so one quota can bound both the packet rate and the ingress bandwidth of
+//! a peer.
+//!
+//! Servers reading from a rama `UdpSocket` check each datagram before
+//! handling it, and drop the ones rejected:
+//!
+//! ```ignore
+//! let policy = DatagramPolicy::new(
+//!     GovernorPolicy::builder()
+//!         .per_second(1_000)
+//!         .burst_size(64)
+//!         .build_with_ip_keys(),
+//! );
+//! loop {
+//!     let (len, peer) = socket.recv_from(&mut buf).await?;
+//!     if policy.check_datagram(peer, len).await.is_ok() {
+//!         handle(&buf[..len], peer).await;
+//!     }
+//! }
+//! ```
+//!
+//! Datagrams carried as requests through a service stack implement
+//! [`Datagram`] and go through the `Policy` impl, which also works with
+//! rama's `LimitLayer`.
+
+use std::net::SocketAddr;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::sync::Arc;
+
+use rama_core::Context;
+use rama_core::layer::limit::policy::{Policy, PolicyOutput, PolicyResult};
+
+use crate::body_cost::BodyCost;
+use crate::ip_key::IpText;
+use crate::{GovernorError, GovernorGuard, GovernorPolicy};
+
+/// A datagram, as far as rate limiting is concerned
+pub trait Datagram {
+    /// Address of the peer that sent the datagram
+    fn source(&self) -> SocketAddr;
+
+    /// Length of the payload, in bytes
+    fn payload_len(&self) -> usize;
+}
+
+/// A payload with the address it was received from, as read from a socket
+impl<B: AsRef<[u8]>> Datagram for (B, SocketAddr) {
+    fn source(&self) -> SocketAddr {
+        self.1
+    }
+
+    fn payload_len(&self) -> usize {
+        self.0.as_ref().len()
+    }
+}
+
+/// Policy checking datagrams under the address of their source
+///
+/// The port is ignored, so a peer can't dodge its quota by sending from
+/// many ports, and IPv4-mapped IPv6 sources count as their IPv4 address.
+/// Build the wrapped policy with
+/// [`build_with_ip_keys`](crate::GovernorPolicyBuilder::build_with_ip_keys)
+/// to check tracked peers without allocating.
+#[derive(Debug, Clone)]
+pub struct DatagramPolicy {
+    policy: Arc<GovernorPolicy>,
+    cost: Option<BodyCost>,
+}
+
+impl DatagramPolicy {
+    /// Check datagrams with `policy`, one cell per datagram
+    pub fn new(policy: impl Into<Arc<GovernorPolicy>>) -> Self {
+        DatagramPolicy {
+            policy: policy.into(),
+            cost: None,
+        }
+    }
+
+    /// Charge one cell per `bytes_per_cell` bytes of payload, and at least
+    /// one per datagram
+    ///
+    /// Payloads beyond the burst size are charged a full burst.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_cell` is zero.
+    pub fn per_bytes(mut self, bytes_per_cell: u64) -> Self {
+        let bytes_per_cell =
+            NonZeroU64::new(bytes_per_cell).expect("Bytes per cell must be non-zero");
+        self.cost = Some(BodyCost::new(bytes_per_cell));
+        self
+    }
+
+    /// The wrapped policy
+    pub fn policy(&self) -> &Arc<GovernorPolicy> {
+        &self.policy
+    }
+
+    /// Check a datagram of `len` bytes received from `source`
+    pub async fn check_datagram(
+        &self,
+        source: SocketAddr,
+        len: usize,
+    ) -> Result<(), GovernorError> {
+        let key = IpText::new(source.ip().to_canonical());
+        self.policy
+            .decide_n(key.as_str(), self.cost_of(len))
+            .await
+            .map(|_| ())
+    }
+
+    fn cost_of(&self, len: usize) -> NonZeroU32 {
+        self.cost
+            .map_or(NonZeroU32::MIN, |cost| cost.of_len(len as u64))
+    }
+}
+
+impl<State, D> Policy<State, D> for DatagramPolicy
+where
+    State: Clone + Send + Sync + 'static,
+    D: Datagram + Send + Sync + 'static,
+{
+    type Guard = GovernorGuard;
+    type Error = GovernorError;
+
+    async fn check(
+        &self,
+        mut ctx: Context<State>,
+        request: D,
+    ) -> PolicyResult<State, D, Self::Guard, Self::Error> {
+        let key = IpText::new(request.source().ip().to_canonical());
+        let output = match self
+            .policy
+            .decide_n(key.as_str(), self.cost_of(request.payload_len()))
+            .await
+        {
+            Ok(decision) => PolicyOutput::Ready(decision.admit(&mut ctx, key.as_str())),
+            Err(err) => PolicyOutput::Abort(err),
+        };
+        PolicyResult {
+            ctx,
+            request,
+            output,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_datagram_policy() {
+        let policy = DatagramPolicy::new(
+            GovernorPolicy::builder()
+                .per_minute(1)
+                .burst_size(4)
+                .build_with_ip_keys(),
+        )
+        .per_bytes(512);
+        let peer: SocketAddr = "203.0.113.7:5353".parse().unwrap();
+        let other_port: SocketAddr = "[::ffff:203.0.113.7]:5354".parse().unwrap();
+
+        // a small query, then 1 KiB costs two cells
+        policy.check_datagram(peer, 40).await.unwrap();
+        policy.check_datagram(other_port, 1024).await.unwrap();
+        let result = policy
+            .check(Context::<()>::default(), (vec![0u8; 600], peer))
+            .await;
+        assert!(matches!(result.output, PolicyOutput::Abort(_)));
+        let result = policy
+            .check(Context::<()>::default(), (vec![0u8; 10], peer))
+            .await;
+        assert!(matches!(result.output, PolicyOutput::Ready(_)));
+
+        // other peers have their own quota
+        let elsewhere: SocketAddr = "198.51.100.1:53".parse().unwrap();
+        policy.check_datagram(elsewhere, 2048).await.unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+//! Short-circuiting checks of keys known to be limited
+//!
+//! A policy built with [`decision_cache`](crate::GovernorPolicyBuilder::decision_cache)
+//! remembers keys rejected with a long retry time and rejects their further
+//! requests until that time has passed, without touching the limiter or its
+//! store. Under attack, when a few keys hammer the policy long after being
+//! limited, this takes the load off a shared backend and the contention off
+//! hot limiter entries.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+/// Drop expired entries every this many insertions
+const GC_EVERY: u64 = 1024;
+
+/// Keys rejected until a known point in time
+#[derive(Debug)]
+pub(crate) struct DecisionCache {
+    /// Rejections with a shorter retry time are not cached
+    threshold: Duration,
+    blocked: DashMap<String, Instant>,
+    inserts: AtomicU64,
+}
+
+impl DecisionCache {
+    pub(crate) fn new(threshold: Duration) -> Self {
+        DecisionCache {
+            threshold,
+            blocked: DashMap::new(),
+            inserts: AtomicU64::new(0),
+        }
+    }
+
+    /// Time until `key` may be let through again, if it is cached as limited
+    pub(crate) fn blocked(&self, key: &str) -> Option<Duration> {
+        let until = *self.blocked.get(key)?;
+        let remaining = until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            self.blocked.remove_if(key, |_, cached| *cached == until);
+            return None;
+        }
+        Some(remaining)
+    }
+
+    /// Remember a rejection of `key`, if it is long enough to be worth it
+    pub(crate) fn rejected(&self, key: &str, retry_after: Option<Duration>) {
+        let Some(retry_after) = retry_after.filter(|retry| *retry > self.threshold) else {
+            return;
+        };
+        let now = Instant::now();
+        self.blocked.insert(key.to_owned(), now + retry_after);
+        if self.inserts.fetch_add(1, Ordering::Relaxed) % GC_EVERY == GC_EVERY - 1 {
+            self.blocked.retain(|_, until| *until > now);
+        }
+    }
+
+    /// Forget the cached rejection of `key`
+    pub(crate) fn remove(&self, key: &str) {
+        self.blocked.remove(key);
+    }
+
+    /// Forget all cached rejections, e.g. because the limiter state changed
+    pub(crate) fn clear(&self) {
+        self.blocked.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{GovernorError, GovernorPolicy, Limiter};
+
+    #[tokio::test]
+    async fn test_decision_cache() {
+        let limiter = Limiter::from(
+            GovernorPolicy::builder()
+                .per_minute(1)
+                .decision_cache(Duration::from_secs(1))
+                .build_with_keyer(|key: &str| key.to_owned()),
+        );
+        let handle = limiter.policy().handle();
+
+        limiter.check_key("scanner").await.unwrap();
+        for _ in 0..2 {
+            let Err(GovernorError::RateLimited(rejected)) = limiter.check_key("scanner").await
+            else {
+                panic!("expected a rejection");
+            };
+            assert!(rejected.retry_after().unwrap() > Duration::from_secs(50));
+        }
+        assert!(
+            handle
+                .decision_cache()
+                .unwrap()
+                .blocked("scanner")
+                .is_some()
+        );
+
+        // resetting the key lifts the cached rejection along with its state
+        handle.reset_key("scanner");
+        limiter.check_key("scanner").await.unwrap();
+    }
+}
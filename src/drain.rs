@@ -0,0 +1,96 @@
+//! Draining traffic off an instance before it is decommissioned
+//!
+//! [`GovernorHandle::drain`](crate::GovernorHandle::drain) lets a shrinking
+//! share of requests through, from all of them when called down to none at
+//! the end of the drain period, so clients move to other instances gradually
+//! while the load balancer takes this one out of rotation. Health checks are
+//! always let through during the drain, so the load balancer decides when the
+//! instance is gone rather than failed probes.
+
+use std::any::Any;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::exempt::http_request;
+
+/// Paths recognized as health checks unless configured otherwise
+pub(crate) const DEFAULT_HEALTH_CHECK_PATHS: [&str; 4] =
+    ["/health", "/healthz", "/livez", "/readyz"];
+
+/// A drain in progress
+pub(crate) struct Drain {
+    started: Instant,
+    period: Duration,
+    requests: AtomicU64,
+    hasher: RandomState,
+}
+
+impl Drain {
+    pub(crate) fn new(period: Duration) -> Self {
+        Drain {
+            started: Instant::now(),
+            period,
+            requests: AtomicU64::new(0),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Share of requests still let through, from 1 down to 0
+    pub(crate) fn share(&self) -> f64 {
+        if self.period.is_zero() {
+            return 0.0;
+        }
+        let elapsed = self.started.elapsed().as_secs_f64() / self.period.as_secs_f64();
+        (1.0 - elapsed).max(0.0)
+    }
+
+    /// Whether to let the next request through
+    pub(crate) fn admits(&self) -> bool {
+        let share = self.share();
+        if share <= 0.0 {
+            return false;
+        }
+        // spread admissions evenly instead of letting a burst through and
+        // then rejecting everything
+        let request = self.requests.fetch_add(1, Ordering::Relaxed);
+        let draw = self.hasher.hash_one(request) as f64 / u64::MAX as f64;
+        draw < share
+    }
+}
+
+/// Whether `request` is an HTTP request for one of the health check `paths`
+pub(crate) fn is_health_check(request: &dyn Any, paths: &[String]) -> bool {
+    http_request(request).is_some_and(|request| {
+        let path = request.uri().path();
+        paths.iter().any(|health| path == health)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+    use rama_http::{Body, Request};
+
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let policy = GovernorPolicy::builder().per_second(1000).build();
+        let request = |path: &str| Request::builder().uri(path).body(Body::empty()).unwrap();
+        let admitted = |result: &PolicyOutput<_, _>| matches!(result, PolicyOutput::Ready(_));
+
+        let handle = policy.handle();
+        handle.drain(std::time::Duration::ZERO);
+        assert!(handle.is_draining());
+        let result = policy.check(Context::default(), request("/api")).await;
+        assert!(!admitted(&result.output));
+        let result = policy.check(Context::default(), request("/healthz")).await;
+        assert!(admitted(&result.output));
+
+        handle.enable();
+        let result = policy.check(Context::default(), request("/api")).await;
+        assert!(admitted(&result.output));
+    }
+}
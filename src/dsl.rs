@@ -0,0 +1,222 @@
+//! Compact policy strings
+//!
+//! Policies written on one line, in the spirit of nginx's `limit_req`, let
+//! quotas live in environment variables and command line flags without a
+//! config file framework.
+
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+use governor::Quota;
+use rama_core::Context;
+use rama_http::{HeaderName, Request};
+use thiserror::Error;
+
+use crate::extract::client_ip;
+use crate::{ExtractingPolicy, GovernorPolicy, GovernorPolicyBuilder, KeyExtractor};
+
+/// Error parsing a policy string
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PolicyParseError {
+    /// The string has no rate like `10r/s`
+    #[error("missing rate, expected e.g. \"10r/s\"")]
+    MissingRate,
+    /// The rate is malformed or zero
+    #[error("invalid rate {0:?}, expected a non-zero count followed by r/s, r/m or r/h")]
+    InvalidRate(String),
+    /// An option has a malformed value
+    #[error("invalid value for {option}: {value:?}")]
+    InvalidValue {
+        /// Name of the option
+        option: String,
+        /// The rejected value
+        value: String,
+    },
+    /// An option is not known
+    #[error("unknown option {0:?}")]
+    UnknownOption(String),
+}
+
+/// What requests of a parsed policy are counted under
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// One key for all requests
+    Global,
+    /// The client address
+    Ip,
+    /// The value of a header; requests without it are not limited
+    Header(HeaderName),
+    /// The value of a header, or the client address for requests without it
+    HeaderOrIp(HeaderName),
+}
+
+impl FromStr for KeySource {
+    type Err = PolicyParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || PolicyParseError::InvalidValue {
+            option: "key".to_owned(),
+            value: value.to_owned(),
+        };
+        match value {
+            "global" => Ok(KeySource::Global),
+            "ip" => Ok(KeySource::Ip),
+            _ => {
+                let (source, name) = value.split_once(':').ok_or_else(invalid)?;
+                let source = match source {
+                    "header" => KeySource::Header,
+                    "header-or-ip" => KeySource::HeaderOrIp,
+                    _ => return Err(invalid()),
+                };
+                HeaderName::try_from(name)
+                    .map(source)
+                    .map_err(|_| invalid())
+            }
+        }
+    }
+}
+
+impl<State, Body> KeyExtractor<State, Request<Body>> for KeySource {
+    fn extract(&self, ctx: &Context<State>, req: &Request<Body>) -> Option<String> {
+        match self {
+            KeySource::Global => Some("global".to_owned()),
+            KeySource::Ip => client_ip(ctx).map(|ip| ip.to_string()),
+            KeySource::Header(name) => {
+                let value = req.headers().get(name)?;
+                value.to_str().ok().map(str::to_owned)
+            }
+            KeySource::HeaderOrIp(name) => {
+                let value = req.headers().get(name).and_then(|v| v.to_str().ok());
+                match value {
+                    Some(value) => Some(value.to_owned()),
+                    None => client_ip(ctx).map(|ip| ip.to_string()),
+                }
+            }
+        }
+    }
+}
+
+/// A policy parsed from a string, ready to be adjusted or built
+///
+/// ```text
+/// 10r/s burst=20 key=ip name=public-api
+/// ```
+///
+/// The rate is required and comes in requests per second (`r/s`), minute
+/// (`r/m`) or hour (`r/h`). Options:
+///
+/// - `burst=N`: burst size, the count of the rate by default
+/// - `key=ip|global|header:NAME|header-or-ip:NAME`: what requests are counted
+///   under, `global` by default
+/// - `name=NAME`: the policy name reported in metrics and logs
+pub struct PolicySpec {
+    /// Builder configured with the rate, burst and name of the string
+    pub builder: GovernorPolicyBuilder,
+    /// What requests are counted under
+    pub key: KeySource,
+}
+
+impl PolicySpec {
+    /// Build the policy, keyed by its key source
+    pub fn build(self) -> ExtractingPolicy<KeySource> {
+        let policy = match self.key {
+            KeySource::Global => self.builder.build(),
+            _ => self
+                .builder
+                .build_with_serde_keyer(|key: &str| key.to_owned()),
+        };
+        ExtractingPolicy::new(policy, self.key)
+    }
+}
+
+impl std::fmt::Debug for PolicySpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PolicySpec")
+            .field("quota", &self.builder.quota)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+pub(crate) fn parse_rate(rate: &str) -> Result<Quota, PolicyParseError> {
+    let invalid = || PolicyParseError::InvalidRate(rate.to_owned());
+    let (count, unit) = rate.split_once("r/").ok_or_else(invalid)?;
+    let count = count.parse::<NonZeroU32>().map_err(|_| invalid())?;
+    match unit {
+        "s" => Ok(Quota::per_second(count)),
+        "m" => Ok(Quota::per_minute(count)),
+        "h" => Ok(Quota::per_hour(count)),
+        _ => Err(invalid()),
+    }
+}
+
+impl FromStr for PolicySpec {
+    type Err = PolicyParseError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut tokens = spec.split_whitespace();
+        let quota = parse_rate(tokens.next().ok_or(PolicyParseError::MissingRate)?)?;
+        let mut builder = GovernorPolicyBuilder::new().quota(quota);
+        let mut key = KeySource::Global;
+
+        for token in tokens {
+            let Some((option, value)) = token.split_once('=') else {
+                return Err(PolicyParseError::UnknownOption(token.to_owned()));
+            };
+            let invalid = || PolicyParseError::InvalidValue {
+                option: option.to_owned(),
+                value: value.to_owned(),
+            };
+            match option {
+                "burst" => {
+                    let burst = value.parse::<NonZeroU32>().map_err(|_| invalid())?;
+                    builder = builder.quota(quota.allow_burst(burst));
+                }
+                "key" => key = value.parse()?,
+                "name" if !value.is_empty() => builder = builder.name(value),
+                "name" => return Err(invalid()),
+                _ => return Err(PolicyParseError::UnknownOption(option.to_owned())),
+            }
+        }
+        Ok(PolicySpec { builder, key })
+    }
+}
+
+impl GovernorPolicy {
+    /// Parse a policy string like `"10r/s burst=20 key=ip"`
+    ///
+    /// See [`PolicySpec`] for the syntax.
+    pub fn parse(spec: &str) -> Result<PolicySpec, PolicyParseError> {
+        spec.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse_policy_string() {
+        let spec = GovernorPolicy::parse("10r/s burst=20 key=ip name=api").unwrap();
+        assert_eq!(spec.key, KeySource::Ip);
+        let status = spec.build().policy().status();
+        assert_eq!(status.name, "api");
+        assert_eq!((status.replenish_interval_ms, status.burst_size), (100, 20));
+
+        // the count of the rate is the burst unless set
+        let spec = GovernorPolicy::parse("10r/s").unwrap();
+        assert_eq!(spec.build().policy().status().burst_size, 10);
+
+        let header = GovernorPolicy::parse("5r/m key=header:x-api-key").unwrap();
+        assert!(matches!(header.key, KeySource::Header(_)));
+        let fallback = GovernorPolicy::parse("5r/m key=header-or-ip:x-api-key").unwrap();
+        assert!(matches!(fallback.key, KeySource::HeaderOrIp(_)));
+        assert_eq!(
+            GovernorPolicy::parse("").unwrap_err(),
+            PolicyParseError::MissingRate
+        );
+        assert!(GovernorPolicy::parse("0r/s").is_err());
+        assert!(GovernorPolicy::parse("1r/s burst=0").is_err());
+        assert!(GovernorPolicy::parse("1r/s delay=2").is_err());
+    }
+}
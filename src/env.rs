@@ -0,0 +1,162 @@
+//! Policies configured through environment variables
+
+use std::env::VarError;
+
+use thiserror::Error;
+
+use crate::{ExtractingPolicy, GovernorPolicy, KeySource, PolicyParseError, PolicySpec};
+
+/// Error reading a policy from the environment
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EnvConfigError {
+    /// A required variable is not set
+    #[error("environment variable {0} is not set")]
+    Missing(String),
+    /// A variable is not valid unicode
+    #[error("environment variable {0} is not valid unicode")]
+    NotUnicode(String),
+    /// A variable has a value that doesn't parse
+    #[error("environment variable {var}: {source}")]
+    Invalid {
+        /// Name of the variable
+        var: String,
+        /// What is wrong with its value
+        source: PolicyParseError,
+    },
+}
+
+/// How the policy treats requests over the limit
+enum EnvMode {
+    Enforce,
+    Shadow,
+    Mirror,
+    Disabled,
+}
+
+/// Read `{prefix}_{suffix}`, treating empty values as unset
+fn var(
+    lookup: &impl Fn(&str) -> Result<String, VarError>,
+    prefix: &str,
+    suffix: &str,
+) -> Result<Option<(String, String)>, EnvConfigError> {
+    let name = format!("{prefix}_{suffix}");
+    match lookup(&name) {
+        Ok(value) if value.trim().is_empty() => Ok(None),
+        Ok(value) => Ok(Some((name, value.trim().to_owned()))),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => Err(EnvConfigError::NotUnicode(name)),
+    }
+}
+
+pub(crate) fn from_lookup(
+    prefix: &str,
+    lookup: impl Fn(&str) -> Result<String, VarError>,
+) -> Result<ExtractingPolicy<KeySource>, EnvConfigError> {
+    let invalid = |var: String, source| EnvConfigError::Invalid { var, source };
+
+    let (rate_var, rate) = var(&lookup, prefix, "RATE")?
+        .ok_or_else(|| EnvConfigError::Missing(format!("{prefix}_RATE")))?;
+    let mut spec: PolicySpec = rate.parse().map_err(|err| invalid(rate_var, err))?;
+
+    if let Some((name, burst)) = var(&lookup, prefix, "BURST")? {
+        let burst = burst
+            .parse::<u32>()
+            .ok()
+            .filter(|&burst| burst > 0)
+            .ok_or_else(|| {
+                let err = PolicyParseError::InvalidValue {
+                    option: "burst".to_owned(),
+                    value: burst.clone(),
+                };
+                invalid(name.clone(), err)
+            })?;
+        spec.builder = spec.builder.burst_size(burst);
+    }
+    if let Some((name, key)) = var(&lookup, prefix, "KEY")? {
+        spec.key = key.parse().map_err(|err| invalid(name, err))?;
+    }
+    let mode = match var(&lookup, prefix, "MODE")? {
+        None => EnvMode::Enforce,
+        Some((name, mode)) => match mode.to_ascii_lowercase().as_str() {
+            "enforce" => EnvMode::Enforce,
+            "shadow" => EnvMode::Shadow,
+            "mirror" => EnvMode::Mirror,
+            "disabled" => EnvMode::Disabled,
+            _ => {
+                let err = PolicyParseError::InvalidValue {
+                    option: "mode".to_owned(),
+                    value: mode,
+                };
+                return Err(invalid(name, err));
+            }
+        },
+    };
+    if let Some((_, name)) = var(&lookup, prefix, "NAME")? {
+        spec.builder = spec.builder.name(name);
+    }
+
+    let policy = spec.build();
+    let handle = policy.policy().handle();
+    match mode {
+        EnvMode::Enforce => {}
+        EnvMode::Shadow => handle.set_shadow(true),
+        EnvMode::Mirror => handle.set_mirror(true),
+        EnvMode::Disabled => handle.disable(),
+    }
+    Ok(policy)
+}
+
+impl GovernorPolicy {
+    /// Build a policy from environment variables starting with `prefix`
+    ///
+    /// With `prefix` "RATE_LIMIT_API" the variables are:
+    ///
+    /// - `RATE_LIMIT_API_RATE`: required rate like `10r/s`, `600r/m` or `1000r/h`
+    /// - `RATE_LIMIT_API_BURST`: burst size, the count of the rate by default
+    /// - `RATE_LIMIT_API_KEY`: `global` (default), `ip`, `header:NAME` or `header-or-ip:NAME`
+    /// - `RATE_LIMIT_API_MODE`: `enforce` (default), `shadow`, `mirror` or `disabled`
+    /// - `RATE_LIMIT_API_NAME`: the policy name reported in metrics and logs
+    ///
+    /// Empty variables count as unset.
+    pub fn from_env(prefix: &str) -> Result<ExtractingPolicy<KeySource>, EnvConfigError> {
+        from_lookup(prefix, |name| std::env::var(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_policy_from_env() {
+        let env = |vars: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            move |name: &str| vars.get(name).cloned().ok_or(VarError::NotPresent)
+        };
+
+        let policy = from_lookup(
+            "API",
+            env(&[
+                ("API_RATE", "10r/s"),
+                ("API_BURST", "20"),
+                ("API_KEY", "ip"),
+                ("API_MODE", "shadow"),
+            ]),
+        )
+        .unwrap();
+        let status = policy.policy().status();
+        assert_eq!((status.replenish_interval_ms, status.burst_size), (100, 20));
+        assert!(policy.policy().handle().is_shadow());
+
+        assert_eq!(
+            from_lookup("API", env(&[])).unwrap_err(),
+            EnvConfigError::Missing("API_RATE".to_owned())
+        );
+        let err = from_lookup("API", env(&[("API_RATE", "10r/s"), ("API_MODE", "loud")]));
+        assert!(matches!(err, Err(EnvConfigError::Invalid { var, .. }) if var == "API_MODE"));
+    }
+}
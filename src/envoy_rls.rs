@@ -0,0 +1,467 @@
+//! Envoy rate limit service (RLS) gRPC protocol
+//!
+//! Envoy, and proxies built on it like Contour, delegate rate limit decisions
+//! to an external service over the `envoy.service.ratelimit.v3` gRPC API.
+//! This module speaks that protocol both ways:
+//!
+//! - [`RlsStore`] is a [`RateLimitStore`] asking an existing RLS cluster, so
+//!   a rama service shares its limits with the Envoy fleet.
+//! - [`RlsService`] is a tonic service answering Envoy from the policies of
+//!   this crate, to serve with `tonic::transport::Server`:
+//!
+//! ```ignore
+//! let service = RlsService::new()
+//!     .descriptor("edge", ["remote_address"], per_ip_policy)
+//!     .descriptor("edge", ["api_key"], per_key_policy);
+//! tonic::transport::Server::builder()
+//!     .add_service(service)
+//!     .serve(addr)
+//!     .await?;
+//! ```
+//!
+//! Only the fields of the protocol that carry decisions are implemented:
+//! response headers, dynamic metadata and quota assignments are neither sent
+//! nor read.
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use governor::Quota;
+use tonic::codegen::{BoxFuture, Service, StdError, empty_body, http};
+use tonic::transport::Channel;
+
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture};
+use crate::{GovernorError, Limiter};
+
+/// gRPC name of the service
+const SERVICE: &str = "envoy.service.ratelimit.v3.RateLimitService";
+
+/// gRPC path of its only method
+const SHOULD_RATE_LIMIT: &str = "/envoy.service.ratelimit.v3.RateLimitService/ShouldRateLimit";
+
+/// Descriptor key [`RlsStore`] sends keys under unless configured otherwise
+const DEFAULT_DESCRIPTOR_KEY: &str = "key";
+
+/// `envoy.service.ratelimit.v3.RateLimitRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+struct RateLimitRequest {
+    #[prost(string, tag = "1")]
+    domain: String,
+    #[prost(message, repeated, tag = "2")]
+    descriptors: Vec<RateLimitDescriptor>,
+    #[prost(uint32, tag = "3")]
+    hits_addend: u32,
+}
+
+/// `envoy.extensions.common.ratelimit.v3.RateLimitDescriptor`
+#[derive(Clone, PartialEq, prost::Message)]
+struct RateLimitDescriptor {
+    #[prost(message, repeated, tag = "1")]
+    entries: Vec<Entry>,
+}
+
+/// `envoy.extensions.common.ratelimit.v3.RateLimitDescriptor.Entry`
+#[derive(Clone, PartialEq, prost::Message)]
+struct Entry {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+struct RateLimitResponse {
+    #[prost(enumeration = "Code", tag = "1")]
+    overall_code: i32,
+    #[prost(message, repeated, tag = "2")]
+    statuses: Vec<DescriptorStatus>,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse.Code`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum Code {
+    Unknown = 0,
+    Ok = 1,
+    OverLimit = 2,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse.DescriptorStatus`
+#[derive(Clone, PartialEq, prost::Message)]
+struct DescriptorStatus {
+    #[prost(enumeration = "Code", tag = "1")]
+    code: i32,
+    #[prost(message, optional, tag = "2")]
+    current_limit: Option<RateLimit>,
+    #[prost(uint32, tag = "3")]
+    limit_remaining: u32,
+    #[prost(message, optional, tag = "4")]
+    duration_until_reset: Option<ProtoDuration>,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse.RateLimit`
+#[derive(Clone, PartialEq, prost::Message)]
+struct RateLimit {
+    #[prost(uint32, tag = "1")]
+    requests_per_unit: u32,
+    #[prost(enumeration = "Unit", tag = "2")]
+    unit: i32,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse.RateLimit.Unit`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum Unit {
+    Unknown = 0,
+    Second = 1,
+    Minute = 2,
+    Hour = 3,
+    Day = 4,
+}
+
+/// `google.protobuf.Duration`
+#[derive(Clone, PartialEq, prost::Message)]
+struct ProtoDuration {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+impl From<Duration> for ProtoDuration {
+    fn from(duration: Duration) -> Self {
+        ProtoDuration {
+            seconds: duration.as_secs() as i64,
+            nanos: duration.subsec_nanos() as i32,
+        }
+    }
+}
+
+impl From<&ProtoDuration> for Duration {
+    fn from(duration: &ProtoDuration) -> Self {
+        Duration::new(duration.seconds.max(0) as u64, duration.nanos.max(0) as u32)
+    }
+}
+
+/// A [`RateLimitStore`] delegating decisions to an Envoy rate limit service
+///
+/// Every check sends one descriptor with the key as value of the
+/// [`descriptor_key`](Self::descriptor_key), in the domain of the store, and
+/// the cost as `hits_addend`. The limits are those the service is configured
+/// with; the quota of the policy only sets the retry time of denials the
+/// service gives no reset time for.
+#[derive(Debug, Clone)]
+pub struct RlsStore {
+    channel: Channel,
+    domain: String,
+    descriptor_key: String,
+}
+
+impl RlsStore {
+    /// Ask the service behind `channel` about descriptors of `domain`
+    ///
+    /// ```ignore
+    /// let channel = Endpoint::from_static("http://ratelimit:8081").connect_lazy();
+    /// let store = RlsStore::new(channel, "edge").descriptor_key("remote_address");
+    /// ```
+    pub fn new(channel: Channel, domain: impl Into<String>) -> Self {
+        RlsStore {
+            channel,
+            domain: domain.into(),
+            descriptor_key: DEFAULT_DESCRIPTOR_KEY.to_owned(),
+        }
+    }
+
+    /// Send keys under descriptor key `key` (default `key`)
+    pub fn descriptor_key(mut self, key: impl Into<String>) -> Self {
+        self.descriptor_key = key.into();
+        self
+    }
+
+    async fn should_rate_limit(
+        &self,
+        request: RateLimitRequest,
+    ) -> Result<RateLimitResponse, StoreError> {
+        let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+        grpc.ready().await.map_err(StoreError::new)?;
+        let response = grpc
+            .unary(
+                tonic::Request::new(request),
+                http::uri::PathAndQuery::from_static(SHOULD_RATE_LIMIT),
+                tonic::codec::ProstCodec::default(),
+            )
+            .await
+            .map_err(StoreError::new)?;
+        Ok(response.into_inner())
+    }
+}
+
+impl RateLimitStore for RlsStore {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        Box::pin(async move {
+            let request = RateLimitRequest {
+                domain: self.domain.clone(),
+                descriptors: vec![RateLimitDescriptor {
+                    entries: vec![Entry {
+                        key: self.descriptor_key.clone(),
+                        value: key.to_owned(),
+                    }],
+                }],
+                hits_addend: n,
+            };
+            let response = self.should_rate_limit(request).await?;
+            match Code::try_from(response.overall_code) {
+                Ok(Code::Ok) => Ok(StoreDecision::Allowed),
+                Ok(Code::OverLimit) => {
+                    let retry_after = response
+                        .statuses
+                        .iter()
+                        .filter_map(|status| status.duration_until_reset.as_ref())
+                        .map(Duration::from)
+                        .max()
+                        .unwrap_or_else(|| quota.replenish_interval());
+                    Ok(StoreDecision::Denied { retry_after })
+                }
+                _ => Err(StoreError::new(format!(
+                    "rate limit service answered with code {}",
+                    response.overall_code
+                ))),
+            }
+        })
+    }
+}
+
+/// Descriptors a [`RlsService`] limits with one policy
+#[derive(Debug, Clone)]
+struct RlsRule {
+    domain: String,
+    keys: Vec<String>,
+    limiter: Limiter,
+}
+
+/// Envoy rate limit service answering from policies of this crate
+///
+/// A descriptor is limited by the first rule of its domain whose keys are
+/// exactly the keys of its entries, in order, and counted under the values
+/// of its entries joined by `,`, e.g. `203.0.113.7` for a `remote_address`
+/// descriptor. Descriptors without a rule are not limited, as in Envoy's own
+/// service. A request is over the limit if any of its descriptors is.
+#[derive(Debug, Clone, Default)]
+pub struct RlsService {
+    rules: Arc<Vec<RlsRule>>,
+}
+
+impl RlsService {
+    /// A service without rules, admitting everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit descriptors of `domain` with entries `keys` by `limiter`, a
+    /// policy keyed by string
+    pub fn descriptor<K: Into<String>>(
+        mut self,
+        domain: impl Into<String>,
+        keys: impl IntoIterator<Item = K>,
+        limiter: impl Into<Limiter>,
+    ) -> Self {
+        Arc::make_mut(&mut self.rules).push(RlsRule {
+            domain: domain.into(),
+            keys: keys.into_iter().map(Into::into).collect(),
+            limiter: limiter.into(),
+        });
+        self
+    }
+
+    async fn should_rate_limit(&self, request: RateLimitRequest) -> RateLimitResponse {
+        let cost = request.hits_addend.max(1);
+        let mut statuses = Vec::with_capacity(request.descriptors.len());
+        for descriptor in &request.descriptors {
+            let rule = self.rules.iter().find(|rule| {
+                rule.domain == request.domain
+                    && rule
+                        .keys
+                        .iter()
+                        .eq(descriptor.entries.iter().map(|entry| &entry.key))
+            });
+            let Some(rule) = rule else {
+                statuses.push(DescriptorStatus {
+                    code: Code::Ok as i32,
+                    ..Default::default()
+                });
+                continue;
+            };
+            let key = descriptor
+                .entries
+                .iter()
+                .map(|entry| entry.value.as_str())
+                .collect::<Vec<_>>()
+                .join(",");
+            let current_limit = Some(rate_limit(rule.limiter.policy().quota()));
+            statuses.push(match rule.limiter.check_n_for(&key, cost).await {
+                Ok(()) => DescriptorStatus {
+                    code: Code::Ok as i32,
+                    current_limit,
+                    ..Default::default()
+                },
+                Err(GovernorError::RateLimited(rejected)) => DescriptorStatus {
+                    code: Code::OverLimit as i32,
+                    current_limit,
+                    duration_until_reset: rejected.retry_after().map(ProtoDuration::from),
+                    ..Default::default()
+                },
+                Err(GovernorError::StoreUnavailable(err)) => {
+                    tracing::warn!("Rate limit store failed answering {}: {}", SERVICE, err);
+                    DescriptorStatus {
+                        code: Code::Unknown as i32,
+                        current_limit,
+                        ..Default::default()
+                    }
+                }
+            });
+        }
+        let overall = if statuses.iter().any(|s| s.code == Code::OverLimit as i32) {
+            Code::OverLimit
+        } else if statuses.iter().any(|s| s.code == Code::Unknown as i32) {
+            Code::Unknown
+        } else {
+            Code::Ok
+        };
+        RateLimitResponse {
+            overall_code: overall as i32,
+            statuses,
+        }
+    }
+}
+
+/// `quota` in the largest unit it admits at least one request per
+fn rate_limit(quota: Quota) -> RateLimit {
+    let interval = quota.replenish_interval().as_nanos().max(1);
+    let units = [
+        (Unit::Second, 1),
+        (Unit::Minute, 60),
+        (Unit::Hour, 3600),
+        (Unit::Day, 86400),
+    ];
+    let (unit, requests) = units
+        .into_iter()
+        .map(|(unit, secs)| (unit, Duration::from_secs(secs).as_nanos() / interval))
+        .find(|(_, requests)| *requests >= 1)
+        .unwrap_or((Unit::Day, 1));
+    RateLimit {
+        requests_per_unit: requests.min(u32::MAX as u128) as u32,
+        unit: unit as i32,
+    }
+}
+
+/// Answers one `ShouldRateLimit` call
+struct ShouldRateLimit(RlsService);
+
+impl tonic::server::UnaryService<RateLimitRequest> for ShouldRateLimit {
+    type Response = RateLimitResponse;
+    type Future = BoxFuture<tonic::Response<RateLimitResponse>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<RateLimitRequest>) -> Self::Future {
+        let service = self.0.clone();
+        Box::pin(async move {
+            let response = service.should_rate_limit(request.into_inner()).await;
+            Ok(tonic::Response::new(response))
+        })
+    }
+}
+
+impl<B> Service<http::Request<B>> for RlsService
+where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if request.uri().path() != SHOULD_RATE_LIMIT {
+            return Box::pin(async move {
+                let mut response = http::Response::new(empty_body());
+                let headers = response.headers_mut();
+                headers.insert(
+                    tonic::Status::GRPC_STATUS,
+                    (tonic::Code::Unimplemented as i32).into(),
+                );
+                headers.insert(
+                    http::header::CONTENT_TYPE,
+                    tonic::metadata::GRPC_CONTENT_TYPE,
+                );
+                Ok(response)
+            });
+        }
+        let method = ShouldRateLimit(self.clone());
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+            Ok(grpc.unary(method, request).await)
+        })
+    }
+}
+
+impl tonic::server::NamedService for RlsService {
+    const NAME: &'static str = SERVICE;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tonic::transport::Endpoint;
+    use tonic::transport::server::TcpIncoming;
+
+    use super::*;
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_rls_round_trip() {
+        let per_ip = GovernorPolicy::builder()
+            .per_minute(1)
+            .burst_size(2)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let service = RlsService::new().descriptor("edge", ["remote_address"], per_ip);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+
+        // a policy backed by the service shares its limits
+        let channel = Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect_lazy();
+        let store = RlsStore::new(channel.clone(), "edge").descriptor_key("remote_address");
+        let limiter = Limiter::new(
+            GovernorPolicy::builder()
+                .per_second(100)
+                .build_with_store(store),
+        );
+        let mut results = Vec::new();
+        for key in ["203.0.113.7", "203.0.113.7", "203.0.113.7", "198.51.100.1"] {
+            results.push(limiter.check_key(key).await.is_ok());
+        }
+        assert_eq!(results, [true, true, false, true]);
+
+        // descriptors without a rule are not limited
+        let other = RlsStore::new(channel, "edge");
+        let decision = other.check_n("203.0.113.7", Quota::per_second(1.try_into().unwrap()), 1);
+        assert_eq!(decision.await.unwrap(), StoreDecision::Allowed);
+    }
+}
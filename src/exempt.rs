@@ -0,0 +1,96 @@
+//! Requests that never count against a quota
+//!
+//! Probes and scrapers hit a service at a steady rate and shouldn't use up
+//! the quota of real clients, or be rejected themselves. A policy built with
+//! [`exempt_paths`](crate::GovernorPolicyBuilder::exempt_paths) or
+//! [`exempt_header`](crate::GovernorPolicyBuilder::exempt_header) lets
+//! matching requests through without checking them, no matcher tree needed.
+
+use std::any::Any;
+
+use rama_http::{HeaderName, Request, header};
+
+/// The HTTP request behind `request`, if it is one
+///
+/// Only requests with rama's default body type are recognized.
+pub(crate) fn http_request(request: &dyn Any) -> Option<&Request> {
+    request.downcast_ref::<Request>()
+}
+
+/// The body length `request` declares in its `Content-Length` header
+pub(crate) fn declared_length(request: &Request) -> Option<u64> {
+    let value = request.headers().get(header::CONTENT_LENGTH)?;
+    value.to_str().ok()?.parse().ok()
+}
+
+/// Paths and headers of requests let through unchecked
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Exemptions {
+    /// Path prefixes
+    pub(crate) paths: Vec<String>,
+    /// Header names with the value exempting a request
+    pub(crate) headers: Vec<(HeaderName, String)>,
+}
+
+impl Exemptions {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.headers.is_empty()
+    }
+
+    /// Whether `request` is an HTTP request matching an exemption
+    pub(crate) fn exempts(&self, request: &dyn Any) -> bool {
+        let Some(request) = http_request(request) else {
+            return false;
+        };
+        let path = request.uri().path();
+        self.paths
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+            || self.headers.iter().any(|(name, value)| {
+                request
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .any(|header| header.as_bytes() == value.as_bytes())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+    use rama_http::Body;
+
+    use super::*;
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_exemptions() {
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .exempt_paths(["/healthz", "/metrics"])
+            .exempt_header(HeaderName::from_static("x-internal-probe"), "synthetic")
+            .build();
+        let request = |path: &str, probe: Option<&str>| {
+            let mut builder = Request::builder().uri(path);
+            if let Some(probe) = probe {
+                builder = builder.header("x-internal-probe", probe);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let mut admitted = 0;
+        for (path, probe) in [
+            ("/api", None),
+            ("/healthz", None),
+            ("/metrics/process", None),
+            ("/api", Some("synthetic")),
+            ("/api", Some("other")),
+        ] {
+            let result = policy.check(Context::default(), request(path, probe)).await;
+            admitted += matches!(result.output, PolicyOutput::Ready(_)) as usize;
+        }
+        assert_eq!(admitted, 4);
+    }
+}
@@ -0,0 +1,244 @@
+//! Explaining decisions without counting requests
+//!
+//! "Why was I rate limited?" is the most common support question about a
+//! policy. [`GovernorPolicy::explain`] runs a request through the same steps
+//! as a check, without counting it or changing any state, and reports each
+//! of them: the key, the quota, the state of its bucket, and what the policy
+//! would decide. [`ExtractingPolicy::explain`](crate::ExtractingPolicy::explain)
+//! and [`SelectingPolicy::explain`](crate::SelectingPolicy::explain) add key
+//! extraction and policy selection. Explanations serialize to JSON, e.g. for
+//! an admin endpoint.
+
+use std::any::Any;
+use std::time::Duration;
+
+use governor::Quota;
+use rama_core::Context;
+use serde::Serialize;
+
+use crate::{GovernorPolicy, RateLimitInfo};
+
+/// What a policy would decide on a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "verdict", rename_all = "snake_case")]
+pub enum Verdict {
+    /// Let through unchecked, as it carries the bypass token
+    Bypassed,
+    /// Let through unchecked, as it is exempt from the policy
+    Exempt,
+    /// Let through unchecked, as no key could be derived from it
+    NoKey,
+    /// Let through, as enforcement is disabled
+    Disabled,
+    /// Rejected, as its key is on the denylist
+    Denylisted,
+    /// Rejected until an upstream's retry time has passed
+    BackedOff {
+        /// Time until the back-off ends, in milliseconds
+        retry_after_ms: u64,
+    },
+    /// Rejected from the decision cache without checking the quota
+    CachedRejection {
+        /// Time until the cached rejection expires, in milliseconds
+        retry_after_ms: u64,
+    },
+    /// Allowed by the quota
+    Allowed,
+    /// Rejected by the quota
+    Limited {
+        /// Time until the request would be allowed, in milliseconds,
+        /// unless its cost exceeds the burst size
+        retry_after_ms: Option<u64>,
+    },
+    /// Decided by the quota, whose state can't be read without counting the
+    /// request: direct and store backed policies
+    Unknown,
+}
+
+/// Every step of the decision on a request, see [`GovernorPolicy::explain`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Explanation {
+    /// Name of the policy deciding
+    pub policy: String,
+    /// Key the request is counted under, if any
+    pub key: Option<String>,
+    /// Cells the request costs
+    pub cost: u32,
+    /// Interval in which one cell of the quota is replenished, in milliseconds
+    pub replenish_interval_ms: u64,
+    /// Maximum number of cells of the quota
+    pub burst_size: u32,
+    /// Cells the key has left, if the state of its bucket can be read
+    pub remaining: Option<u32>,
+    /// Time until the bucket of the key is full again, in milliseconds
+    pub reset_ms: Option<u64>,
+    /// Whether a lockdown clamp applies on top of the quota
+    pub locked_down: bool,
+    /// Whether a drain rejects a share of the requests the quota allows
+    pub draining: bool,
+    /// Whether shadow mode lets the request through even if it is rejected
+    pub shadow: bool,
+    /// Whether mirror mode lets the request through even if it is over the
+    /// limit, tagged as such
+    pub mirror: bool,
+    /// What the policy would decide
+    pub verdict: Verdict,
+}
+
+impl Explanation {
+    /// Whether the policy would let the request through
+    ///
+    /// Unknown verdicts are assumed to be allowed. Doesn't account for the
+    /// lockdown clamp and drain, whose outcome is decided by chance or the
+    /// traffic at the time of the request.
+    pub fn would_allow(&self) -> bool {
+        match self.verdict {
+            Verdict::Bypassed
+            | Verdict::Exempt
+            | Verdict::NoKey
+            | Verdict::Disabled
+            | Verdict::Allowed
+            | Verdict::Unknown => true,
+            Verdict::Denylisted | Verdict::BackedOff { .. } | Verdict::CachedRejection { .. } => {
+                self.shadow
+            }
+            Verdict::Limited { .. } => self.shadow || self.mirror,
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// State of a bucket of `quota` whose state lies `backlog` ahead of a full
+/// one, and the verdict on a request costing `cost` cells
+fn check_backlog(quota: Quota, backlog: Duration, cost: u32) -> (RateLimitInfo, Verdict) {
+    let interval = quota.replenish_interval();
+    let burst = quota.burst_size().get();
+    let used = backlog.as_nanos().div_ceil(interval.as_nanos().max(1));
+    let info = RateLimitInfo::used(quota, used.try_into().unwrap_or(u32::MAX));
+    let needed = backlog + interval * cost;
+    let capacity = interval * burst;
+    let verdict = match needed <= capacity {
+        true => Verdict::Allowed,
+        false => Verdict::Limited {
+            retry_after_ms: (cost <= burst).then(|| millis(needed - capacity)),
+        },
+    };
+    (info, verdict)
+}
+
+impl GovernorPolicy {
+    /// Explain the decision on `request` without counting it
+    ///
+    /// Requests are explained under the key `default`, like this policy checks
+    /// them; see [`ExtractingPolicy::explain`](crate::ExtractingPolicy::explain)
+    /// for keys derived from the request.
+    pub fn explain<State, Request: 'static>(
+        &self,
+        _ctx: &Context<State>,
+        request: &Request,
+    ) -> Explanation {
+        self.explain_key(request, "default")
+    }
+
+    /// Explain the decision on `request` counted under `key`
+    pub(crate) fn explain_key(&self, request: &dyn Any, key: &str) -> Explanation {
+        let handle = self.handle_ref();
+        let quota = self.quota();
+        let cost = handle.cost_of(request);
+        let mut explanation = Explanation {
+            policy: self.name().to_owned(),
+            key: Some(key.to_owned()),
+            cost: cost.get(),
+            replenish_interval_ms: millis(quota.replenish_interval()),
+            burst_size: quota.burst_size().get(),
+            remaining: None,
+            reset_ms: None,
+            locked_down: handle.is_locked_down(),
+            draining: handle.is_draining(),
+            shadow: handle.is_shadow(),
+            mirror: handle.is_mirror(),
+            verdict: Verdict::Unknown,
+        };
+        if let GovernorPolicy::Keyed(policy) = self {
+            let (info, verdict) = check_backlog(quota, policy.backlog(key), cost.get());
+            explanation.remaining = Some(info.remaining);
+            explanation.reset_ms = Some(millis(info.reset));
+            explanation.verdict = verdict;
+        }
+
+        let cached = handle.decision_cache().and_then(|cache| cache.blocked(key));
+        explanation.verdict = if handle.carries_bypass(request) {
+            Verdict::Bypassed
+        } else if handle.exempts(request) {
+            Verdict::Exempt
+        } else if handle.is_denied(key) {
+            Verdict::Denylisted
+        } else if !handle.is_enabled() {
+            Verdict::Disabled
+        } else if let Some(retry_after) = handle.backed_off(key) {
+            Verdict::BackedOff {
+                retry_after_ms: millis(retry_after),
+            }
+        } else if let Some(retry_after) = cached {
+            Verdict::CachedRejection {
+                retry_after_ms: millis(retry_after),
+            }
+        } else {
+            explanation.verdict
+        };
+        explanation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rama_core::layer::limit::policy::Policy;
+
+    use super::*;
+    use crate::ExtractingPolicy;
+
+    #[tokio::test]
+    async fn test_explain_without_counting() {
+        let policy = ExtractingPolicy::new(
+            GovernorPolicy::builder()
+                .per_minute(2)
+                .name("api")
+                .build_with_keyer(|key: &str| key.to_owned()),
+            |_: &Context<()>, user: &Option<&str>| user.map(str::to_owned),
+        );
+        let ctx = Context::default();
+
+        let explanation = policy.explain(&ctx, &Some("alice"));
+        assert_eq!(explanation.policy, "api");
+        assert_eq!(explanation.key.as_deref(), Some("alice"));
+        assert_eq!(
+            (explanation.burst_size, explanation.remaining),
+            (2, Some(2))
+        );
+        assert_eq!(explanation.verdict, Verdict::Allowed);
+        // explaining counted nothing
+        assert_eq!(policy.explain(&ctx, &Some("alice")).remaining, Some(2));
+
+        for _ in 0..2 {
+            policy.check(Context::default(), Some("alice")).await;
+        }
+        let explanation = policy.explain(&ctx, &Some("alice"));
+        assert_eq!(explanation.remaining, Some(0));
+        let Verdict::Limited {
+            retry_after_ms: Some(retry_after_ms),
+        } = explanation.verdict
+        else {
+            panic!("Expected Limited, got {:?}", explanation.verdict);
+        };
+        assert!(retry_after_ms > 29_000 && retry_after_ms <= 30_000);
+        assert!(!explanation.would_allow());
+
+        policy.policy().handle().deny("bob");
+        let explanation = policy.explain(&ctx, &Some("bob"));
+        assert_eq!(explanation.verdict, Verdict::Denylisted);
+        assert_eq!(policy.explain(&ctx, &None).verdict, Verdict::NoKey);
+    }
+}
@@ -0,0 +1,289 @@
+//! Deriving limiter keys from requests
+//!
+//! Used as a plain `Policy`, a [`GovernorPolicy`] counts all requests under one
+//! key. Wrap it in an [`ExtractingPolicy`] to count each request under the key
+//! its [`KeyExtractor`] derives, e.g. a header value or a client address. The
+//! key function of a keyed policy then maps that string to the limiter key.
+//!
+//! Extractors are implemented for closures taking the context and the request.
+//! Tuples of extractors try each in turn and use the first key found, which
+//! suits mixed traffic, e.g. API key, then authenticated user, then client IP:
+//!
+//! ```ignore
+//! (HeaderKey::new(X_API_KEY), ExtensionKey::<UserId>::new(), ClientIpKey)
+//! ```
+//!
+//! Keys of all extractors in a tuple share one key space, so give them
+//! distinct shapes or prefixes if an API key could ever equal an address.
+//!
+//! Transports that identify a client beyond its address, e.g. a QUIC
+//! connection surviving a migration across client IPs, can store that
+//! identity in the context for an [`ExtensionKey`] to key on, so the client
+//! keeps its bucket when its address changes. rama doesn't serve HTTP/3 yet,
+//! so there is no built-in extractor for QUIC connection IDs.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use rama_core::Context;
+use rama_core::layer::limit::policy::{Policy, PolicyOutput, PolicyResult};
+use rama_http::{HeaderName, Request};
+use rama_net::stream::SocketInfo;
+
+use crate::{Explanation, GovernorError, GovernorGuard, GovernorPolicy, Verdict};
+
+/// Derives the key a request is counted under
+///
+/// Requests without a key, e.g. lacking the header an extractor looks for,
+/// are not limited by the policy.
+pub trait KeyExtractor<State, Request>: Send + Sync + 'static {
+    /// The key of `req`, if it has one
+    fn extract(&self, ctx: &Context<State>, req: &Request) -> Option<String>;
+}
+
+impl<State, Request, F> KeyExtractor<State, Request> for F
+where
+    F: Fn(&Context<State>, &Request) -> Option<String> + Send + Sync + 'static,
+{
+    fn extract(&self, ctx: &Context<State>, req: &Request) -> Option<String> {
+        self(ctx, req)
+    }
+}
+
+/// Address of the peer the request came from
+pub(crate) fn client_ip<State>(ctx: &Context<State>) -> Option<IpAddr> {
+    ctx.get::<SocketInfo>().map(|info| info.peer_addr().ip())
+}
+
+/// The `X-Api-Key` header
+pub const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+macro_rules! impl_key_extractor_chain {
+    ($($extractor:ident),+) => {
+        impl<State, Request, $($extractor),+> KeyExtractor<State, Request> for ($($extractor,)+)
+        where
+            $($extractor: KeyExtractor<State, Request>,)+
+        {
+            #[allow(non_snake_case)]
+            fn extract(&self, ctx: &Context<State>, req: &Request) -> Option<String> {
+                let ($($extractor,)+) = self;
+                None$(.or_else(|| $extractor.extract(ctx, req)))+
+            }
+        }
+    };
+}
+
+impl_key_extractor_chain!(A, B);
+impl_key_extractor_chain!(A, B, C);
+impl_key_extractor_chain!(A, B, C, D);
+
+/// Keys requests by the address of the peer, taken from its `SocketInfo`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientIpKey;
+
+impl<State, Request> KeyExtractor<State, Request> for ClientIpKey {
+    fn extract(&self, ctx: &Context<State>, _req: &Request) -> Option<String> {
+        client_ip(ctx).map(|ip| ip.to_string())
+    }
+}
+
+/// Keys requests by a context extension, e.g. the user an auth layer stored
+///
+/// Requests without the extension have no key.
+pub struct ExtensionKey<T> {
+    _extension: PhantomData<fn() -> T>,
+}
+
+impl<T> ExtensionKey<T> {
+    /// Key requests by their `T` extension
+    pub fn new() -> Self {
+        ExtensionKey {
+            _extension: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for ExtensionKey<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for ExtensionKey<T> {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for ExtensionKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtensionKey")
+            .field("extension", &std::any::type_name::<T>())
+            .finish()
+    }
+}
+
+impl<T, State, Request> KeyExtractor<State, Request> for ExtensionKey<T>
+where
+    T: fmt::Display + Send + Sync + 'static,
+{
+    fn extract(&self, ctx: &Context<State>, _req: &Request) -> Option<String> {
+        ctx.get::<T>().map(T::to_string)
+    }
+}
+
+/// Keys HTTP requests by the value of a header
+///
+/// Requests without the header, or with a value that isn't visible ASCII,
+/// have no key.
+#[derive(Debug, Clone)]
+pub struct HeaderKey {
+    name: HeaderName,
+}
+
+impl HeaderKey {
+    /// Key requests by the value of the `name` header
+    pub fn new(name: HeaderName) -> Self {
+        HeaderKey { name }
+    }
+}
+
+impl<State, Body> KeyExtractor<State, Request<Body>> for HeaderKey {
+    fn extract(&self, _ctx: &Context<State>, req: &Request<Body>) -> Option<String> {
+        let value = req.headers().get(&self.name)?;
+        value.to_str().ok().map(str::to_owned)
+    }
+}
+
+/// Policy checking each request under the key derived by a [`KeyExtractor`]
+pub struct ExtractingPolicy<E> {
+    policy: Arc<GovernorPolicy>,
+    extractor: E,
+}
+
+impl<E> ExtractingPolicy<E> {
+    /// Check requests with `policy` under the keys derived by `extractor`
+    pub fn new(policy: impl Into<Arc<GovernorPolicy>>, extractor: E) -> Self {
+        ExtractingPolicy {
+            policy: policy.into(),
+            extractor,
+        }
+    }
+
+    /// The wrapped policy
+    pub fn policy(&self) -> &Arc<GovernorPolicy> {
+        &self.policy
+    }
+
+    /// Explain the decision on `request` without counting it, see
+    /// [`GovernorPolicy::explain`]
+    pub fn explain<State, Request: 'static>(
+        &self,
+        ctx: &Context<State>,
+        request: &Request,
+    ) -> Explanation
+    where
+        E: KeyExtractor<State, Request>,
+    {
+        match self.extractor.extract(ctx, request) {
+            Some(key) => self.policy.explain_key(request, &key),
+            None => {
+                let mut explanation = self.policy.explain(ctx, request);
+                explanation.key = None;
+                explanation.remaining = None;
+                explanation.reset_ms = None;
+                explanation.verdict = Verdict::NoKey;
+                explanation
+            }
+        }
+    }
+}
+
+impl<E: Clone> Clone for ExtractingPolicy<E> {
+    fn clone(&self) -> Self {
+        ExtractingPolicy {
+            policy: self.policy.clone(),
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+impl<E> fmt::Debug for ExtractingPolicy<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtractingPolicy")
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E, State, Request> Policy<State, Request> for ExtractingPolicy<E>
+where
+    E: KeyExtractor<State, Request>,
+    State: Clone + Send + Sync + 'static,
+    Request: Send + Sync + 'static,
+{
+    type Guard = GovernorGuard;
+    type Error = GovernorError;
+
+    async fn check(
+        &self,
+        ctx: Context<State>,
+        request: Request,
+    ) -> PolicyResult<State, Request, Self::Guard, Self::Error> {
+        match self.extractor.extract(&ctx, &request) {
+            Some(key) => self.policy.check_with_key(ctx, request, &key).await,
+            None => PolicyResult {
+                ctx,
+                request,
+                output: PolicyOutput::Ready(GovernorGuard::unlimited()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_extracting_policy() {
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let policy = ExtractingPolicy::new(policy, |_: &Context<()>, user: &Option<&str>| {
+            user.map(str::to_owned)
+        });
+
+        let mut admitted = 0;
+        for user in [Some("alice"), Some("alice"), Some("bob"), None, None] {
+            let result = policy.check(Context::default(), user).await;
+            admitted += matches!(result.output, PolicyOutput::Ready(_)) as usize;
+        }
+        assert_eq!(admitted, 4);
+    }
+
+    #[test]
+    fn test_key_fallback_chain() {
+        let chain = (
+            HeaderKey::new(X_API_KEY),
+            ExtensionKey::<u64>::new(),
+            ClientIpKey,
+        );
+        let req = |api_key: Option<&str>| {
+            let mut builder = Request::builder();
+            if let Some(api_key) = api_key {
+                builder = builder.header(X_API_KEY, api_key);
+            }
+            builder.body(()).unwrap()
+        };
+
+        let mut ctx = Context::default();
+        ctx.insert(SocketInfo::new(None, "192.0.2.1:443".parse().unwrap()));
+        assert_eq!(chain.extract(&ctx, &req(None)).unwrap(), "192.0.2.1");
+        ctx.insert(42u64);
+        assert_eq!(chain.extract(&ctx, &req(None)).unwrap(), "42");
+        assert_eq!(chain.extract(&ctx, &req(Some("k1"))).unwrap(), "k1");
+    }
+}
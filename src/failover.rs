@@ -0,0 +1,280 @@
+//! Automatic fallback for unhealthy remote stores
+//!
+//! [`FailoverStore`] watches the outcome of every call to its inner store.
+//! After a run of failures it marks the backend unhealthy and answers from
+//! its fallback instead, an in-process limiter or plain fail-open. While
+//! unhealthy, one request per probe interval is sent to the backend as a probe,
+//! and the first success switches back. Call [`FailoverStore::probe`] from a
+//! timer to also recover while there is no traffic.
+
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use governor::Quota;
+
+use crate::ShardedStore;
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture};
+
+/// Key [`FailoverStore::probe`] checks, kept apart from real keys by its prefix
+const PROBE_KEY: &str = "__governor_health_probe";
+
+/// What a [`FailoverStore`] answers with while its backend is unhealthy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StoreFallback {
+    /// Enforce the quota per instance with an in-process limiter (default)
+    #[default]
+    Local,
+    /// Allow every request
+    Open,
+}
+
+/// Health of the backend of a [`FailoverStore`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendHealth {
+    /// Checks go to the backend
+    Healthy,
+    /// Checks are answered by the fallback
+    Unhealthy,
+}
+
+type StateChangeHook = Box<dyn Fn(BackendHealth) + Send + Sync>;
+
+/// A [`RateLimitStore`] switching to a fallback while its inner store fails
+pub struct FailoverStore<S> {
+    inner: S,
+    fallback: StoreFallback,
+    local: ShardedStore,
+    failure_threshold: u32,
+    probe_interval: Duration,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+    /// Nanoseconds since `start` after which the next probe may go out
+    next_probe: AtomicU64,
+    start: Instant,
+    on_state_change: Vec<StateChangeHook>,
+}
+
+impl<S: RateLimitStore> FailoverStore<S> {
+    /// Fall back to a local limiter after 3 consecutive failures of `inner`,
+    /// probing it again every 5 seconds
+    pub fn new(inner: S) -> Self {
+        FailoverStore {
+            inner,
+            fallback: StoreFallback::default(),
+            local: ShardedStore::new(),
+            failure_threshold: 3,
+            probe_interval: Duration::from_secs(5),
+            healthy: AtomicBool::new(true),
+            failures: AtomicU32::new(0),
+            next_probe: AtomicU64::new(0),
+            start: Instant::now(),
+            on_state_change: Vec::new(),
+        }
+    }
+
+    /// What to answer with while the backend is unhealthy
+    pub fn fallback(mut self, fallback: StoreFallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Consecutive failures after which the backend is considered unhealthy
+    ///
+    /// Failures below the threshold are returned to the policy, which handles
+    /// them according to its [`FailureMode`](crate::FailureMode).
+    pub fn failure_threshold(mut self, failures: u32) -> Self {
+        self.failure_threshold = failures.max(1);
+        self
+    }
+
+    /// How often an unhealthy backend is probed
+    pub fn probe_interval(mut self, interval: Duration) -> Self {
+        self.probe_interval = interval;
+        self
+    }
+
+    /// Call `hook` whenever the backend turns unhealthy or recovers
+    pub fn on_state_change(mut self, hook: impl Fn(BackendHealth) + Send + Sync + 'static) -> Self {
+        self.on_state_change.push(Box::new(hook));
+        self
+    }
+
+    /// Current health of the backend
+    pub fn health(&self) -> BackendHealth {
+        if self.healthy.load(Ordering::Acquire) {
+            BackendHealth::Healthy
+        } else {
+            BackendHealth::Unhealthy
+        }
+    }
+
+    /// Check the backend with a probe key, updating the health accordingly
+    pub async fn probe(&self) -> BackendHealth {
+        let quota = Quota::per_second(NonZeroU32::MAX);
+        match self.inner.check_n(PROBE_KEY, quota, 1).await {
+            Ok(_) => self.succeeded(),
+            Err(err) => {
+                tracing::debug!("Rate limit store health probe failed: {}", err);
+                self.failed();
+            }
+        }
+        self.health()
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    fn transition(&self, health: BackendHealth) {
+        match health {
+            BackendHealth::Healthy => tracing::info!("Rate limit store recovered"),
+            BackendHealth::Unhealthy => tracing::warn!(
+                "Rate limit store unhealthy, falling back to {:?}",
+                self.fallback
+            ),
+        }
+        for hook in &self.on_state_change {
+            hook(health);
+        }
+    }
+
+    fn succeeded(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        if !self.healthy.swap(true, Ordering::AcqRel) {
+            self.transition(BackendHealth::Healthy);
+        }
+    }
+
+    fn failed(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < self.failure_threshold {
+            return;
+        }
+        let next_probe = self.now() + self.probe_interval.as_nanos() as u64;
+        self.next_probe.store(next_probe, Ordering::Relaxed);
+        if self.healthy.swap(false, Ordering::AcqRel) {
+            self.transition(BackendHealth::Unhealthy);
+        }
+    }
+
+    /// Claim the next probe if it is due, so only one request probes per interval
+    fn claim_probe(&self) -> bool {
+        let now = self.now();
+        let due = self.next_probe.load(Ordering::Relaxed);
+        let next = now + self.probe_interval.as_nanos() as u64;
+        due <= now
+            && self
+                .next_probe
+                .compare_exchange(due, next, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn check_fallback(&self, key: &str, quota: Quota, n: u32) -> StoreDecision {
+        match self.fallback {
+            StoreFallback::Local => self.local.check(key, quota, n),
+            StoreFallback::Open => StoreDecision::Allowed,
+        }
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for FailoverStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FailoverStore")
+            .field("inner", &self.inner)
+            .field("fallback", &self.fallback)
+            .field("healthy", &self.healthy.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl<S: RateLimitStore> RateLimitStore for FailoverStore<S> {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        Box::pin(async move {
+            let healthy = self.healthy.load(Ordering::Acquire);
+            if !healthy && !self.claim_probe() {
+                return Ok(self.check_fallback(key, quota, n));
+            }
+            match self.inner.check_n(key, quota, n).await {
+                Ok(decision) => {
+                    self.succeeded();
+                    Ok(decision)
+                }
+                Err(err) => {
+                    self.failed();
+                    if self.healthy.load(Ordering::Acquire) {
+                        Err(err)
+                    } else {
+                        Ok(self.check_fallback(key, quota, n))
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct FlakyStore {
+        down: AtomicBool,
+    }
+
+    impl RateLimitStore for FlakyStore {
+        fn check_n<'a>(
+            &'a self,
+            _key: &'a str,
+            _quota: Quota,
+            _n: u32,
+        ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+            let down = self.down.load(Ordering::Relaxed);
+            Box::pin(async move {
+                match down {
+                    true => Err(StoreError::new("connection refused")),
+                    false => Ok(StoreDecision::Allowed),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover_and_recovery() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let store = FailoverStore::new(FlakyStore::default())
+            .failure_threshold(2)
+            .probe_interval(Duration::from_secs(3600))
+            .on_state_change(move |health| recorded.lock().unwrap().push(health));
+        let quota = Quota::per_minute(NonZeroU32::new(1).unwrap());
+
+        store.inner.down.store(true, Ordering::Relaxed);
+        assert!(store.check_n("alice", quota, 1).await.is_err());
+        // the second failure trips the fallback, which enforces the quota locally
+        assert_eq!(
+            store.check_n("alice", quota, 1).await.unwrap(),
+            StoreDecision::Allowed
+        );
+        assert_eq!(store.health(), BackendHealth::Unhealthy);
+        assert!(matches!(
+            store.check_n("alice", quota, 1).await.unwrap(),
+            StoreDecision::Denied { .. }
+        ));
+
+        store.inner.down.store(false, Ordering::Relaxed);
+        assert_eq!(store.probe().await, BackendHealth::Healthy);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [BackendHealth::Unhealthy, BackendHealth::Healthy]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Fixed-window counters as an alternative to GCRA
+//!
+//! With [`Algorithm::FixedWindow`] a policy counts requests per key in
+//! windows of `burst_size * replenish_interval` and resets the count when a
+//! new window starts. A key whose window has passed is dropped as a whole,
+//! which keeps very high-cardinality limits cheap, at the cost of allowing up
+//! to twice the burst around a window edge.
+
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use governor::Quota;
+use serde::{Deserialize, Serialize};
+
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture};
+
+/// Drop counters of past windows every this many new keys
+const GC_EVERY: u64 = 4096;
+
+/// Rate limiting algorithm of a policy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// Generic cell rate algorithm, smoothing requests over the quota (default)
+    #[default]
+    Gcra,
+    /// Counters reset at the start of every window
+    FixedWindow,
+}
+
+/// Index and request count of the current window of a key
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    index: u64,
+    count: u32,
+}
+
+/// Fixed-window counters per key, used as the store of a policy
+pub(crate) struct FixedWindowStore<K> {
+    key_fn: Box<dyn Fn(&str) -> K + Send + Sync>,
+    windows: DashMap<K, Window>,
+    inserts: AtomicU64,
+    start: Instant,
+}
+
+impl<K: Hash + Eq + Send + Sync + 'static> FixedWindowStore<K> {
+    pub(crate) fn new(key_fn: impl Fn(&str) -> K + Send + Sync + 'static) -> Self {
+        FixedWindowStore {
+            key_fn: Box::new(key_fn),
+            windows: DashMap::new(),
+            inserts: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    fn check(&self, key: &str, quota: Quota, n: u32) -> StoreDecision {
+        self.check_at(key, quota, n, self.start.elapsed())
+    }
+
+    /// Check `key` at `now`, measured from any fixed point in time
+    pub(crate) fn check_at(&self, key: &str, quota: Quota, n: u32, now: Duration) -> StoreDecision {
+        let limit = quota.burst_size().get();
+        let length = (quota.replenish_interval() * limit).as_nanos().max(1) as u64;
+        let now = now.as_nanos() as u64;
+        let index = now / length;
+        let retry_after = Duration::from_nanos((index + 1) * length - now);
+
+        let key = (self.key_fn)(key);
+        let mut inserted = false;
+        let decision = {
+            let mut window = self.windows.entry(key).or_insert_with(|| {
+                inserted = true;
+                Window { index, count: 0 }
+            });
+            if window.index != index {
+                *window = Window { index, count: 0 };
+            }
+            match window.count.checked_add(n) {
+                Some(count) if count <= limit => {
+                    window.count = count;
+                    StoreDecision::Allowed
+                }
+                _ => StoreDecision::Denied { retry_after },
+            }
+        };
+
+        if inserted && self.inserts.fetch_add(1, Ordering::Relaxed) % GC_EVERY == GC_EVERY - 1 {
+            self.windows.retain(|_, window| window.index >= index);
+        }
+        decision
+    }
+}
+
+impl<K: Hash + Eq + Send + Sync + 'static> RateLimitStore for FixedWindowStore<K> {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        let decision = self.check(key, quota, n);
+        Box::pin(async move { Ok(decision) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GovernorError, GovernorPolicy, Limiter};
+
+    #[tokio::test]
+    async fn test_fixed_window() {
+        let limiter = Limiter::from(
+            GovernorPolicy::builder()
+                .per_minute(2)
+                .algorithm(Algorithm::FixedWindow)
+                .build_with_keyer(|key: &str| key.to_owned()),
+        );
+        limiter.check_key("alice").await.unwrap();
+        limiter.check_key("alice").await.unwrap();
+        let Err(GovernorError::RateLimited(rejected)) = limiter.check_key("alice").await else {
+            panic!("Expected the window to be used up");
+        };
+        assert!(rejected.retry_after().unwrap() <= Duration::from_secs(60));
+        limiter.check_key("bob").await.unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+//! Fuzzing entry points
+//!
+//! Compiled with `--cfg fuzzing`, which `cargo fuzz` sets, and driven by the
+//! targets in `fuzz/`. Each function feeds untrusted input through one parsing
+//! path of the crate; any panic is a bug, errors and missing keys are not.
+//!
+//! ```text
+//! cargo +nightly fuzz run header_key
+//! ```
+
+use arbitrary::Arbitrary;
+use rama_core::Context;
+use rama_http::{HeaderName, HeaderValue, Request};
+
+use crate::{GovernorConfig, HeaderKey, KeyExtractor, KeyNormalizer, KeySource, PolicySpec};
+
+/// A request header and the normalization steps its key runs through
+#[derive(Debug, Arbitrary)]
+pub struct HeaderInput<'a> {
+    /// Header name; inputs with an invalid one are skipped
+    pub name: &'a str,
+    /// Raw header value
+    pub value: &'a [u8],
+    /// Normalization steps applied to the extracted key
+    pub steps: Vec<KeyNormalizer>,
+}
+
+/// Extract a key from a request carrying an arbitrary header and normalize it
+pub fn header_key(input: HeaderInput<'_>) {
+    let (Ok(name), Ok(value)) = (
+        HeaderName::try_from(input.name),
+        HeaderValue::from_bytes(input.value),
+    ) else {
+        return;
+    };
+    let req = Request::builder()
+        .header(name.clone(), value)
+        .body(())
+        .unwrap();
+    let ctx = Context::default();
+
+    let key = HeaderKey::new(name.clone()).extract(&ctx, &req);
+    assert_eq!(key, KeySource::Header(name).extract(&ctx, &req));
+    if let Some(key) = key {
+        input
+            .steps
+            .into_iter()
+            .fold(key, |key, step| step.apply(key));
+    }
+}
+
+/// Parse a policy string, building the policy if it is valid
+pub fn policy_spec(spec: &str) {
+    if let Ok(spec) = spec.parse::<PolicySpec>() {
+        spec.build();
+    }
+}
+
+/// Deserialize a JSON rule set, validating and building it if it parses
+pub fn config(json: &[u8]) {
+    if let Ok(config) = serde_json::from_slice::<GovernorConfig>(json) {
+        config.validate();
+        let _ = config.build();
+    }
+}
@@ -0,0 +1,204 @@
+//! Keying and quota selection by country and network
+//!
+//! The crate ships no geo database. Implement [`GeoResolver`] on top of
+//! MaxMind or your own data, then key requests with [`CountryKey`] or
+//! [`AsnKey`], or give specific networks stricter quotas with [`GeoSelector`].
+//! The client address is taken from the `SocketInfo` in the context.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use rama_core::Context;
+
+use crate::extract::client_ip;
+use crate::{GovernorPolicy, KeyExtractor, PolicySelector};
+
+/// Where an address is located, as far as known
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 country code, e.g. "NL"
+    pub country: Option<String>,
+    /// Autonomous system number of the network
+    pub asn: Option<u32>,
+}
+
+/// Looks up the location of client addresses
+pub trait GeoResolver: Send + Sync + 'static {
+    /// Location of `ip`
+    fn resolve(&self, ip: IpAddr) -> GeoInfo;
+}
+
+impl<R: GeoResolver + ?Sized> GeoResolver for Arc<R> {
+    fn resolve(&self, ip: IpAddr) -> GeoInfo {
+        (**self).resolve(ip)
+    }
+}
+
+/// Key used for addresses that don't resolve
+const UNKNOWN: &str = "unknown";
+
+/// Keys requests by the country of the client
+///
+/// Clients whose country doesn't resolve share the key "unknown".
+#[derive(Debug, Clone)]
+pub struct CountryKey<R> {
+    resolver: R,
+}
+
+impl<R> CountryKey<R> {
+    /// Key by the country `resolver` finds for the client address
+    pub fn new(resolver: R) -> Self {
+        CountryKey { resolver }
+    }
+}
+
+impl<R: GeoResolver, State, Request> KeyExtractor<State, Request> for CountryKey<R> {
+    fn extract(&self, ctx: &Context<State>, _req: &Request) -> Option<String> {
+        let info = self.resolver.resolve(client_ip(ctx)?);
+        Some(info.country.unwrap_or_else(|| UNKNOWN.to_owned()))
+    }
+}
+
+/// Keys requests by the autonomous system of the client
+///
+/// Clients whose network doesn't resolve share the key "unknown".
+#[derive(Debug, Clone)]
+pub struct AsnKey<R> {
+    resolver: R,
+}
+
+impl<R> AsnKey<R> {
+    /// Key by the ASN `resolver` finds for the client address
+    pub fn new(resolver: R) -> Self {
+        AsnKey { resolver }
+    }
+}
+
+impl<R: GeoResolver, State, Request> KeyExtractor<State, Request> for AsnKey<R> {
+    fn extract(&self, ctx: &Context<State>, _req: &Request) -> Option<String> {
+        let info = self.resolver.resolve(client_ip(ctx)?);
+        Some(
+            info.asn
+                .map_or_else(|| UNKNOWN.to_owned(), |asn| format!("AS{asn}")),
+        )
+    }
+}
+
+/// Selects the policy by the network or country of the client
+///
+/// A policy set for the client's ASN wins over one for its country. All other
+/// requests, including those without a client address, use the default policy.
+pub struct GeoSelector<R> {
+    resolver: R,
+    by_asn: HashMap<u32, GovernorPolicy>,
+    by_country: HashMap<String, GovernorPolicy>,
+    default: GovernorPolicy,
+}
+
+impl<R> GeoSelector<R> {
+    /// Resolve clients with `resolver`, using `default` unless configured otherwise
+    pub fn new(resolver: R, default: GovernorPolicy) -> Self {
+        GeoSelector {
+            resolver,
+            by_asn: HashMap::new(),
+            by_country: HashMap::new(),
+            default,
+        }
+    }
+
+    /// Use `policy` for clients in autonomous system `asn`
+    pub fn asn(mut self, asn: u32, policy: GovernorPolicy) -> Self {
+        self.by_asn.insert(asn, policy);
+        self
+    }
+
+    /// Use `policy` for clients in `country`, an ISO 3166-1 alpha-2 code
+    pub fn country(mut self, country: impl Into<String>, policy: GovernorPolicy) -> Self {
+        self.by_country
+            .insert(country.into().to_ascii_uppercase(), policy);
+        self
+    }
+}
+
+impl<R> std::fmt::Debug for GeoSelector<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoSelector")
+            .field("asns", &self.by_asn.keys())
+            .field("countries", &self.by_country.keys())
+            .finish()
+    }
+}
+
+impl<R: GeoResolver, State, Request> PolicySelector<State, Request> for GeoSelector<R> {
+    fn select(&self, ctx: &Context<State>, _req: &Request) -> &GovernorPolicy {
+        let Some(ip) = client_ip(ctx) else {
+            return &self.default;
+        };
+        let info = self.resolver.resolve(ip);
+        info.asn
+            .and_then(|asn| self.by_asn.get(&asn))
+            .or_else(|| {
+                let country = info.country?.to_ascii_uppercase();
+                self.by_country.get(&country)
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rama_net::stream::SocketInfo;
+
+    /// Everything in 10.0.0.0/8 is AS64500 in NL, the rest is unknown
+    struct TestResolver;
+
+    impl GeoResolver for TestResolver {
+        fn resolve(&self, ip: IpAddr) -> GeoInfo {
+            match ip {
+                IpAddr::V4(v4) if v4.octets()[0] == 10 => GeoInfo {
+                    country: Some("NL".to_owned()),
+                    asn: Some(64500),
+                },
+                _ => GeoInfo::default(),
+            }
+        }
+    }
+
+    fn ctx(peer: &str) -> Context<()> {
+        let mut ctx = Context::default();
+        ctx.insert(SocketInfo::new(None, peer.parse().unwrap()));
+        ctx
+    }
+
+    #[tokio::test]
+    async fn test_geo_keys_and_selection() {
+        let nl = ctx("10.1.2.3:443");
+        let other = ctx("192.0.2.1:443");
+        assert_eq!(
+            CountryKey::new(TestResolver).extract(&nl, &()).unwrap(),
+            "NL"
+        );
+        assert_eq!(
+            AsnKey::new(TestResolver).extract(&nl, &()).unwrap(),
+            "AS64500"
+        );
+        assert_eq!(
+            AsnKey::new(TestResolver).extract(&other, &()).unwrap(),
+            "unknown"
+        );
+
+        let selector = GeoSelector::new(
+            TestResolver,
+            GovernorPolicy::builder().per_second(10).build(),
+        )
+        .country("nl", GovernorPolicy::builder().per_second(5).build())
+        .asn(64500, GovernorPolicy::builder().per_second(1).build());
+        let interval = |ctx: &Context<()>| {
+            let policy = selector.select(ctx, &());
+            policy.status().replenish_interval_ms
+        };
+        assert_eq!((interval(&nl), interval(&other)), (1000, 100));
+    }
+}
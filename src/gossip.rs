@@ -0,0 +1,340 @@
+//! Peer-to-peer state sync for small clusters
+//!
+//! Every node counts the requests it accepted per key and periodically sends
+//! those deltas to its peers over UDP. Deltas received from peers are charged
+//! against the local limiter, so each node enforces an approximation of the
+//! cluster-wide rate without a shared backend. Messages are neither
+//! authenticated nor encrypted: only use this on a trusted network.
+
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::Poll;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+
+use crate::GovernorKey;
+use crate::keyed::KeyedLimiter;
+use crate::runtime::{self, Runtime, RuntimeFuture};
+use crate::snapshot::KeyCodec;
+use crate::sync::StartOnce;
+
+/// Largest UDP payload over IPv4, the size messages are filled up to
+const MAX_DATAGRAM: usize = 65_507;
+
+/// Start and end of a message around its deltas
+const MESSAGE_START: &[u8] = br#"{"deltas":["#;
+const MESSAGE_END: &[u8] = b"]}";
+
+/// Configuration of the gossip sync mode
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    bind: SocketAddr,
+    peers: Vec<SocketAddr>,
+    interval: Duration,
+}
+
+impl GossipConfig {
+    /// Listen on `bind` and exchange deltas with `peers`
+    pub fn new(bind: SocketAddr, peers: impl IntoIterator<Item = SocketAddr>) -> Self {
+        GossipConfig {
+            bind,
+            peers: peers.into_iter().collect(),
+            interval: Duration::from_millis(500),
+        }
+    }
+
+    /// How often deltas are sent to peers (default 500ms)
+    ///
+    /// Shorter intervals tighten the cluster-wide bound at the cost of more traffic.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+#[derive(Deserialize)]
+struct DeltaMessage {
+    deltas: Vec<(serde_json::Value, u32)>,
+}
+
+/// Local consumption not yet sent to peers
+pub(crate) struct Gossip<K> {
+    config: GossipConfig,
+    codec: KeyCodec<K>,
+    deltas: Mutex<HashMap<K, u32>>,
+    started: StartOnce,
+}
+
+impl<K: GovernorKey> Gossip<K> {
+    pub(crate) fn new(config: GossipConfig, codec: KeyCodec<K>) -> Self {
+        Gossip {
+            config,
+            codec,
+            deltas: Mutex::new(HashMap::new()),
+            started: StartOnce::new(),
+        }
+    }
+
+    pub(crate) fn record(&self, key: &K) {
+        let mut deltas = self.deltas.lock().unwrap();
+        match deltas.get_mut(key) {
+            Some(count) => *count = count.saturating_add(1),
+            None => {
+                deltas.insert(key.clone(), 1);
+            }
+        }
+    }
+
+    /// Take the deltas as messages, each filled up to a datagram
+    fn take_messages(&self) -> Vec<Vec<u8>> {
+        let deltas = std::mem::take(&mut *self.deltas.lock().unwrap());
+        let framing = MESSAGE_START.len() + MESSAGE_END.len();
+        let mut messages = Vec::new();
+        let mut message = MESSAGE_START.to_vec();
+        for (key, count) in deltas {
+            let Ok(key) = self.codec.encode(&key) else {
+                continue;
+            };
+            let Ok(delta) = serde_json::to_vec(&(key, count)) else {
+                continue;
+            };
+            if framing + delta.len() > MAX_DATAGRAM {
+                tracing::warn!(
+                    "Not gossiping a key of {} bytes, too long for a datagram",
+                    delta.len()
+                );
+                continue;
+            }
+            let first = message.len() == MESSAGE_START.len();
+            if !first && message.len() + 1 + delta.len() + MESSAGE_END.len() > MAX_DATAGRAM {
+                message.extend_from_slice(MESSAGE_END);
+                messages.push(std::mem::replace(&mut message, MESSAGE_START.to_vec()));
+            } else if !first {
+                message.push(b',');
+            }
+            message.extend_from_slice(&delta);
+        }
+        if message.len() > MESSAGE_START.len() {
+            message.extend_from_slice(MESSAGE_END);
+            messages.push(message);
+        }
+        messages
+    }
+
+    /// Spawn the send and receive tasks on `runtime`, once
+    ///
+    /// Both tasks stop, releasing the socket, once the policy is dropped or
+    /// the runtime shuts down.
+    pub(crate) fn start_if_needed(
+        self: &Arc<Self>,
+        runtime: &Arc<dyn Runtime>,
+        limiter: Weak<ArcSwap<KeyedLimiter<K>>>,
+    ) {
+        self.started.call(|| {
+            let gossip = Arc::downgrade(self);
+            let bind = self.config.bind;
+            let interval = self.config.interval;
+            let codec = self.codec;
+            let spawner = runtime.clone();
+            runtime.spawn(Box::pin(async move {
+                let socket = match UdpSocket::bind(bind).await {
+                    Ok(socket) => Arc::new(socket),
+                    Err(err) => {
+                        tracing::error!("Failed to bind gossip socket on {}: {}", bind, err);
+                        return;
+                    }
+                };
+                spawner.spawn(Box::pin(receive(
+                    socket.clone(),
+                    limiter,
+                    codec,
+                    spawner.clone(),
+                    interval,
+                )));
+                send(socket, gossip, spawner).await;
+            }));
+        });
+    }
+}
+
+async fn send<K: GovernorKey>(
+    socket: Arc<UdpSocket>,
+    gossip: Weak<Gossip<K>>,
+    runtime: Arc<dyn Runtime>,
+) {
+    let Some(interval) = gossip.upgrade().map(|gossip| gossip.config.interval) else {
+        return;
+    };
+    let mut shutdown = runtime.shutdown();
+    loop {
+        let running = runtime::tick(&*runtime, interval, &mut shutdown).await;
+        let Some(gossip) = gossip.upgrade() else {
+            return;
+        };
+        for message in gossip.take_messages() {
+            for peer in &gossip.config.peers {
+                if let Err(err) = socket.send_to(&message, peer).await {
+                    tracing::warn!("Failed to send gossip to {}: {}", peer, err);
+                }
+            }
+        }
+        if !running {
+            return;
+        }
+    }
+}
+
+/// What the receive task woke up for
+enum Wakeup {
+    Datagram(io::Result<(usize, SocketAddr)>),
+    /// The liveness check interval passed, false on shutdown
+    Tick(bool),
+}
+
+/// Charge deltas from peers to `limiter`, until it is dropped or `runtime`
+/// shuts down
+///
+/// Whether the limiter is still alive is checked every `interval` even
+/// without traffic, so the socket is released soon after the policy goes.
+async fn receive<K: GovernorKey>(
+    socket: Arc<UdpSocket>,
+    limiter: Weak<ArcSwap<KeyedLimiter<K>>>,
+    codec: KeyCodec<K>,
+    runtime: Arc<dyn Runtime>,
+    interval: Duration,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let mut shutdown: Option<RuntimeFuture> = runtime.shutdown();
+    loop {
+        let wakeup = {
+            let mut datagram = pin!(socket.recv_from(&mut buf));
+            let mut tick = pin!(runtime::tick(&*runtime, interval, &mut shutdown));
+            poll_fn(|cx| {
+                if let Poll::Ready(received) = datagram.as_mut().poll(cx) {
+                    return Poll::Ready(Wakeup::Datagram(received));
+                }
+                tick.as_mut().poll(cx).map(Wakeup::Tick)
+            })
+            .await
+        };
+        let (len, from) = match wakeup {
+            Wakeup::Tick(false) => return,
+            Wakeup::Tick(true) if limiter.strong_count() == 0 => return,
+            Wakeup::Tick(true) => continue,
+            Wakeup::Datagram(Ok(received)) => received,
+            Wakeup::Datagram(Err(err)) => {
+                tracing::debug!("Failed to receive gossip: {}", err);
+                continue;
+            }
+        };
+        let Some(limiter) = limiter.upgrade() else {
+            return;
+        };
+        let message: DeltaMessage = match serde_json::from_slice(&buf[..len]) {
+            Ok(message) => message,
+            Err(err) => {
+                tracing::debug!("Ignoring malformed gossip from {}: {}", from, err);
+                continue;
+            }
+        };
+
+        let limiter = limiter.load();
+        for (key, count) in message.deltas {
+            if let Ok(key) = codec.decode(key) {
+                limiter.charge(&key, count);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use governor::Quota;
+    use std::num::NonZeroU32;
+
+    #[tokio::test]
+    async fn test_gossip_charges_peer_usage() {
+        let quota = Quota::per_minute(NonZeroU32::new(2).unwrap());
+        let addr_a: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        // node B listens, node A only sends
+        let socket_b = UdpSocket::bind(addr_a).await.unwrap();
+        let addr_b = socket_b.local_addr().unwrap();
+        let limiter_b = Arc::new(ArcSwap::from_pointee(KeyedLimiter::<String>::new(quota)));
+        tokio::spawn(receive(
+            Arc::new(socket_b),
+            Arc::downgrade(&limiter_b),
+            KeyCodec::serde(),
+            Arc::new(crate::TokioRuntime),
+            Duration::from_millis(500),
+        ));
+
+        let gossip_a =
+            Gossip::<String>::new(GossipConfig::new(addr_a, [addr_b]), KeyCodec::serde());
+        gossip_a.record(&"alice".to_owned());
+        gossip_a.record(&"alice".to_owned());
+
+        let socket_a = UdpSocket::bind(addr_a).await.unwrap();
+        for message in gossip_a.take_messages() {
+            socket_a.send_to(&message, addr_b).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // both cells were consumed on node A
+        assert!(limiter_b.load().check_key(&"alice".to_owned()).is_err());
+        assert!(limiter_b.load().check_key(&"bob".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn test_messages_fit_datagrams() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let gossip = Gossip::<String>::new(GossipConfig::new(addr, []), KeyCodec::serde());
+        // long header values as keys, and one no datagram can hold
+        for i in 0..500 {
+            gossip.record(&format!("{i:04}{}", "k".repeat(1000)));
+        }
+        gossip.record(&"k".repeat(MAX_DATAGRAM));
+
+        let messages = gossip.take_messages();
+        assert!(messages.len() > 1);
+        let mut deltas = 0;
+        for message in &messages {
+            assert!(message.len() <= MAX_DATAGRAM);
+            let message: DeltaMessage = serde_json::from_slice(message).unwrap();
+            deltas += message.deltas.len();
+        }
+        assert_eq!(deltas, 500);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_policy_releases_socket() {
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let build = || {
+            crate::GovernorPolicy::builder()
+                .per_second(1)
+                .gossip(GossipConfig::new(addr, []).interval(Duration::from_millis(20)))
+                .build_with_serde_keyer(|key: &str| key.to_owned())
+        };
+        let policy = build();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(UdpSocket::bind(addr).await.is_err());
+
+        // without any traffic, the tasks notice and let go of the port
+        drop(policy);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        drop(socket);
+        let _rebuilt = build();
+    }
+}
@@ -0,0 +1,117 @@
+//! Metadata of an allowed request
+//!
+//! Every request a policy lets through yields a [`GovernorGuard`], which is
+//! both the `Guard` of the policy and inserted into the [`Context`] so
+//! handlers and later layers can read the decision without recomputing the
+//! key, e.g. to emit rate limit headers or account usage. Policies that
+//! know the state of the quota also insert a [`RateLimitInfo`], for handlers
+//! to pass on to clients or base decisions on.
+//!
+//! [`Context`]: rama_core::Context
+
+use std::time::{Duration, SystemTime};
+
+use governor::Quota;
+use governor::middleware::StateSnapshot;
+
+/// State of the quota of a key right after an allowed request
+///
+/// Inserted into the [`Context`](rama_core::Context) by direct and keyed
+/// policies. Store backed policies don't learn the state of their keys, and
+/// requests let through without a check, e.g. in shadow mode, have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitInfo {
+    /// Maximum number of requests in a burst
+    pub limit: u32,
+    /// Requests left before the key is limited
+    pub remaining: u32,
+    /// Time until the quota is fully replenished
+    pub reset: Duration,
+}
+
+impl RateLimitInfo {
+    pub(crate) fn from_snapshot(snapshot: &StateSnapshot) -> Self {
+        Self::used(
+            snapshot.quota(),
+            snapshot.quota().burst_size().get() - snapshot.remaining_burst_capacity(),
+        )
+    }
+
+    /// State of a bucket of `quota` with `used` cells taken
+    pub(crate) fn used(quota: Quota, used: u32) -> Self {
+        let limit = quota.burst_size().get();
+        let used = used.min(limit);
+        RateLimitInfo {
+            limit,
+            remaining: limit - used,
+            reset: quota.replenish_interval() * used,
+        }
+    }
+}
+
+/// Decision metadata of a request a policy let through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GovernorGuard {
+    key: Option<String>,
+    remaining: Option<u32>,
+    decided_at: SystemTime,
+}
+
+impl GovernorGuard {
+    pub(crate) fn new(key: Option<String>, remaining: Option<u32>) -> Self {
+        GovernorGuard {
+            key,
+            remaining,
+            decided_at: SystemTime::now(),
+        }
+    }
+
+    /// Guard of a request that no policy counted, e.g. one without a key
+    pub(crate) fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    /// Key the request was counted under, if any
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// Cells left for the key after this request
+    ///
+    /// Unknown for store backed policies and for requests let through
+    /// without a check, e.g. while the policy is disabled or in shadow mode.
+    pub fn remaining(&self) -> Option<u32> {
+        self.remaining
+    }
+
+    /// When the decision was made
+    pub fn decided_at(&self) -> SystemTime {
+        self.decided_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GovernorPolicy;
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+
+    #[tokio::test]
+    async fn test_guard_in_context() {
+        let policy = GovernorPolicy::builder()
+            .per_second(1)
+            .burst_size(3)
+            .build();
+        let result = policy.check(Context::default(), ()).await;
+        let PolicyOutput::Ready(guard) = result.output else {
+            panic!("Expected Ready");
+        };
+        assert_eq!(guard.key(), Some("default"));
+        assert_eq!(guard.remaining(), Some(2));
+        assert_eq!(result.ctx.get::<GovernorGuard>(), Some(&guard));
+        let info = result.ctx.get::<RateLimitInfo>().unwrap();
+        assert_eq!((info.limit, info.remaining), (3, 2));
+        assert_eq!(info.reset, Duration::from_secs(1));
+    }
+}
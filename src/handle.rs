@@ -0,0 +1,917 @@
+//! Runtime controls for a built policy
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use governor::{InsufficientCapacity, Quota};
+use thiserror::Error;
+
+use crate::audit::{AuditEvent, Auditor, OverrideAction};
+use crate::body_cost::BodyCost;
+use crate::bypass::BypassToken;
+use crate::clock::{self, DirectLimiter};
+use crate::decision_cache::DecisionCache;
+use crate::drain::{self, DEFAULT_HEALTH_CHECK_PATHS, Drain};
+use crate::exempt::Exemptions;
+use crate::key_stats::{KeyStats, KeyStatsStore};
+use crate::keyed::KeyedLimiter;
+use crate::runtime;
+use crate::warmup::Warmup;
+use crate::{GovernorKey, RateLimitStore, Runtime, StoreDecision, StoreError, StoreFuture};
+
+/// How long [`GovernorHandle::acquire`] waits before retrying when the reason
+/// for a rejection carries no retry time, e.g. a lockdown clamp
+const ACQUIRE_RETRY: Duration = Duration::from_millis(10);
+
+/// Error drawing cells from a policy through its [`GovernorHandle`]
+#[derive(Debug, Error)]
+pub enum AcquireError {
+    /// Not enough cells are available right now
+    #[error("rate limit exceeded")]
+    RateLimited {
+        /// Earliest time after which the same request could succeed, if known
+        retry_after: Option<Duration>,
+    },
+    /// More cells were requested than the burst size allows, so waiting won't help
+    #[error("{requested} cells exceed the burst size of {burst}")]
+    InsufficientCapacity {
+        /// Number of cells requested
+        requested: u32,
+        /// Burst size of the quota
+        burst: u32,
+    },
+    /// The key is on the denylist
+    #[error("key is denylisted")]
+    Denied,
+    /// The policy is draining, see [`GovernorHandle::drain`], so fewer and
+    /// fewer cells become available until none do
+    #[error("policy is draining")]
+    Draining,
+    /// The store of the policy failed
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    /// The policy has been dropped
+    #[error("policy has been dropped")]
+    Closed,
+    /// Waiting needs a runtime, and the policy was built without one
+    #[error("no runtime to wait on")]
+    NoRuntime,
+}
+
+impl AcquireError {
+    fn insufficient(requested: NonZeroU32, err: InsufficientCapacity) -> Self {
+        AcquireError::InsufficientCapacity {
+            requested: requested.get(),
+            burst: err.0,
+        }
+    }
+}
+
+/// Limiter state of a policy that a handle can act on
+pub(crate) trait HandleTarget: Send + Sync + 'static {
+    /// Forget the state of `key`, returning false if that isn't supported
+    fn reset_key(&self, key: &str) -> bool;
+    /// Replace the quota, starting from a fresh state
+    fn set_quota(&self, quota: Quota);
+    /// Consume `n` cells for `key` if all of them are available
+    fn try_acquire<'a>(
+        &'a self,
+        key: &'a str,
+        n: NonZeroU32,
+    ) -> StoreFuture<'a, Result<(), AcquireError>>;
+}
+
+impl HandleTarget for Weak<ArcSwap<DirectLimiter>> {
+    fn reset_key(&self, _key: &str) -> bool {
+        let Some(limiter) = self.upgrade() else {
+            return false;
+        };
+        let quota = limiter.load().quota();
+        limiter.store(Arc::new(DirectLimiter::new(quota)));
+        true
+    }
+
+    fn set_quota(&self, quota: Quota) {
+        if let Some(limiter) = self.upgrade() {
+            limiter.store(Arc::new(DirectLimiter::new(quota)));
+        }
+    }
+
+    fn try_acquire<'a>(
+        &'a self,
+        _key: &'a str,
+        n: NonZeroU32,
+    ) -> StoreFuture<'a, Result<(), AcquireError>> {
+        let result = match self.upgrade() {
+            None => Err(AcquireError::Closed),
+            Some(limiter) => match limiter.load().check_n(n) {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(not_until)) => Err(AcquireError::RateLimited {
+                    retry_after: Some(clock::wait_time(&not_until)),
+                }),
+                Err(err) => Err(AcquireError::insufficient(n, err)),
+            },
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+/// The limiter of a keyed policy together with its key function
+pub(crate) struct KeyedTarget<K: GovernorKey, F> {
+    pub(crate) limiter: Weak<ArcSwap<KeyedLimiter<K>>>,
+    pub(crate) key_fn: Arc<F>,
+}
+
+impl<K, F> HandleTarget for KeyedTarget<K, F>
+where
+    K: GovernorKey,
+    F: Fn(&str) -> K + Send + Sync + 'static,
+{
+    fn reset_key(&self, key: &str) -> bool {
+        let Some(limiter) = self.limiter.upgrade() else {
+            return false;
+        };
+        limiter.load().remove(&(self.key_fn)(key));
+        true
+    }
+
+    fn set_quota(&self, quota: Quota) {
+        if let Some(limiter) = self.limiter.upgrade() {
+            limiter.store(Arc::new(limiter.load().with_quota(quota)));
+        }
+    }
+
+    fn try_acquire<'a>(
+        &'a self,
+        key: &'a str,
+        n: NonZeroU32,
+    ) -> StoreFuture<'a, Result<(), AcquireError>> {
+        let result = match self.limiter.upgrade() {
+            None => Err(AcquireError::Closed),
+            Some(limiter) => match limiter.load().check_key_n(&(self.key_fn)(key), n) {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(not_until)) => Err(AcquireError::RateLimited {
+                    retry_after: Some(clock::wait_time(&not_until)),
+                }),
+                Err(err) => Err(AcquireError::insufficient(n, err)),
+            },
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+/// The quota and store of a store backed policy
+pub(crate) struct StoreTarget {
+    pub(crate) quota: Weak<ArcSwap<Quota>>,
+    pub(crate) store: Weak<dyn RateLimitStore>,
+}
+
+impl HandleTarget for StoreTarget {
+    /// The state lives in the store, which offers no way to reset it
+    fn reset_key(&self, _key: &str) -> bool {
+        false
+    }
+
+    fn set_quota(&self, quota: Quota) {
+        if let Some(current) = self.quota.upgrade() {
+            current.store(Arc::new(quota));
+        }
+    }
+
+    fn try_acquire<'a>(
+        &'a self,
+        key: &'a str,
+        n: NonZeroU32,
+    ) -> StoreFuture<'a, Result<(), AcquireError>> {
+        Box::pin(async move {
+            let (Some(quota), Some(store)) = (self.quota.upgrade(), self.store.upgrade()) else {
+                return Err(AcquireError::Closed);
+            };
+            let quota = **quota.load();
+            let burst = quota.burst_size();
+            if n > burst {
+                return Err(AcquireError::insufficient(
+                    n,
+                    InsufficientCapacity(burst.get()),
+                ));
+            }
+            match store.check_n(key, quota, n.get()).await? {
+                StoreDecision::Allowed => Ok(()),
+                StoreDecision::Denied { retry_after } => Err(AcquireError::RateLimited {
+                    retry_after: Some(retry_after),
+                }),
+            }
+        })
+    }
+}
+
+/// Enforcement mode of a policy, switched at runtime through a [`GovernorHandle`]
+enum Mode {
+    /// Normal enforcement of the configured quota
+    Enforcing,
+    /// Every request is allowed
+    Disabled,
+    /// The configured quota is enforced, and on top of that all requests
+    /// share a global clamp
+    Lockdown {
+        clamp: DirectLimiter,
+        /// Share of each key let through once the clamp is exhausted
+        minimum: Option<Box<MinimumShare>>,
+    },
+    /// The configured quota is enforced on a shrinking share of requests,
+    /// the rest is rejected
+    Draining(Drain),
+}
+
+/// The share of each key a lockdown still lets through
+struct MinimumShare {
+    /// Keyed by the hash of the key, so checks don't allocate
+    limiter: KeyedLimiter<u64>,
+    hasher: RandomState,
+}
+
+impl MinimumShare {
+    fn admits(&self, key: &str) -> bool {
+        self.limiter.check_key(&self.hasher.hash_one(key)).is_ok()
+    }
+}
+
+/// Outcome of consulting the runtime controls before the regular limiter
+pub(crate) enum Control {
+    /// Skip the limiter and allow the request
+    Bypass,
+    /// Continue with the regular limiter
+    Enforce,
+    /// The lockdown clamp rejected the request
+    Reject,
+    /// The key is on the denylist
+    Denied,
+    /// The request is outside the share a drain still lets through
+    Drained,
+}
+
+/// A cloneable handle to flip enforcement of a running policy
+///
+/// Obtained through [`GovernorPolicy::handle`](crate::GovernorPolicy::handle).
+/// All clones control the same policy, so the handle can be stored in an admin
+/// endpoint or signal handler while the policy itself lives in the service stack.
+#[derive(Clone)]
+pub struct GovernorHandle {
+    mode: Arc<ArcSwap<Mode>>,
+    shadow: Arc<AtomicBool>,
+    mirror: Arc<AtomicBool>,
+    denylist: Arc<ArcSwap<HashSet<String>>>,
+    /// Keys whose decisions are traced, until when
+    traced: Arc<ArcSwap<HashMap<String, Instant>>>,
+    target: Arc<dyn HandleTarget>,
+    runtime: Option<Arc<dyn Runtime>>,
+    audit: Option<Arc<Auditor>>,
+    cache: Option<Arc<DecisionCache>>,
+    /// Keys limited by an upstream
+    backoff: Arc<DecisionCache>,
+    warmup: Option<Arc<Warmup>>,
+    health_checks: Arc<[String]>,
+    exemptions: Option<Arc<Exemptions>>,
+    bypass: Option<Arc<BypassToken>>,
+    body_cost: Option<BodyCost>,
+    /// Callers waiting for capacity
+    waiting: Arc<AtomicUsize>,
+    key_stats: Option<Arc<KeyStatsStore>>,
+}
+
+impl GovernorHandle {
+    pub(crate) fn new(target: impl HandleTarget, runtime: Option<Arc<dyn Runtime>>) -> Self {
+        GovernorHandle {
+            mode: Arc::new(ArcSwap::from_pointee(Mode::Enforcing)),
+            shadow: Arc::new(AtomicBool::new(false)),
+            mirror: Arc::new(AtomicBool::new(false)),
+            denylist: Arc::new(ArcSwap::default()),
+            traced: Arc::new(ArcSwap::default()),
+            target: Arc::new(target),
+            runtime,
+            audit: None,
+            cache: None,
+            backoff: Arc::new(DecisionCache::new(Duration::ZERO)),
+            warmup: None,
+            health_checks: DEFAULT_HEALTH_CHECK_PATHS.map(str::to_owned).into(),
+            exemptions: None,
+            bypass: None,
+            body_cost: None,
+            waiting: Arc::new(AtomicUsize::new(0)),
+            key_stats: None,
+        }
+    }
+
+    /// Report changes made from here on to `audit`
+    pub(crate) fn audited(mut self, audit: Option<Arc<Auditor>>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Short-circuit checks of keys cached as limited in `cache`, which is
+    /// cleared whenever the limiter state changes from here
+    pub(crate) fn cached(mut self, cache: Option<DecisionCache>) -> Self {
+        self.cache = cache.map(Arc::new);
+        self
+    }
+
+    /// Ramp the quota up with `warmup` once background tasks start
+    pub(crate) fn warming_up(mut self, warmup: Option<Warmup>) -> Self {
+        self.warmup = warmup.map(Arc::new);
+        self
+    }
+
+    /// Let requests for `paths` through while draining
+    pub(crate) fn with_health_checks(mut self, paths: Option<Vec<String>>) -> Self {
+        if let Some(paths) = paths {
+            self.health_checks = paths.into();
+        }
+        self
+    }
+
+    /// Let requests matching `exemptions` through unchecked
+    pub(crate) fn exempting(mut self, exemptions: Exemptions) -> Self {
+        self.exemptions = (!exemptions.is_empty()).then(|| Arc::new(exemptions));
+        self
+    }
+
+    /// Let requests carrying `bypass` through unchecked
+    pub(crate) fn bypassed_by(mut self, bypass: Option<Arc<BypassToken>>) -> Self {
+        self.bypass = bypass;
+        self
+    }
+
+    /// Charge requests by their declared body size through `body_cost`
+    pub(crate) fn costed(mut self, body_cost: Option<BodyCost>) -> Self {
+        self.body_cost = body_cost;
+        self
+    }
+
+    /// Keep the decision history of keys in `key_stats`
+    pub(crate) fn with_key_stats(mut self, key_stats: Option<KeyStatsStore>) -> Self {
+        self.key_stats = key_stats.map(Arc::new);
+        self
+    }
+
+    pub(crate) fn key_stats_store(&self) -> Option<&KeyStatsStore> {
+        self.key_stats.as_deref()
+    }
+
+    /// Cells to charge for `request`
+    pub(crate) fn cost_of(&self, request: &dyn Any) -> NonZeroU32 {
+        self.body_cost
+            .map_or(NonZeroU32::MIN, |body_cost| body_cost.of(request))
+    }
+
+    pub(crate) fn start_warmup(&self) {
+        if let (Some(warmup), Some(runtime)) = (&self.warmup, &self.runtime) {
+            warmup.start_if_needed(runtime, self.target.clone());
+        }
+    }
+
+    pub(crate) fn decision_cache(&self) -> Option<&DecisionCache> {
+        self.cache.as_deref()
+    }
+
+    /// Time until `key` is no longer backed off, if it is
+    pub(crate) fn backed_off(&self, key: &str) -> Option<Duration> {
+        self.backoff.blocked(key)
+    }
+
+    fn clear_decision_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+    }
+
+    fn audit(&self, key: Option<&str>, event: AuditEvent) {
+        if let Some(audit) = &self.audit {
+            audit.record(key, event);
+        }
+    }
+
+    fn audit_override(&self, key: Option<&str>, action: OverrideAction) {
+        self.audit(key, AuditEvent::Override { action });
+    }
+
+    /// Stop enforcing limits, letting all requests pass
+    pub fn disable(&self) {
+        tracing::warn!("Rate limiting disabled");
+        self.audit_override(None, OverrideAction::Disable);
+        self.mode.store(Arc::new(Mode::Disabled));
+    }
+
+    /// Clamp all traffic through this policy to `quota` in addition to the configured limits
+    ///
+    /// The clamp is global: it is shared by all keys of a keyed policy.
+    /// Call [`enable`](Self::enable) to lift it again.
+    pub fn lockdown(&self, quota: Quota) {
+        tracing::warn!("Rate limiting lockdown engaged with quota {:?}", quota);
+        self.audit_override(None, OverrideAction::Lockdown);
+        self.mode.store(Arc::new(Mode::Lockdown {
+            clamp: DirectLimiter::new(quota),
+            minimum: None,
+        }));
+    }
+
+    /// Clamp all traffic to `quota` like [`lockdown`](Self::lockdown), but
+    /// guarantee every key a `minimum` share
+    ///
+    /// Requests the clamp rejects are still let through while their key is
+    /// within its minimum share, so one noisy key can't use up the whole
+    /// clamp and starve all others. Traffic can so exceed the clamp by up to
+    /// the minimum share of each active key.
+    pub fn lockdown_with_minimum(&self, quota: Quota, minimum: Quota) {
+        tracing::warn!(
+            "Rate limiting lockdown engaged with quota {:?} and minimum share {:?}",
+            quota,
+            minimum
+        );
+        self.audit_override(None, OverrideAction::Lockdown);
+        let mode = Arc::new(Mode::Lockdown {
+            clamp: DirectLimiter::new(quota),
+            minimum: Some(Box::new(MinimumShare {
+                limiter: KeyedLimiter::new(minimum),
+                hasher: RandomState::new(),
+            })),
+        });
+        self.mode.store(mode.clone());
+        // keys are forgotten once their share replenished, like in the policy
+        if let Some(runtime) = &self.runtime {
+            let mode = Arc::downgrade(&mode);
+            let interval = minimum.replenish_interval() * minimum.burst_size().get();
+            let timer = runtime.clone();
+            let mut shutdown = runtime.shutdown();
+            runtime.spawn(Box::pin(async move {
+                while runtime::tick(&*timer, interval, &mut shutdown).await {
+                    // gone once the lockdown is lifted or replaced
+                    let Some(mode) = mode.upgrade() else {
+                        return;
+                    };
+                    gc_minimum(&mode);
+                }
+            }));
+        }
+    }
+
+    /// Forget the keys whose minimum share in a lockdown is fully replenished
+    pub(crate) fn gc(&self) {
+        gc_minimum(&self.mode.load());
+    }
+
+    /// Let a shrinking share of requests through, down to none after `period`
+    ///
+    /// Meant for decommissioning an instance during a rolling deploy: clients
+    /// move to other instances gradually while the load balancer removes this
+    /// one. The configured quota still applies to the requests let through.
+    /// Health checks, `/health`, `/healthz`, `/livez` and `/readyz` unless
+    /// configured through
+    /// [`health_check_paths`](crate::GovernorPolicyBuilder::health_check_paths),
+    /// are always let through. Call [`enable`](Self::enable) to stop draining.
+    pub fn drain(&self, period: Duration) {
+        tracing::warn!("Draining traffic over {:?}", period);
+        self.audit_override(None, OverrideAction::Drain);
+        self.mode
+            .store(Arc::new(Mode::Draining(Drain::new(period))));
+    }
+
+    /// Return to normal enforcement, lifting any lockdown or drain
+    pub fn enable(&self) {
+        tracing::info!("Rate limiting enabled");
+        self.audit_override(None, OverrideAction::Enable);
+        self.mode.store(Arc::new(Mode::Enforcing));
+    }
+
+    /// Returns false while the policy is disabled
+    pub fn is_enabled(&self) -> bool {
+        !matches!(**self.mode.load(), Mode::Disabled)
+    }
+
+    /// Returns true while a lockdown clamp is in place
+    pub fn is_locked_down(&self) -> bool {
+        matches!(**self.mode.load(), Mode::Lockdown { .. })
+    }
+
+    /// Returns true while draining, even once no requests are let through anymore
+    pub fn is_draining(&self) -> bool {
+        matches!(**self.mode.load(), Mode::Draining(_))
+    }
+
+    /// Whether `request` carries the bypass token, auditing the bypass under `key`
+    pub(crate) fn bypasses(&self, request: &dyn Any, key: &str) -> bool {
+        let bypassed = self.carries_bypass(request);
+        if bypassed {
+            tracing::debug!("Bypass token let a request for key {} through", key);
+            self.audit(Some(key), AuditEvent::Bypassed);
+        }
+        bypassed
+    }
+
+    /// Whether `request` carries the bypass token, without auditing it
+    pub(crate) fn carries_bypass(&self, request: &dyn Any) -> bool {
+        self.bypass
+            .as_ref()
+            .is_some_and(|bypass| bypass.matches(request))
+    }
+
+    /// Whether to let `request` through unchecked: it is exempt from the
+    /// policy, or a health check during a drain
+    pub(crate) fn exempts(&self, request: &dyn Any) -> bool {
+        self.exemptions
+            .as_ref()
+            .is_some_and(|exemptions| exemptions.exempts(request))
+            || self.is_draining() && drain::is_health_check(request, &self.health_checks)
+    }
+
+    /// Only log requests over the limit instead of rejecting them
+    ///
+    /// Useful to try out a new quota on live traffic. Lockdown clamps and the
+    /// denylist are still enforced.
+    pub fn set_shadow(&self, shadow: bool) {
+        tracing::warn!(
+            "Rate limiting shadow mode {}",
+            if shadow { "on" } else { "off" }
+        );
+        self.audit_override(
+            None,
+            match shadow {
+                true => OverrideAction::ShadowOn,
+                false => OverrideAction::ShadowOff,
+            },
+        );
+        self.shadow.store(shadow, Ordering::Relaxed);
+    }
+
+    /// Returns true while over-limit requests are only logged
+    pub fn is_shadow(&self) -> bool {
+        self.shadow.load(Ordering::Relaxed)
+    }
+
+    /// Let requests over the limit through, tagged with a
+    /// [`RateLimitExceeded`](crate::RateLimitExceeded) context extension
+    ///
+    /// Downstream services can then degrade gracefully, e.g. serve cached
+    /// data, instead of failing. Lockdown clamps and the denylist are still
+    /// enforced.
+    pub fn set_mirror(&self, mirror: bool) {
+        self.audit_override(
+            None,
+            match mirror {
+                true => OverrideAction::MirrorOn,
+                false => OverrideAction::MirrorOff,
+            },
+        );
+        self.mirror.store(mirror, Ordering::Relaxed);
+    }
+
+    /// Returns true while over-limit requests are tagged instead of rejected
+    pub fn is_mirror(&self) -> bool {
+        self.mirror.load(Ordering::Relaxed)
+    }
+
+    /// Reject all requests for `key`, regardless of its quota
+    pub fn deny(&self, key: impl Into<String>) {
+        let key = key.into();
+        tracing::warn!("Denylisting key: {}", key);
+        self.audit(Some(&key), AuditEvent::Banned);
+        self.denylist.rcu(|denylist| {
+            let mut denylist = HashSet::clone(denylist);
+            denylist.insert(key.clone());
+            denylist
+        });
+    }
+
+    /// Remove `key` from the denylist, returning whether it was on it
+    pub fn undeny(&self, key: &str) -> bool {
+        let mut removed = false;
+        self.denylist.rcu(|denylist| {
+            let mut denylist = HashSet::clone(denylist);
+            removed = denylist.remove(key);
+            denylist
+        });
+        if removed {
+            self.audit(Some(key), AuditEvent::Unbanned);
+        }
+        removed
+    }
+
+    /// Whether `key` is on the denylist
+    pub(crate) fn is_denied(&self, key: &str) -> bool {
+        self.denylist.load().contains(key)
+    }
+
+    /// Keys currently on the denylist
+    pub fn denylist(&self) -> Vec<String> {
+        self.denylist.load().iter().cloned().collect()
+    }
+
+    /// Reject all requests for `key` during `retry_after`
+    ///
+    /// Meant for keys an upstream limited, so the edge sheds their requests
+    /// instead of passing them on to be rejected again, see
+    /// [`UpstreamBackoffLayer`](crate::UpstreamBackoffLayer). Backing off a
+    /// key again replaces its earlier retry time, and
+    /// [`reset_key`](Self::reset_key) ends the back-off.
+    pub fn back_off(&self, key: &str, retry_after: Duration) {
+        tracing::info!("Backing off key {} for {:?}", key, retry_after);
+        self.backoff.rejected(key, Some(retry_after));
+    }
+
+    /// Log every decision on `key` in detail at info level, for `duration`
+    ///
+    /// Debugs the limits of a single client without enabling debug logs
+    /// for all of them. Tracing a key again replaces its duration.
+    pub fn trace_key(&self, key: impl Into<String>, duration: Duration) {
+        let key = key.into();
+        tracing::info!("Tracing decisions on key {} for {:?}", key, duration);
+        self.audit_override(Some(&key), OverrideAction::TraceKey);
+        let now = Instant::now();
+        self.traced.rcu(|traced| {
+            let mut traced: HashMap<_, _> = traced
+                .iter()
+                .filter(|(_, until)| **until > now)
+                .map(|(key, until)| (key.clone(), *until))
+                .collect();
+            traced.insert(key.clone(), now + duration);
+            traced
+        });
+    }
+
+    /// Recent decision history of `key`
+    ///
+    /// `None` unless the policy was built with
+    /// [`key_stats`](crate::GovernorPolicyBuilder::key_stats) and the key is
+    /// among the ones tracked.
+    pub fn key_stats(&self, key: &str) -> Option<KeyStats> {
+        self.key_stats.as_ref()?.get(key)
+    }
+
+    /// Keys whose decisions are currently traced
+    pub fn traced_keys(&self) -> Vec<String> {
+        let now = Instant::now();
+        let traced = self.traced.load();
+        traced
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Whether decisions on `key` are traced
+    pub(crate) fn is_traced(&self, key: &str) -> bool {
+        let traced = self.traced.load();
+        !traced.is_empty() && traced.get(key).is_some_and(|until| *until > Instant::now())
+    }
+
+    /// Forget the limiter state of `key`, giving it a full burst again
+    ///
+    /// Direct policies reset their single state regardless of `key`. Returns
+    /// false for store backed policies, whose stores can't be reset this way.
+    pub fn reset_key(&self, key: &str) -> bool {
+        tracing::info!("Resetting rate limit state of key: {}", key);
+        self.audit_override(Some(key), OverrideAction::ResetKey);
+        self.clear_decision_cache();
+        self.backoff.remove(key);
+        self.target.reset_key(key)
+    }
+
+    /// Replace the quota of the policy
+    ///
+    /// Keyed and direct policies start over from a fresh state. A configured
+    /// schedule switches to its own quota again the next time an entry fires.
+    pub fn set_quota(&self, quota: Quota) {
+        tracing::warn!("Switching to quota {:?}", quota);
+        self.audit_override(None, OverrideAction::SetQuota);
+        self.clear_decision_cache();
+        self.target.set_quota(quota);
+    }
+
+    /// Draw `n` cells from the quota, as `n` requests without a key would
+    ///
+    /// Lets code outside the request path, like batch jobs or queue
+    /// consumers, share a quota with the HTTP layer. Fails right away if the
+    /// cells are not available; see [`acquire`](Self::acquire) to wait for them.
+    pub async fn try_acquire(&self, n: u32) -> Result<(), AcquireError> {
+        self.try_acquire_key("default", n).await
+    }
+
+    /// Draw `n` cells from the quota of `key`
+    ///
+    /// Direct policies ignore the key. Runtime controls apply as they do to
+    /// requests: the denylist, lockdown clamp and kill switch.
+    pub async fn try_acquire_key(&self, key: &str, n: u32) -> Result<(), AcquireError> {
+        let Some(n) = NonZeroU32::new(n) else {
+            return Ok(());
+        };
+        match self.control(key) {
+            Control::Bypass => Ok(()),
+            Control::Denied => Err(AcquireError::Denied),
+            Control::Reject => Err(AcquireError::RateLimited { retry_after: None }),
+            Control::Drained => Err(AcquireError::Draining),
+            Control::Enforce => self.target.try_acquire(key, n).await,
+        }
+    }
+
+    /// Wait until `n` cells are available and draw them, see [`try_acquire`](Self::try_acquire)
+    pub async fn acquire(&self, n: u32) -> Result<(), AcquireError> {
+        self.acquire_key("default", n).await
+    }
+
+    /// Wait until `n` cells are available for `key` and draw them
+    ///
+    /// Waits on the [`Runtime`] of the policy. Fails without waiting if the
+    /// cells can never become available, e.g. because `n` exceeds the burst
+    /// size, the key is denylisted or the policy is draining.
+    pub async fn acquire_key(&self, key: &str, n: u32) -> Result<(), AcquireError> {
+        loop {
+            match self.try_acquire_key(key, n).await {
+                Err(AcquireError::RateLimited { retry_after }) => {
+                    let runtime = self.runtime.as_ref().ok_or(AcquireError::NoRuntime)?;
+                    let _waiting = self.enter_wait();
+                    runtime.sleep(retry_after.unwrap_or(ACQUIRE_RETRY)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Number of callers currently waiting for capacity in
+    /// [`acquire`](Self::acquire) or [`Limiter::until_ready`](crate::Limiter::until_ready)
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Count the caller as waiting until the returned guard is dropped
+    pub(crate) fn enter_wait(&self) -> WaitGuard {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        WaitGuard {
+            waiting: self.waiting.clone(),
+        }
+    }
+
+    pub(crate) fn control(&self, key: &str) -> Control {
+        let denylist = self.denylist.load();
+        if !denylist.is_empty() && denylist.contains(key) {
+            return Control::Denied;
+        }
+        match &**self.mode.load() {
+            Mode::Enforcing => Control::Enforce,
+            Mode::Disabled => Control::Bypass,
+            Mode::Lockdown { clamp, minimum } => {
+                let within_minimum = || minimum.as_ref().is_some_and(|minimum| minimum.admits(key));
+                if clamp.check().is_ok() || within_minimum() {
+                    Control::Enforce
+                } else {
+                    Control::Reject
+                }
+            }
+            Mode::Draining(drain) => match drain.admits() {
+                true => Control::Enforce,
+                false => Control::Drained,
+            },
+        }
+    }
+}
+
+fn gc_minimum(mode: &Mode) {
+    if let Mode::Lockdown {
+        minimum: Some(minimum),
+        ..
+    } = mode
+    {
+        minimum.limiter.retain_recent();
+    }
+}
+
+/// A caller waiting for capacity, counted by [`GovernorHandle::waiting`]
+pub(crate) struct WaitGuard {
+    waiting: Arc<AtomicUsize>,
+}
+
+impl Drop for WaitGuard {
+    fn drop(&mut self) {
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Debug for GovernorHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GovernorHandle")
+            .field("enabled", &self.is_enabled())
+            .field("locked_down", &self.is_locked_down())
+            .field("draining", &self.is_draining())
+            .field("shadow", &self.is_shadow())
+            .field("mirror", &self.is_mirror())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_acquire_from_handle() {
+        let policy = GovernorPolicy::builder()
+            .per_second(100)
+            .burst_size(5)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let handle = policy.handle();
+
+        handle.try_acquire_key("jobs", 5).await.unwrap();
+        assert!(matches!(
+            handle.try_acquire_key("jobs", 1).await,
+            Err(AcquireError::RateLimited {
+                retry_after: Some(_)
+            })
+        ));
+        handle.try_acquire_key("reports", 1).await.unwrap();
+        assert!(matches!(
+            handle.acquire_key("jobs", 6).await,
+            Err(AcquireError::InsufficientCapacity { burst: 5, .. })
+        ));
+        // replenished at 100 per second, so this waits about 20ms
+        handle.acquire_key("jobs", 2).await.unwrap();
+
+        // a finished drain lets nothing through until enabled again
+        handle.drain(Duration::ZERO);
+        assert!(matches!(
+            handle.acquire_key("reports", 1).await,
+            Err(AcquireError::Draining)
+        ));
+        assert_eq!(handle.waiting(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_lockdown_minimum_share() {
+        let policy = GovernorPolicy::builder()
+            .per_second(100)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let handle = policy.handle();
+        let per_minute = |n| Quota::per_minute(NonZeroU32::new(n).unwrap());
+        handle.lockdown_with_minimum(per_minute(2), per_minute(1));
+
+        // the noisy key uses up the clamp and its own share
+        for _ in 0..3 {
+            handle.try_acquire_key("noisy", 1).await.unwrap();
+        }
+        assert!(handle.try_acquire_key("noisy", 1).await.is_err());
+        handle.try_acquire_key("quiet", 1).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lockdown_minimum_share_gc() {
+        let policy = GovernorPolicy::builder()
+            .per_second(100)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let handle = policy.handle();
+        let per_second = |n| Quota::per_second(NonZeroU32::new(n).unwrap());
+        handle.lockdown_with_minimum(per_second(1), per_second(50));
+        for i in 0..10 {
+            handle
+                .try_acquire_key(&format!("client-{i}"), 1)
+                .await
+                .unwrap();
+        }
+        let tracked = || match &**handle.mode.load() {
+            Mode::Lockdown {
+                minimum: Some(minimum),
+                ..
+            } => minimum.limiter.len(),
+            _ => unreachable!(),
+        };
+        assert_eq!(tracked(), 9);
+
+        // swept along with the keys of the policy
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        policy.gc();
+        assert_eq!(tracked(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_trace_key_expires() {
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let handle = policy.handle();
+        handle.trace_key("alice", Duration::from_millis(20));
+        assert_eq!(handle.traced_keys(), ["alice"]);
+        assert!(!handle.is_traced("bob"));
+
+        // tracing doesn't change the decisions
+        assert!(policy.decide("alice").await.is_ok());
+        assert!(policy.decide("alice").await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!handle.is_traced("alice"));
+        assert!(handle.traced_keys().is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+//! Limits on hierarchical keys, with optional spillover
+//!
+//! A [`HierarchicalPolicy`] counts a request under its key, e.g.
+//! `acme/alice`, and under the parent key `acme`, each against its own
+//! policy, so every user has a limit and their organization a pooled one.
+//! With [`spillover`](HierarchicalPolicy::spillover), a user over their own
+//! limit may borrow from the unused headroom of the parent instead of being
+//! rejected.
+//!
+//! Requests rejected by either limit don't count against the other one: the
+//! parent is checked first, and without spillover a key over its own limit
+//! is rejected before it draws from the parent.
+
+use std::fmt;
+use std::sync::Arc;
+
+use rama_core::Context;
+use rama_core::layer::limit::policy::{Policy, PolicyOutput, PolicyResult};
+
+use crate::{GovernorError, GovernorGuard, GovernorPolicy, KeyExtractor, Verdict};
+
+/// Policy limiting a key and its parent key
+pub struct HierarchicalPolicy<E> {
+    child: Arc<GovernorPolicy>,
+    parent: Arc<GovernorPolicy>,
+    extractor: E,
+    separator: char,
+    spillover: bool,
+}
+
+impl<E> HierarchicalPolicy<E> {
+    /// Check the keys derived by `extractor` with `child`, and their parent
+    /// keys with `parent`
+    ///
+    /// The parent of a key is everything before its last `/`; keys without a
+    /// parent are only checked by `child`.
+    pub fn new(
+        child: impl Into<Arc<GovernorPolicy>>,
+        parent: impl Into<Arc<GovernorPolicy>>,
+        extractor: E,
+    ) -> Self {
+        HierarchicalPolicy {
+            child: child.into(),
+            parent: parent.into(),
+            extractor,
+            separator: '/',
+            spillover: false,
+        }
+    }
+
+    /// Separate the parent from the rest of a key at the last `separator`
+    pub fn separator(mut self, separator: char) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Let requests over the limit of their key through if the parent key
+    /// has headroom left, counting them against the parent only
+    pub fn spillover(mut self, spillover: bool) -> Self {
+        self.spillover = spillover;
+        self
+    }
+
+    /// Policy of the keys
+    pub fn child(&self) -> &Arc<GovernorPolicy> {
+        &self.child
+    }
+
+    /// Policy of the parent keys
+    pub fn parent(&self) -> &Arc<GovernorPolicy> {
+        &self.parent
+    }
+
+    /// Decide on a request counted under `key`, returning the guard
+    async fn decide<State>(
+        &self,
+        ctx: &mut Context<State>,
+        key: &str,
+    ) -> Result<GovernorGuard, GovernorError> {
+        let Some((parent_key, _)) = key.rsplit_once(self.separator) else {
+            return Ok(self.child.decide(key).await?.admit(ctx, key));
+        };
+        // a key over its own limit is rejected before it draws from the pool
+        if !self.spillover
+            && matches!(
+                self.child.explain_key(&(), key).verdict,
+                Verdict::Limited { .. }
+            )
+        {
+            return Ok(self.child.decide(key).await?.admit(ctx, key));
+        }
+        // the parent pools the requests of all its children, and is checked
+        // first so a pool over its limit leaves the budget of the key alone
+        let pooled = self.parent.decide(parent_key).await?;
+        match self.child.decide(key).await {
+            Ok(decision) => Ok(decision.admit(ctx, key)),
+            Err(GovernorError::RateLimited(_)) if self.spillover => {
+                tracing::debug!("Key {} borrowed from parent {}", key, parent_key);
+                Ok(pooled.admit(ctx, parent_key))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<E: Clone> Clone for HierarchicalPolicy<E> {
+    fn clone(&self) -> Self {
+        HierarchicalPolicy {
+            child: self.child.clone(),
+            parent: self.parent.clone(),
+            extractor: self.extractor.clone(),
+            separator: self.separator,
+            spillover: self.spillover,
+        }
+    }
+}
+
+impl<E> fmt::Debug for HierarchicalPolicy<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HierarchicalPolicy")
+            .field("child", &self.child)
+            .field("parent", &self.parent)
+            .field("spillover", &self.spillover)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E, State, Request> Policy<State, Request> for HierarchicalPolicy<E>
+where
+    E: KeyExtractor<State, Request>,
+    State: Clone + Send + Sync + 'static,
+    Request: Send + Sync + 'static,
+{
+    type Guard = GovernorGuard;
+    type Error = GovernorError;
+
+    async fn check(
+        &self,
+        mut ctx: Context<State>,
+        request: Request,
+    ) -> PolicyResult<State, Request, Self::Guard, Self::Error> {
+        let output = match self.extractor.extract(&ctx, &request) {
+            Some(key) => match self.decide(&mut ctx, &key).await {
+                Ok(guard) => PolicyOutput::Ready(guard),
+                Err(err) => PolicyOutput::Abort(err),
+            },
+            None => PolicyOutput::Ready(GovernorGuard::unlimited()),
+        };
+        PolicyResult {
+            ctx,
+            request,
+            output,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyed(per_minute: u32) -> GovernorPolicy {
+        GovernorPolicy::builder()
+            .per_minute(per_minute)
+            .build_with_keyer(|key: &str| key.to_owned())
+    }
+
+    #[tokio::test]
+    async fn test_spillover() {
+        let policy = HierarchicalPolicy::new(keyed(1), keyed(3), |_: &Context<()>, key: &&str| {
+            Some(key.to_string())
+        })
+        .spillover(true);
+
+        let mut admitted = Vec::new();
+        for key in ["acme/alice", "acme/alice", "acme/bob", "acme/alice"] {
+            let result = policy.check(Context::default(), key).await;
+            if let PolicyOutput::Ready(guard) = result.output {
+                admitted.push(guard.key().unwrap().to_owned());
+            }
+        }
+        // alice borrows once from the pool of three, which bob then uses up
+        assert_eq!(admitted, ["acme/alice", "acme", "acme/bob"]);
+    }
+
+    #[tokio::test]
+    async fn test_parent_checked_first() {
+        let policy = HierarchicalPolicy::new(keyed(1), keyed(2), |_: &Context<()>, key: &&str| {
+            Some(key.to_string())
+        });
+
+        let mut admitted = Vec::new();
+        for key in [
+            "acme/alice",
+            "acme/alice",
+            "acme/alice",
+            "acme/bob",
+            "acme/carol",
+        ] {
+            let result = policy.check(Context::default(), key).await;
+            if let PolicyOutput::Ready(guard) = result.output {
+                admitted.push(guard.key().unwrap().to_owned());
+            }
+        }
+        // alice over her limit leaves the pool to bob, who uses it up
+        assert_eq!(admitted, ["acme/alice", "acme/bob"]);
+        // carol was rejected by the pool, and her own budget is untouched
+        let carol = policy.child().explain_key(&(), "acme/carol");
+        assert_eq!(carol.remaining, Some(1));
+    }
+}
@@ -0,0 +1,160 @@
+//! Local token leases on top of a remote store
+//!
+//! Instead of a round trip per request, [`HybridStore`] leases a batch of
+//! cells for a key from the inner store and serves checks for that key from
+//! the local batch until it runs out or expires. Cells left over when a lease
+//! expires are discarded, so the cluster-wide rate can only be undershot, and
+//! the error is bounded by the lease size times the number of instances.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use governor::Quota;
+
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture};
+
+/// Prune expired leases every this many lease acquisitions
+const PRUNE_EVERY: u64 = 1024;
+
+struct Lease {
+    remaining: u32,
+    expires: Instant,
+}
+
+/// A [`RateLimitStore`] serving checks from locally leased batches of cells
+pub struct HybridStore<S> {
+    inner: S,
+    lease_size: u32,
+    resync_interval: Duration,
+    leases: DashMap<String, Lease>,
+    acquisitions: AtomicU64,
+}
+
+impl<S: RateLimitStore> HybridStore<S> {
+    /// Lease cells from `inner`, 50 at a time, resynchronizing every second
+    pub fn new(inner: S) -> Self {
+        HybridStore {
+            inner,
+            lease_size: 50,
+            resync_interval: Duration::from_secs(1),
+            leases: DashMap::new(),
+            acquisitions: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of cells leased per round trip
+    ///
+    /// Capped at the burst size of the quota, as a larger batch could never be granted.
+    pub fn lease_size(mut self, size: u32) -> Self {
+        self.lease_size = size.max(1);
+        self
+    }
+
+    /// How long a lease may be used before going back to the inner store
+    pub fn resync_interval(mut self, interval: Duration) -> Self {
+        self.resync_interval = interval;
+        self
+    }
+
+    /// Take `n` cells from a live local lease, if there is one
+    fn take_local(&self, key: &str, n: u32, now: Instant) -> bool {
+        match self.leases.get_mut(key) {
+            Some(mut lease) if lease.expires > now && lease.remaining >= n => {
+                lease.remaining -= n;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn store_lease(&self, key: &str, remaining: u32, now: Instant) {
+        self.leases.insert(
+            key.to_owned(),
+            Lease {
+                remaining,
+                expires: now + self.resync_interval,
+            },
+        );
+        if self.acquisitions.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == 0 {
+            self.leases.retain(|_, lease| lease.expires > now);
+        }
+    }
+}
+
+impl<S: std::fmt::Debug> std::fmt::Debug for HybridStore<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HybridStore")
+            .field("inner", &self.inner)
+            .field("lease_size", &self.lease_size)
+            .field("resync_interval", &self.resync_interval)
+            .field("leases", &self.leases.len())
+            .finish()
+    }
+}
+
+impl<S: RateLimitStore> RateLimitStore for HybridStore<S> {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            if self.take_local(key, n, now) {
+                return Ok(StoreDecision::Allowed);
+            }
+
+            let size = self.lease_size.min(quota.burst_size().get()).max(n);
+            match self.inner.check_n(key, quota, size).await? {
+                StoreDecision::Allowed => {
+                    self.store_lease(key, size - n, now);
+                    Ok(StoreDecision::Allowed)
+                }
+                // not enough for a full batch, fall back to an exact check
+                StoreDecision::Denied { .. } if size > n => self.inner.check_n(key, quota, n).await,
+                denied => Ok(denied),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU32;
+    use std::sync::atomic::AtomicU32;
+
+    #[derive(Default)]
+    struct CountingStore {
+        calls: AtomicU32,
+    }
+
+    impl RateLimitStore for CountingStore {
+        fn check_n<'a>(
+            &'a self,
+            _key: &'a str,
+            _quota: Quota,
+            _n: u32,
+        ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Ok(StoreDecision::Allowed) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_store_leases() {
+        let store = HybridStore::new(CountingStore::default()).lease_size(10);
+        let quota = Quota::per_second(NonZeroU32::new(100).unwrap());
+
+        for _ in 0..25 {
+            assert_eq!(
+                store.check_n("alice", quota, 1).await.unwrap(),
+                StoreDecision::Allowed
+            );
+        }
+        // one round trip per ten checks
+        assert_eq!(store.inner.calls.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,72 @@
+//! Duplicate submission suppression through the `Idempotency-Key` header
+//!
+//! Clients send the same idempotency key with every retry of one logical
+//! operation. Limiting per key catches double-clicked forms and retry storms
+//! without affecting distinct operations of the same client.
+
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use governor::Quota;
+use rama_http::HeaderName;
+
+use crate::{ExtractingPolicy, GovernorPolicy, HeaderKey};
+
+/// The `Idempotency-Key` header
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Reject more than `max_submissions` requests with the same `Idempotency-Key`
+/// within `window`
+///
+/// Requests without the header are not limited. Capacity comes back gradually,
+/// one submission per `window`, like any other quota.
+///
+/// # Panics
+///
+/// Panics if `max_submissions` or `window` is zero.
+pub fn idempotency_policy(max_submissions: u32, window: Duration) -> ExtractingPolicy<HeaderKey> {
+    let burst = NonZeroU32::new(max_submissions).expect("Submission count must be non-zero");
+    let quota = Quota::with_period(window)
+        .expect("Window must be non-zero")
+        .allow_burst(burst);
+
+    let policy = GovernorPolicy::builder()
+        .quota(quota)
+        .gc_interval(window)
+        .name("idempotency")
+        .build_with_serde_keyer(|key: &str| key.to_owned());
+    ExtractingPolicy::new(policy, HeaderKey::new(IDEMPOTENCY_KEY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+    use rama_http::{Body, Request};
+
+    #[tokio::test]
+    async fn test_idempotency_policy() {
+        let policy = idempotency_policy(1, Duration::from_secs(60));
+        let request = |key: Option<&str>| {
+            let mut builder = Request::builder();
+            if let Some(key) = key {
+                builder = builder.header(IDEMPOTENCY_KEY, key);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let mut admitted = 0;
+        for key in [
+            Some("order-1"),
+            Some("order-1"),
+            Some("order-2"),
+            None,
+            None,
+        ] {
+            let result = policy.check(Context::default(), request(key)).await;
+            admitted += matches!(result.output, PolicyOutput::Ready(_)) as usize;
+        }
+        assert_eq!(admitted, 4);
+    }
+}
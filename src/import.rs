@@ -0,0 +1,605 @@
+//! Importing rate limits from nginx and Envoy configs
+//!
+//! Edges moving to rama usually already have their limits written down for
+//! another proxy. [`GovernorConfig::from_nginx`] reads the `limit_req_zone`
+//! and `limit_req` directives of an nginx config, and [`EnvoyImport`] the
+//! descriptors of an Envoy rate limit service config, into an equivalent
+//! [`GovernorConfig`] to review, [`validate`](GovernorConfig::validate) and
+//! build like any other.
+//!
+//! Neither proxy maps one to one:
+//!
+//! - nginx delays requests within the burst unless `nodelay` is given; the
+//!   imported rules admit them right away, as with `nodelay`.
+//! - nginx `location = /path` matches one path exactly; the imported rule
+//!   matches it as a prefix. Regex and named locations are not imported.
+//! - Envoy counts per fixed window, so its rules import with
+//!   [`Algorithm::FixedWindow`] and a burst of one window's requests.
+//! - Envoy decides which requests carry which descriptors in its route
+//!   config, which this import doesn't read. Descriptors whose values come
+//!   from a header need [`EnvoyImport::header`], and descriptor values that
+//!   stand for routes need [`EnvoyImport::route`] to limit only those paths.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{Algorithm, GovernorConfig, RuleConfig};
+
+/// Error importing a config of another proxy
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ImportError {
+    /// The config doesn't parse
+    #[error("line {line}: {reason}")]
+    Syntax {
+        /// Line of the problem, starting at 1
+        line: usize,
+        /// What is wrong
+        reason: String,
+    },
+    /// The config uses something without an equivalent
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    /// `limit_req` refers to a zone that isn't declared
+    #[error("unknown zone {0:?}")]
+    UnknownZone(String),
+    /// A zone is used with different bursts, which one bucket can't have
+    #[error("zone {0:?} is used with different bursts")]
+    ConflictingBurst(String),
+    /// A descriptor has a bucket per value, but nothing says where the value
+    /// comes from
+    #[error("descriptor key {0:?} has no source, map it to a header")]
+    UnmappedDescriptor(String),
+}
+
+/// A token of an nginx config and the line it is on
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// Split an nginx config into words and `{`, `}` and `;`, without comments
+fn tokenize(conf: &str) -> Result<Vec<Token>, ImportError> {
+    let mut tokens = Vec::new();
+    let mut chars = conf.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '{' | '}' | ';' => tokens.push(Token {
+                text: c.to_string(),
+                line,
+            }),
+            '"' | '\'' => {
+                let start = line;
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(next) => {
+                            line += usize::from(next == '\n');
+                            text.push(next);
+                        }
+                        None => {
+                            return Err(ImportError::Syntax {
+                                line: start,
+                                reason: "unterminated string".to_owned(),
+                            });
+                        }
+                    }
+                }
+                tokens.push(Token { text, line: start });
+            }
+            c => {
+                let mut text = c.to_string();
+                while let Some(next) = chars.next_if(|c| !c.is_whitespace() && !"{};#".contains(*c))
+                {
+                    text.push(next);
+                }
+                tokens.push(Token { text, line });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// A `limit_req_zone` directive
+struct Zone {
+    key: String,
+    rate: String,
+    line: usize,
+}
+
+/// The uses of one zone by `limit_req` directives
+#[derive(Default)]
+struct ZoneUse {
+    /// Prefixes of the locations limiting by the zone
+    paths: Vec<String>,
+    /// Whether a server or http block limits all of its requests by the zone
+    everywhere: bool,
+    burst: Option<u32>,
+}
+
+/// The key source of an nginx key variable
+fn nginx_key(key: &str, line: usize) -> Result<String, ImportError> {
+    match key {
+        "$binary_remote_addr" | "$remote_addr" => Ok("ip".to_owned()),
+        _ => match key.strip_prefix("$http_") {
+            Some(header) => Ok(format!("header:{}", header.replace('_', "-"))),
+            None if !key.contains('$') => Ok("global".to_owned()),
+            None => Err(ImportError::Unsupported(format!(
+                "line {line}: key {key:?}, only the client address, request headers and constants are"
+            ))),
+        },
+    }
+}
+
+/// The value of the `name=value` argument `name` of a directive
+fn argument<'a>(words: &'a [Token], name: &str) -> Option<&'a str> {
+    words
+        .iter()
+        .find_map(|word| word.text.strip_prefix(name)?.strip_prefix('='))
+}
+
+impl GovernorConfig {
+    /// Import the `limit_req_zone` and `limit_req` directives of an nginx config
+    ///
+    /// Every zone a `limit_req` uses becomes one rule named after the zone,
+    /// limiting the locations using it, or all paths if a `server` or `http`
+    /// block uses it. Zones no `limit_req` uses are left out, whatever their
+    /// key. An nginx burst of `n` admits `n + 1` requests at once, the burst
+    /// of the rule.
+    ///
+    /// ```ignore
+    /// let config = GovernorConfig::from_nginx(&std::fs::read_to_string("nginx.conf")?)?;
+    /// let policy = config.build()?;
+    /// ```
+    pub fn from_nginx(conf: &str) -> Result<GovernorConfig, ImportError> {
+        let mut zones = BTreeMap::new();
+        let mut uses: Vec<(String, ZoneUse)> = Vec::new();
+        // location prefix of every open block, if it is a location
+        let mut blocks: Vec<Option<String>> = Vec::new();
+        let mut words: Vec<Token> = Vec::new();
+
+        for token in tokenize(conf)? {
+            let line = token.line;
+            match token.text.as_str() {
+                "{" => {
+                    let location = match words.first().map(|word| word.text.as_str()) {
+                        Some("location") => Some(location_prefix(&words)?),
+                        _ => None,
+                    };
+                    blocks.push(location);
+                    words.clear();
+                }
+                "}" => {
+                    if !words.is_empty() || blocks.pop().is_none() {
+                        return Err(ImportError::Syntax {
+                            line,
+                            reason: "unexpected \"}\"".to_owned(),
+                        });
+                    }
+                }
+                ";" => {
+                    let directive = std::mem::take(&mut words);
+                    match directive.first().map(|word| word.text.as_str()) {
+                        Some("limit_req_zone") => {
+                            let (name, zone) = nginx_zone(&directive, line)?;
+                            zones.insert(name, zone);
+                        }
+                        Some("limit_req") => {
+                            let zone = argument(&directive, "zone").ok_or_else(|| {
+                                ImportError::Syntax {
+                                    line,
+                                    reason: "limit_req without zone".to_owned(),
+                                }
+                            })?;
+                            let burst = match argument(&directive, "burst") {
+                                None => 0,
+                                Some(burst) => {
+                                    burst.parse::<u32>().map_err(|_| ImportError::Syntax {
+                                        line,
+                                        reason: format!("invalid burst {burst:?}"),
+                                    })?
+                                }
+                            };
+                            let index = match uses.iter().position(|(name, _)| name == zone) {
+                                Some(index) => index,
+                                None => {
+                                    uses.push((zone.to_owned(), ZoneUse::default()));
+                                    uses.len() - 1
+                                }
+                            };
+                            let zone_use = &mut uses[index].1;
+                            if zone_use.burst.is_some_and(|other| other != burst) {
+                                return Err(ImportError::ConflictingBurst(zone.to_owned()));
+                            }
+                            zone_use.burst = Some(burst);
+                            match blocks.iter().rev().find_map(Option::as_ref) {
+                                Some(path) => zone_use.paths.push(path.clone()),
+                                None => zone_use.everywhere = true,
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => words.push(token),
+            }
+        }
+        if let Some(word) = words.first() {
+            return Err(ImportError::Syntax {
+                line: word.line,
+                reason: "directive without \";\"".to_owned(),
+            });
+        }
+
+        let mut rules = Vec::new();
+        for (name, zone_use) in uses {
+            let zone = zones
+                .get(&name)
+                .ok_or_else(|| ImportError::UnknownZone(name.clone()))?;
+            rules.push(RuleConfig {
+                rate: zone.rate.clone(),
+                burst: zone_use.burst.map(|burst| burst + 1),
+                key: Some(nginx_key(&zone.key, zone.line)?),
+                algorithm: None,
+                paths: match zone_use.everywhere {
+                    true => Vec::new(),
+                    false => zone_use.paths,
+                },
+                exempt: Vec::new(),
+                name,
+            });
+        }
+        // locations take precedence over the limits of their server
+        rules.sort_by_key(|rule| rule.paths.is_empty());
+        Ok(GovernorConfig { rules })
+    }
+}
+
+/// Name and zone of a `limit_req_zone` directive
+fn nginx_zone(directive: &[Token], line: usize) -> Result<(String, Zone), ImportError> {
+    let syntax = |reason: &str| ImportError::Syntax {
+        line,
+        reason: reason.to_owned(),
+    };
+    let key = directive
+        .get(1)
+        .filter(|word| !word.text.contains('='))
+        .ok_or_else(|| syntax("limit_req_zone without key"))?;
+    let zone = argument(directive, "zone").ok_or_else(|| syntax("limit_req_zone without zone"))?;
+    let name = zone.split_once(':').map_or(zone, |(name, _size)| name);
+    let rate = argument(directive, "rate").ok_or_else(|| syntax("limit_req_zone without rate"))?;
+    Ok((
+        name.to_owned(),
+        Zone {
+            key: key.text.clone(),
+            rate: rate.to_owned(),
+            line,
+        },
+    ))
+}
+
+/// Path prefix of the location block opened by `words`
+fn location_prefix(words: &[Token]) -> Result<String, ImportError> {
+    let line = words[0].line;
+    let path = match &words[1..] {
+        [path] => &path.text,
+        [modifier, path] if modifier.text == "=" || modifier.text == "^~" => &path.text,
+        _ => {
+            let location: Vec<_> = words.iter().map(|word| word.text.as_str()).collect();
+            return Err(ImportError::Unsupported(format!(
+                "line {line}: {:?}, only prefix locations are",
+                location.join(" ")
+            )));
+        }
+    };
+    if path.starts_with('@') {
+        return Err(ImportError::Unsupported(format!(
+            "line {line}: named location {path:?}"
+        )));
+    }
+    Ok(path.clone())
+}
+
+/// Rate limit service config of Envoy, as read by `envoyproxy/ratelimit`
+///
+/// Deserialize it from the YAML file with any serde format crate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvoyRateLimitConfig {
+    /// Domain of the descriptors, prefixed to the imported rule names
+    pub domain: String,
+    /// The descriptors
+    #[serde(default)]
+    pub descriptors: Vec<EnvoyDescriptor>,
+}
+
+/// One descriptor entry, matching requests carrying it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvoyDescriptor {
+    /// Key of the entry
+    pub key: String,
+    /// Value of the entry; without one, every value has a bucket of its own
+    #[serde(default)]
+    pub value: Option<String>,
+    /// Limit of requests matching the entry
+    #[serde(default)]
+    pub rate_limit: Option<EnvoyRateLimit>,
+    /// Entries matching requests that also carry this one
+    #[serde(default)]
+    pub descriptors: Vec<EnvoyDescriptor>,
+}
+
+/// The limit of an [`EnvoyDescriptor`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvoyRateLimit {
+    /// The window requests are counted in
+    #[serde(default)]
+    pub unit: Option<EnvoyUnit>,
+    /// Requests admitted per window
+    #[serde(default)]
+    pub requests_per_unit: u32,
+    /// Whether requests matching the descriptor aren't limited at all
+    #[serde(default)]
+    pub unlimited: bool,
+}
+
+/// Window of an [`EnvoyRateLimit`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvoyUnit {
+    /// One second
+    #[serde(alias = "SECOND")]
+    Second,
+    /// One minute
+    #[serde(alias = "MINUTE")]
+    Minute,
+    /// One hour
+    #[serde(alias = "HOUR")]
+    Hour,
+    /// One day
+    #[serde(alias = "DAY")]
+    Day,
+}
+
+/// Imports an [`EnvoyRateLimitConfig`] into a [`GovernorConfig`]
+///
+/// Every descriptor with a limit becomes a rule named after the domain and
+/// the entries leading to it, e.g. `edge.path_/api.remote_address`. Entries
+/// with a value match the requests of the routes given for it, or all
+/// requests without any; an entry without a value keys the rule by the
+/// client address for `remote_address`, or by the header given for it.
+/// Unlimited descriptors are left out.
+#[derive(Debug, Clone, Default)]
+pub struct EnvoyImport {
+    headers: BTreeMap<String, String>,
+    routes: BTreeMap<String, Vec<String>>,
+}
+
+impl EnvoyImport {
+    /// Import with only `remote_address` as descriptor source and no routes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Descriptor values of `key` are the values of `header`, as set up by a
+    /// `request_headers` action in Envoy
+    pub fn header(mut self, key: impl Into<String>, header: impl Into<String>) -> Self {
+        self.headers.insert(key.into(), header.into());
+        self
+    }
+
+    /// Requests carrying descriptor value `value` are those of the path
+    /// prefixes `paths`
+    pub fn route<P: Into<String>>(
+        mut self,
+        value: impl Into<String>,
+        paths: impl IntoIterator<Item = P>,
+    ) -> Self {
+        self.routes
+            .entry(value.into())
+            .or_default()
+            .extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Import `config`
+    pub fn import(&self, config: &EnvoyRateLimitConfig) -> Result<GovernorConfig, ImportError> {
+        let mut rules = Vec::new();
+        let mut chain = Vec::new();
+        for descriptor in &config.descriptors {
+            self.import_descriptor(&config.domain, descriptor, &mut chain, &mut rules)?;
+        }
+        Ok(GovernorConfig { rules })
+    }
+
+    fn import_descriptor<'a>(
+        &self,
+        domain: &str,
+        descriptor: &'a EnvoyDescriptor,
+        chain: &mut Vec<&'a EnvoyDescriptor>,
+        rules: &mut Vec<RuleConfig>,
+    ) -> Result<(), ImportError> {
+        if let Some(entry) = chain.iter().find(|entry| entry.value.is_none()) {
+            return Err(ImportError::Unsupported(format!(
+                "descriptors nested in {:?} without a value, keys of several values are not",
+                entry.key
+            )));
+        }
+        chain.push(descriptor);
+        if let Some(limit) = descriptor
+            .rate_limit
+            .as_ref()
+            .filter(|limit| !limit.unlimited)
+        {
+            let name = chain
+                .iter()
+                .map(|entry| match &entry.value {
+                    Some(value) => format!("{}_{value}", entry.key),
+                    None => entry.key.clone(),
+                })
+                .fold(domain.to_owned(), |name, entry| name + "." + &entry);
+            let key = match &descriptor.value {
+                Some(_) => "global".to_owned(),
+                None if descriptor.key == "remote_address" => "ip".to_owned(),
+                None => match self.headers.get(&descriptor.key) {
+                    Some(header) => format!("header:{header}"),
+                    None => return Err(ImportError::UnmappedDescriptor(descriptor.key.clone())),
+                },
+            };
+            let requests = limit.requests_per_unit;
+            let rate = match limit.unit {
+                Some(EnvoyUnit::Second) => format!("{requests}r/s"),
+                Some(EnvoyUnit::Minute) => format!("{requests}r/m"),
+                Some(EnvoyUnit::Hour) => format!("{requests}r/h"),
+                Some(EnvoyUnit::Day) if requests % 24 == 0 => format!("{}r/h", requests / 24),
+                Some(EnvoyUnit::Day) => {
+                    return Err(ImportError::Unsupported(format!(
+                        "{name}: {requests} requests per day, only multiples of 24 are"
+                    )));
+                }
+                None => {
+                    return Err(ImportError::Unsupported(format!(
+                        "{name}: limit without unit"
+                    )));
+                }
+            };
+            rules.push(RuleConfig {
+                name,
+                rate,
+                burst: Some(requests),
+                key: Some(key),
+                algorithm: Some(Algorithm::FixedWindow),
+                paths: chain
+                    .iter()
+                    .filter_map(|entry| self.routes.get(entry.value.as_ref()?))
+                    .flatten()
+                    .cloned()
+                    .collect(),
+                exempt: Vec::new(),
+            });
+        }
+        for nested in &descriptor.descriptors {
+            self.import_descriptor(domain, nested, chain, rules)?;
+        }
+        chain.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_nginx_and_envoy() {
+        let nginx = r#"
+            http {
+                limit_req_zone $binary_remote_addr zone=perip:10m rate=10r/s;
+                limit_req_zone $http_x_api_key zone=apikey:10m rate=100r/m;
+                limit_req_zone $server_name zone=unused:1m rate=1r/s;
+
+                server {
+                    limit_req zone=perip burst=5; # all paths
+                    location /api/ {
+                        limit_req zone=apikey burst=20 nodelay;
+                    }
+                    location = "/login" { limit_req zone=apikey burst=20; }
+                }
+            }
+        "#;
+        let config = GovernorConfig::from_nginx(nginx).unwrap();
+        let rules: Vec<_> = config
+            .rules
+            .iter()
+            .map(|rule| {
+                (
+                    rule.name.as_str(),
+                    rule.rate.as_str(),
+                    rule.burst,
+                    rule.key.as_deref(),
+                    rule.paths.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                (
+                    "apikey",
+                    "100r/m",
+                    Some(21),
+                    Some("header:x-api-key"),
+                    vec!["/api/".to_owned(), "/login".to_owned()]
+                ),
+                ("perip", "10r/s", Some(6), Some("ip"), vec![]),
+            ]
+        );
+        assert!(config.validate().is_empty());
+        assert_eq!(
+            GovernorConfig::from_nginx("location ~ ^/api { limit_req zone=a; }")
+                .unwrap_err()
+                .to_string(),
+            "unsupported: line 1: \"location ~ ^/api\", only prefix locations are"
+        );
+        assert_eq!(
+            GovernorConfig::from_nginx("limit_req zone=missing;").unwrap_err(),
+            ImportError::UnknownZone("missing".to_owned())
+        );
+
+        let envoy: EnvoyRateLimitConfig = serde_json::from_value(serde_json::json!({
+            "domain": "edge",
+            "descriptors": [
+                {
+                    "key": "path",
+                    "value": "/api",
+                    "descriptors": [{
+                        "key": "remote_address",
+                        "rate_limit": { "unit": "minute", "requests_per_unit": 60 },
+                    }],
+                },
+                { "key": "api_key", "rate_limit": { "unit": "DAY", "requests_per_unit": 2400 } },
+                { "key": "internal", "value": "yes", "rate_limit": { "unlimited": true } },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            EnvoyImport::new().import(&envoy).unwrap_err(),
+            ImportError::UnmappedDescriptor("api_key".to_owned())
+        );
+        let config = EnvoyImport::new()
+            .header("api_key", "x-api-key")
+            .route("/api", ["/api"])
+            .import(&envoy)
+            .unwrap();
+        let rules: Vec<_> = config
+            .rules
+            .iter()
+            .map(|rule| {
+                (
+                    rule.name.as_str(),
+                    rule.rate.as_str(),
+                    rule.burst,
+                    rule.paths.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                (
+                    "edge.path_/api.remote_address",
+                    "60r/m",
+                    Some(60),
+                    vec!["/api".to_owned()]
+                ),
+                ("edge.api_key", "100r/h", Some(2400), vec![]),
+            ]
+        );
+        assert!(config.build().is_ok());
+    }
+}
@@ -0,0 +1,134 @@
+//! Checking peer addresses without allocating
+//!
+//! Keys travel through a policy as strings, and formatting an address into a
+//! `String` allocates on every check. At hundreds of thousands of requests
+//! per second that shows. Policies built with
+//! [`build_with_ip_keys`](crate::GovernorPolicyBuilder::build_with_ip_keys)
+//! keep their keys as [`IpAddr`], and [`Limiter::check_ip`](crate::Limiter::check_ip)
+//! formats the address on the stack, so checking an address the policy
+//! already tracks performs no heap allocation. New addresses allocate only
+//! when the state map grows. Keys that aren't addresses keep a bucket of
+//! their own under their text.
+//!
+//! rama's `Policy` path stores a guard in the request context, which
+//! allocates regardless of the key.
+
+use std::fmt::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+/// Longest text of an address, an IPv6 address with an embedded IPv4 one
+const MAX_IP_LEN: usize = "ffff:ffff:ffff:ffff:ffff:ffff:255.255.255.255".len();
+
+/// Text of an address, formatted into a buffer on the stack
+pub(crate) struct IpText {
+    buf: [u8; MAX_IP_LEN],
+    len: usize,
+}
+
+impl IpText {
+    pub(crate) fn new(ip: IpAddr) -> Self {
+        let mut text = IpText {
+            buf: [0; MAX_IP_LEN],
+            len: 0,
+        };
+        write!(text, "{ip}").expect("addresses fit the buffer");
+        text
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.buf[..self.len]).expect("addresses are ASCII")
+    }
+}
+
+impl Write for IpText {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Key of a policy built with
+/// [`build_with_ip_keys`](crate::GovernorPolicyBuilder::build_with_ip_keys)
+///
+/// Serializes as the text of the key either way, so snapshots of addresses
+/// read the same as those of plain string keys.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum IpKey {
+    /// A client address
+    Addr(IpAddr),
+    /// Any other key, e.g. a header value, counted under its text
+    Other(String),
+}
+
+/// The address a key is the text of, or the key itself if it isn't one
+///
+/// IPv4-mapped IPv6 addresses count as their IPv4 address, and a port
+/// suffix, as in `203.0.113.7:443` or `[2001:db8::1]:443`, is ignored.
+pub(crate) fn parse_ip_key(key: &str) -> IpKey {
+    let ip = key
+        .parse::<IpAddr>()
+        .or_else(|_| key.parse::<SocketAddr>().map(|addr| addr.ip()));
+    match ip {
+        Ok(ip) => IpKey::Addr(ip.to_canonical()),
+        Err(_) => IpKey::Other(key.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_text_roundtrip() {
+        for ip in [
+            "203.0.113.7",
+            "2001:db8::1",
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+            "::ffff:255.255.255.255",
+        ] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert_eq!(IpText::new(ip).as_str(), ip.to_string());
+        }
+        let addr = |ip: &str| IpKey::Addr(ip.parse().unwrap());
+        assert_eq!(parse_ip_key("::ffff:203.0.113.7"), addr("203.0.113.7"));
+        // ports don't split an address into several buckets
+        assert_eq!(parse_ip_key("203.0.113.7:443"), addr("203.0.113.7"));
+        assert_eq!(parse_ip_key("[2001:db8::1]:8080"), addr("2001:db8::1"));
+        // other keys keep their own
+        assert_eq!(parse_ip_key("alice"), IpKey::Other("alice".to_owned()));
+
+        // snapshots hold the text of either
+        let keys = [addr("203.0.113.7"), IpKey::Other("alice".to_owned())];
+        let json = serde_json::to_string(&keys).unwrap();
+        assert_eq!(json, r#"["203.0.113.7","alice"]"#);
+        assert_eq!(serde_json::from_str::<[IpKey; 2]>(&json).unwrap(), keys);
+    }
+
+    #[tokio::test]
+    async fn test_ip_key_buckets() {
+        let limiter = crate::Limiter::new(
+            crate::GovernorPolicy::builder()
+                .per_minute(1)
+                .build_with_ip_keys(),
+        );
+        let mut admitted = Vec::new();
+        for key in [
+            "203.0.113.7:1000",
+            "203.0.113.7:2000",
+            "alice",
+            "bob",
+            "alice",
+        ] {
+            admitted.push(limiter.check_key(key).await.is_ok());
+        }
+        assert_eq!(admitted, [true, false, true, true, false]);
+    }
+}
@@ -0,0 +1,267 @@
+//! Per-key decision history for support engineers
+//!
+//! The bucket level of a key only tells how it stands right now. A policy
+//! built with [`key_stats`](crate::GovernorPolicyBuilder::key_stats) also
+//! keeps, per key, the requests and denials of each of the last hour's
+//! minutes and the gaps between its recent requests, available through
+//! [`GovernorHandle::key_stats`](crate::GovernorHandle::key_stats). Memory is
+//! bounded by the number of keys tracked: once full, a key not seen since
+//! the last round of evictions makes room for a new one, picked by the CLOCK
+//! approximation of least recently used so that a client rotating keys costs
+//! no more than a short scan per new key.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+/// Minutes of history kept per key
+const MINUTES: usize = 60;
+
+/// Gaps between requests kept per key for the inter-arrival percentiles
+const GAPS: usize = 256;
+
+/// Requests and denials of a key within one minute
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MinuteStats {
+    /// Requests decided, allowed or not
+    pub requests: u64,
+    /// Requests rejected, or let through over the limit by mirror mode
+    pub denials: u64,
+}
+
+/// History of one key, see [`GovernorHandle::key_stats`](crate::GovernorHandle::key_stats)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyStats {
+    /// Requests since the key is tracked
+    pub requests: u64,
+    /// Denials since the key is tracked
+    pub denials: u64,
+    /// The minutes of the last hour the key is tracked for, oldest first and
+    /// the current one last
+    pub minutes: Vec<MinuteStats>,
+    /// Median gap between the recent requests of the key
+    pub p50_inter_arrival: Option<Duration>,
+    /// 99th percentile gap between the recent requests of the key
+    pub p99_inter_arrival: Option<Duration>,
+    /// Time of the last request
+    pub last_seen: SystemTime,
+}
+
+struct History {
+    requests: u64,
+    denials: u64,
+    /// Minute since the store was created the history started in
+    first_minute: u64,
+    /// Minute of the last request
+    minute: u64,
+    /// Ring of minutes, indexed by the minute modulo its length
+    minutes: [MinuteStats; MINUTES],
+    /// Ring of gaps between requests, in microseconds
+    gaps: Box<[u64; GAPS]>,
+    gap_count: usize,
+    last: Instant,
+    last_seen: SystemTime,
+    /// Whether the key was seen since the clock hand last passed it
+    referenced: bool,
+}
+
+impl History {
+    fn new(now: Instant, minute: u64) -> Self {
+        History {
+            requests: 0,
+            denials: 0,
+            first_minute: minute,
+            minute,
+            minutes: [MinuteStats::default(); MINUTES],
+            gaps: Box::new([0; GAPS]),
+            gap_count: 0,
+            last: now,
+            last_seen: SystemTime::now(),
+            referenced: true,
+        }
+    }
+
+    fn record(&mut self, now: Instant, minute: u64, denied: bool) {
+        for passed in (self.minute + 1..=minute).take(MINUTES) {
+            self.minutes[passed as usize % MINUTES] = MinuteStats::default();
+        }
+        self.minute = self.minute.max(minute);
+        let bucket = &mut self.minutes[minute as usize % MINUTES];
+        bucket.requests += 1;
+        bucket.denials += u64::from(denied);
+        self.requests += 1;
+        self.denials += u64::from(denied);
+
+        if self.requests > 1 {
+            let gap = now.saturating_duration_since(self.last).as_micros() as u64;
+            self.gaps[self.gap_count % GAPS] = gap;
+            self.gap_count += 1;
+        }
+        self.last = now;
+        self.last_seen = SystemTime::now();
+        self.referenced = true;
+    }
+
+    fn stats(&self, minute: u64) -> KeyStats {
+        let first = self
+            .first_minute
+            .max((minute + 1).saturating_sub(MINUTES as u64));
+        let minutes = (first..=minute)
+            .map(|m| match m <= self.minute {
+                true => self.minutes[m as usize % MINUTES],
+                false => MinuteStats::default(),
+            })
+            .collect();
+        let mut gaps = self.gaps[..self.gap_count.min(GAPS)].to_vec();
+        gaps.sort_unstable();
+        let percentile = |q: f64| {
+            let rank = (q * gaps.len() as f64).ceil() as usize;
+            gaps.get(rank.saturating_sub(1))
+                .map(|micros| Duration::from_micros(*micros))
+        };
+        KeyStats {
+            requests: self.requests,
+            denials: self.denials,
+            minutes,
+            p50_inter_arrival: percentile(0.5),
+            p99_inter_arrival: percentile(0.99),
+            last_seen: self.last_seen,
+        }
+    }
+}
+
+/// Tracked keys in the order the clock hand visits them
+#[derive(Default)]
+struct Clock {
+    keys: Vec<String>,
+    hand: usize,
+}
+
+/// Decision history of up to a bounded number of keys
+pub(crate) struct KeyStatsStore {
+    max_keys: usize,
+    keys: DashMap<String, History>,
+    clock: Mutex<Clock>,
+    start: Instant,
+}
+
+impl KeyStatsStore {
+    pub(crate) fn new(max_keys: usize) -> Self {
+        KeyStatsStore {
+            max_keys: max_keys.max(1),
+            keys: DashMap::new(),
+            clock: Mutex::default(),
+            start: Instant::now(),
+        }
+    }
+
+    fn minute(&self, now: Instant) -> u64 {
+        now.duration_since(self.start).as_secs() / 60
+    }
+
+    /// Record a decision on `key`
+    pub(crate) fn record(&self, key: &str, denied: bool) {
+        let now = Instant::now();
+        let minute = self.minute(now);
+        if let Some(mut history) = self.keys.get_mut(key) {
+            history.record(now, minute, denied);
+            return;
+        }
+        let mut clock = self.clock.lock().unwrap();
+        // another thread may have added the key meanwhile
+        let added = self.keys.contains_key(key);
+        if !added && clock.keys.len() < self.max_keys {
+            clock.keys.push(key.to_owned());
+        } else if !added {
+            // every full turn of the hand clears all marks, so this ends
+            // within two turns
+            loop {
+                let hand = clock.hand;
+                clock.hand = (hand + 1) % clock.keys.len();
+                let referenced = self
+                    .keys
+                    .get_mut(&clock.keys[hand])
+                    .map(|mut history| std::mem::take(&mut history.referenced));
+                if referenced != Some(true) {
+                    let idle = std::mem::replace(&mut clock.keys[hand], key.to_owned());
+                    self.keys.remove(&idle);
+                    break;
+                }
+            }
+        }
+        self.keys
+            .entry(key.to_owned())
+            .or_insert_with(|| History::new(now, minute))
+            .record(now, minute, denied);
+    }
+
+    /// History of `key`, if it is tracked
+    pub(crate) fn get(&self, key: &str) -> Option<KeyStats> {
+        let minute = self.minute(Instant::now());
+        self.keys.get(key).map(|history| history.stats(minute))
+    }
+}
+
+impl std::fmt::Debug for KeyStatsStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyStatsStore")
+            .field("max_keys", &self.max_keys)
+            .field("tracked", &self.keys.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_key_stats() {
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .burst_size(2)
+            .key_stats(2)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let limiter = crate::Limiter::new(policy);
+        for key in ["alice", "alice", "alice", "bob"] {
+            let _ = limiter.check_key(key).await;
+        }
+        let handle = limiter.handle();
+        let stats = handle.key_stats("alice").unwrap();
+        assert_eq!((stats.requests, stats.denials), (3, 1));
+        assert_eq!(stats.minutes.len(), 1);
+        assert_eq!(stats.minutes[0].denials, 1);
+        assert!(stats.p99_inter_arrival >= stats.p50_inter_arrival);
+        assert!(handle.key_stats("bob").unwrap().p50_inter_arrival.is_none());
+
+        // tracking a third key evicts the one seen least recently
+        let _ = limiter.check_key("carol").await;
+        assert!(handle.key_stats("alice").is_none());
+        assert!(handle.key_stats("carol").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_key_stats_eviction_keeps_hot_keys() {
+        let policy = GovernorPolicy::builder()
+            .per_second(1000)
+            .key_stats(4)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let limiter = crate::Limiter::new(policy);
+        // a client rotating keys doesn't push out a key in steady use
+        for i in 0..100 {
+            let _ = limiter.check_key("hot").await;
+            let _ = limiter.check_key(&format!("rotating-{i}")).await;
+        }
+        let handle = limiter.handle();
+        // new keys start marked, so the first turn of the hand may evict it
+        assert!(handle.key_stats("hot").unwrap().requests > 90);
+        assert!(handle.key_stats("rotating-99").is_some());
+        assert!(handle.key_stats("rotating-0").is_none());
+        let tracked = (0..100)
+            .filter(|i| handle.key_stats(&format!("rotating-{i}")).is_some())
+            .count();
+        assert_eq!(tracked, 3);
+    }
+}
@@ -0,0 +1,371 @@
+//! Keyed limiter with an inspectable state store
+//!
+//! governor's own keyed limiters own their state store and don't expose it.
+//! [`KeyedLimiter`] keeps a shared handle to the store next to the limiter so
+//! per-key GCRA state can be read and restored (e.g. for snapshots).
+//!
+//! Keys are hashed with std's SipHash by default, which resists hash flooding
+//! but shows up in profiles of short keys at high rates. [`KeyHasher`] picks
+//! a faster hash through
+//! [`GovernorPolicyBuilder::key_hasher`](crate::GovernorPolicyBuilder::key_hasher).
+
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState};
+use std::num::NonZeroU32;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use governor::clock::{Clock, Reference};
+use governor::middleware::{StateInformationMiddleware, StateSnapshot};
+use governor::nanos::Nanos;
+use governor::state::keyed::ShrinkableKeyedStateStore;
+use governor::state::{InMemoryState, NotKeyed, RateLimiter, StateStore};
+use governor::{InsufficientCapacity, NotUntil, Quota};
+
+use crate::clock::{LimiterClock, LimiterInstant};
+use crate::runtime::{self, Runtime};
+use crate::telemetry::TrackedKeysGauge;
+
+/// Hash function of the keys of a keyed policy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyHasher {
+    /// std's SipHash-1-3 with random keys, resistant to hash flooding
+    #[default]
+    SipHash,
+    /// foldhash, several times faster on short keys like addresses
+    ///
+    /// Seeded randomly per limiter, which makes crafting colliding keys
+    /// impractical, without SipHash's cryptographic guarantees.
+    FoldHash,
+}
+
+impl KeyHasher {
+    fn build(self) -> KeyHashState {
+        match self {
+            KeyHasher::SipHash => KeyHashState::SipHash(RandomState::new()),
+            KeyHasher::FoldHash => KeyHashState::FoldHash(foldhash::fast::RandomState::default()),
+        }
+    }
+}
+
+/// Seeded state of a [`KeyHasher`]
+#[derive(Debug, Clone)]
+pub(crate) enum KeyHashState {
+    SipHash(RandomState),
+    FoldHash(foldhash::fast::RandomState),
+}
+
+impl BuildHasher for KeyHashState {
+    type Hasher = KeyHash;
+
+    fn build_hasher(&self) -> KeyHash {
+        match self {
+            KeyHashState::SipHash(state) => KeyHash::SipHash(state.build_hasher()),
+            KeyHashState::FoldHash(state) => KeyHash::FoldHash(state.build_hasher()),
+        }
+    }
+}
+
+pub(crate) enum KeyHash {
+    SipHash(DefaultHasher),
+    FoldHash(foldhash::fast::FoldHasher),
+}
+
+impl Hasher for KeyHash {
+    fn finish(&self) -> u64 {
+        match self {
+            KeyHash::SipHash(hasher) => hasher.finish(),
+            KeyHash::FoldHash(hasher) => hasher.finish(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            KeyHash::SipHash(hasher) => hasher.write(bytes),
+            KeyHash::FoldHash(hasher) => hasher.write(bytes),
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        match self {
+            KeyHash::SipHash(hasher) => hasher.write_u8(i),
+            KeyHash::FoldHash(hasher) => hasher.write_u8(i),
+        }
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        match self {
+            KeyHash::SipHash(hasher) => hasher.write_u32(i),
+            KeyHash::FoldHash(hasher) => hasher.write_u32(i),
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        match self {
+            KeyHash::SipHash(hasher) => hasher.write_u64(i),
+            KeyHash::FoldHash(hasher) => hasher.write_u64(i),
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        match self {
+            KeyHash::SipHash(hasher) => hasher.write_usize(i),
+            KeyHash::FoldHash(hasher) => hasher.write_usize(i),
+        }
+    }
+}
+
+type StateMap<K> = DashMap<K, InMemoryState, KeyHashState>;
+
+/// A keyed state store sharing its map with the [`KeyedLimiter`] that owns it
+#[derive(Debug)]
+pub(crate) struct SharedStore<K: Hash + Eq>(Arc<StateMap<K>>);
+
+impl<K: Hash + Eq + Clone> StateStore for SharedStore<K> {
+    type Key = K;
+
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        measure_and_replace(&self.0, key, f)
+    }
+}
+
+/// Update the state of `key`, like governor's own map stores do, which only
+/// support the default hasher
+fn measure_and_replace<K, T, F, E>(map: &StateMap<K>, key: &K, f: F) -> Result<T, E>
+where
+    K: Hash + Eq + Clone,
+    F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+{
+    if let Some(state) = map.get(key) {
+        return state.measure_and_replace(&NotKeyed::NonKey, f);
+    }
+    let state = map.entry(key.clone()).or_default();
+    state.measure_and_replace(&NotKeyed::NonKey, f)
+}
+
+impl<K: Hash + Eq + Clone> ShrinkableKeyedStateStore<K> for SharedStore<K> {
+    fn retain_recent(&self, drop_below: Nanos) {
+        self.0
+            .retain(|_, state| load_tat(state).is_some_and(|tat| tat > drop_below));
+    }
+
+    fn shrink_to_fit(&self) {
+        self.0.shrink_to_fit()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Read the theoretical arrival time stored in a state cell
+fn load_tat(state: &InMemoryState) -> Option<Nanos> {
+    // A measurement that always refuses to update hands us the current value.
+    state
+        .measure_and_replace(&NotKeyed::NonKey, Err::<((), Nanos), Option<Nanos>>)
+        .err()
+        .flatten()
+}
+
+/// Raise the theoretical arrival time of a state cell to at least `tat`
+fn raise_tat(state: &InMemoryState, tat: Nanos) {
+    let _ = state.measure_and_replace(&NotKeyed::NonKey, |prev| match prev {
+        Some(prev) if prev >= tat => Err(()),
+        _ => Ok(((), tat)),
+    });
+}
+
+/// A GCRA keyed rate limiter whose state can be inspected
+pub(crate) struct KeyedLimiter<K: Hash + Eq + Clone> {
+    limiter: RateLimiter<K, SharedStore<K>, LimiterClock, StateInformationMiddleware>,
+    store: Arc<StateMap<K>>,
+    clock: LimiterClock,
+    start: LimiterInstant,
+    quota: Quota,
+    hasher: KeyHasher,
+}
+
+impl<K: Hash + Eq + Clone> KeyedLimiter<K> {
+    pub(crate) fn new(quota: Quota) -> Self {
+        Self::with_hasher(quota, KeyHasher::default())
+    }
+
+    pub(crate) fn with_hasher(quota: Quota, hasher: KeyHasher) -> Self {
+        let store = Arc::new(DashMap::with_hasher(hasher.build()));
+        let clock = LimiterClock::default();
+        // Taken right before the limiter records its own start, so our view of
+        // "now" lags the limiter's by a few nanoseconds at most.
+        let start = clock.now();
+        let limiter = RateLimiter::new(quota, SharedStore(store.clone()), &clock);
+
+        KeyedLimiter {
+            limiter,
+            store,
+            clock,
+            start,
+            quota,
+            hasher,
+        }
+    }
+
+    /// An empty limiter for `quota` hashing keys like this one
+    pub(crate) fn with_quota(&self, quota: Quota) -> Self {
+        Self::with_hasher(quota, self.hasher)
+    }
+
+    pub(crate) fn check_key(&self, key: &K) -> Result<StateSnapshot, NotUntil<LimiterInstant>> {
+        self.limiter.check_key(key)
+    }
+
+    pub(crate) fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<Result<StateSnapshot, NotUntil<LimiterInstant>>, InsufficientCapacity> {
+        self.limiter.check_key_n(key, n)
+    }
+
+    pub(crate) fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Forget the state of `key`
+    pub(crate) fn remove(&self, key: &K) {
+        self.store.remove(key);
+    }
+
+    /// Number of keys currently tracked
+    pub(crate) fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    /// Forget keys whose bucket is fully replenished
+    pub(crate) fn retain_recent(&self) {
+        self.limiter.retain_recent();
+        self.limiter.shrink_to_fit();
+    }
+
+    fn now(&self) -> Nanos {
+        Reference::duration_since(&self.clock.now(), self.start)
+    }
+
+    /// Consume `n` cells for `key` without checking, e.g. for usage observed elsewhere.
+    ///
+    /// The key's state is never pushed further than one full burst into the future.
+    pub(crate) fn charge(&self, key: &K, n: u32) {
+        let now = self.now();
+        let t = Nanos::from(self.quota.replenish_interval());
+        let tau = t * u64::from(self.quota.burst_size().get());
+        // same starting state and update rule as governor's GCRA
+        let limit = now + tau + t;
+        let _ = measure_and_replace(&self.store, key, |tat| {
+            let tat = tat.unwrap_or(now + t).max(now) + t * u64::from(n);
+            Ok::<_, ()>(((), tat.min(limit)))
+        });
+    }
+
+    /// How far the state of `key` lies ahead of a fully replenished bucket,
+    /// read without counting a request
+    pub(crate) fn backlog(&self, key: &K) -> Duration {
+        let now = self.now();
+        let t = Nanos::from(self.quota.replenish_interval());
+        let tat = self.store.get(key).and_then(|state| load_tat(&state));
+        match tat {
+            // a fresh bucket starts one interval ahead, see `charge`
+            Some(tat) if tat > now + t => Duration::from_nanos(tat.as_u64() - (now + t).as_u64()),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// All keys whose bucket is not fully replenished, with how far their
+    /// theoretical arrival time lies ahead of now
+    pub(crate) fn export(&self) -> Vec<(K, Duration)> {
+        let now = self.now();
+        self.store
+            .iter()
+            .filter_map(|entry| {
+                let tat = load_tat(entry.value())?;
+                (tat > now).then(|| {
+                    let offset = Duration::from_nanos(tat.as_u64() - now.as_u64());
+                    (entry.key().clone(), offset)
+                })
+            })
+            .collect()
+    }
+
+    /// Restore a key whose theoretical arrival time lies `offset` ahead of now.
+    ///
+    /// Never lowers existing state, so an import can't hand out extra capacity.
+    pub(crate) fn import(&self, key: K, offset: Duration) {
+        let tat = self.now() + Nanos::from(offset);
+        let entry = self.store.entry(key).or_default();
+        raise_tat(&entry, tat);
+    }
+}
+
+/// Spawn the task that periodically drops replenished keys from the live
+/// limiter, reporting the number of keys left to `gauge`.
+///
+/// The task holds only a weak reference to the limiter and exits once the
+/// owning policy has been dropped.
+pub(crate) fn spawn_gc<K>(
+    runtime: &Arc<dyn Runtime>,
+    limiter: Weak<ArcSwap<KeyedLimiter<K>>>,
+    interval: Duration,
+    gauge: TrackedKeysGauge,
+) where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    let timer = runtime.clone();
+    let mut shutdown = runtime.shutdown();
+    runtime.spawn(Box::pin(async move {
+        while runtime::tick(&*timer, interval, &mut shutdown).await {
+            let Some(limiter) = limiter.upgrade() else {
+                return;
+            };
+            let limiter = limiter.load();
+            limiter.retain_recent();
+            gauge.record(limiter.len());
+        }
+    }));
+}
+
+impl<K: Hash + Eq + Clone> std::fmt::Debug for KeyedLimiter<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyedLimiter")
+            .field("quota", &self.quota)
+            .field("keys", &self.store.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    #[test]
+    fn test_fold_hash_keys() {
+        let quota = Quota::per_hour(NonZeroU32::new(1).unwrap());
+        let limiter = KeyedLimiter::with_hasher(quota, KeyHasher::FoldHash);
+        for key in ["alice", "bob"] {
+            assert!(limiter.check_key(&key).is_ok());
+            assert!(limiter.check_key(&key).is_err());
+        }
+        assert_eq!(limiter.len(), 2);
+        assert!(limiter.backlog(&"alice") > Duration::ZERO);
+
+        let limiter = limiter.with_quota(quota);
+        assert_eq!(limiter.hasher, KeyHasher::FoldHash);
+        assert!(limiter.check_key(&"alice").is_ok());
+    }
+}
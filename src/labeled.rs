@@ -0,0 +1,234 @@
+//! Decisions per branch of a matcher map
+//!
+//! A vector of matchers and policies passed to `LimitLayer` runs whichever
+//! policy matches first, but the metrics of the policies don't tell which
+//! branch that was. Wrap each policy in a [`LabeledPolicy`] to tag its
+//! decisions with a label, reported in a `branch` metrics label, as the
+//! `governor.branch` span attribute and in trace events. Labeling through a
+//! shared [`DecisionBreakdown`] also counts the decisions of all branches
+//! for a single snapshot:
+//!
+//! ```ignore
+//! let breakdown = DecisionBreakdown::new();
+//! LimitLayer::new(Arc::new(vec![
+//!     (HttpMatcher::path("/api/*"), Some(breakdown.label("api", api_policy))),
+//!     (HttpMatcher::path("/login"), Some(breakdown.label("login", login_policy))),
+//! ]))
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+use rama_core::Context;
+use rama_core::layer::limit::policy::{Policy, PolicyOutput, PolicyResult};
+use serde::Serialize;
+
+use crate::{GovernorError, GovernorGuard};
+
+/// Decisions of one branch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BranchDecisions {
+    /// Label of the branch
+    pub label: String,
+    /// Requests let through
+    pub allowed: u64,
+    /// Requests rejected as rate limited
+    pub denied: u64,
+    /// Requests failed for other reasons, e.g. an unavailable store
+    pub errors: u64,
+}
+
+struct BranchCounters {
+    label: Arc<str>,
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    errors: AtomicU64,
+    #[cfg(feature = "metrics")]
+    metrics: crate::metrics::BranchHandles,
+}
+
+impl BranchCounters {
+    fn new(label: Arc<str>) -> Self {
+        BranchCounters {
+            #[cfg(feature = "metrics")]
+            metrics: crate::metrics::BranchHandles::new(&label),
+            label,
+            allowed: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    fn record<T>(&self, output: &PolicyOutput<T, GovernorError>) {
+        let (counter, decision) = match output {
+            PolicyOutput::Ready(_) => (&self.allowed, "allowed"),
+            PolicyOutput::Abort(GovernorError::RateLimited(_)) => (&self.denied, "denied"),
+            PolicyOutput::Abort(_) => (&self.errors, "error"),
+            PolicyOutput::Retry => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.record(decision);
+        #[cfg(feature = "otel")]
+        crate::otel::record_branch(&self.label);
+        tracing::trace!(branch = %self.label, decision, "Rate limit decision");
+    }
+
+    fn snapshot(&self) -> BranchDecisions {
+        BranchDecisions {
+            label: self.label.to_string(),
+            allowed: self.allowed.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Policy tagging the decisions of a wrapped policy with a branch label
+pub struct LabeledPolicy<P> {
+    inner: P,
+    counters: Arc<BranchCounters>,
+}
+
+impl<P> LabeledPolicy<P> {
+    /// Tag the decisions of `inner` with `label`
+    pub fn new(label: impl Into<Arc<str>>, inner: P) -> Self {
+        LabeledPolicy {
+            inner,
+            counters: Arc::new(BranchCounters::new(label.into())),
+        }
+    }
+
+    /// Label of the branch
+    pub fn label(&self) -> &str {
+        &self.counters.label
+    }
+
+    /// The wrapped policy
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Decisions of this branch so far
+    pub fn decisions(&self) -> BranchDecisions {
+        self.counters.snapshot()
+    }
+}
+
+impl<P: Clone> Clone for LabeledPolicy<P> {
+    fn clone(&self) -> Self {
+        LabeledPolicy {
+            inner: self.inner.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+impl<P: fmt::Debug> fmt::Debug for LabeledPolicy<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LabeledPolicy")
+            .field("label", &self.label())
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<P, State, Request> Policy<State, Request> for LabeledPolicy<P>
+where
+    P: Policy<State, Request, Guard = GovernorGuard, Error = GovernorError>,
+    State: Clone + Send + Sync + 'static,
+    Request: Send + Sync + 'static,
+{
+    type Guard = GovernorGuard;
+    type Error = GovernorError;
+
+    async fn check(
+        &self,
+        ctx: Context<State>,
+        request: Request,
+    ) -> PolicyResult<State, Request, Self::Guard, Self::Error> {
+        let result = self.inner.check(ctx, request).await;
+        self.counters.record(&result.output);
+        result
+    }
+}
+
+/// Decision counts of all branches labeled through it
+///
+/// Clones share the counts. Labeling two policies with the same label counts
+/// them as one branch.
+#[derive(Clone, Default)]
+pub struct DecisionBreakdown {
+    branches: Arc<DashMap<Arc<str>, Arc<BranchCounters>>>,
+}
+
+impl DecisionBreakdown {
+    /// Create a breakdown without branches
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tag the decisions of `policy` with `label`, counting them in this breakdown
+    pub fn label<P>(&self, label: impl Into<Arc<str>>, policy: P) -> LabeledPolicy<P> {
+        let label = label.into();
+        let counters = self
+            .branches
+            .entry(label.clone())
+            .or_insert_with(|| Arc::new(BranchCounters::new(label)))
+            .clone();
+        LabeledPolicy {
+            inner: policy,
+            counters,
+        }
+    }
+
+    /// Decisions of every branch so far, ordered by label
+    pub fn snapshot(&self) -> Vec<BranchDecisions> {
+        let mut branches: Vec<_> = self
+            .branches
+            .iter()
+            .map(|branch| branch.snapshot())
+            .collect();
+        branches.sort_by(|a, b| a.label.cmp(&b.label));
+        branches
+    }
+}
+
+impl fmt::Debug for DecisionBreakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecisionBreakdown")
+            .field("branches", &self.branches.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_decision_breakdown() {
+        let breakdown = DecisionBreakdown::new();
+        let api = breakdown.label("api", GovernorPolicy::builder().per_minute(2).build());
+        let login = breakdown.label("login", GovernorPolicy::builder().per_minute(1).build());
+
+        for _ in 0..3 {
+            api.check(Context::default(), ()).await;
+            login.check(Context::default(), ()).await;
+        }
+
+        assert_eq!(api.decisions().allowed, 2);
+        let decisions: Vec<_> = breakdown
+            .snapshot()
+            .into_iter()
+            .map(|branch| (branch.label, branch.allowed, branch.denied))
+            .collect();
+        assert_eq!(
+            decisions,
+            [("api".to_owned(), 2, 1), ("login".to_owned(), 1, 2)]
+        );
+    }
+}
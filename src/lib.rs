@@ -3,62 +3,446 @@
 //! This crate provides a `GovernorPolicy` that can be used with Rama's `LimitLayer`
 //! for rate limiting HTTP requests or any other kind of request.
 
-use std::collections::HashSet;
 use std::fmt;
-use std::num::NonZeroU32;
-use std::sync::{Arc, Mutex};
+use std::num::{NonZeroU32, NonZeroU64};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
-use governor::{DefaultDirectRateLimiter, DefaultKeyedRateLimiter, Quota};
-use once_cell::sync::{Lazy, OnceCell};
+use arc_swap::ArcSwap;
 use rama_core::Context;
 use rama_core::layer::limit::policy::{Policy, PolicyOutput, PolicyResult};
+use rama_http::HeaderName;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use thiserror::Error;
 
+mod accounting;
+#[cfg(feature = "http")]
+mod admin;
+mod audit;
+mod bank;
+mod body_cost;
+#[cfg(feature = "body-hash")]
+mod body_hash;
+mod bypass;
+mod chaos;
+mod classify;
+mod clock;
+#[cfg(feature = "tokio")]
+mod coalesce;
+mod commands;
+mod config;
+mod datagram;
+mod decision_cache;
+mod drain;
+mod dsl;
+mod env;
+#[cfg(feature = "envoy-rls")]
+mod envoy_rls;
+mod exempt;
+mod explain;
+mod extract;
+mod failover;
+mod fixed_window;
+#[cfg(fuzzing)]
+pub mod fuzz;
+mod geo;
+#[cfg(feature = "gossip")]
+mod gossip;
+mod guard;
+mod handle;
+mod hierarchy;
+mod hybrid;
+mod idempotency;
+mod import;
+mod ip_key;
+mod key_stats;
+mod keyed;
+mod labeled;
+mod limiter;
+mod local;
+#[cfg(feature = "memcached")]
+mod memcached_store;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod namespace;
+mod normalize;
+#[cfg(feature = "otel")]
+mod otel;
+mod pacing;
+mod per_core;
+mod persist;
+mod plan;
+#[cfg(feature = "postgres")]
+mod postgres_store;
+mod prefilter;
+pub mod presets;
+mod pressure;
+mod propagate;
+mod quota;
+#[cfg(feature = "redis")]
+mod redis_store;
+mod registry;
+mod rejection;
+mod runtime;
+mod sampling;
+mod schedule;
+mod score;
+mod select;
+mod self_check;
+mod sharded;
+#[cfg(feature = "shm")]
+mod shm_store;
+#[cfg(feature = "tokio")]
+mod slow_read;
+mod snapshot;
+mod stable_hash;
+mod stale;
+mod status;
+mod store;
+mod sync;
+mod telemetry;
+pub mod testing;
+mod tls_key;
+#[cfg(feature = "tokio")]
+mod tunnel;
+mod ua_key;
+mod upstream;
+mod upstream_backoff;
+mod version;
+mod warmup;
+pub use accounting::{DIRECT_USAGE_KEY, FileUsageSink, UsageRecord, UsageSink};
+#[cfg(feature = "http")]
+pub use admin::{GovernorAdminService, governor_admin_service};
+pub use audit::{AuditEvent, AuditRecord, AuditSink, DenyReason, OverrideAction, TracingAuditSink};
+#[cfg(feature = "body-hash")]
+pub use body_hash::{BodyHash, BodyHashKey, BodyHashLayer, BodyHashService};
+pub use bypass::BYPASS_TOKEN;
+pub use classify::{ClassSelector, ClientClass, ClientClassifier, UserAgentClassifier};
+#[cfg(feature = "tokio")]
+pub use coalesce::{CoalesceLayer, CoalesceService};
+pub use commands::{CommandLimiter, ConnectionCommands};
+pub use config::{
+    ConfigPolicy, Diagnostic, DiagnosticKind, GovernorConfig, NamespaceConfig, QuotaConfig,
+    RuleConfig, Severity,
+};
+pub use datagram::{Datagram, DatagramPolicy};
+pub use dsl::{KeySource, PolicyParseError, PolicySpec};
+pub use env::EnvConfigError;
+#[cfg(feature = "envoy-rls")]
+pub use envoy_rls::{RlsService, RlsStore};
+pub use explain::{Explanation, Verdict};
+pub use extract::{
+    ClientIpKey, ExtensionKey, ExtractingPolicy, HeaderKey, KeyExtractor, X_API_KEY,
+};
+pub use failover::{BackendHealth, FailoverStore, StoreFallback};
+pub use fixed_window::Algorithm;
+pub use geo::{AsnKey, CountryKey, GeoInfo, GeoResolver, GeoSelector};
+#[cfg(feature = "gossip")]
+pub use gossip::GossipConfig;
+pub use governor::Quota;
+pub use guard::{GovernorGuard, RateLimitInfo};
+pub use handle::{AcquireError, GovernorHandle};
+pub use hierarchy::HierarchicalPolicy;
+pub use hybrid::HybridStore;
+pub use idempotency::{IDEMPOTENCY_KEY, idempotency_policy};
+pub use import::{
+    EnvoyDescriptor, EnvoyImport, EnvoyRateLimit, EnvoyRateLimitConfig, EnvoyUnit, ImportError,
+};
+pub use key_stats::{KeyStats, MinuteStats};
+pub use keyed::KeyHasher;
+pub use labeled::{BranchDecisions, DecisionBreakdown, LabeledPolicy};
+pub use limiter::Limiter;
+pub use local::LocalGovernorPolicy;
+#[cfg(feature = "memcached")]
+pub use memcached_store::MemcachedStore;
+pub use namespace::{NamespaceQuotas, NamespacedPolicy, TemporaryOverride};
+pub use normalize::{KeyNormalizer, Normalized};
+pub use per_core::PerCoreStore;
+pub use plan::{ChangeKind, ConfigPlan, RuleChange};
+#[cfg(feature = "postgres")]
+pub use postgres_store::PostgresStore;
+pub use pressure::{Pressure, PressureExporter, PressureSink};
+pub use propagate::{
+    PropagateRateLimitLayer, PropagateRateLimitService, X_RATELIMIT_CLIENT_KEY, X_RATELIMIT_LIMIT,
+    X_RATELIMIT_REMAINING,
+};
+pub use quota::QuotaExt;
+#[cfg(feature = "redis")]
+pub use redis_store::RedisStore;
+pub use registry::PolicyRegistry;
+pub use rejection::{RateLimitExceeded, Rejected, RejectionResponse, RetryAfter};
+#[cfg(feature = "tokio")]
+pub use runtime::{GracefulRuntime, TokioRuntime};
+pub use runtime::{Runtime, RuntimeFuture};
+pub use sampling::DenialLogSampling;
+pub use schedule::{CronError, CronExpr};
+pub use score::{ScoreProvider, ScoreSelector, ScoreTier};
+pub use select::{PolicySelector, SelectingPolicy};
+pub use self_check::{SelfCheckItem, SelfCheckKind, SelfCheckOutcome, SelfCheckReport};
+pub use sharded::{ShardedStore, SweepBudget};
+#[cfg(feature = "shm")]
+pub use shm_store::SharedMemoryStore;
+#[cfg(feature = "tokio")]
+pub use slow_read::{HeadReadLayer, HeadReadService, HeadReadStream};
+pub use snapshot::{SnapshotEntry, SnapshotError, StateSnapshot};
+pub use stale::{StaleResponseLayer, StaleResponseService};
+#[cfg(feature = "http")]
+pub use status::{GovernorStatusService, governor_status_service};
+pub use status::{PolicyKind, PolicyStatus};
+pub use store::{FailureMode, RateLimitStore, StoreDecision, StoreError, StoreFuture};
+pub use tls_key::{TlsFingerprint, TlsFingerprintKey};
+#[cfg(feature = "tokio")]
+pub use tunnel::{ANONYMOUS_USER, ThrottledStream, Tunnel, TunnelPolicy};
+pub use ua_key::UserAgentFingerprintKey;
+pub use upstream::{Upstream, UpstreamKey, upstream_policy};
+pub use upstream_backoff::{UpstreamBackoffLayer, UpstreamBackoffService};
+pub use version::{VersionSelector, VersionSource};
+
+use accounting::Accounting;
+use audit::Auditor;
+use bank::BurstBank;
+use body_cost::BodyCost;
+use bypass::BypassToken;
+use clock::DirectLimiter;
+use decision_cache::DecisionCache;
+use exempt::Exemptions;
+use fixed_window::FixedWindowStore;
+#[cfg(feature = "gossip")]
+use gossip::Gossip;
+use handle::{Control, KeyedTarget, StoreTarget};
+use key_stats::KeyStatsStore;
+use keyed::KeyedLimiter;
+use pacing::Pacer;
+use persist::{PersistConfig, Persister};
+use prefilter::{KeyPrefilter, Sighting};
+use schedule::Schedule;
+use snapshot::KeyCodec;
+use sync::StartOnce;
+use telemetry::{DEFAULT_POLICY_NAME, Telemetry};
+use warmup::Warmup;
+
 /// Error returned when rate limit is exceeded
 #[derive(Debug, Error)]
 pub enum GovernorError {
     /// Rate limit has been exceeded
     #[error("rate limit exceeded")]
-    RateLimited,
+    RateLimited(Rejected),
+    /// The rate limit store failed and the policy fails closed
+    #[error(transparent)]
+    StoreUnavailable(#[from] StoreError),
 }
 
-/// A policy that uses the governor crate for rate limiting
+impl GovernorError {
+    /// HTTP response for this error
+    ///
+    /// Rejections use the [`RejectionResponse`] of the rejecting policy, an
+    /// unavailable store answers 503.
+    pub fn into_response(self) -> rama_http::Response {
+        match self {
+            GovernorError::RateLimited(rejected) => rejected.to_response(),
+            GovernorError::StoreUnavailable(_) => {
+                let mut response = rama_http::Response::new(rama_http::Body::empty());
+                *response.status_mut() = rama_http::StatusCode::SERVICE_UNAVAILABLE;
+                response
+            }
+        }
+    }
+}
+
+/// Bounds required of the key type of a keyed policy
+///
+/// Exporting and importing state through a [`StateSnapshot`] additionally
+/// needs keys that serialize with serde, see
+/// [`build_with_serde_keyer`](GovernorPolicyBuilder::build_with_serde_keyer).
+pub trait GovernorKey: Clone + Eq + std::hash::Hash + Send + Sync + 'static {}
 
+impl<K> GovernorKey for K where K: Clone + Eq + std::hash::Hash + Send + Sync + 'static {}
+
+/// A policy that uses the governor crate for rate limiting
 pub enum GovernorPolicy {
     /// Direct rate limiter (single global state)
     Direct(DirectPolicy),
     /// Keyed rate limiter (one state per key)
     Keyed(Box<dyn AnyKeyedPolicy + Send + Sync>),
+    /// Rate limiter with its state in a [`RateLimitStore`]
+    Store(StorePolicy),
 }
 
 /// Direct rate limiter policy
 pub struct DirectPolicy {
-    limiter: Arc<DefaultDirectRateLimiter>,
+    limiter: Arc<ArcSwap<DirectLimiter>>,
     gc_interval: Duration,
+    schedule: Option<Arc<Schedule>>,
+    scheduler_started: StartOnce,
+    handle: GovernorHandle,
+    telemetry: Telemetry,
+    accounting: Option<Arc<Accounting>>,
+    pacer: Option<Pacer>,
+    bank: Option<BurstBank>,
+    runtime: Option<Arc<dyn Runtime>>,
+}
+
+impl DirectPolicy {
+    #[inline]
+    fn check(&self, cost: NonZeroU32) -> Result<Option<RateLimitInfo>, GovernorError> {
+        let limiter = self.limiter.load();
+        let snapshot = match limiter.check_n(cost.min(limiter.quota().burst_size())) {
+            Ok(Ok(snapshot)) => snapshot,
+            Ok(Err(not_until)) => {
+                return Err(self.telemetry.rejected(Some(clock::wait_time(&not_until))));
+            }
+            Err(_) => return Err(self.telemetry.rejected(None)),
+        };
+        self.telemetry.allowed();
+        if let Some(accounting) = &self.accounting {
+            accounting.record(DIRECT_USAGE_KEY);
+        }
+        Ok(Some(RateLimitInfo::from_snapshot(&snapshot)))
+    }
+
+    fn start_tasks_if_needed(&self) {
+        let Some(runtime) = &self.runtime else {
+            return;
+        };
+        if let Some(accounting) = &self.accounting {
+            accounting.start_if_needed(runtime);
+        }
+        if let Some(schedule) = &self.schedule {
+            self.scheduler_started.call(|| {
+                schedule::spawn_scheduler(
+                    runtime,
+                    schedule.clone(),
+                    Arc::downgrade(&self.limiter),
+                    |_, quota| DirectLimiter::new(quota),
+                );
+            });
+        }
+    }
+}
+
+/// Rate limiter policy backed by a [`RateLimitStore`]
+pub struct StorePolicy {
+    store: Arc<dyn RateLimitStore>,
+    quota: Arc<ArcSwap<Quota>>,
+    failure_mode: FailureMode,
+    timeout: Option<Duration>,
+    handle: GovernorHandle,
+    telemetry: Telemetry,
+    accounting: Option<Arc<Accounting>>,
+    pacer: Option<Pacer>,
+    runtime: Option<Arc<dyn Runtime>>,
+}
+
+impl StorePolicy {
+    /// Stores don't report the remaining cells, so there is never any [`RateLimitInfo`]
+    async fn check_key(
+        &self,
+        key: &str,
+        cost: NonZeroU32,
+    ) -> Result<Option<RateLimitInfo>, GovernorError> {
+        let quota = **self.quota.load();
+        let check = self
+            .store
+            .check_n(key, quota, cost.min(quota.burst_size()).get());
+        let result = match (self.timeout, &self.runtime) {
+            (Some(timeout), Some(runtime)) => runtime::timeout(&**runtime, timeout, check)
+                .await
+                .unwrap_or_else(|| {
+                    self.telemetry.store_timeout();
+                    Err(StoreError::timeout(timeout))
+                }),
+            _ => check.await,
+        };
+        match result {
+            Ok(StoreDecision::Allowed) => {}
+            Ok(StoreDecision::Denied { retry_after }) => {
+                return Err(self.telemetry.rejected(Some(retry_after)));
+            }
+            Err(err) => match self.failure_mode {
+                FailureMode::Open => {
+                    tracing::warn!("Rate limit store failed, allowing request: {}", err);
+                }
+                FailureMode::Closed => {
+                    tracing::warn!("Rate limit store failed, rejecting request: {}", err);
+                    self.telemetry.denied(None);
+                    return Err(err.into());
+                }
+            },
+        }
+        self.telemetry.allowed();
+        if let Some(accounting) = &self.accounting {
+            accounting.record(key);
+        }
+        Ok(None)
+    }
 }
 
 /// Trait to erase the generic types from KeyedPolicy
 pub trait AnyKeyedPolicy: fmt::Debug {
-    fn check_key(&self, key_str: &str) -> Result<(), ()>;
-    fn start_gc_if_needed(&self);
+    /// Check `key_str`, returning the state of its quota afterwards
+    fn check_key(
+        &self,
+        key_str: &str,
+        cost: NonZeroU32,
+    ) -> Result<Option<RateLimitInfo>, GovernorError>;
+    /// How far the state of `key_str` lies ahead of a fully replenished bucket
+    fn backlog(&self, key_str: &str) -> Duration;
+    fn start_tasks_if_needed(&self);
+    fn runtime(&self) -> Option<&Arc<dyn Runtime>>;
+    fn gc(&self);
     fn gc_interval(&self) -> Duration;
+    fn handle(&self) -> &GovernorHandle;
+    fn telemetry(&self) -> &Telemetry;
+    fn pacer(&self) -> Option<&Pacer>;
+    fn bank(&self) -> Option<&BurstBank>;
+    fn quota(&self) -> Quota;
+    fn tracked_keys(&self) -> usize;
+    fn export_state(&self) -> Result<StateSnapshot, SnapshotError>;
+    fn import_state(&self, snapshot: &StateSnapshot) -> Result<usize, SnapshotError>;
 }
 
 /// Keyed rate limiter policy
 pub struct KeyedPolicy<K, F>
 where
-    K: Clone + Eq + std::hash::Hash + Send + Sync + 'static,
+    K: GovernorKey,
     F: Fn(&str) -> K + Send + Sync + 'static,
 {
-    limiter: Arc<DefaultKeyedRateLimiter<K>>,
-    key_fn: F,
+    limiter: Arc<ArcSwap<KeyedLimiter<K>>>,
+    key_fn: Arc<F>,
     gc_interval: Duration,
+    gc_started: StartOnce,
+    schedule: Option<Arc<Schedule>>,
+    scheduler_started: StartOnce,
+    handle: GovernorHandle,
+    telemetry: Telemetry,
+    accounting: Option<Arc<Accounting>>,
+    persister: Option<Arc<Persister<K>>>,
+    #[cfg(feature = "gossip")]
+    gossip: Option<Arc<Gossip<K>>>,
+    pacer: Option<Pacer>,
+    bank: Option<BurstBank>,
+    prefilter: Option<KeyPrefilter>,
+    codec: Option<KeyCodec<K>>,
+    runtime: Option<Arc<dyn Runtime>>,
+}
+
+impl<K, F> Drop for KeyedPolicy<K, F>
+where
+    K: GovernorKey,
+    F: Fn(&str) -> K + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        if let Some(persister) = &self.persister {
+            persister.save(&self.limiter.load());
+        }
+    }
 }
 
 impl<K, F> fmt::Debug for KeyedPolicy<K, F>
 where
-    K: Clone + Eq + std::hash::Hash + Send + Sync + 'static,
+    K: GovernorKey,
     F: Fn(&str) -> K + Send + Sync + 'static,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -70,21 +454,129 @@ where
 
 impl<K, F> AnyKeyedPolicy for KeyedPolicy<K, F>
 where
-    K: Clone + Eq + std::hash::Hash + Send + Sync + 'static,
+    K: GovernorKey,
     F: Fn(&str) -> K + Send + Sync + 'static,
 {
-    fn check_key(&self, key_str: &str) -> Result<(), ()> {
+    fn check_key(
+        &self,
+        key_str: &str,
+        cost: NonZeroU32,
+    ) -> Result<Option<RateLimitInfo>, GovernorError> {
         let key = (self.key_fn)(key_str);
-        self.limiter.check_key(&key).map_err(|_| ())
+        let limiter = self.limiter.load();
+        let sighting = self
+            .prefilter
+            .as_ref()
+            .map(|prefilter| prefilter.observe(key_str, cost, limiter.quota()));
+        let info = match sighting {
+            Some(Sighting::Untracked(used)) => RateLimitInfo::used(limiter.quota(), used),
+            sighting => {
+                if let Some(Sighting::Track(earlier @ 1..)) = sighting {
+                    limiter.charge(&key, earlier);
+                }
+                match limiter.check_key_n(&key, cost.min(limiter.quota().burst_size())) {
+                    Ok(Ok(snapshot)) => RateLimitInfo::from_snapshot(&snapshot),
+                    Ok(Err(not_until)) => {
+                        return Err(self.telemetry.rejected(Some(clock::wait_time(&not_until))));
+                    }
+                    Err(_) => return Err(self.telemetry.rejected(None)),
+                }
+            }
+        };
+        self.telemetry.allowed();
+        if let Some(accounting) = &self.accounting {
+            accounting.record(key_str);
+        }
+        #[cfg(feature = "gossip")]
+        if let Some(gossip) = &self.gossip {
+            gossip.record(&key);
+        }
+        Ok(Some(info))
+    }
+
+    fn backlog(&self, key_str: &str) -> Duration {
+        self.limiter.load().backlog(&(self.key_fn)(key_str))
+    }
+
+    fn start_tasks_if_needed(&self) {
+        let Some(runtime) = &self.runtime else {
+            return;
+        };
+        self.gc_started.call(|| {
+            keyed::spawn_gc(
+                runtime,
+                Arc::downgrade(&self.limiter),
+                self.gc_interval,
+                self.telemetry.tracked_keys_gauge(),
+            );
+        });
+        if let Some(accounting) = &self.accounting {
+            accounting.start_if_needed(runtime);
+        }
+        if let Some(persister) = &self.persister {
+            persister.start_if_needed(runtime, Arc::downgrade(&self.limiter));
+        }
+        #[cfg(feature = "gossip")]
+        if let Some(gossip) = &self.gossip {
+            gossip.start_if_needed(runtime, Arc::downgrade(&self.limiter));
+        }
+        if let Some(schedule) = &self.schedule {
+            self.scheduler_started.call(|| {
+                schedule::spawn_scheduler(
+                    runtime,
+                    schedule.clone(),
+                    Arc::downgrade(&self.limiter),
+                    KeyedLimiter::<K>::with_quota,
+                );
+            });
+        }
+    }
+
+    fn runtime(&self) -> Option<&Arc<dyn Runtime>> {
+        self.runtime.as_ref()
     }
 
-    fn start_gc_if_needed(&self) {
-        // GC implementation here
+    fn gc(&self) {
+        self.limiter.load().retain_recent();
     }
 
     fn gc_interval(&self) -> Duration {
         self.gc_interval
     }
+
+    fn handle(&self) -> &GovernorHandle {
+        &self.handle
+    }
+
+    fn telemetry(&self) -> &Telemetry {
+        &self.telemetry
+    }
+
+    fn pacer(&self) -> Option<&Pacer> {
+        self.pacer.as_ref()
+    }
+
+    fn bank(&self) -> Option<&BurstBank> {
+        self.bank.as_ref()
+    }
+
+    fn quota(&self) -> Quota {
+        self.limiter.load().quota()
+    }
+
+    fn tracked_keys(&self) -> usize {
+        self.limiter.load().len()
+    }
+
+    fn export_state(&self) -> Result<StateSnapshot, SnapshotError> {
+        let codec = self.codec.ok_or(SnapshotError::NotSerializable)?;
+        snapshot::export(&self.limiter.load(), codec)
+    }
+
+    fn import_state(&self, snapshot: &StateSnapshot) -> Result<usize, SnapshotError> {
+        let codec = self.codec.ok_or(SnapshotError::NotSerializable)?;
+        snapshot::import(&self.limiter.load(), codec, snapshot)
+    }
 }
 
 impl fmt::Debug for GovernorPolicy {
@@ -98,6 +590,11 @@ impl fmt::Debug for GovernorPolicy {
                 .debug_struct("GovernorPolicy::Keyed")
                 .field("policy", policy)
                 .finish(),
+            Self::Store(policy) => f
+                .debug_struct("GovernorPolicy::Store")
+                .field("quota", &**policy.quota.load())
+                .field("failure_mode", &policy.failure_mode)
+                .finish(),
         }
     }
 }
@@ -110,6 +607,31 @@ pub struct Initialized;
 pub struct GovernorPolicyBuilder {
     quota: Option<Quota>,
     gc_interval: Duration,
+    schedule: Vec<(CronExpr, Quota)>,
+    accounting: Option<Accounting>,
+    persister: Option<PersistConfig>,
+    #[cfg(feature = "gossip")]
+    gossip: Option<GossipConfig>,
+    failure_mode: FailureMode,
+    store_timeout: Option<Duration>,
+    runtime: Option<Arc<dyn Runtime>>,
+    name: Option<String>,
+    denial_log_sampling: DenialLogSampling,
+    rejection: RejectionResponse,
+    mirror: bool,
+    algorithm: Algorithm,
+    pacing: bool,
+    burst_bank: Option<u32>,
+    audit: Option<Arc<dyn AuditSink>>,
+    decision_cache: Option<Duration>,
+    prefilter: Option<u8>,
+    warmup: Option<Duration>,
+    health_checks: Option<Vec<String>>,
+    exemptions: Exemptions,
+    bypass_token: Option<Arc<BypassToken>>,
+    body_cost: Option<BodyCost>,
+    key_hasher: KeyHasher,
+    key_stats: Option<usize>,
 }
 
 impl Default for GovernorPolicyBuilder {
@@ -124,6 +646,31 @@ impl GovernorPolicyBuilder {
         GovernorPolicyBuilder {
             quota: None,
             gc_interval: Duration::from_secs(60), // Default GC interval
+            schedule: Vec::new(),
+            accounting: None,
+            persister: None,
+            #[cfg(feature = "gossip")]
+            gossip: None,
+            failure_mode: FailureMode::default(),
+            store_timeout: None,
+            runtime: runtime::default_runtime(),
+            name: None,
+            denial_log_sampling: DenialLogSampling::default(),
+            rejection: RejectionResponse::default(),
+            mirror: false,
+            algorithm: Algorithm::default(),
+            pacing: false,
+            burst_bank: None,
+            audit: None,
+            decision_cache: None,
+            prefilter: None,
+            warmup: None,
+            health_checks: None,
+            exemptions: Exemptions::default(),
+            bypass_token: None,
+            body_cost: None,
+            key_hasher: KeyHasher::default(),
+            key_stats: None,
         }
     }
 
@@ -135,7 +682,7 @@ impl GovernorPolicyBuilder {
             quota: Some(Quota::per_second(
                 NonZeroU32::new(count).expect("Rate limit count must be non-zero"),
             )),
-            gc_interval: self.gc_interval,
+            ..self
         }
     }
 
@@ -147,7 +694,15 @@ impl GovernorPolicyBuilder {
             quota: Some(Quota::per_minute(
                 NonZeroU32::new(count).expect("Rate limit count must be non-zero"),
             )),
-            gc_interval: self.gc_interval,
+            ..self
+        }
+    }
+
+    /// Use `quota` as is, e.g. for periods other than a second or a minute
+    pub fn quota(self, quota: Quota) -> GovernorPolicyBuilder {
+        GovernorPolicyBuilder {
+            quota: Some(quota),
+            ..self
         }
     }
 }
@@ -168,89 +723,751 @@ impl GovernorPolicyBuilder {
         self
     }
 
-    /// Build the GovernorPolicy with a direct (non-keyed) rate limiter
-    pub fn build(self) -> GovernorPolicy {
+    /// Switch between quotas on a schedule
+    ///
+    /// Each entry pairs a five field cron expression (evaluated in UTC) with the
+    /// quota that becomes active whenever the expression fires, e.g.
+    /// `[("0 9 * * 1-5", business_hours), ("0 18 * * *", off_peak)]`.
+    /// The quota set through `per_second`/`per_minute` applies until the first
+    /// entry fires. Switching quotas starts from a fresh limiter state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the cron expressions is invalid.
+    pub fn schedule<I, S>(mut self, entries: I) -> Self
+    where
+        I: IntoIterator<Item = (S, Quota)>,
+        S: AsRef<str>,
+    {
+        self.schedule = entries
+            .into_iter()
+            .map(|(expr, quota)| {
+                let expr = expr.as_ref();
+                let cron = expr
+                    .parse()
+                    .unwrap_or_else(|err| panic!("invalid cron expression {expr:?}: {err}"));
+                (cron, quota)
+            })
+            .collect();
+        self
+    }
+
+    /// Aggregate accepted requests per key and flush them to `sink` every `interval`
+    ///
+    /// Useful to feed metering or billing pipelines from the same place that
+    /// enforces the quota. Requests of a direct policy are accounted under
+    /// [`DIRECT_USAGE_KEY`]. Counts of the last, partial interval are flushed
+    /// when the policy is dropped.
+    pub fn usage_accounting(mut self, interval: Duration, sink: impl UsageSink) -> Self {
+        self.accounting = Some(Accounting::new(interval, Box::new(sink)));
+        self
+    }
+
+    /// Persist the state of a keyed policy to `path` every `interval`
+    ///
+    /// State found at `path` is restored when the policy is built, and written
+    /// one last time when the policy is dropped. This gives single-node
+    /// deployments continuity across restarts without a shared backend.
+    /// Needs keys that serialize, see
+    /// [`build_with_serde_keyer`](Self::build_with_serde_keyer); ignored for
+    /// direct policies.
+    pub fn persist_state(mut self, path: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.persister = Some(PersistConfig {
+            path: path.into(),
+            interval,
+        });
+        self
+    }
+
+    /// Sync per-key consumption with peer nodes over UDP
+    ///
+    /// Each node charges the usage reported by its peers against its own
+    /// limiter, approximating a cluster-wide limit without Redis. Needs keys
+    /// that serialize, see [`build_with_serde_keyer`](Self::build_with_serde_keyer);
+    /// ignored for direct policies.
+    #[cfg(feature = "gossip")]
+    pub fn gossip(mut self, config: GossipConfig) -> Self {
+        self.gossip = Some(config);
+        self
+    }
+
+    /// What a store backed policy does when its store fails (default: fail open)
+    pub fn failure_mode(mut self, mode: FailureMode) -> Self {
+        self.failure_mode = mode;
+        self
+    }
+
+    /// Give up on store calls taking longer than `timeout`
+    ///
+    /// A timed out call is handled like a store failure, according to the
+    /// [`failure_mode`](Self::failure_mode), and counted in the
+    /// `governor_store_timeouts_total` metric. Needs a [`Runtime`] for its
+    /// timer. Only applies to store backed policies.
+    pub fn store_timeout(mut self, timeout: Duration) -> Self {
+        self.store_timeout = Some(timeout);
+        self
+    }
+
+    /// Name of the policy, reported with its metrics and trace attributes
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Log only a sample of denials, with a periodic summary of the counts
+    ///
+    /// Keeps the denial log line from flooding the logs under attack.
+    pub fn denial_log_sampling(mut self, sampling: DenialLogSampling) -> Self {
+        self.denial_log_sampling = sampling;
+        self
+    }
+
+    /// Status, headers and body of the response for rejected requests
+    ///
+    /// See [`GovernorError::into_response`].
+    pub fn rejection_response(mut self, response: RejectionResponse) -> Self {
+        self.rejection = response;
+        self
+    }
+
+    /// Let over-limit requests through, tagged with a [`RateLimitExceeded`]
+    /// context extension instead of rejecting them
+    ///
+    /// Can be toggled at runtime through [`GovernorHandle::set_mirror`].
+    pub fn mirror(mut self, mirror: bool) -> Self {
+        self.mirror = mirror;
+        self
+    }
+
+    /// Algorithm counting the requests (default: GCRA)
+    ///
+    /// With [`Algorithm::FixedWindow`] the policy keeps its counters in an
+    /// in-memory store, so schedules and state persistence don't apply.
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Release allowed requests at the steady rate of the quota
+    ///
+    /// Requests the quota admits in a burst are queued and released one per
+    /// replenish interval and key, for upstreams that can't absorb bursts.
+    /// Waits on the [`Runtime`] of the policy.
+    pub fn pacing(mut self, pacing: bool) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Bank unused capacity, up to `cap` cells, for requests over the quota
+    ///
+    /// Capacity lost while the bucket of a key is full accrues as credits,
+    /// which pay for requests the quota would reject, so a mostly idle client
+    /// can occasionally exceed its burst. Denials paid from the bank still
+    /// count as denied in metrics. Only applies to in-memory GCRA policies.
+    pub fn burst_bank(mut self, cap: u32) -> Self {
+        self.burst_bank = Some(cap);
+        self
+    }
+
+    /// Report every rejected request and every change made through the
+    /// [`GovernorHandle`] to `sink`, see [`AuditSink`]
+    pub fn audit(mut self, sink: impl AuditSink) -> Self {
+        self.audit = Some(Arc::new(sink));
+        self
+    }
+
+    /// Reject keys limited for longer than `min_retry_after` right away until
+    /// their retry time has passed, without checking the limiter or store
+    ///
+    /// Cuts backend load and contention when limited keys keep hammering the
+    /// policy. Cached rejections are dropped when the state of a key is reset
+    /// or the quota changes through the [`GovernorHandle`].
+    pub fn decision_cache(mut self, min_retry_after: Duration) -> Self {
+        self.decision_cache = Some(min_retry_after);
+        self
+    }
+
+    /// Allocate limiter state only for keys seen more than `threshold` times,
+    /// counting sightings of new keys in a fixed-size sketch until then
+    ///
+    /// Saves memory churn from one-hit keys, e.g. scanners, on keyed policies
+    /// with generous quotas. Requests made before a key is tracked are charged
+    /// to it once it is, at their cost, so `threshold` is capped below the
+    /// burst size of the current quota. Only applies to in-memory keyed
+    /// policies.
+    pub fn prefilter(mut self, threshold: u8) -> Self {
+        self.prefilter = Some(threshold);
+        self
+    }
+
+    /// Build the key pre-filter for `quota`, if one is configured
+    fn key_prefilter(&self, quota: Quota) -> Option<KeyPrefilter> {
+        let threshold = self.prefilter?;
+        let max = quota.burst_size().get().saturating_sub(1);
+        if u32::from(threshold) > max {
+            tracing::warn!(
+                "Pre-filter threshold {} lowered to {} to stay within the burst size",
+                threshold,
+                max
+            );
+        }
+        // lowered again on every check, so a swapped quota gets its own
+        (threshold > 0).then(|| KeyPrefilter::new(threshold))
+    }
+
+    /// Start at a tenth of the quota and ramp up to the full quota in equal
+    /// steps over `period` once background tasks start
+    ///
+    /// Protects cold caches and upstreams from being hit at full rate the
+    /// instant a new instance joins the pool. Each step replaces the quota
+    /// like [`GovernorHandle::set_quota`], so it needs a runtime, and a quota
+    /// set by hand during the warm-up only lasts until the next step.
+    pub fn warmup(mut self, period: Duration) -> Self {
+        self.warmup = Some(period);
+        self
+    }
+
+    /// Paths of health checks let through while the policy is draining,
+    /// replacing `/health`, `/healthz`, `/livez` and `/readyz`
+    ///
+    /// See [`GovernorHandle::drain`]. Paths are matched exactly.
+    pub fn health_check_paths<I, S>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.health_checks = Some(paths.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Let requests for paths starting with any of `prefixes` through
+    /// without counting them, e.g. `["/healthz", "/metrics"]`
+    ///
+    /// Only applies to HTTP requests with rama's default body type. Adds to
+    /// the prefixes of earlier calls.
+    pub fn exempt_paths<I, S>(mut self, prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.exemptions
+            .paths
+            .extend(prefixes.into_iter().map(Into::into));
+        self
+    }
+
+    /// Let requests carrying the `name` header with `value` through without
+    /// counting them, e.g. internal probes
+    ///
+    /// Only applies to HTTP requests with rama's default body type. Anyone
+    /// who knows the value can skip the limits, so strip the header from
+    /// untrusted traffic at the edge.
+    pub fn exempt_header(mut self, name: HeaderName, value: impl Into<String>) -> Self {
+        self.exemptions.headers.push((name, value.into()));
+        self
+    }
+
+    /// Let HTTP requests carrying `secret` in the [`BYPASS_TOKEN`] header
+    /// skip all checks, including the denylist and lockdown
+    ///
+    /// A break-glass mechanism for internal tooling. The token is compared in
+    /// constant time and every bypass is reported to the [`audit`](Self::audit)
+    /// sink. Strip the header from untrusted traffic at the edge so it never
+    /// reaches logs or upstreams.
+    pub fn bypass_token(mut self, secret: impl Into<String>) -> Self {
+        self.bypass_token = Some(Arc::new(BypassToken::new(secret)));
+        self
+    }
+
+    /// Charge each request a cell per `bytes_per_token` of the body its
+    /// `Content-Length` declares, and at least one
+    ///
+    /// Limits the request count and the ingress bytes of a key in one check,
+    /// before the body is read. A request is charged at most a full burst, so
+    /// a large upload can still pass a key with a full bucket. Requests
+    /// without a declared length cost one cell, as do requests that aren't
+    /// HTTP requests with rama's default body type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_token` is zero.
+    pub fn max_body_cost(mut self, bytes_per_token: u64) -> Self {
+        let bytes_per_token =
+            NonZeroU64::new(bytes_per_token).expect("Bytes per token must be non-zero");
+        self.body_cost = Some(BodyCost::new(bytes_per_token));
+        self
+    }
+
+    /// Hash the keys of keyed policies with `hasher`, SipHash by default
+    ///
+    /// [`KeyHasher::FoldHash`] takes noticeably less time per check on short
+    /// keys at high rates. Quota changes keep the hasher.
+    pub fn key_hasher(mut self, hasher: KeyHasher) -> Self {
+        self.key_hasher = hasher;
+        self
+    }
+
+    /// Keep the decision history of up to `max_keys` keys, read through
+    /// [`GovernorHandle::key_stats`]
+    ///
+    /// Each tracked key costs a few kilobytes. Once `max_keys` are tracked,
+    /// the key seen least recently is forgotten for a new one.
+    pub fn key_stats(mut self, max_keys: usize) -> Self {
+        self.key_stats = Some(max_keys);
+        self
+    }
+
+    /// The warm-up towards `quota`, if one is configured and can run
+    fn warmup_from(&self, quota: Quota) -> Option<Warmup> {
+        let period = self.warmup?;
+        if self.runtime.is_none() {
+            tracing::warn!("Warm-up needs a runtime and is ignored");
+            return None;
+        }
+        Some(Warmup::new(quota, period))
+    }
+
+    /// Build the auditor reporting to the configured sink, if any
+    fn auditor(&self) -> Option<Arc<Auditor>> {
+        let name = self.name.as_deref().unwrap_or(DEFAULT_POLICY_NAME);
+        let sink = self.audit.clone()?;
+        Some(Arc::new(Auditor::new(name, sink)))
+    }
+
+    /// Build the pacer if pacing is enabled and there is a runtime to wait on
+    fn pacer(&self) -> Option<Pacer> {
+        if self.pacing && self.runtime.is_none() {
+            tracing::warn!("Pacing needs a runtime and is ignored");
+        }
+        (self.pacing && self.runtime.is_some()).then(Pacer::new)
+    }
+
+    /// Drive background tasks with `runtime` instead of tokio
+    ///
+    /// Required to run garbage collection, schedules, accounting and
+    /// persistence when the `tokio` feature is disabled.
+    pub fn runtime(mut self, runtime: impl Runtime) -> Self {
+        self.runtime = Some(Arc::new(runtime));
+        self
+    }
+
+    /// Resolve the schedule (if any) and the quota active right now
+    fn initial_quota(&mut self) -> (Quota, Option<Arc<Schedule>>) {
         let quota = self.quota.expect("Quota must be set");
-        let limiter = Arc::new(DefaultDirectRateLimiter::direct(quota));
+        if self.schedule.is_empty() {
+            return (quota, None);
+        }
+
+        let schedule = Schedule::new(std::mem::take(&mut self.schedule));
+        let quota = schedule
+            .active_at(schedule::now_unix_minute())
+            .map(|index| schedule.quota(index))
+            .unwrap_or(quota);
+        (quota, Some(Arc::new(schedule)))
+    }
+
+    /// Build the GovernorPolicy with a direct (non-keyed) rate limiter
+    pub fn build(mut self) -> GovernorPolicy {
+        if self.algorithm == Algorithm::FixedWindow {
+            return self.build_with_store(FixedWindowStore::new(|_| ()));
+        }
+        let (quota, schedule) = self.initial_quota();
+        let warmup = self.warmup_from(quota);
+        let quota = warmup.as_ref().map_or(quota, Warmup::initial_quota);
+        if self.persister.is_some() || self.prefilter.is_some() {
+            tracing::warn!(
+                "State persistence and pre-filters are only supported for keyed policies"
+            );
+        }
+        let limiter = Arc::new(ArcSwap::from_pointee(DirectLimiter::new(quota)));
+        let audit = self.auditor();
+        let handle = GovernorHandle::new(Arc::downgrade(&limiter), self.runtime.clone());
+        handle.set_mirror(self.mirror);
+        let handle = handle
+            .audited(audit.clone())
+            .cached(self.decision_cache.map(DecisionCache::new))
+            .warming_up(warmup)
+            .with_health_checks(self.health_checks.clone())
+            .exempting(self.exemptions.clone())
+            .bypassed_by(self.bypass_token.clone())
+            .costed(self.body_cost)
+            .with_key_stats(self.key_stats.map(KeyStatsStore::new));
 
+        let pacer = self.pacer();
+        let bank = self.burst_bank.map(BurstBank::new);
         GovernorPolicy::Direct(DirectPolicy {
             limiter,
             gc_interval: self.gc_interval,
+            schedule,
+            scheduler_started: StartOnce::new(),
+            handle,
+            telemetry: Telemetry::new(self.name, self.denial_log_sampling, self.rejection, audit),
+            accounting: self.accounting.map(Arc::new),
+            pacer,
+            bank,
+            runtime: self.runtime,
         })
+        .started()
     }
 
     /// Build the GovernorPolicy with a custom key function
+    ///
+    /// The state of the policy can't be exported, persisted or gossiped, as
+    /// that needs keys that serialize; see
+    /// [`build_with_serde_keyer`](Self::build_with_serde_keyer).
     pub fn build_with_keyer<K, F>(self, key_fn: F) -> GovernorPolicy
     where
-        K: Clone + Eq + std::hash::Hash + Send + Sync + 'static,
+        K: GovernorKey,
         F: Fn(&str) -> K + Send + Sync + 'static,
     {
-        let quota = self.quota.expect("Quota must be set");
-        let limiter = Arc::new(DefaultKeyedRateLimiter::keyed(quota));
+        self.build_keyed(key_fn, None)
+    }
+
+    /// Build the GovernorPolicy with a custom key function returning keys
+    /// that serialize with serde
+    ///
+    /// Like [`build_with_keyer`](Self::build_with_keyer), but the state of the
+    /// policy can also be exported and imported through
+    /// [`GovernorPolicy::export_state`], persisted with
+    /// [`persist_state`](Self::persist_state) and synced with peers.
+    pub fn build_with_serde_keyer<K, F>(self, key_fn: F) -> GovernorPolicy
+    where
+        K: GovernorKey + Serialize + DeserializeOwned,
+        F: Fn(&str) -> K + Send + Sync + 'static,
+    {
+        self.build_keyed(key_fn, Some(KeyCodec::serde()))
+    }
+
+    fn build_keyed<K, F>(mut self, key_fn: F, codec: Option<KeyCodec<K>>) -> GovernorPolicy
+    where
+        K: GovernorKey,
+        F: Fn(&str) -> K + Send + Sync + 'static,
+    {
+        if self.algorithm == Algorithm::FixedWindow {
+            return self.build_with_store(FixedWindowStore::new(key_fn));
+        }
+        let (quota, schedule) = self.initial_quota();
+        let warmup = self.warmup_from(quota);
+        let quota = warmup.as_ref().map_or(quota, Warmup::initial_quota);
+        let limiter = Arc::new(ArcSwap::from_pointee(KeyedLimiter::with_hasher(
+            quota,
+            self.key_hasher,
+        )));
+        let pacer = self.pacer();
+        let audit = self.auditor();
+        let bank = self.burst_bank.map(BurstBank::new);
+        let prefilter = self.key_prefilter(quota);
+        #[cfg(feature = "gossip")]
+        let gossip_configured = self.gossip.is_some();
+        #[cfg(not(feature = "gossip"))]
+        let gossip_configured = false;
+        if codec.is_none() && (self.persister.is_some() || gossip_configured) {
+            tracing::warn!(
+                "State persistence and gossip need serializable keys and are ignored, see build_with_serde_keyer"
+            );
+        }
+        let persister = Option::zip(self.persister, codec)
+            .map(|(config, codec)| Arc::new(Persister::new(config, codec)));
+        if let Some(persister) = &persister {
+            persister.restore(&limiter.load());
+        }
+        let key_fn = Arc::new(key_fn);
+        let handle = GovernorHandle::new(
+            KeyedTarget {
+                limiter: Arc::downgrade(&limiter),
+                key_fn: key_fn.clone(),
+            },
+            self.runtime.clone(),
+        );
+        handle.set_mirror(self.mirror);
+        let handle = handle
+            .audited(audit.clone())
+            .cached(self.decision_cache.map(DecisionCache::new))
+            .warming_up(warmup)
+            .with_health_checks(self.health_checks.clone())
+            .exempting(self.exemptions.clone())
+            .bypassed_by(self.bypass_token.clone())
+            .costed(self.body_cost)
+            .with_key_stats(self.key_stats.map(KeyStatsStore::new));
 
         let keyed_policy = KeyedPolicy {
             limiter,
             key_fn,
             gc_interval: self.gc_interval,
+            gc_started: StartOnce::new(),
+            schedule,
+            scheduler_started: StartOnce::new(),
+            handle,
+            telemetry: Telemetry::new(self.name, self.denial_log_sampling, self.rejection, audit),
+            accounting: self.accounting.map(Arc::new),
+            persister,
+            #[cfg(feature = "gossip")]
+            gossip: Option::zip(self.gossip, codec)
+                .map(|(config, codec)| Arc::new(Gossip::new(config, codec))),
+            pacer,
+            bank,
+            prefilter,
+            codec,
+            runtime: self.runtime,
         };
 
-        GovernorPolicy::Keyed(Box::new(keyed_policy))
+        GovernorPolicy::Keyed(Box::new(keyed_policy)).started()
+    }
+
+    /// Build the GovernorPolicy keyed by client address
+    ///
+    /// Keys are parsed into [`IpAddr`](std::net::IpAddr)s, so checking an
+    /// address through [`Limiter::check_ip`] doesn't allocate. A port after
+    /// the address, as in `203.0.113.7:443`, is ignored.
+    ///
+    /// Keys that aren't addresses, e.g. from a [`HeaderKey`] or passed to
+    /// [`Limiter::check_key`], get a bucket of their own under their text.
+    /// Like any keyed policy, used as a plain `Policy` it counts all requests
+    /// under one key; wrap it in an [`ExtractingPolicy`] with a
+    /// [`ClientIpKey`] to count each client on its own.
+    pub fn build_with_ip_keys(self) -> GovernorPolicy {
+        self.build_with_serde_keyer(ip_key::parse_ip_key)
+    }
+
+    /// Build a single-threaded [`LocalGovernorPolicy`] with one global state
+    ///
+    /// Only the quota applies; background features such as schedules,
+    /// accounting and persistence are not supported for local policies.
+    pub fn build_local(self) -> LocalGovernorPolicy {
+        self.build_local_with_keyer(|_| ())
+    }
+
+    /// Build a single-threaded [`LocalGovernorPolicy`] with a custom key function
+    pub fn build_local_with_keyer<K, F>(self, key_fn: F) -> LocalGovernorPolicy<K>
+    where
+        K: Eq + std::hash::Hash,
+        F: Fn(&str) -> K + 'static,
+    {
+        let quota = self.quota.expect("Quota must be set");
+        if !self.schedule.is_empty() || self.accounting.is_some() || self.persister.is_some() {
+            tracing::warn!("Schedules, accounting and persistence are ignored for local policies");
+        }
+        LocalGovernorPolicy::new(quota, Box::new(key_fn), self.name, self.rejection)
+    }
+
+    /// Build the GovernorPolicy with its state kept in `store`
+    ///
+    /// Use this to share one limit between several instances, e.g. through a
+    /// Redis backed store. Schedules and state persistence are not supported
+    /// for store backed policies.
+    pub fn build_with_store(self, store: impl RateLimitStore) -> GovernorPolicy {
+        let quota = self.quota.expect("Quota must be set");
+        let warmup = self.warmup_from(quota);
+        let quota = warmup.as_ref().map_or(quota, Warmup::initial_quota);
+        if !self.schedule.is_empty()
+            || self.persister.is_some()
+            || self.burst_bank.is_some()
+            || self.prefilter.is_some()
+        {
+            tracing::warn!(
+                "Schedules, persistence, burst banks and pre-filters are ignored for store backed policies"
+            );
+        }
+        if self.store_timeout.is_some() && self.runtime.is_none() {
+            tracing::warn!("Store timeout needs a runtime and is ignored");
+        }
+
+        let quota = Arc::new(ArcSwap::from_pointee(quota));
+        let store: Arc<dyn RateLimitStore> = Arc::new(store);
+        let audit = self.auditor();
+        let handle = GovernorHandle::new(
+            StoreTarget {
+                quota: Arc::downgrade(&quota),
+                store: Arc::downgrade(&store),
+            },
+            self.runtime.clone(),
+        );
+        handle.set_mirror(self.mirror);
+        let handle = handle
+            .audited(audit.clone())
+            .cached(self.decision_cache.map(DecisionCache::new))
+            .warming_up(warmup)
+            .with_health_checks(self.health_checks.clone())
+            .exempting(self.exemptions.clone())
+            .bypassed_by(self.bypass_token.clone())
+            .costed(self.body_cost)
+            .with_key_stats(self.key_stats.map(KeyStatsStore::new));
+
+        let pacer = self.pacer();
+        GovernorPolicy::Store(StorePolicy {
+            store,
+            quota,
+            failure_mode: self.failure_mode,
+            timeout: self.store_timeout,
+            handle,
+            telemetry: Telemetry::new(self.name, self.denial_log_sampling, self.rejection, audit),
+            accounting: self.accounting.map(Arc::new),
+            pacer,
+            runtime: self.runtime,
+        })
+        .started()
     }
 }
 
 impl GovernorPolicy {
     /// Create a new builder for GovernorPolicy
     pub fn builder() -> GovernorPolicyBuilder {
-        GovernorPolicyBuilder {
-            quota: None,
-            gc_interval: Duration::from_secs(60),
+        GovernorPolicyBuilder::new()
+    }
+
+    /// Export the per-key state of a keyed policy
+    ///
+    /// Only keys that are not fully replenished are included. The snapshot can
+    /// be serialized with serde and fed to [`import_state`](Self::import_state)
+    /// of a policy with the same key type, e.g. after a restart.
+    pub fn export_state(&self) -> Result<StateSnapshot, SnapshotError> {
+        match self {
+            GovernorPolicy::Direct(_) | GovernorPolicy::Store(_) => Err(SnapshotError::NotKeyed),
+            GovernorPolicy::Keyed(policy) => policy.export_state(),
         }
     }
 
-    /// Start garbage collection if needed
-    fn start_gc_if_needed(&self) {
-        static DIRECT_GC_STARTED: OnceCell<()> = OnceCell::new();
-        static KEYED_GC_STARTED: Lazy<Mutex<HashSet<usize>>> =
-            Lazy::new(|| Mutex::new(HashSet::new()));
+    /// Restore per-key state from a snapshot, returning the number of keys restored
+    ///
+    /// Time passed since the snapshot was taken is credited to every key.
+    /// Importing never lowers the state of keys already tracked, so it can't
+    /// hand out more capacity than either side allows on its own.
+    pub fn import_state(&self, snapshot: &StateSnapshot) -> Result<usize, SnapshotError> {
+        match self {
+            GovernorPolicy::Direct(_) | GovernorPolicy::Store(_) => Err(SnapshotError::NotKeyed),
+            GovernorPolicy::Keyed(policy) => policy.import_state(snapshot),
+        }
+    }
 
+    /// Name of this policy, as set through [`GovernorPolicyBuilder::name`]
+    pub fn name(&self) -> &str {
+        self.telemetry_ref().name()
+    }
+
+    /// Current configuration and recent decisions of this policy
+    pub fn status(&self) -> PolicyStatus {
+        let quota = self.quota();
+        let (kind, tracked_keys, telemetry) = match self {
+            GovernorPolicy::Direct(policy) => (PolicyKind::Direct, None, &policy.telemetry),
+            GovernorPolicy::Keyed(policy) => (
+                PolicyKind::Keyed,
+                Some(policy.tracked_keys()),
+                policy.telemetry(),
+            ),
+            GovernorPolicy::Store(policy) => (PolicyKind::Store, None, &policy.telemetry),
+        };
+        let handle = self.handle();
+        let (allowed_last_minute, denied_last_minute) = telemetry.counts().last_minute();
+
+        PolicyStatus {
+            name: telemetry.name().to_owned(),
+            kind,
+            replenish_interval_ms: quota.replenish_interval().as_millis() as u64,
+            burst_size: quota.burst_size().get(),
+            enabled: handle.is_enabled(),
+            locked_down: handle.is_locked_down(),
+            tracked_keys,
+            allowed_last_minute,
+            denied_last_minute,
+        }
+    }
+
+    /// Get a handle to control enforcement of this policy at runtime
+    pub fn handle(&self) -> GovernorHandle {
+        self.handle_ref().clone()
+    }
+
+    pub(crate) fn handle_ref(&self) -> &GovernorHandle {
         match self {
-            GovernorPolicy::Direct(policy) => {
-                DIRECT_GC_STARTED.get_or_init(|| {
-                    let gc_interval = policy.gc_interval;
-
-                    tokio::spawn(async move {
-                        let mut interval = tokio::time::interval(gc_interval);
-                        loop {
-                            interval.tick().await;
-                            // No need to do anything for direct rate limiter
-                        }
-                    });
-                });
-            }
-            GovernorPolicy::Keyed(policy) => {
-                // Use the pointer address as a unique identifier for this policy instance
-                let policy_ptr = policy as *const _ as usize;
-                let mut started = KEYED_GC_STARTED.lock().unwrap();
-
-                if !started.contains(&policy_ptr) {
-                    started.insert(policy_ptr);
-
-                    // Start GC for this keyed policy
-                    let interval = policy.gc_interval();
-
-                    // Instead of cloning the policy, we'll just create a new task
-                    // that calls the start_gc_if_needed method periodically
-                    tokio::spawn(async move {
-                        let mut interval_timer = tokio::time::interval(interval);
-                        loop {
-                            interval_timer.tick().await;
-                            // We can't access the policy here anymore, but that's ok
-                            // because the policy will be checked again on each request
-                        }
-                    });
+            GovernorPolicy::Direct(policy) => &policy.handle,
+            GovernorPolicy::Keyed(policy) => policy.handle(),
+            GovernorPolicy::Store(policy) => &policy.handle,
+        }
+    }
+
+    pub(crate) fn telemetry_ref(&self) -> &Telemetry {
+        match self {
+            GovernorPolicy::Direct(policy) => &policy.telemetry,
+            GovernorPolicy::Keyed(policy) => policy.telemetry(),
+            GovernorPolicy::Store(policy) => &policy.telemetry,
+        }
+    }
+
+    /// Start the background tasks of this policy: garbage collection, quota
+    /// schedules, usage accounting, persistence and gossip, as configured
+    ///
+    /// The builder does this already when its [`Runtime`] is available, e.g.
+    /// when called inside a tokio runtime. Policies built outside of one must
+    /// call this from within it before use. Calling it again has no effect.
+    ///
+    /// Each task is started exactly once per policy, even when requests on
+    /// several threads race to start them, and concurrent callers never wait
+    /// on each other. Tasks only hold weak references to the policy's state,
+    /// so dropping the last handle to the policy stops them on their next
+    /// tick; nothing is shared between policy instances.
+    pub fn start_background_tasks(&self) {
+        match self {
+            GovernorPolicy::Direct(policy) => policy.start_tasks_if_needed(),
+            GovernorPolicy::Keyed(policy) => policy.start_tasks_if_needed(),
+            GovernorPolicy::Store(policy) => {
+                if let (Some(accounting), Some(runtime)) = (&policy.accounting, &policy.runtime) {
+                    accounting.start_if_needed(runtime);
                 }
             }
         }
+        self.handle_ref().start_warmup();
+    }
+
+    /// Drop the state of keys whose quota is fully replenished, including
+    /// the minimum shares of a lockdown
+    ///
+    /// Runs periodically in the background when a runtime is available. Call
+    /// it directly where there is none, e.g. on edge runtimes without timers.
+    pub fn gc(&self) {
+        if let GovernorPolicy::Keyed(policy) = self {
+            policy.gc();
+        }
+        self.handle_ref().gc();
+    }
+
+    /// Number of keys whose state this policy keeps in memory
+    ///
+    /// Always zero for direct policies, which have no keys, and for store
+    /// backed policies, whose state lives in the store. Also exported as the
+    /// `governor_tracked_keys` gauge after each GC run (`metrics` feature).
+    pub fn len(&self) -> usize {
+        match self {
+            GovernorPolicy::Keyed(policy) => policy.tracked_keys(),
+            GovernorPolicy::Direct(_) | GovernorPolicy::Store(_) => 0,
+        }
+    }
+
+    /// Whether this policy keeps no key state in memory, see [`len`](Self::len)
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn runtime(&self) -> Option<&Arc<dyn Runtime>> {
+        match self {
+            GovernorPolicy::Direct(policy) => policy.runtime.as_ref(),
+            GovernorPolicy::Keyed(policy) => policy.runtime(),
+            GovernorPolicy::Store(policy) => policy.runtime.as_ref(),
+        }
+    }
+
+    /// Start background tasks right away if there is a runtime to run them,
+    /// keeping the check path free of any initialization
+    fn started(self) -> Self {
+        match self.runtime() {
+            Some(runtime) if runtime.is_available() => self.start_background_tasks(),
+            Some(_) => tracing::debug!(
+                "Runtime not available at build time, call start_background_tasks() before use"
+            ),
+            None => {
+                tracing::warn!("No runtime configured, background tasks such as GC will not run")
+            }
+        }
+        self
     }
 }
 
@@ -259,7 +1476,7 @@ where
     State: Clone + Send + Sync + 'static,
     Request: Send + Sync + 'static,
 {
-    type Guard = ();
+    type Guard = GovernorGuard;
     type Error = GovernorError;
 
     async fn check(
@@ -267,52 +1484,281 @@ where
         ctx: Context<State>,
         request: Request,
     ) -> PolicyResult<State, Request, Self::Guard, Self::Error> {
-        // Initialize GC if needed
-        self.start_gc_if_needed();
+        // Without a key extractor all requests share one key, see `ExtractingPolicy`
+        self.check_with_key(ctx, request, "default").await
+    }
+}
 
-        match self {
-            GovernorPolicy::Direct(policy) => match policy.limiter.check() {
-                Ok(_) => {
-                    tracing::debug!("Rate limit check passed for direct limiter");
-                    PolicyResult {
-                        ctx,
-                        request,
-                        output: PolicyOutput::Ready(()),
-                    }
+/// Outcome of a check that let the request through
+#[derive(Debug, Default)]
+pub(crate) struct Decision {
+    /// State of the quota of the key, if known
+    pub(crate) info: Option<RateLimitInfo>,
+    /// Set when mirror mode let an over-limit request through
+    pub(crate) exceeded: Option<RateLimitExceeded>,
+}
+
+impl Decision {
+    /// Insert the decision into `ctx` as extensions, returning the guard of
+    /// the request counted under `key`
+    pub(crate) fn admit<State>(self, ctx: &mut Context<State>, key: &str) -> GovernorGuard {
+        let remaining = match (&self.info, &self.exceeded) {
+            (Some(info), _) => Some(info.remaining),
+            (None, Some(_)) => Some(0),
+            (None, None) => None,
+        };
+        if let Some(info) = self.info {
+            ctx.insert(info);
+        }
+        if let Some(exceeded) = self.exceeded {
+            ctx.insert(exceeded);
+        }
+        let guard = GovernorGuard::new(Some(key.to_owned()), remaining);
+        ctx.insert(guard.clone());
+        guard
+    }
+}
+
+impl GovernorPolicy {
+    /// Decide on a request counted under `key`
+    pub(crate) async fn decide(&self, key: &str) -> Result<Decision, GovernorError> {
+        self.decide_n(key, NonZeroU32::MIN).await
+    }
+
+    /// Decide on a request counted under `key` that costs `cost` cells
+    pub(crate) async fn decide_n(
+        &self,
+        key: &str,
+        cost: NonZeroU32,
+    ) -> Result<Decision, GovernorError> {
+        let result = self.enforce(key, cost).await;
+        if let Some(key_stats) = self.handle_ref().key_stats_store() {
+            let denied = matches!(
+                result,
+                Err(GovernorError::RateLimited(_))
+                    | Ok(Decision {
+                        exceeded: Some(_),
+                        ..
+                    })
+            );
+            key_stats.record(key, denied);
+        }
+        if self.handle_ref().is_traced(key) {
+            self.trace_decision(key, cost, &result);
+        }
+        result
+    }
+
+    /// Log the details of a decision on a traced key
+    fn trace_decision(
+        &self,
+        key: &str,
+        cost: NonZeroU32,
+        result: &Result<Decision, GovernorError>,
+    ) {
+        let handle = self.handle_ref();
+        let mode = match (handle.is_enabled(), handle.is_locked_down()) {
+            (false, _) => "disabled",
+            (true, true) => "lockdown",
+            (true, false) if handle.is_draining() => "draining",
+            (true, false) => "enforcing",
+        };
+        let quota = self.quota();
+        match result {
+            Ok(decision) => tracing::info!(
+                key,
+                cost,
+                ?quota,
+                mode,
+                shadow = handle.is_shadow(),
+                limit = decision.info.map(|info| info.limit),
+                remaining = decision.info.map(|info| info.remaining),
+                reset = ?decision.info.map(|info| info.reset),
+                over_limit = decision.exceeded.is_some(),
+                "Traced decision: allowed"
+            ),
+            Err(err) => {
+                let retry_after = match err {
+                    GovernorError::RateLimited(rejected) => rejected.retry_after(),
+                    GovernorError::StoreUnavailable(_) => None,
+                };
+                tracing::info!(
+                    key,
+                    cost,
+                    ?quota,
+                    mode,
+                    shadow = handle.is_shadow(),
+                    ?retry_after,
+                    error = %err,
+                    "Traced decision: rejected"
+                )
+            }
+        }
+    }
+
+    /// Enforce the runtime controls and quota on a request counted under
+    /// `key` that costs `cost` cells
+    async fn enforce(&self, key: &str, cost: NonZeroU32) -> Result<Decision, GovernorError> {
+        let (handle, telemetry, pacer, bank) = match self {
+            GovernorPolicy::Direct(policy) => (
+                &policy.handle,
+                &policy.telemetry,
+                policy.pacer.as_ref(),
+                policy.bank.as_ref(),
+            ),
+            GovernorPolicy::Keyed(policy) => (
+                policy.handle(),
+                policy.telemetry(),
+                policy.pacer(),
+                policy.bank(),
+            ),
+            GovernorPolicy::Store(policy) => (
+                &policy.handle,
+                &policy.telemetry,
+                policy.pacer.as_ref(),
+                None,
+            ),
+        };
+        // pacing, banking and cached rejections are per key, and a direct
+        // policy has a single one
+        let state_key = match self {
+            GovernorPolicy::Direct(_) => DIRECT_USAGE_KEY,
+            _ => key,
+        };
+        match handle.control(key) {
+            Control::Enforce => {}
+            Control::Bypass => return Ok(Decision::default()),
+            Control::Reject => {
+                if telemetry.sample_denial(key) {
+                    tracing::info!("Rate limit exceeded for lockdown clamp");
                 }
-                Err(_) => {
-                    tracing::info!("Rate limit exceeded for direct limiter");
-                    PolicyResult {
-                        ctx,
-                        request,
-                        output: PolicyOutput::Abort(GovernorError::RateLimited),
-                    }
+                telemetry.audit_denial(key, DenyReason::Lockdown, None);
+                return Err(telemetry.rejected(None));
+            }
+            Control::Denied => {
+                if telemetry.sample_denial(key) {
+                    tracing::info!("Rejecting denylisted key: {}", key);
                 }
-            },
-            GovernorPolicy::Keyed(policy) => {
-                // Create a default key (in real applications, derive from request)
-                let key = "default";
-                match policy.check_key(key) {
-                    Ok(_) => {
-                        tracing::debug!("Rate limit check passed for key: {}", key);
-                        PolicyResult {
-                            ctx,
-                            request,
-                            output: PolicyOutput::Ready(()),
-                        }
-                    }
-                    Err(_) => {
-                        tracing::info!("Rate limit exceeded for key: {}", key);
-                        PolicyResult {
-                            ctx,
-                            request,
-                            output: PolicyOutput::Abort(GovernorError::RateLimited),
-                        }
+                telemetry.audit_denial(key, DenyReason::Denylist, None);
+                return Err(telemetry.rejected(None));
+            }
+            Control::Drained => {
+                if telemetry.sample_denial(key) {
+                    tracing::info!("Rejecting request while draining");
+                }
+                telemetry.audit_denial(key, DenyReason::Drain, None);
+                return Err(telemetry.rejected(None));
+            }
+        }
+        if let Some(retry_after) = handle.backed_off(key) {
+            if telemetry.sample_denial(key) {
+                tracing::info!("Rejecting key backed off by an upstream: {}", key);
+            }
+            telemetry.audit_denial(key, DenyReason::Upstream, Some(retry_after));
+            return Err(telemetry.rejected(Some(retry_after)));
+        }
+
+        let cache = handle.decision_cache();
+        let result = match cache.and_then(|cache| cache.blocked(state_key)) {
+            Some(retry_after) => Err(telemetry.rejected(Some(retry_after))),
+            None => {
+                let result = match self {
+                    GovernorPolicy::Direct(policy) => policy.check(cost),
+                    GovernorPolicy::Keyed(policy) => policy.check_key(key, cost),
+                    GovernorPolicy::Store(policy) => policy.check_key(key, cost).await,
+                };
+                if let (Some(cache), Err(GovernorError::RateLimited(rejected))) = (cache, &result) {
+                    cache.rejected(state_key, rejected.retry_after());
+                }
+                result
+            }
+        };
+
+        match result {
+            Ok(info) => {
+                tracing::debug!("Rate limit check passed for key: {}", key);
+                if let (Some(bank), Some(info)) = (bank, &info) {
+                    bank.allowed(state_key, self.quota(), info);
+                }
+                if let (Some(pacer), Some(runtime)) = (pacer, self.runtime()) {
+                    let delay = pacer.reserve(state_key, self.quota().replenish_interval());
+                    if !delay.is_zero() {
+                        runtime.sleep(delay).await;
                     }
                 }
+                Ok(Decision {
+                    info,
+                    exceeded: None,
+                })
+            }
+            Err(GovernorError::RateLimited(_))
+                if bank.is_some_and(|bank| bank.withdraw(state_key, self.quota())) =>
+            {
+                tracing::debug!(
+                    "Paid over-limit request from the burst bank of key: {}",
+                    key
+                );
+                Ok(Decision::default())
+            }
+            Err(GovernorError::RateLimited(rejected)) if handle.is_mirror() => {
+                tracing::debug!("Mirror mode, tagging over-limit request for key: {}", key);
+                Ok(Decision {
+                    info: None,
+                    exceeded: Some(RateLimitExceeded {
+                        retry_after: rejected.retry_after(),
+                    }),
+                })
+            }
+            Err(err) if handle.is_shadow() => {
+                if telemetry.sample_denial(key) {
+                    tracing::info!("Shadow mode, would have limited key {}: {}", key, err);
+                }
+                Ok(Decision::default())
+            }
+            Err(err) => {
+                if telemetry.sample_denial(key) {
+                    tracing::info!("Rate limit exceeded for key: {}", key);
+                }
+                if let GovernorError::RateLimited(rejected) = &err {
+                    telemetry.audit_denial(key, DenyReason::RateLimit, rejected.retry_after());
+                }
+                Err(err)
             }
         }
     }
+
+    /// Check a request counted under `key`
+    pub(crate) async fn check_with_key<State, Request>(
+        &self,
+        mut ctx: Context<State>,
+        request: Request,
+        key: &str,
+    ) -> PolicyResult<State, Request, GovernorGuard, GovernorError>
+    where
+        Request: 'static,
+    {
+        let handle = self.handle_ref();
+        if handle.bypasses(&request, key) || handle.exempts(&request) {
+            return PolicyResult {
+                ctx,
+                request,
+                output: PolicyOutput::Ready(GovernorGuard::unlimited()),
+            };
+        }
+        let output = match self.decide_n(key, handle.cost_of(&request)).await {
+            Ok(decision) => PolicyOutput::Ready(decision.admit(&mut ctx, key)),
+            Err(GovernorError::RateLimited(mut rejected)) => {
+                rejected.of_request(&request);
+                PolicyOutput::Abort(GovernorError::RateLimited(rejected))
+            }
+            Err(err) => PolicyOutput::Abort(err),
+        };
+        PolicyResult {
+            ctx,
+            request,
+            output,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -343,8 +1789,124 @@ mod tests {
         // Third request should be rate limited
         let result3 = policy.check(Context::default(), ()).await;
         match result3.output {
-            PolicyOutput::Abort(GovernorError::RateLimited) => {}
+            PolicyOutput::Abort(GovernorError::RateLimited(_)) => {}
             _ => panic!("Expected Abort"),
         }
     }
+
+    #[tokio::test]
+    async fn test_handle_controls() {
+        let policy = GovernorPolicy::builder()
+            .per_second(10)
+            .burst_size(1)
+            .build();
+        let handle = policy.handle();
+
+        assert!(matches!(
+            policy.check(Context::default(), ()).await.output,
+            PolicyOutput::Ready(_)
+        ));
+
+        handle.disable();
+        for _ in 0..5 {
+            assert!(matches!(
+                policy.check(Context::default(), ()).await.output,
+                PolicyOutput::Ready(_)
+            ));
+        }
+
+        handle.lockdown(Quota::per_minute(NonZeroU32::new(1).unwrap()));
+        assert!(handle.is_locked_down());
+        // the lockdown clamp allows one request, which the regular limiter then rejects
+        assert!(matches!(
+            policy.check(Context::default(), ()).await.output,
+            PolicyOutput::Abort(GovernorError::RateLimited(_))
+        ));
+
+        handle.enable();
+        assert!(handle.is_enabled() && !handle.is_locked_down());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_mode() {
+        let policy = GovernorPolicy::builder().per_minute(1).mirror(true).build();
+        let _ = policy.check(Context::default(), ()).await;
+
+        let result = policy.check(Context::default(), ()).await;
+        assert!(matches!(result.output, PolicyOutput::Ready(_)));
+        let exceeded = result.ctx.get::<RateLimitExceeded>().unwrap();
+        assert!(exceeded.retry_after.is_some());
+
+        policy.handle().set_mirror(false);
+        assert!(matches!(
+            policy.check(Context::default(), ()).await.output,
+            PolicyOutput::Abort(GovernorError::RateLimited(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_store_timeout() {
+        struct Stalled;
+
+        impl RateLimitStore for Stalled {
+            fn check_n<'a>(
+                &'a self,
+                _key: &'a str,
+                _quota: Quota,
+                _n: u32,
+            ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+                Box::pin(std::future::pending())
+            }
+        }
+
+        let policy = GovernorPolicy::builder()
+            .per_second(10)
+            .failure_mode(FailureMode::Closed)
+            .store_timeout(Duration::from_millis(10))
+            .build_with_store(Stalled);
+        match policy.check(Context::default(), ()).await.output {
+            PolicyOutput::Abort(GovernorError::StoreUnavailable(err)) => assert!(err.is_timeout()),
+            _ => panic!("Expected the timed out check to fail closed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_snapshot_roundtrip() {
+        let build = || {
+            GovernorPolicy::builder()
+                .per_minute(1)
+                .build_with_serde_keyer(|key: &str| key.to_owned())
+        };
+
+        let policy = build();
+        assert!(matches!(
+            policy.check(Context::default(), ()).await.output,
+            PolicyOutput::Ready(_)
+        ));
+
+        let snapshot = policy.export_state().unwrap();
+        assert_eq!(snapshot.entries.len(), 1);
+        let json = serde_json::to_string(&snapshot).unwrap();
+
+        let restored = build();
+        assert!(restored.is_empty());
+        let snapshot: StateSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.import_state(&snapshot).unwrap(), 1);
+        assert_eq!(restored.len(), 1);
+        assert!(matches!(
+            restored.check(Context::default(), ()).await.output,
+            PolicyOutput::Abort(GovernorError::RateLimited(_))
+        ));
+
+        // keys without serde still build, just without snapshots
+        #[derive(Clone, PartialEq, Eq, Hash)]
+        struct Tenant(String);
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .build_with_keyer(|key: &str| Tenant(key.to_owned()));
+        assert!(matches!(
+            policy.export_state(),
+            Err(SnapshotError::NotSerializable)
+        ));
+    }
 }
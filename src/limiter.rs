@@ -0,0 +1,195 @@
+//! Using a policy outside of a rama service stack
+//!
+//! A [`Limiter`] applies a built [`GovernorPolicy`] to plain keys, so CLI
+//! tools and workers can embed the same configured limiter, runtime controls
+//! and telemetry included, without constructing requests. The `Policy` impl
+//! of [`GovernorPolicy`] is a thin adapter over the same decision.
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use crate::ip_key::IpText;
+use crate::{GovernorError, GovernorHandle, GovernorPolicy};
+
+/// Key used by [`Limiter::check`], the same key `Policy::check` uses
+const DEFAULT_KEY: &str = "default";
+
+/// How long [`Limiter::until_ready`] waits when a rejection carries no retry time
+const RETRY_WITHOUT_HINT: Duration = Duration::from_millis(10);
+
+/// A policy checked directly, without rama
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    policy: Arc<GovernorPolicy>,
+}
+
+impl Limiter {
+    /// Check keys against `policy`
+    pub fn new(policy: impl Into<Arc<GovernorPolicy>>) -> Self {
+        Limiter {
+            policy: policy.into(),
+        }
+    }
+
+    /// The wrapped policy
+    pub fn policy(&self) -> &Arc<GovernorPolicy> {
+        &self.policy
+    }
+
+    /// A handle to control the wrapped policy at runtime
+    pub fn handle(&self) -> GovernorHandle {
+        self.policy.handle()
+    }
+
+    /// Check one event without a key
+    pub async fn check(&self) -> Result<(), GovernorError> {
+        self.check_key(DEFAULT_KEY).await
+    }
+
+    /// Check one event counted under `key`
+    ///
+    /// Direct policies ignore the key. Mirror and shadow mode let over-limit
+    /// events pass, as they do for requests.
+    pub async fn check_key(&self, key: &str) -> Result<(), GovernorError> {
+        self.policy.decide(key).await.map(|_| ())
+    }
+
+    /// Check `n` events counted under `key` at once
+    ///
+    /// The events are allowed or rejected together. As for requests, a cost
+    /// above the burst size is charged as a full burst. Checking zero events
+    /// always succeeds.
+    pub async fn check_n_for(&self, key: &str, n: u32) -> Result<(), GovernorError> {
+        match NonZeroU32::new(n) {
+            Some(cost) => self.policy.decide_n(key, cost).await.map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Check one event per key of a batch, returning the results in order
+    ///
+    /// The checks run concurrently, so checks of a store-backed policy are
+    /// in flight together: a `RedisStore` with batching sends them in one pipeline
+    /// instead of one round trip per key. In-memory policies decide each key
+    /// as [`check_key`](Self::check_key) does.
+    pub async fn check_keys<K: AsRef<str>>(&self, keys: &[K]) -> Vec<Result<(), GovernorError>> {
+        type Check<'a> = Pin<Box<dyn Future<Output = Result<(), GovernorError>> + Send + 'a>>;
+        let mut checks: Vec<Option<Check<'_>>> = keys
+            .iter()
+            .map(|key| Some(Box::pin(self.check_key(key.as_ref())) as Check<'_>))
+            .collect();
+        let mut results: Vec<Option<Result<(), GovernorError>>> =
+            keys.iter().map(|_| None).collect();
+        std::future::poll_fn(|cx| {
+            let mut done = true;
+            for (check, result) in checks.iter_mut().zip(&mut results) {
+                if let Some(future) = check {
+                    match future.as_mut().poll(cx) {
+                        Poll::Ready(output) => {
+                            *result = Some(output);
+                            *check = None;
+                        }
+                        Poll::Pending => done = false,
+                    }
+                }
+            }
+            if done { Poll::Ready(()) } else { Poll::Pending }
+        })
+        .await;
+        results
+            .into_iter()
+            .map(|result| result.expect("every check completed"))
+            .collect()
+    }
+
+    /// Check one event counted under the address `ip`
+    ///
+    /// Doesn't allocate on the heap for addresses the policy already tracks,
+    /// when the policy is built with
+    /// [`build_with_ip_keys`](crate::GovernorPolicyBuilder::build_with_ip_keys).
+    pub async fn check_ip(&self, ip: IpAddr) -> Result<(), GovernorError> {
+        self.check_key(IpText::new(ip).as_str()).await
+    }
+
+    /// Wait until one event without a key is allowed
+    pub async fn until_ready(&self) -> Result<(), GovernorError> {
+        self.until_key_ready(DEFAULT_KEY).await
+    }
+
+    /// Wait until one event counted under `key` is allowed
+    ///
+    /// Waits on the [`Runtime`](crate::Runtime) of the policy. Store failures,
+    /// and rejections of policies built without a runtime, are returned
+    /// instead of waited out.
+    pub async fn until_key_ready(&self, key: &str) -> Result<(), GovernorError> {
+        loop {
+            match self.check_key(key).await {
+                Err(GovernorError::RateLimited(rejected)) => {
+                    let Some(runtime) = self.policy.runtime() else {
+                        return Err(GovernorError::RateLimited(rejected));
+                    };
+                    let wait = rejected.retry_after().unwrap_or(RETRY_WITHOUT_HINT);
+                    let _waiting = self.policy.handle_ref().enter_wait();
+                    runtime.sleep(wait).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl From<GovernorPolicy> for Limiter {
+    fn from(policy: GovernorPolicy) -> Self {
+        Limiter::new(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_limiter_without_rama() {
+        let limiter = Limiter::from(
+            GovernorPolicy::builder()
+                .per_second(50)
+                .burst_size(1)
+                .build_with_keyer(|key: &str| key.to_owned()),
+        );
+        limiter.check_key("alice").await.unwrap();
+        limiter.check_key("bob").await.unwrap();
+        assert!(matches!(
+            limiter.check_key("alice").await,
+            Err(GovernorError::RateLimited(_))
+        ));
+
+        let start = Instant::now();
+        limiter.until_key_ready("alice").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn test_batch_checks() {
+        let limiter = Limiter::from(
+            GovernorPolicy::builder()
+                .per_minute(1)
+                .burst_size(3)
+                .build_with_keyer(|key: &str| key.to_owned()),
+        );
+        let results = limiter
+            .check_keys(&["alice", "bob", "alice", "alice", "alice"])
+            .await;
+        let allowed: Vec<bool> = results.iter().map(Result::is_ok).collect();
+        assert_eq!(allowed, [true, true, true, true, false]);
+
+        limiter.check_n_for("bob", 0).await.unwrap();
+        limiter.check_n_for("bob", 2).await.unwrap();
+        assert!(limiter.check_n_for("bob", 1).await.is_err());
+    }
+}
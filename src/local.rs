@@ -0,0 +1,151 @@
+//! Single-threaded policy for thread-per-core executors
+//!
+//! [`LocalGovernorPolicy`] keeps its GCRA state in plain cells instead of
+//! atomics and is neither `Send` nor `Sync`. Create one per thread, e.g. per
+//! `LocalSet` worker, and check it directly from the service.
+//!
+//! rama's `Policy` trait requires `Send + Sync`, so a local policy can't be
+//! used with `LimitLayer`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use governor::Quota;
+use governor::clock::{Clock, Reference};
+
+use crate::GovernorError;
+use crate::clock::{LimiterClock, LimiterInstant};
+use crate::rejection::{Rejected, RejectionResponse};
+use crate::telemetry::DEFAULT_POLICY_NAME;
+
+struct Inner<K> {
+    /// Emission interval and burst tolerance, in nanoseconds
+    t: u64,
+    tau: u64,
+    clock: LimiterClock,
+    start: LimiterInstant,
+    /// Theoretical arrival time per key, in nanoseconds since `start`
+    tats: RefCell<HashMap<K, u64>>,
+    key_fn: Box<dyn Fn(&str) -> K>,
+    name: Arc<str>,
+    rejection: Arc<RejectionResponse>,
+}
+
+/// A rate limiting policy for use on a single thread
+///
+/// Clones share their state. Build one through
+/// [`GovernorPolicyBuilder::build_local`](crate::GovernorPolicyBuilder::build_local)
+/// or [`GovernorPolicyBuilder::build_local_with_keyer`](crate::GovernorPolicyBuilder::build_local_with_keyer).
+pub struct LocalGovernorPolicy<K = ()> {
+    inner: Rc<Inner<K>>,
+}
+
+impl<K: Hash + Eq> LocalGovernorPolicy<K> {
+    pub(crate) fn new(
+        quota: Quota,
+        key_fn: Box<dyn Fn(&str) -> K>,
+        name: Option<String>,
+        rejection: RejectionResponse,
+    ) -> Self {
+        let t = (quota.replenish_interval().as_nanos() as u64).max(1);
+        let clock = LimiterClock::default();
+        let start = clock.now();
+
+        LocalGovernorPolicy {
+            inner: Rc::new(Inner {
+                t,
+                tau: t * u64::from(quota.burst_size().get()),
+                clock,
+                start,
+                tats: RefCell::new(HashMap::new()),
+                key_fn,
+                name: name.as_deref().unwrap_or(DEFAULT_POLICY_NAME).into(),
+                rejection: Arc::new(rejection),
+            }),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        Reference::duration_since(&self.inner.clock.now(), self.inner.start).as_u64()
+    }
+
+    /// Check a request for `key`, consuming one cell if allowed
+    pub fn check_key(&self, key: &str) -> Result<(), GovernorError> {
+        let inner = &*self.inner;
+        let key = (inner.key_fn)(key);
+        let now = self.now();
+
+        let mut tats = inner.tats.borrow_mut();
+        let tat = tats.get(&key).copied().unwrap_or(now).max(now) + inner.t;
+        let allow_at = tat.saturating_sub(inner.tau);
+        if allow_at > now {
+            tracing::info!("Rate limit exceeded for local policy");
+            return Err(GovernorError::RateLimited(Rejected::new(
+                inner.name.clone(),
+                Some(Duration::from_nanos(allow_at - now)),
+                inner.rejection.clone(),
+            )));
+        }
+        tats.insert(key, tat);
+        Ok(())
+    }
+
+    /// Drop the state of keys whose quota is fully replenished
+    pub fn gc(&self) {
+        let now = self.now();
+        self.inner.tats.borrow_mut().retain(|_, tat| *tat > now);
+    }
+
+    /// Number of keys currently tracked
+    pub fn len(&self) -> usize {
+        self.inner.tats.borrow().len()
+    }
+
+    /// Whether no keys are tracked
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K> Clone for LocalGovernorPolicy<K> {
+    fn clone(&self) -> Self {
+        LocalGovernorPolicy {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K> fmt::Debug for LocalGovernorPolicy<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalGovernorPolicy")
+            .field("replenish_interval", &Duration::from_nanos(self.inner.t))
+            .field("keys", &self.inner.tats.borrow().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::GovernorPolicy;
+
+    #[test]
+    fn test_local_policy() {
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .burst_size(2)
+            .build_local_with_keyer(|key: &str| key.to_owned());
+
+        for key in ["alice", "bob"] {
+            assert!(policy.check_key(key).is_ok());
+            assert!(policy.clone().check_key(key).is_ok());
+            assert!(policy.check_key(key).is_err());
+        }
+        policy.gc();
+        assert_eq!(policy.len(), 2);
+    }
+}
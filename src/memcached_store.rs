@@ -0,0 +1,160 @@
+//! memcached backed rate limit store
+//!
+//! memcached has no scripting, so instead of GCRA this store counts requests
+//! in fixed windows with atomic `incr`. A window lasts as long as it takes the
+//! quota to replenish its full burst and admits up to one burst of requests.
+//! Bursts at window edges are therefore possible, up to twice the burst size.
+
+use std::hash::Hasher;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use governor::Quota;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::stable_hash::stable_hasher;
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture};
+
+/// A [`RateLimitStore`] keeping windowed counters in memcached
+///
+/// Speaks the memcached text protocol over a single connection, which is
+/// re-established on the next check after an error.
+pub struct MemcachedStore {
+    addr: SocketAddr,
+    prefix: String,
+    conn: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl MemcachedStore {
+    /// Use the memcached server at `addr`
+    pub fn new(addr: SocketAddr) -> Self {
+        MemcachedStore {
+            addr,
+            prefix: "governor:".to_owned(),
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// Prefix prepended to every key (default `governor:`)
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    async fn incr(&self, key: &str, n: u32, ttl: u64) -> io::Result<u64> {
+        let mut guard = self.conn.lock().await;
+        let conn = match &mut *guard {
+            Some(conn) => conn,
+            None => guard.insert(BufStream::new(TcpStream::connect(self.addr).await?)),
+        };
+
+        let result = incr_or_add(conn, key, n, ttl).await;
+        if result.is_err() {
+            // the stream may be out of sync with the server, start over next time
+            *guard = None;
+        }
+        result
+    }
+}
+
+impl std::fmt::Debug for MemcachedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemcachedStore")
+            .field("addr", &self.addr)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+async fn command(conn: &mut BufStream<TcpStream>, cmd: &str) -> io::Result<String> {
+    conn.write_all(cmd.as_bytes()).await?;
+    conn.flush().await?;
+    let mut line = String::new();
+    if conn.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end().to_owned())
+}
+
+/// Increment the counter at `key`, creating it with the given expiry if missing
+async fn incr_or_add(
+    conn: &mut BufStream<TcpStream>,
+    key: &str,
+    n: u32,
+    ttl: u64,
+) -> io::Result<u64> {
+    // two rounds: a concurrent `add` from another instance may win the race
+    for _ in 0..2 {
+        let reply = command(conn, &format!("incr {key} {n}\r\n")).await?;
+        if reply != "NOT_FOUND" {
+            return reply
+                .parse()
+                .map_err(|_| io::Error::other(format!("unexpected reply to incr: {reply}")));
+        }
+
+        let value = n.to_string();
+        let reply = command(
+            conn,
+            &format!("add {key} 0 {ttl} {}\r\n{value}\r\n", value.len()),
+        )
+        .await?;
+        match reply.as_str() {
+            "STORED" => return Ok(u64::from(n)),
+            "NOT_STORED" => continue,
+            _ => {
+                return Err(io::Error::other(format!(
+                    "unexpected reply to add: {reply}"
+                )));
+            }
+        }
+    }
+    Err(io::Error::other("counter kept disappearing"))
+}
+
+/// memcached keys are limited to 250 bytes without whitespace or control characters
+fn counter_key(prefix: &str, key: &str, index: u128) -> String {
+    let counter = format!("{prefix}{key}:{index}");
+    if counter.len() <= 250 && !counter.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return counter;
+    }
+
+    let mut hasher = stable_hasher();
+    hasher.write(key.as_bytes());
+    format!("{prefix}#{:016x}:{index}", hasher.finish())
+}
+
+impl RateLimitStore for MemcachedStore {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        Box::pin(async move {
+            let window = quota
+                .burst_size_replenished_in()
+                .max(Duration::from_millis(1));
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let index = now.as_millis() / window.as_millis();
+            let window_end = Duration::from_millis(((index + 1) * window.as_millis()) as u64);
+            // memcached expiries are in whole seconds
+            let ttl = window.as_secs() + 1;
+
+            let counter = counter_key(&self.prefix, key, index);
+            let count = self.incr(&counter, n, ttl).await.map_err(StoreError::new)?;
+
+            Ok(if count <= u64::from(quota.burst_size().get()) {
+                StoreDecision::Allowed
+            } else {
+                StoreDecision::Denied {
+                    retry_after: window_end.saturating_sub(now),
+                }
+            })
+        })
+    }
+}
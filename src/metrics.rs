@@ -0,0 +1,73 @@
+//! Export of policy decisions through the `metrics` facade
+//!
+//! Handles are registered with the global recorder when the policy is built,
+//! so install the recorder of your exporter before building policies.
+
+use std::time::Duration;
+
+use metrics::{Counter, Gauge, Histogram, counter, gauge, histogram};
+
+pub(crate) struct MetricsHandles {
+    allowed: Counter,
+    denied: Counter,
+    retry_after: Histogram,
+    store_timeouts: Counter,
+}
+
+impl MetricsHandles {
+    pub(crate) fn new(policy: &str) -> Self {
+        let policy = policy.to_owned();
+        MetricsHandles {
+            allowed: counter!("governor_decisions_total", "policy" => policy.clone(), "decision" => "allowed"),
+            denied: counter!("governor_decisions_total", "policy" => policy.clone(), "decision" => "denied"),
+            retry_after: histogram!("governor_retry_after_seconds", "policy" => policy.clone()),
+            store_timeouts: counter!("governor_store_timeouts_total", "policy" => policy),
+        }
+    }
+
+    pub(crate) fn record(&self, allowed: bool, retry_after: Option<Duration>) {
+        if allowed {
+            self.allowed.increment(1);
+        } else {
+            self.denied.increment(1);
+        }
+        if let Some(retry_after) = retry_after {
+            self.retry_after.record(retry_after.as_secs_f64());
+        }
+    }
+
+    pub(crate) fn record_store_timeout(&self) {
+        self.store_timeouts.increment(1);
+    }
+}
+
+/// Gauge of the number of keys `policy` tracks in memory
+pub(crate) fn tracked_keys(policy: &str) -> Gauge {
+    gauge!("governor_tracked_keys", "policy" => policy.to_owned())
+}
+
+/// Decision counters of one branch of a matcher map
+pub(crate) struct BranchHandles {
+    allowed: Counter,
+    denied: Counter,
+    error: Counter,
+}
+
+impl BranchHandles {
+    pub(crate) fn new(branch: &str) -> Self {
+        let branch = branch.to_owned();
+        BranchHandles {
+            allowed: counter!("governor_branch_decisions_total", "branch" => branch.clone(), "decision" => "allowed"),
+            denied: counter!("governor_branch_decisions_total", "branch" => branch.clone(), "decision" => "denied"),
+            error: counter!("governor_branch_decisions_total", "branch" => branch, "decision" => "error"),
+        }
+    }
+
+    pub(crate) fn record(&self, decision: &str) {
+        match decision {
+            "allowed" => self.allowed.increment(1),
+            "denied" => self.denied.increment(1),
+            _ => self.error.increment(1),
+        }
+    }
+}
@@ -0,0 +1,589 @@
+//! Isolated keyed state per tenant
+//!
+//! A multi-tenant gateway wants every tenant's keys limited on their own,
+//! without one policy per tenant and its background tasks. A
+//! [`NamespacedPolicy`] derives a namespace, e.g. the tenant, and a key from
+//! each request, and keeps a separate keyed store per namespace that can be
+//! inspected, collected and reset on its own:
+//!
+//! ```ignore
+//! let policy = NamespacedPolicy::new(
+//!     Quota::per_second(nonzero!(10u32)),
+//!     HeaderKey::new(HeaderName::from_static("x-tenant")),
+//!     ClientIpKey,
+//! );
+//! policy.gc_every(TokioRuntime, Duration::from_secs(60));
+//! ```
+//!
+//! Requests without a namespace or key are not limited.
+//!
+//! Namespaces share a default quota unless [`NamespaceQuotas`] override it,
+//! e.g. per pricing tier. The quotas can be replaced at runtime through
+//! [`NamespacedPolicy::set_quotas`], typically from a reloaded
+//! [`NamespaceConfig`](crate::NamespaceConfig), so tiers are data rather
+//! than code.
+//!
+//! Temporary changes, like boosting a tenant for a launch, are set through
+//! [`NamespacedPolicy::override_for`] and
+//! [`schedule_override`](NamespacedPolicy::schedule_override) with an end
+//! time, and revert on their own rather than waiting for someone to clean
+//! them up. Their start and lapse are reported to the [`AuditSink`] of the
+//! policy, if any.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use governor::Quota;
+use rama_core::Context;
+use rama_core::layer::limit::policy::{Policy, PolicyOutput, PolicyResult};
+
+use crate::audit::{AuditEvent, AuditRecord, AuditSink, OverrideAction};
+use crate::clock;
+use crate::keyed::KeyedLimiter;
+use crate::rejection::{Rejected, RejectionResponse};
+use crate::runtime::{self, Runtime};
+use crate::telemetry::DEFAULT_POLICY_NAME;
+use crate::{Decision, GovernorError, GovernorGuard, KeyExtractor, RateLimitInfo};
+
+type Namespaces = DashMap<String, Arc<KeyedLimiter<String>>>;
+
+/// Quota of each namespace: a default, and overrides for some namespaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceQuotas {
+    default: Quota,
+    overrides: HashMap<String, Quota>,
+}
+
+impl NamespaceQuotas {
+    /// Give every namespace `default`
+    pub fn new(default: Quota) -> Self {
+        NamespaceQuotas {
+            default,
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// Give `namespace` its own `quota`
+    pub fn with_override(mut self, namespace: impl Into<String>, quota: Quota) -> Self {
+        self.overrides.insert(namespace.into(), quota);
+        self
+    }
+
+    /// The quota of `namespace`
+    pub fn quota(&self, namespace: &str) -> Quota {
+        self.overrides
+            .get(namespace)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// A quota override of one namespace applying for a limited time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemporaryOverride {
+    /// The namespace
+    pub namespace: String,
+    /// Quota of the namespace while the override applies
+    pub quota: Quota,
+    /// When the override starts to apply
+    pub start: SystemTime,
+    /// When the override lapses and the regular quota applies again
+    pub end: SystemTime,
+}
+
+/// Temporary overrides by namespace, and where to report them
+struct Temporary {
+    overrides: ArcSwap<HashMap<String, TemporaryOverride>>,
+    audit: Option<Arc<dyn AuditSink>>,
+}
+
+impl Temporary {
+    /// The quota of `namespace` if an override applies at `now`, dropping
+    /// it if it has lapsed
+    fn quota(&self, namespace: &str, policy: &str) -> Option<Quota> {
+        let overrides = self.overrides.load();
+        if overrides.is_empty() {
+            return None;
+        }
+        let now = SystemTime::now();
+        let temporary = overrides.get(namespace)?;
+        if temporary.end <= now {
+            drop(overrides);
+            self.expire(now, policy);
+            return None;
+        }
+        (temporary.start <= now).then_some(temporary.quota)
+    }
+
+    /// Drop the overrides lapsed at `now`, reporting each once
+    fn expire(&self, now: SystemTime, policy: &str) {
+        let previous = self.overrides.rcu(|overrides| {
+            overrides
+                .iter()
+                .filter(|(_, temporary)| temporary.end > now)
+                .map(|(namespace, temporary)| (namespace.clone(), temporary.clone()))
+                .collect::<HashMap<_, _>>()
+        });
+        for temporary in previous.values().filter(|temporary| temporary.end <= now) {
+            tracing::info!("Quota override of namespace {} lapsed", temporary.namespace);
+            self.audit(
+                policy,
+                &temporary.namespace,
+                OverrideAction::TemporaryQuotaExpired,
+            );
+        }
+    }
+
+    fn audit(&self, policy: &str, namespace: &str, action: OverrideAction) {
+        if let Some(sink) = &self.audit {
+            sink.record(AuditRecord {
+                time: SystemTime::now(),
+                policy: policy.to_owned(),
+                key: Some(namespace.to_owned()),
+                event: AuditEvent::Override { action },
+            });
+        }
+    }
+}
+
+impl From<Quota> for NamespaceQuotas {
+    fn from(default: Quota) -> Self {
+        NamespaceQuotas::new(default)
+    }
+}
+
+/// Policy limiting the keys of each namespace in a store of their own
+///
+/// Clones share their state.
+pub struct NamespacedPolicy<N, E> {
+    quotas: Arc<ArcSwap<NamespaceQuotas>>,
+    temporary: Arc<Temporary>,
+    namespaces: Arc<Namespaces>,
+    namespace: N,
+    extractor: E,
+    name: Arc<str>,
+    rejection: Arc<RejectionResponse>,
+}
+
+impl<N, E> NamespacedPolicy<N, E> {
+    /// Limit the keys derived by `extractor` to `quotas`, a [`Quota`] or
+    /// [`NamespaceQuotas`], separately in each namespace derived by `namespace`
+    pub fn new(quotas: impl Into<NamespaceQuotas>, namespace: N, extractor: E) -> Self {
+        NamespacedPolicy {
+            quotas: Arc::new(ArcSwap::from_pointee(quotas.into())),
+            temporary: Arc::new(Temporary {
+                overrides: ArcSwap::default(),
+                audit: None,
+            }),
+            namespaces: Arc::default(),
+            namespace,
+            extractor,
+            name: DEFAULT_POLICY_NAME.into(),
+            rejection: Arc::default(),
+        }
+    }
+
+    /// Name the policy in rejections
+    pub fn name(mut self, name: impl Into<Arc<str>>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Respond to rejected requests with `response`
+    pub fn rejection_response(mut self, response: RejectionResponse) -> Self {
+        self.rejection = Arc::new(response);
+        self
+    }
+
+    /// Report temporary quota overrides and their lapse to `sink`
+    pub fn audit(mut self, sink: impl AuditSink) -> Self {
+        self.temporary = Arc::new(Temporary {
+            overrides: ArcSwap::new(self.temporary.overrides.load_full()),
+            audit: Some(Arc::new(sink)),
+        });
+        self
+    }
+
+    /// The quotas in effect
+    pub fn quotas(&self) -> Arc<NamespaceQuotas> {
+        self.quotas.load_full()
+    }
+
+    /// Replace the quotas, e.g. after reloading the configuration
+    ///
+    /// Namespaces whose quota changed start over from a fresh state, like
+    /// [`GovernorHandle::set_quota`](crate::GovernorHandle::set_quota) does.
+    /// The change applies to all clones of the policy.
+    pub fn set_quotas(&self, quotas: NamespaceQuotas) {
+        tracing::info!("Switching namespace quotas to {:?}", quotas);
+        self.quotas.store(Arc::new(quotas));
+    }
+
+    /// Give `namespace` `quota` from now on for `ttl`, then revert to its
+    /// regular quota
+    ///
+    /// Replaces any temporary override of the namespace. Like any quota
+    /// change, the namespace starts over from a fresh state when the
+    /// override starts and lapses.
+    pub fn override_for(&self, namespace: impl Into<String>, quota: Quota, ttl: Duration) {
+        let now = SystemTime::now();
+        self.schedule_override(namespace, quota, now, now + ttl);
+    }
+
+    /// Give `namespace` `quota` from `start` until `end`, see
+    /// [`override_for`](Self::override_for)
+    pub fn schedule_override(
+        &self,
+        namespace: impl Into<String>,
+        quota: Quota,
+        start: SystemTime,
+        end: SystemTime,
+    ) {
+        let namespace = namespace.into();
+        tracing::info!(
+            "Overriding quota of namespace {} with {:?} from {:?} until {:?}",
+            namespace,
+            quota,
+            start,
+            end
+        );
+        let temporary = TemporaryOverride {
+            namespace: namespace.clone(),
+            quota,
+            start,
+            end,
+        };
+        self.temporary.overrides.rcu(|overrides| {
+            let mut overrides = HashMap::clone(overrides);
+            overrides.insert(namespace.clone(), temporary.clone());
+            overrides
+        });
+        self.temporary
+            .audit(&self.name, &namespace, OverrideAction::TemporaryQuota);
+    }
+
+    /// Remove the temporary override of `namespace`, returning it if there was one
+    pub fn cancel_override(&self, namespace: &str) -> Option<TemporaryOverride> {
+        let previous = self.temporary.overrides.rcu(|overrides| {
+            let mut overrides = HashMap::clone(overrides);
+            overrides.remove(namespace);
+            overrides
+        });
+        let cancelled = previous.get(namespace).cloned();
+        if cancelled.is_some() {
+            self.temporary
+                .audit(&self.name, namespace, OverrideAction::TemporaryQuotaExpired);
+        }
+        cancelled
+    }
+
+    /// Temporary overrides that haven't lapsed yet, sorted by namespace
+    pub fn temporary_overrides(&self) -> Vec<TemporaryOverride> {
+        let now = SystemTime::now();
+        let mut overrides: Vec<_> = self
+            .temporary
+            .overrides
+            .load()
+            .values()
+            .filter(|temporary| temporary.end > now)
+            .cloned()
+            .collect();
+        overrides.sort_by(|a, b| a.namespace.cmp(&b.namespace));
+        overrides
+    }
+
+    /// The quota `namespace` is limited to right now
+    pub fn quota(&self, namespace: &str) -> Quota {
+        self.temporary
+            .quota(namespace, &self.name)
+            .unwrap_or_else(|| self.quotas.load().quota(namespace))
+    }
+
+    /// Namespaces with keys currently tracked, sorted
+    pub fn namespaces(&self) -> Vec<String> {
+        let mut namespaces: Vec<_> = self
+            .namespaces
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        namespaces.sort();
+        namespaces
+    }
+
+    /// Number of keys currently tracked in `namespace`
+    pub fn tracked_keys(&self, namespace: &str) -> usize {
+        self.namespaces
+            .get(namespace)
+            .map_or(0, |limiter| limiter.len())
+    }
+
+    /// Forget the state of all keys of `namespace`, returning whether it had any
+    pub fn reset_namespace(&self, namespace: &str) -> bool {
+        tracing::info!("Resetting rate limit state of namespace: {}", namespace);
+        self.namespaces.remove(namespace).is_some()
+    }
+
+    /// Forget the state of `key` in `namespace`, giving it a full burst again
+    pub fn reset_key(&self, namespace: &str, key: &str) {
+        if let Some(limiter) = self.namespaces.get(namespace) {
+            limiter.remove(&key.to_owned());
+        }
+    }
+
+    /// Drop the state of fully replenished keys, namespaces left without
+    /// keys and lapsed temporary overrides
+    pub fn gc(&self) {
+        gc(&self.namespaces);
+        self.temporary.expire(SystemTime::now(), &self.name);
+    }
+
+    /// Run [`gc`](Self::gc) every `interval` on `runtime`, until the policy
+    /// is dropped or the runtime shuts down
+    pub fn gc_every(&self, runtime: impl Runtime, interval: Duration) {
+        let namespaces = Arc::downgrade(&self.namespaces);
+        let temporary = Arc::downgrade(&self.temporary);
+        let name = self.name.clone();
+        let runtime = Arc::new(runtime);
+        let timer = runtime.clone();
+        let mut shutdown = runtime.shutdown();
+        runtime.spawn(Box::pin(async move {
+            while runtime::tick(&*timer, interval, &mut shutdown).await {
+                let (Some(namespaces), Some(temporary)) =
+                    (namespaces.upgrade(), temporary.upgrade())
+                else {
+                    return;
+                };
+                gc(&namespaces);
+                temporary.expire(SystemTime::now(), &name);
+            }
+        }));
+    }
+
+    /// Check a request counted under `key` in `namespace`
+    fn decide(&self, namespace: &str, key: String) -> Result<Decision, GovernorError> {
+        let quota = self.quota(namespace);
+        let current = self
+            .namespaces
+            .get(namespace)
+            .filter(|limiter| limiter.quota() == quota)
+            .map(|limiter| limiter.clone());
+        let limiter = current.unwrap_or_else(|| {
+            let mut limiter = self
+                .namespaces
+                .entry(namespace.to_owned())
+                .or_insert_with(|| Arc::new(KeyedLimiter::new(quota)));
+            // the quota changed since the namespace was last seen
+            if limiter.quota() != quota {
+                *limiter = Arc::new(KeyedLimiter::new(quota));
+            }
+            limiter.clone()
+        });
+        match limiter.check_key(&key) {
+            Ok(snapshot) => Ok(Decision {
+                info: Some(RateLimitInfo::from_snapshot(&snapshot)),
+                exceeded: None,
+            }),
+            Err(not_until) => {
+                tracing::debug!("Rate limit exceeded for key {} in {}", key, namespace);
+                Err(GovernorError::RateLimited(Rejected::new(
+                    self.name.clone(),
+                    Some(clock::wait_time(&not_until)),
+                    self.rejection.clone(),
+                )))
+            }
+        }
+    }
+}
+
+fn gc(namespaces: &Namespaces) {
+    for limiter in namespaces.iter() {
+        limiter.retain_recent();
+    }
+    namespaces.retain(|_, limiter| limiter.len() > 0);
+}
+
+impl<N: Clone, E: Clone> Clone for NamespacedPolicy<N, E> {
+    fn clone(&self) -> Self {
+        NamespacedPolicy {
+            quotas: self.quotas.clone(),
+            temporary: self.temporary.clone(),
+            namespaces: self.namespaces.clone(),
+            namespace: self.namespace.clone(),
+            extractor: self.extractor.clone(),
+            name: self.name.clone(),
+            rejection: self.rejection.clone(),
+        }
+    }
+}
+
+impl<N, E> fmt::Debug for NamespacedPolicy<N, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamespacedPolicy")
+            .field("name", &self.name)
+            .field("quotas", &self.quotas.load())
+            .field(
+                "temporary_overrides",
+                &self.temporary.overrides.load().len(),
+            )
+            .field("namespaces", &self.namespaces.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<N, E, State, Request> Policy<State, Request> for NamespacedPolicy<N, E>
+where
+    N: KeyExtractor<State, Request>,
+    E: KeyExtractor<State, Request>,
+    State: Clone + Send + Sync + 'static,
+    Request: Send + Sync + 'static,
+{
+    type Guard = GovernorGuard;
+    type Error = GovernorError;
+
+    async fn check(
+        &self,
+        mut ctx: Context<State>,
+        request: Request,
+    ) -> PolicyResult<State, Request, Self::Guard, Self::Error> {
+        let namespace = self.namespace.extract(&ctx, &request);
+        let key = self.extractor.extract(&ctx, &request);
+        let output = match namespace.zip(key) {
+            Some((namespace, key)) => match self.decide(&namespace, key.clone()) {
+                Ok(decision) => PolicyOutput::Ready(decision.admit(&mut ctx, &key)),
+                Err(err) => PolicyOutput::Abort(err),
+            },
+            None => PolicyOutput::Ready(GovernorGuard::unlimited()),
+        };
+        PolicyResult {
+            ctx,
+            request,
+            output,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_namespaces_are_isolated() {
+        let policy = NamespacedPolicy::new(
+            Quota::per_minute(NonZeroU32::new(1).unwrap()),
+            |_: &Context<()>, req: &(&str, &str)| Some(req.0.to_owned()),
+            |_: &Context<()>, req: &(&str, &str)| Some(req.1.to_owned()),
+        );
+
+        let mut admitted = Vec::new();
+        for req in [("acme", "alice"), ("acme", "alice"), ("globex", "alice")] {
+            let result = policy.check(Context::default(), req).await;
+            admitted.push(matches!(result.output, PolicyOutput::Ready(_)));
+        }
+        assert_eq!(admitted, [true, false, true]);
+        assert_eq!(policy.namespaces(), ["acme", "globex"]);
+        assert_eq!(policy.tracked_keys("acme"), 1);
+
+        assert!(policy.reset_namespace("acme"));
+        let result = policy.check(Context::default(), ("acme", "alice")).await;
+        assert!(matches!(result.output, PolicyOutput::Ready(_)));
+        assert_eq!(policy.tracked_keys("globex"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reload_namespace_quotas() {
+        let config: crate::NamespaceConfig = serde_json::from_value(serde_json::json!({
+            "default": { "rate": "1r/m" },
+            "overrides": { "acme": { "rate": "1r/m", "burst": 2 } },
+        }))
+        .unwrap();
+        let policy = NamespacedPolicy::new(
+            config.quotas().unwrap(),
+            |_: &Context<()>, req: &&str| Some(req.to_string()),
+            |_: &Context<()>, _: &&str| Some("alice".to_owned()),
+        );
+        let admitted = |namespaces: &'static [&'static str]| {
+            let policy = policy.clone();
+            async move {
+                let mut admitted = 0;
+                for namespace in namespaces {
+                    let result = policy.check(Context::default(), *namespace).await;
+                    admitted += matches!(result.output, PolicyOutput::Ready(_)) as usize;
+                }
+                admitted
+            }
+        };
+        assert_eq!(
+            admitted(&["acme", "acme", "acme", "globex", "globex"]).await,
+            3
+        );
+
+        // globex moves up a tier and starts over with its new quota
+        let tiers = policy.quotas().as_ref().clone();
+        let quota = tiers.quota("acme");
+        policy.set_quotas(tiers.with_override("globex", quota));
+        assert_eq!(admitted(&["acme", "globex", "globex", "globex"]).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_temporary_overrides_lapse() {
+        let records = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let policy = NamespacedPolicy::new(
+            Quota::per_minute(NonZeroU32::new(1).unwrap()),
+            |_: &Context<()>, req: &&str| Some(req.to_string()),
+            |_: &Context<()>, _: &&str| Some("alice".to_owned()),
+        )
+        .name("tenants")
+        .audit(move |record: AuditRecord| sink.lock().unwrap().push(record));
+        let boosted =
+            Quota::per_minute(NonZeroU32::new(1).unwrap()).allow_burst(NonZeroU32::new(3).unwrap());
+        let admitted = |n| {
+            let policy = policy.clone();
+            async move {
+                let mut admitted = 0;
+                for _ in 0..n {
+                    let result = policy.check(Context::default(), "acme").await;
+                    admitted += matches!(result.output, PolicyOutput::Ready(_)) as usize;
+                }
+                admitted
+            }
+        };
+
+        // scheduled for later, the regular quota applies until then
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        policy.schedule_override("globex", boosted, later, later + Duration::from_secs(60));
+        assert_eq!(policy.quota("globex"), policy.quotas().quota("globex"));
+
+        policy.override_for("acme", boosted, Duration::from_millis(50));
+        assert_eq!(admitted(4).await, 3);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        // back to the regular quota, from a fresh state
+        assert_eq!(admitted(2).await, 1);
+        assert_eq!(policy.temporary_overrides().len(), 1);
+
+        let actions: Vec<_> = records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|record| (record.key.clone().unwrap(), record.event.clone()))
+            .collect();
+        let action = |action| AuditEvent::Override { action };
+        assert_eq!(
+            actions,
+            [
+                ("globex".to_owned(), action(OverrideAction::TemporaryQuota)),
+                ("acme".to_owned(), action(OverrideAction::TemporaryQuota)),
+                (
+                    "acme".to_owned(),
+                    action(OverrideAction::TemporaryQuotaExpired)
+                ),
+            ]
+        );
+    }
+}
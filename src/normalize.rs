@@ -0,0 +1,116 @@
+//! Key normalization between extraction and the limiter
+//!
+//! Extracted keys often differ only in spelling: `User@Example.com` and
+//! `user@example.com`, or one address with different source ports. Wrap an
+//! extractor in [`Normalized`] to run its keys through [`KeyNormalizer`] steps
+//! so such variants share one bucket:
+//!
+//! ```ignore
+//! Normalized::new(
+//!     HeaderKey::new(HeaderName::from_static("x-user-email")),
+//!     [KeyNormalizer::Trim, KeyNormalizer::Lowercase],
+//! )
+//! ```
+
+use std::hash::Hasher;
+use std::net::SocketAddr;
+
+use rama_core::Context;
+
+use crate::KeyExtractor;
+use crate::stable_hash::stable_hasher;
+
+/// A single normalization step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(fuzzing, derive(arbitrary::Arbitrary))]
+pub enum KeyNormalizer {
+    /// Remove leading and trailing whitespace
+    Trim,
+    /// Lowercase ASCII letters
+    Lowercase,
+    /// Reduce `ip:port` and `[ipv6]:port` to the address
+    StripPort,
+    /// Keep at most this many bytes, cut at a character boundary
+    Truncate(usize),
+    /// Replace the key with a 64-bit hash in hex, e.g. to bound key size
+    Hash,
+}
+
+impl KeyNormalizer {
+    /// Apply this step to `key`
+    pub fn apply(self, mut key: String) -> String {
+        match self {
+            KeyNormalizer::Trim => key.trim().to_owned(),
+            KeyNormalizer::Lowercase => {
+                key.make_ascii_lowercase();
+                key
+            }
+            KeyNormalizer::StripPort => match key.parse::<SocketAddr>() {
+                Ok(addr) => addr.ip().to_string(),
+                Err(_) => key,
+            },
+            KeyNormalizer::Truncate(len) => {
+                if key.len() > len {
+                    let end = (0..=len).rev().find(|&i| key.is_char_boundary(i));
+                    key.truncate(end.unwrap_or(0));
+                }
+                key
+            }
+            KeyNormalizer::Hash => {
+                let mut hasher = stable_hasher();
+                hasher.write(key.as_bytes());
+                format!("{:016x}", hasher.finish())
+            }
+        }
+    }
+}
+
+/// Extractor normalizing the keys of another extractor
+#[derive(Debug, Clone)]
+pub struct Normalized<E> {
+    extractor: E,
+    steps: Vec<KeyNormalizer>,
+}
+
+impl<E> Normalized<E> {
+    /// Run the keys of `extractor` through `steps`, in order
+    pub fn new(extractor: E, steps: impl IntoIterator<Item = KeyNormalizer>) -> Self {
+        Normalized {
+            extractor,
+            steps: steps.into_iter().collect(),
+        }
+    }
+}
+
+impl<E, State, Request> KeyExtractor<State, Request> for Normalized<E>
+where
+    E: KeyExtractor<State, Request>,
+{
+    fn extract(&self, ctx: &Context<State>, req: &Request) -> Option<String> {
+        let key = self.extractor.extract(ctx, req)?;
+        Some(self.steps.iter().fold(key, |key, step| step.apply(key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_normalizers() {
+        let email = |_: &Context<()>, key: &&str| Some(key.to_string());
+        let normalized = Normalized::new(email, [KeyNormalizer::Trim, KeyNormalizer::Lowercase]);
+        let ctx = Context::default();
+        assert_eq!(
+            normalized.extract(&ctx, &" User@Example.com "),
+            normalized.extract(&ctx, &"user@example.com")
+        );
+
+        let strip = |key: &str| KeyNormalizer::StripPort.apply(key.to_owned());
+        assert_eq!(strip("192.0.2.1:8080"), "192.0.2.1");
+        assert_eq!(strip("[2001:db8::1]:443"), "2001:db8::1");
+        assert_eq!(strip("2001:db8::1"), "2001:db8::1");
+        assert_eq!(KeyNormalizer::Truncate(2).apply("zürich".to_owned()), "z");
+        assert_eq!(KeyNormalizer::Hash.apply("key".to_owned()).len(), 16);
+    }
+}
@@ -0,0 +1,64 @@
+//! OpenTelemetry export of policy decisions
+//!
+//! Instruments are created on the global meter provider, so they report to
+//! whatever provider the application installs. Decisions are also attached
+//! to the active span as `governor.decision`, `governor.retry_after_ms` and
+//! `governor.policy`, so denials show up in distributed traces.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::trace::get_active_span;
+use opentelemetry::{KeyValue, StringValue};
+
+pub(crate) struct OtelInstruments {
+    decisions: Counter<u64>,
+    retry_after: Histogram<f64>,
+}
+
+impl OtelInstruments {
+    pub(crate) fn new() -> Self {
+        let meter = opentelemetry::global::meter("rama-x-governor");
+        OtelInstruments {
+            decisions: meter
+                .u64_counter("governor.decisions")
+                .with_description("Rate limit decisions by policy and outcome")
+                .build(),
+            retry_after: meter
+                .f64_histogram("governor.retry_after")
+                .with_description("Time until a rejected request could succeed")
+                .with_unit("ms")
+                .build(),
+        }
+    }
+
+    pub(crate) fn record(&self, policy: &Arc<str>, allowed: bool, retry_after: Option<Duration>) {
+        let decision = if allowed { "allowed" } else { "denied" };
+        let retry_after_ms = retry_after.map(|d| d.as_secs_f64() * 1000.0);
+
+        let attributes = [
+            // shares the name instead of copying it on every decision
+            KeyValue::new("governor.policy", StringValue::from(policy.clone())),
+            KeyValue::new("governor.decision", decision),
+        ];
+        self.decisions.add(1, &attributes);
+        if let Some(ms) = retry_after_ms {
+            self.retry_after.record(ms, &attributes[..1]);
+        }
+
+        get_active_span(|span| {
+            span.set_attributes(attributes);
+            if let Some(ms) = retry_after_ms {
+                span.set_attribute(KeyValue::new("governor.retry_after_ms", ms as i64));
+            }
+        });
+    }
+}
+
+/// Attach the matcher branch that decided a request to the active span
+pub(crate) fn record_branch(branch: &str) {
+    get_active_span(|span| {
+        span.set_attribute(KeyValue::new("governor.branch", branch.to_owned()));
+    });
+}
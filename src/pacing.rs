@@ -0,0 +1,78 @@
+//! Leaky-bucket pacing of allowed requests
+//!
+//! A policy built with [`pacing`](crate::GovernorPolicyBuilder::pacing)
+//! still admits requests according to its quota, but releases them at the
+//! steady rate of that quota: requests arriving in a burst each reserve the
+//! next free slot and wait for it, so the protected upstream never sees more
+//! than one request per replenish interval and key.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+/// Drop slots that lie in the past every this many new keys
+const GC_EVERY: u64 = 4096;
+
+/// Next free slot per key
+#[derive(Debug)]
+pub struct Pacer {
+    /// Nanoseconds since `start` at which the next request of a key may go
+    slots: DashMap<String, u64>,
+    inserts: AtomicU64,
+    start: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn new() -> Self {
+        Pacer {
+            slots: DashMap::new(),
+            inserts: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /// Reserve the next slot of `key`, returning how long to wait for it
+    pub(crate) fn reserve(&self, key: &str, interval: Duration) -> Duration {
+        let now = self.start.elapsed().as_nanos() as u64;
+        let interval = (interval.as_nanos() as u64).max(1);
+        let slot = match self.slots.get_mut(key) {
+            Some(mut next) => {
+                let slot = (*next).max(now);
+                *next = slot + interval;
+                slot
+            }
+            None => {
+                self.slots.insert(key.to_owned(), now + interval);
+                if self.inserts.fetch_add(1, Ordering::Relaxed) % GC_EVERY == GC_EVERY - 1 {
+                    self.slots.retain(|_, next| *next > now);
+                }
+                now
+            }
+        };
+        Duration::from_nanos(slot - now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{GovernorPolicy, Limiter};
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_pacing() {
+        let limiter = Limiter::from(
+            GovernorPolicy::builder()
+                .per_second(100)
+                .burst_size(5)
+                .pacing(true)
+                .build(),
+        );
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.check().await.unwrap();
+        }
+        // admitted as one burst, released 10ms apart
+        assert!(start.elapsed() >= std::time::Duration::from_millis(40));
+    }
+}
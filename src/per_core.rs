@@ -0,0 +1,330 @@
+//! Per-core cell leases for hot limiters
+//!
+//! A direct policy keeps one GCRA state that every core updates, so in high
+//! throughput proxies the cache line holding it bounces between cores on
+//! every request. [`PerCoreStore`] keeps one lease of cells per core instead:
+//! a thread takes cells from the lease of its core with a single atomic
+//! operation, and only goes to the shared state, holding the full quota, to
+//! lease `1/N` of the burst size once its lease runs out.
+//!
+//! The "core" of a thread is not the CPU it runs on, which may change at any
+//! time, but the order in which threads first used any store, modulo the
+//! number of leases. With one worker thread per core, as in tokio's
+//! multi-threaded runtime, each worker has a lease of its own.
+//!
+//! Cells leased by a core that then goes idle would be missing from the
+//! others, so the leases of a key are reconciled with its shared state when
+//! the key is checked at least
+//! [`reconcile_interval`](PerCoreStore::reconcile_interval) after its last
+//! reconciliation: their leftover cells are returned, and busy cores lease
+//! anew. This costs one lookup per core, whatever the number of keys. Cells
+//! are taken from the shared state before they are handed out, so the quota
+//! is never exceeded; between reconciliations it can be undershot by up to
+//! the leased cells.
+//!
+//! Keys that are no longer checked keep their state until a full
+//! [`reconcile`](PerCoreStore::reconcile), e.g. from
+//! [`reconcile_every`](PerCoreStore::reconcile_every).
+//!
+//! ```ignore
+//! let policy = GovernorPolicy::builder()
+//!     .per_second(100_000)
+//!     .burst_size(10_000)
+//!     .build_with_store(PerCoreStore::new());
+//! ```
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use governor::Quota;
+
+use crate::runtime::{self, Runtime};
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture};
+
+/// Interval between reconciliations unless configured otherwise
+const DEFAULT_RECONCILE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Source of the thread indices picking the lease of a thread
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Index of the current thread, handed out in order of first use; not a
+    /// CPU index
+    static THREAD: usize = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Shared GCRA state of a key
+#[derive(Default)]
+struct Bucket {
+    /// Theoretical arrival time, in nanoseconds since the store was created
+    tat: AtomicU64,
+    /// Emission interval of the quota cells were last taken at, in nanoseconds
+    interval: AtomicU64,
+    /// Time the leases of the key were last reconciled, in nanoseconds
+    reconciled: AtomicU64,
+}
+
+/// A [`RateLimitStore`] serving checks from per-core leases of a shared quota
+pub struct PerCoreStore {
+    /// Leftover leased cells per key, one map per core
+    leases: Box<[DashMap<String, AtomicU32>]>,
+    /// The full quota, per key
+    shared: DashMap<String, Bucket>,
+    reconcile_interval: Duration,
+    start: Instant,
+}
+
+impl PerCoreStore {
+    /// Create a store with one lease per available core
+    pub fn new() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_cores(cores)
+    }
+
+    /// Create a store with `cores` leases, assigned to threads in the order
+    /// they first check a key, wrapping around
+    pub fn with_cores(cores: usize) -> Self {
+        PerCoreStore {
+            leases: (0..cores.max(1)).map(|_| DashMap::new()).collect(),
+            shared: DashMap::new(),
+            reconcile_interval: DEFAULT_RECONCILE_INTERVAL,
+            start: Instant::now(),
+        }
+    }
+
+    /// Return the leftover leased cells of a key at most every `interval`,
+    /// 10ms by default
+    ///
+    /// The leases of a key are reconciled on its first check after the
+    /// interval, no runtime needed.
+    pub fn reconcile_interval(mut self, interval: Duration) -> Self {
+        self.reconcile_interval = interval;
+        self
+    }
+
+    /// Number of leases, see the [module docs](self) on what a core is
+    pub fn core_count(&self) -> usize {
+        self.leases.len()
+    }
+
+    /// Return the leftover cells of all leases to the shared state
+    ///
+    /// Also forgets leases with no cells left and keys whose bucket is full.
+    /// Walks every key, so call it from a timer rather than per request.
+    pub fn reconcile(&self) {
+        let now = self.now();
+        for leases in &self.leases {
+            leases.retain(|key, cells| {
+                let cells = cells.swap(0, Ordering::AcqRel);
+                if cells > 0 {
+                    self.give_back(key, cells);
+                }
+                cells > 0
+            });
+        }
+        self.shared.retain(|_, bucket| *bucket.tat.get_mut() > now);
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    /// Leases of the core of the current thread
+    fn local(&self) -> &DashMap<String, AtomicU32> {
+        &self.leases[THREAD.with(|index| *index) % self.leases.len()]
+    }
+
+    /// [`reconcile`](Self::reconcile) on `runtime` every `interval`, until
+    /// the store is dropped or the runtime shuts down
+    pub fn reconcile_every(self: &Arc<Self>, runtime: impl Runtime, interval: Duration) {
+        let store = Arc::downgrade(self);
+        let runtime = Arc::new(runtime);
+        let timer = runtime.clone();
+        let mut shutdown = runtime.shutdown();
+        runtime.spawn(Box::pin(async move {
+            while runtime::tick(&*timer, interval, &mut shutdown).await {
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                store.reconcile();
+            }
+        }));
+    }
+
+    /// Return the leftover cells of the leases of `key` if the interval has
+    /// passed since they were last returned
+    fn reconcile_key_if_due(&self, key: &str, now: u64) {
+        let Some(bucket) = self.shared.get(key) else {
+            return;
+        };
+        let last = bucket.reconciled.load(Ordering::Relaxed);
+        let interval = self.reconcile_interval.as_nanos() as u64;
+        if now.saturating_sub(last) < interval
+            || bucket
+                .reconciled
+                .compare_exchange(last, now, Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        drop(bucket);
+        for leases in &self.leases {
+            let cells = leases
+                .get(key)
+                .map_or(0, |cells| cells.swap(0, Ordering::AcqRel));
+            if cells > 0 {
+                self.give_back(key, cells);
+            }
+        }
+    }
+
+    /// Take `n` cells from the lease of the current core
+    fn take_leased(&self, key: &str, n: u32) -> bool {
+        self.local().get(key).is_some_and(|cells| {
+            cells
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |cells| {
+                    cells.checked_sub(n)
+                })
+                .is_ok()
+        })
+    }
+
+    /// Take `n` cells from the shared state of `key`
+    fn take_shared(&self, key: &str, quota: &Quota, n: u32, now: u64) -> StoreDecision {
+        let t = (quota.replenish_interval().as_nanos() as u64).max(1);
+        let tau = t * u64::from(quota.burst_size().get());
+        let bucket = match self.shared.get(key) {
+            Some(bucket) => bucket,
+            None => self.shared.entry(key.to_owned()).or_default().downgrade(),
+        };
+        bucket.interval.store(t, Ordering::Relaxed);
+        let mut retry_after = 0;
+        let taken = bucket
+            .tat
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tat| {
+                let new_tat = tat.max(now) + u64::from(n) * t;
+                let allow_at = new_tat.saturating_sub(tau);
+                retry_after = allow_at.saturating_sub(now);
+                (allow_at <= now).then_some(new_tat)
+            });
+        match taken {
+            Ok(_) => StoreDecision::Allowed,
+            Err(_) => StoreDecision::Denied {
+                retry_after: Duration::from_nanos(retry_after),
+            },
+        }
+    }
+
+    /// Return `cells` leased cells of `key` to its shared state
+    fn give_back(&self, key: &str, cells: u32) {
+        if let Some(bucket) = self.shared.get(key) {
+            let interval = bucket.interval.load(Ordering::Relaxed);
+            let _ = bucket
+                .tat
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tat| {
+                    Some(tat.saturating_sub(u64::from(cells) * interval))
+                });
+        }
+    }
+
+    pub(crate) fn check(&self, key: &str, quota: Quota, n: u32) -> StoreDecision {
+        let now = self.now();
+        self.reconcile_key_if_due(key, now);
+        if self.take_leased(key, n) {
+            return StoreDecision::Allowed;
+        }
+
+        let burst = quota.burst_size().get();
+        let lease = (burst / self.leases.len() as u32).clamp(n, burst.max(n));
+        if lease > n && self.take_shared(key, &quota, lease, now) == StoreDecision::Allowed {
+            self.local()
+                .entry(key.to_owned())
+                .or_default()
+                .fetch_add(lease - n, Ordering::AcqRel);
+            return StoreDecision::Allowed;
+        }
+        // close to the limit, take just what is needed
+        self.take_shared(key, &quota, n, now)
+    }
+}
+
+impl Default for PerCoreStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for PerCoreStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PerCoreStore")
+            .field("cores", &self.leases.len())
+            .field("reconcile_interval", &self.reconcile_interval)
+            .finish()
+    }
+}
+
+impl RateLimitStore for PerCoreStore {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        let decision = self.check(key, quota, n);
+        Box::pin(async move { Ok(decision) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    #[test]
+    fn test_leases_reconciled() {
+        let store = PerCoreStore::with_cores(4).reconcile_interval(Duration::from_secs(3600));
+        let quota =
+            Quota::per_hour(NonZeroU32::new(1).unwrap()).allow_burst(NonZeroU32::new(8).unwrap());
+        let check = || store.check("default", quota, 1) == StoreDecision::Allowed;
+
+        // leases two of the eight cells, one of them left over
+        assert!(check());
+        // another core leases two more
+        std::thread::scope(|scope| {
+            scope.spawn(|| assert!(check()));
+        });
+        let allowed = (0..8).take_while(|_| check()).count();
+        assert_eq!(allowed, 5);
+
+        // the cell left over on the idle core comes back
+        store.reconcile();
+        assert!(check());
+        assert!(!check());
+    }
+
+    #[test]
+    fn test_key_reconciled_on_check() {
+        let store = PerCoreStore::with_cores(2).reconcile_interval(Duration::from_millis(20));
+        let quota =
+            Quota::per_hour(NonZeroU32::new(1).unwrap()).allow_burst(NonZeroU32::new(4).unwrap());
+        let check = |key| store.check(key, quota, 1) == StoreDecision::Allowed;
+
+        // another core leases two cells and leaves one over
+        std::thread::scope(|scope| {
+            scope.spawn(|| assert!(check("alice")));
+        });
+        assert!(check("bob"));
+        std::thread::sleep(Duration::from_millis(30));
+        // checking alice returns her leftover cell, bob's lease stays put
+        assert_eq!((0..4).take_while(|_| check("alice")).count(), 3);
+        let leased: u32 = store
+            .leases
+            .iter()
+            .filter_map(|leases| leases.get("bob").map(|cells| cells.load(Ordering::Relaxed)))
+            .sum();
+        assert_eq!(leased, 1);
+    }
+}
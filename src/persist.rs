@@ -0,0 +1,165 @@
+//! Periodic persistence of keyed limiter state to disk
+//!
+//! For single-node deployments without a shared backend: the state of a keyed
+//! policy is written to a JSON file every interval and restored when the
+//! policy is built again.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+
+use crate::GovernorKey;
+use crate::keyed::KeyedLimiter;
+use crate::runtime::{self, Runtime};
+use crate::snapshot::{self, KeyCodec, StateSnapshot};
+use crate::sync::StartOnce;
+
+/// Where and how often to persist the state of a keyed limiter
+#[derive(Debug, Clone)]
+pub(crate) struct PersistConfig {
+    pub(crate) path: PathBuf,
+    pub(crate) interval: Duration,
+}
+
+/// Writes the state of a keyed limiter to a file at a fixed interval
+pub(crate) struct Persister<K> {
+    path: PathBuf,
+    interval: Duration,
+    codec: KeyCodec<K>,
+    started: StartOnce,
+}
+
+impl<K: GovernorKey> Persister<K> {
+    pub(crate) fn new(config: PersistConfig, codec: KeyCodec<K>) -> Self {
+        Persister {
+            path: config.path,
+            interval: config.interval,
+            codec,
+            started: StartOnce::new(),
+        }
+    }
+
+    /// Restore previously persisted state, if any
+    pub(crate) fn restore(&self, limiter: &KeyedLimiter<K>) {
+        let snapshot = match read_snapshot(&self.path) {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) => return,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to read rate limit state from {}: {}",
+                    self.path.display(),
+                    err
+                );
+                return;
+            }
+        };
+
+        match snapshot::import(limiter, self.codec, &snapshot) {
+            Ok(count) => tracing::info!(
+                "Restored rate limit state for {} keys from {}",
+                count,
+                self.path.display()
+            ),
+            Err(err) => tracing::warn!(
+                "Failed to restore rate limit state from {}: {}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+
+    /// Persist the current state of the limiter
+    pub(crate) fn save(&self, limiter: &KeyedLimiter<K>) {
+        let result = snapshot::export(limiter, self.codec)
+            .map_err(io::Error::other)
+            .and_then(|snapshot| write_snapshot(&self.path, &snapshot));
+        if let Err(err) = result {
+            tracing::error!(
+                "Failed to persist rate limit state to {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    /// Spawn the periodic persistence task, once
+    pub(crate) fn start_if_needed(
+        self: &Arc<Self>,
+        runtime: &Arc<dyn Runtime>,
+        limiter: Weak<ArcSwap<KeyedLimiter<K>>>,
+    ) {
+        self.started.call(|| {
+            let persister = Arc::downgrade(self);
+            let interval = self.interval;
+            let timer = runtime.clone();
+            let mut shutdown = runtime.shutdown();
+            runtime.spawn(Box::pin(async move {
+                loop {
+                    let running = runtime::tick(&*timer, interval, &mut shutdown).await;
+                    let (Some(persister), Some(limiter)) = (persister.upgrade(), limiter.upgrade())
+                    else {
+                        return;
+                    };
+                    persister.save(&limiter.load());
+                    if !running {
+                        return;
+                    }
+                }
+            }));
+        });
+    }
+}
+
+fn read_snapshot(path: &Path) -> io::Result<Option<StateSnapshot>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Write through a temporary file so a crash never leaves a truncated snapshot behind
+fn write_snapshot(path: &Path, snapshot: &StateSnapshot) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    fs::write(&tmp, serde_json::to_vec(snapshot)?)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use governor::Quota;
+    use std::num::NonZeroU32;
+
+    #[test]
+    fn test_persist_and_restore() {
+        let path = std::env::temp_dir().join(format!(
+            "rama-x-governor-persist-{}.json",
+            std::process::id()
+        ));
+        let quota = Quota::per_minute(NonZeroU32::new(1).unwrap());
+        let config = PersistConfig {
+            path: path.clone(),
+            interval: Duration::from_secs(60),
+        };
+        let persister = Persister::new(config, KeyCodec::serde());
+
+        let limiter = KeyedLimiter::new(quota);
+        assert!(limiter.check_key(&"alice".to_owned()).is_ok());
+        persister.save(&limiter);
+
+        let restored = KeyedLimiter::new(quota);
+        persister.restore(&restored);
+        assert!(restored.check_key(&"alice".to_owned()).is_err());
+        assert!(restored.check_key(&"bob".to_owned()).is_ok());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+//! Reviewing rule set changes before applying them
+//!
+//! Reloading a [`GovernorConfig`] can silently reset the state of many keys,
+//! or stop limiting a route. [`ConfigPolicy::plan`] compares a new rule set
+//! with the one in effect and returns a [`ConfigPlan`] listing the rules
+//! added, removed and changed, and how many tracked keys each change affects,
+//! for an operator to review before [`ConfigPolicy::apply`] makes it so:
+//!
+//! ```ignore
+//! let plan = policy.plan(new_config)?;
+//! println!("{plan}");
+//! if confirmed() {
+//!     policy.apply(plan);
+//! }
+//! ```
+//!
+//! Rules are matched by name. Changes to the paths and exemptions of a rule,
+//! or its position, keep its state; changes to its rate, burst size, key or
+//! algorithm start it over with a fresh state.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::config::{ConfigRule, RuleConfig};
+use crate::{ConfigPolicy, Diagnostic, GovernorConfig};
+
+/// How a rule changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The rule is new
+    Added,
+    /// The rule is gone, along with the state of its keys
+    Removed,
+    /// The paths, exemptions or position of the rule change, its state is kept
+    Retargeted,
+    /// The rate, burst size, key or algorithm of the rule change, and its
+    /// keys start over with a full burst
+    Replaced,
+}
+
+/// A change of one rule, see [`ConfigPlan`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleChange {
+    /// Name of the rule
+    pub rule: String,
+    /// How it changes
+    pub kind: ChangeKind,
+    /// Number of keys tracked in memory whose state is dropped or reset
+    pub affected_keys: usize,
+}
+
+/// Changes to the rules of a [`ConfigPolicy`], see [`ConfigPolicy::plan`]
+#[derive(Debug, Clone)]
+pub struct ConfigPlan {
+    config: GovernorConfig,
+    changes: Vec<RuleChange>,
+}
+
+impl ConfigPlan {
+    /// The changes, in the order of the new rules followed by removed rules
+    pub fn changes(&self) -> &[RuleChange] {
+        &self.changes
+    }
+
+    /// Whether applying the plan changes nothing
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The rule set the plan applies
+    pub fn config(&self) -> &GovernorConfig {
+        &self.config
+    }
+}
+
+impl fmt::Display for ConfigPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "No changes");
+        }
+        for change in &self.changes {
+            let (sign, what) = match change.kind {
+                ChangeKind::Added => ("+", "added"),
+                ChangeKind::Removed => ("-", "removed"),
+                ChangeKind::Retargeted => ("~", "retargeted, state kept"),
+                ChangeKind::Replaced => ("-/+", "replaced"),
+            };
+            write!(f, "{sign} rule {:?}: {what}", change.rule)?;
+            if change.affected_keys > 0 {
+                write!(f, ", {} keys lose their state", change.affected_keys)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether changing `old` into `new` needs a new policy
+fn replaces(old: &RuleConfig, new: &RuleConfig) -> bool {
+    old.rate != new.rate
+        || old.burst != new.burst
+        || old.key != new.key
+        || old.algorithm != new.algorithm
+}
+
+impl ConfigPolicy {
+    /// Compare `config` with the rules in effect, without changing anything
+    ///
+    /// Fails on the error diagnostics of `config`, like
+    /// [`GovernorConfig::build`] does.
+    pub fn plan(&self, config: GovernorConfig) -> Result<ConfigPlan, Vec<Diagnostic>> {
+        config.check()?;
+        let rules = self.rules.load();
+        let current: HashMap<_, _> = rules
+            .iter()
+            .enumerate()
+            .map(|(position, rule)| (rule.config.name.as_str(), (position, rule)))
+            .collect();
+
+        let mut changes = Vec::new();
+        for (position, new) in config.rules.iter().enumerate() {
+            let change = match current.get(new.name.as_str()) {
+                None => Some((ChangeKind::Added, 0)),
+                Some((_, old)) if replaces(&old.config, new) => {
+                    Some((ChangeKind::Replaced, old.policy.policy().len()))
+                }
+                Some((old_position, old)) if *old_position != position || old.config != *new => {
+                    Some((ChangeKind::Retargeted, 0))
+                }
+                Some(_) => None,
+            };
+            if let Some((kind, affected_keys)) = change {
+                changes.push(RuleChange {
+                    rule: new.name.clone(),
+                    kind,
+                    affected_keys,
+                });
+            }
+        }
+        for old in rules.iter() {
+            if !config.rules.iter().any(|new| new.name == old.config.name) {
+                changes.push(RuleChange {
+                    rule: old.config.name.clone(),
+                    kind: ChangeKind::Removed,
+                    affected_keys: old.policy.policy().len(),
+                });
+            }
+        }
+        Ok(ConfigPlan { config, changes })
+    }
+
+    /// Switch to the rules of `plan`
+    ///
+    /// Rules are matched by name against the rules in effect when applying,
+    /// so a plan made before another change still applies as reviewed where
+    /// the two don't overlap. Requests already being checked finish with
+    /// the rules they started with.
+    pub fn apply(&self, plan: ConfigPlan) {
+        tracing::info!("Applying rule set changes:\n{}", plan);
+        self.rules.rcu(|rules| {
+            plan.config
+                .rules
+                .iter()
+                .map(|new| {
+                    let kept = rules
+                        .iter()
+                        .find(|old| old.config.name == new.name && !replaces(&old.config, new));
+                    match kept {
+                        Some(old) => ConfigRule {
+                            config: new.clone(),
+                            policy: old.policy.clone(),
+                        },
+                        None => ConfigRule::build(new),
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+    use rama_http::Request;
+
+    use super::*;
+
+    fn rule(name: &str, rate: &str, paths: &[&str]) -> RuleConfig {
+        RuleConfig {
+            name: name.to_owned(),
+            rate: rate.to_owned(),
+            burst: None,
+            key: None,
+            algorithm: None,
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+            exempt: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plan_and_apply() {
+        let per_user = |rate| RuleConfig {
+            key: Some("header:x-user".to_owned()),
+            ..rule("api", rate, &["/api"])
+        };
+        let policy = GovernorConfig {
+            rules: vec![
+                per_user("1r/m"),
+                rule("login", "1r/m", &["/login"]),
+                rule("legacy", "1r/m", &["/v1"]),
+            ],
+        }
+        .build()
+        .unwrap();
+        let admitted = async |path: &str| {
+            let req = Request::builder()
+                .uri(path)
+                .header("x-user", "alice")
+                .body(())
+                .unwrap();
+            let result = policy.check(Context::<()>::default(), req).await;
+            matches!(result.output, PolicyOutput::Ready(_))
+        };
+        assert!(admitted("/login").await);
+        assert!(admitted("/api").await);
+
+        let plan = policy
+            .plan(GovernorConfig {
+                rules: vec![
+                    rule("login", "1r/m", &["/login", "/signin"]),
+                    per_user("2r/m"),
+                    rule("search", "1r/m", &["/search"]),
+                ],
+            })
+            .unwrap();
+        let changes: Vec<_> = plan
+            .changes()
+            .iter()
+            .map(|change| (change.rule.as_str(), change.kind))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("login", ChangeKind::Retargeted),
+                ("api", ChangeKind::Replaced),
+                ("search", ChangeKind::Added),
+                ("legacy", ChangeKind::Removed),
+            ]
+        );
+        assert_eq!(plan.changes()[1].affected_keys, 1);
+        // planning changed nothing
+        assert!(admitted("/signin").await);
+        assert!(policy.rule("legacy").is_some());
+
+        policy.apply(plan);
+        assert!(policy.rule("legacy").is_none());
+        // login kept its state, and now covers /signin
+        assert!(!admitted("/signin").await);
+        assert!(admitted("/search").await);
+        assert!(policy.plan(policy.config()).unwrap().is_empty());
+    }
+}
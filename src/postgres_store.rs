@@ -0,0 +1,181 @@
+//! PostgreSQL backed rate limit store
+//!
+//! Meant for low-traffic limits where correctness matters more than latency,
+//! like "3 password resets per day". Every check is a single atomic upsert
+//! evaluated against the database clock, so the limit holds across any number
+//! of instances and survives restarts.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use governor::Quota;
+use tokio_postgres::Client;
+
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture, gcra_micros};
+
+const NOW_MICROS: &str = "(extract(epoch from clock_timestamp()) * 1000000)::bigint";
+
+/// A [`RateLimitStore`] keeping GCRA state in a PostgreSQL table
+///
+/// Parameters of the generated statements: `$1` key, `$2` cells, `$3` emission
+/// interval and `$4` burst tolerance, all times in microseconds.
+pub struct PostgresStore {
+    client: Arc<Client>,
+    table: String,
+    check_sql: String,
+    retry_sql: String,
+}
+
+impl PostgresStore {
+    /// Use `client`, keeping state in the `governor_limits` table
+    pub fn new(client: Arc<Client>) -> Self {
+        Self::with_table(client, "governor_limits")
+    }
+
+    /// Use `client`, keeping state in `table`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `table` is not a plain identifier (ASCII letters, digits and `_`).
+    pub fn with_table(client: Arc<Client>, table: &str) -> Self {
+        assert!(
+            !table.is_empty() && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "invalid table name {table:?}"
+        );
+
+        // parameters are cast once, at their first use: untyped, Postgres
+        // can't pick the operators they are used with
+        let check_sql = format!(
+            "WITH now AS (SELECT {NOW_MICROS} AS ts) \
+             INSERT INTO {table} AS l (key, tat) \
+             SELECT $1::text, now.ts + $2::bigint * $3::bigint FROM now \
+             WHERE $2 * $3 <= $4::bigint \
+             ON CONFLICT (key) DO UPDATE \
+             SET tat = GREATEST(l.tat, (SELECT ts FROM now)) + $2 * $3 \
+             WHERE GREATEST(l.tat, (SELECT ts FROM now)) + $2 * $3 - $4 <= (SELECT ts FROM now) \
+             RETURNING l.tat"
+        );
+        let retry_sql = format!(
+            "SELECT GREATEST(l.tat, now.ts) + $2::bigint * $3::bigint - $4::bigint - now.ts \
+             FROM {table} AS l, (SELECT {NOW_MICROS} AS ts) AS now WHERE l.key = $1::text"
+        );
+
+        PostgresStore {
+            client,
+            table: table.to_owned(),
+            check_sql,
+            retry_sql,
+        }
+    }
+
+    /// Create the state table if it doesn't exist yet
+    pub async fn create_table(&self) -> Result<(), StoreError> {
+        self.client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, tat BIGINT NOT NULL)",
+                self.table
+            ))
+            .await
+            .map_err(StoreError::new)
+    }
+
+    /// Delete rows of keys that are fully replenished, returning how many were removed
+    ///
+    /// Rows are never removed on their own, so call this periodically.
+    pub async fn prune(&self) -> Result<u64, StoreError> {
+        self.client
+            .execute(
+                &format!("DELETE FROM {} WHERE tat < {NOW_MICROS}", self.table),
+                &[],
+            )
+            .await
+            .map_err(StoreError::new)
+    }
+}
+
+impl std::fmt::Debug for PostgresStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PostgresStore")
+            .field("table", &self.table)
+            .finish()
+    }
+}
+
+impl RateLimitStore for PostgresStore {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        Box::pin(async move {
+            let (t, tau) = gcra_micros(&quota);
+            let (n, t, tau) = (i64::from(n), t as i64, tau as i64);
+
+            let row = self
+                .client
+                .query_opt(&self.check_sql, &[&key, &n, &t, &tau])
+                .await
+                .map_err(StoreError::new)?;
+            if row.is_some() {
+                return Ok(StoreDecision::Allowed);
+            }
+
+            let retry_after = self
+                .client
+                .query_opt(&self.retry_sql, &[&key, &n, &t, &tau])
+                .await
+                .map_err(StoreError::new)?
+                .map(|row| row.get::<_, i64>(0))
+                // a fresh key only fails when `n` exceeds the burst size
+                .unwrap_or(tau);
+            Ok(StoreDecision::Denied {
+                retry_after: Duration::from_micros(retry_after.max(0) as u64),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connection string of the server the tests run against
+    fn url() -> String {
+        std::env::var("POSTGRES_URL").unwrap_or_else(|_| "host=127.0.0.1 user=postgres".to_owned())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL server, taken from POSTGRES_URL"]
+    async fn test_postgres_store() {
+        let (client, connection) = tokio_postgres::connect(&url(), tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let store = PostgresStore::with_table(Arc::new(client), "governor_limits_test");
+        store.create_table().await.unwrap();
+        store
+            .client
+            .execute("DELETE FROM governor_limits_test", &[])
+            .await
+            .unwrap();
+
+        let quota = Quota::per_hour(std::num::NonZeroU32::new(1).unwrap())
+            .allow_burst(std::num::NonZeroU32::new(2).unwrap());
+        let mut decisions = Vec::new();
+        for _ in 0..3 {
+            decisions.push(store.check_n("alice", quota, 1).await.unwrap());
+        }
+        assert_eq!(decisions[..2], [StoreDecision::Allowed; 2]);
+        let StoreDecision::Denied { retry_after } = decisions[2] else {
+            panic!("third request admitted");
+        };
+        assert!(retry_after > Duration::from_secs(3500));
+        // more cells than the burst never fit, even for a fresh key
+        assert!(matches!(
+            store.check_n("bob", quota, 3).await.unwrap(),
+            StoreDecision::Denied { .. }
+        ));
+        assert_eq!(store.prune().await.unwrap(), 0);
+    }
+}
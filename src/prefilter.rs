@@ -0,0 +1,170 @@
+//! Counting pre-filter deferring state for first-time keys
+//!
+//! Scanners and crawlers hit a keyed policy with huge numbers of keys that
+//! are never seen again, and each one allocates limiter state until the next
+//! GC. A policy built with [`prefilter`](crate::GovernorPolicyBuilder::prefilter)
+//! counts sightings of keys in a fixed-size count-min sketch first, and only
+//! allocates state for keys that made more requests than a threshold, counting
+//! each at its cost. The requests a key made before that are charged to its
+//! state once it is allocated, so the quota still holds. The threshold stays
+//! below the burst size of the current quota, also after it is swapped.
+//!
+//! The sketch may overcount, which only means state is allocated earlier.
+//! Counts are halved periodically so keys that went quiet are forgotten; a
+//! tracked key whose count drops below the threshold again may so be let
+//! through a few times beyond its quota.
+
+use std::hash::{BuildHasher, RandomState};
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use governor::Quota;
+
+/// Counters in the sketch, 64 KiB
+const SLOTS: usize = 1 << 16;
+/// Counters incremented per key
+const HASHES: u64 = 3;
+/// Halve all counts every this many sightings
+const AGE_EVERY: u64 = 10 * SLOTS as u64;
+
+/// What to do with a sighting of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Sighting {
+    /// Let the request through without limiter state; the key used this many
+    /// cells so far, including this request
+    Untracked(u32),
+    /// Allocate state for the key, charging this many earlier cells first
+    Track(u32),
+    /// The key is tracked already
+    Tracked,
+}
+
+/// Count-min sketch of key sightings
+pub(crate) struct KeyPrefilter {
+    threshold: u8,
+    counters: Box<[AtomicU8]>,
+    sightings: AtomicU64,
+    hasher: RandomState,
+}
+
+impl KeyPrefilter {
+    /// Defer state for keys until they used more than `threshold` cells
+    pub(crate) fn new(threshold: u8) -> Self {
+        KeyPrefilter {
+            // counters saturate, so a higher threshold could never be passed
+            threshold: threshold.min(u8::MAX - 1),
+            counters: (0..SLOTS).map(|_| AtomicU8::new(0)).collect(),
+            sightings: AtomicU64::new(0),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Count a sighting of `key` costing `cost` cells of `quota`
+    pub(crate) fn observe(&self, key: &str, cost: NonZeroU32, quota: Quota) -> Sighting {
+        let cost = u8::try_from(cost.get()).unwrap_or(u8::MAX);
+        let hash = self.hasher.hash_one(key);
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        let previous = (0..HASHES)
+            .map(|i| {
+                let slot = h1.wrapping_add(i.wrapping_mul(h2)) as usize % SLOTS;
+                self.counters[slot]
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                        Some(count.saturating_add(cost))
+                    })
+                    .unwrap_or(u8::MAX)
+            })
+            .min()
+            .unwrap_or(u8::MAX);
+        let count = previous.saturating_add(cost);
+
+        if self.sightings.fetch_add(1, Ordering::Relaxed) % AGE_EVERY == AGE_EVERY - 1 {
+            self.age();
+        }
+
+        // untracked requests are charged later, so they must fit in a burst
+        let max = quota.burst_size().get().saturating_sub(1);
+        let threshold = u8::try_from(max).unwrap_or(u8::MAX).min(self.threshold);
+        if count <= threshold {
+            Sighting::Untracked(u32::from(count))
+        } else if previous <= threshold {
+            Sighting::Track(u32::from(previous))
+        } else {
+            Sighting::Tracked
+        }
+    }
+
+    /// Halve all counts
+    fn age(&self) {
+        for counter in self.counters.iter() {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                Some(count / 2)
+            });
+        }
+    }
+}
+
+impl std::fmt::Debug for KeyPrefilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyPrefilter")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use governor::Quota;
+
+    use crate::{GovernorError, GovernorPolicy, Limiter};
+
+    #[tokio::test]
+    async fn test_prefilter() {
+        let limiter = Limiter::from(
+            GovernorPolicy::builder()
+                .per_minute(3)
+                .prefilter(2)
+                .build_with_keyer(|key: &str| key.to_owned()),
+        );
+
+        for i in 0..100 {
+            limiter.check_key(&format!("scanner-{i}")).await.unwrap();
+        }
+        assert_eq!(limiter.policy().status().tracked_keys, Some(0));
+
+        // the two untracked requests are charged once the key is tracked
+        for _ in 0..3 {
+            limiter.check_key("client").await.unwrap();
+        }
+        assert!(matches!(
+            limiter.check_key("client").await,
+            Err(GovernorError::RateLimited(_))
+        ));
+        assert_eq!(limiter.policy().status().tracked_keys, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_prefilter_counts_cost() {
+        let limiter = Limiter::from(
+            GovernorPolicy::builder()
+                .per_minute(4)
+                .prefilter(3)
+                .build_with_keyer(|key: &str| key.to_owned()),
+        );
+
+        // the first request costs three cells, all charged once tracked
+        limiter.check_n_for("client", 3).await.unwrap();
+        assert_eq!(limiter.policy().status().tracked_keys, Some(0));
+        limiter.check_key("client").await.unwrap();
+        assert!(limiter.check_key("client").await.is_err());
+
+        // a smaller quota lowers the threshold below its burst size
+        limiter
+            .handle()
+            .set_quota(Quota::per_minute(NonZeroU32::new(2).unwrap()));
+        limiter.check_key("other").await.unwrap();
+        limiter.check_key("other").await.unwrap();
+        assert!(limiter.check_key("other").await.is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! Preconfigured policies for common endpoints
+//!
+//! Each preset returns a [`PolicySpec`]: a builder with a quota, burst,
+//! rejection and escalation behavior that work well as a starting point, and
+//! the [`KeySource`] requests are counted under. Adjust them like any parsed
+//! policy before building:
+//!
+//! ```ignore
+//! let mut login = presets::login();
+//! login.builder = login.builder.burst_size(3);
+//! let policy = login.build();
+//! ```
+//!
+//! Presets are named after the endpoint they are meant for, so their metrics
+//! and logs are told apart without further configuration.
+
+use std::time::Duration;
+
+use crate::{
+    DenialLogSampling, GovernorPolicyBuilder, KeySource, PolicySpec, RejectionResponse, RetryAfter,
+    X_API_KEY,
+};
+
+/// General purpose public API: 10 requests per second with a burst of 20
+///
+/// Keyed by the `x-api-key` header, and anonymous requests by client IP.
+/// Denials are logged at most once per second per key, as a misbehaving
+/// client tends to hammer the limit.
+pub fn public_api() -> PolicySpec {
+    PolicySpec {
+        builder: GovernorPolicyBuilder::new()
+            .per_second(10)
+            .burst_size(20)
+            .name("public-api")
+            .denial_log_sampling(DenialLogSampling::PerKeyPerSecond(1)),
+        key: KeySource::HeaderOrIp(X_API_KEY),
+    }
+}
+
+/// Incoming webhooks: 50 requests per second with a burst of 200
+///
+/// Keyed by client IP, the sender. Senders deliver in bursts after outages
+/// and retry on their own schedule, so the burst is large and the advertised
+/// retry-after is rounded up to whole seconds.
+pub fn webhook_receiver() -> PolicySpec {
+    PolicySpec {
+        builder: GovernorPolicyBuilder::new()
+            .per_second(50)
+            .burst_size(200)
+            .name("webhook-receiver")
+            .rejection_response(
+                RejectionResponse::new().retry_after(RetryAfter::RoundUp(Duration::from_secs(1))),
+            ),
+        key: KeySource::Ip,
+    }
+}
+
+/// Login and other credential checks: 5 attempts per minute
+///
+/// Keyed by client IP; key by account instead to slow down credential
+/// stuffing from many addresses. The retry-after is jittered by up to 30
+/// seconds so scripted clients can't line up their next attempts, and an
+/// address that used up its attempts is rejected without touching the
+/// limiter until it may retry.
+pub fn login() -> PolicySpec {
+    PolicySpec {
+        builder: GovernorPolicyBuilder::new()
+            .per_minute(5)
+            .burst_size(5)
+            .name("login")
+            .rejection_response(
+                RejectionResponse::new().retry_after(RetryAfter::Jittered(Duration::from_secs(30))),
+            )
+            .decision_cache(Duration::from_secs(10)),
+        key: KeySource::Ip,
+    }
+}
+
+/// Search and other expensive queries: 2 requests per second with a burst of 10
+///
+/// Keyed by client IP. The burst covers type-ahead search, the low rate keeps
+/// scrapers from walking the index.
+pub fn search() -> PolicySpec {
+    PolicySpec {
+        builder: GovernorPolicyBuilder::new()
+            .per_second(2)
+            .burst_size(10)
+            .name("search"),
+        key: KeySource::Ip,
+    }
+}
+
+/// Large downloads: 10 per minute with a burst of 3
+///
+/// Keyed by client IP. Each request is expensive, so only a few may run back
+/// to back, and a client retrying before its time is rejected without
+/// touching the limiter.
+pub fn download() -> PolicySpec {
+    PolicySpec {
+        builder: GovernorPolicyBuilder::new()
+            .per_minute(10)
+            .burst_size(3)
+            .name("download")
+            .decision_cache(Duration::from_secs(5)),
+        key: KeySource::Ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+    use rama_http::{Body, Request};
+    use rama_net::stream::SocketInfo;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_presets() {
+        let login = login();
+        assert_eq!(login.key, KeySource::Ip);
+        let status = login.build().policy().status();
+        assert_eq!(status.name, "login");
+        assert_eq!(
+            (status.replenish_interval_ms, status.burst_size),
+            (12_000, 5)
+        );
+
+        for preset in [public_api, webhook_receiver, search, download] {
+            assert_ne!(preset().build().policy().status().name, "governor");
+        }
+
+        // anonymous requests count against their address
+        let public_api = public_api().build();
+        let request = |api_key: Option<&str>| {
+            let mut builder = Request::builder();
+            if let Some(api_key) = api_key {
+                builder = builder.header(X_API_KEY, api_key);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let mut ctx = Context::default();
+        ctx.insert(SocketInfo::new(None, "203.0.113.7:443".parse().unwrap()));
+        let mut admitted = 0;
+        for api_key in [None; 21].into_iter().chain([Some("k1")]) {
+            let result = public_api.check(ctx.clone(), request(api_key)).await;
+            admitted += matches!(result.output, PolicyOutput::Ready(_)) as usize;
+        }
+        assert_eq!(admitted, 21);
+    }
+}
@@ -0,0 +1,217 @@
+//! Rate limit pressure as an autoscaling signal
+//!
+//! CPU is a poor scaling signal for services whose limits bite before their
+//! cores do. A [`PressureExporter`] samples watched policies periodically and
+//! publishes how much of their quota was consumed, how many requests were
+//! denied and how many callers wait for capacity to a [`PressureSink`], e.g.
+//! one setting gauges an autoscaler scrapes:
+//!
+//! ```ignore
+//! PressureExporter::new(|pressure: Pressure| {
+//!     metrics::gauge!("quota_utilization", "policy" => pressure.policy).set(pressure.utilization);
+//! })
+//! .interval(Duration::from_secs(15))
+//! .watch(&api_policy)
+//! .start(TokioRuntime);
+//! ```
+
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::GovernorPolicy;
+use crate::runtime::{self, Runtime};
+
+/// Interval between samples unless configured otherwise
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Aggregate load of one policy over one sampling interval
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pressure {
+    /// Name of the policy
+    pub policy: String,
+    /// Allowed requests as a fraction of what the quota admits over the
+    /// interval
+    ///
+    /// For direct and store-backed global policies this is the share of the
+    /// global quota consumed. Keyed policies give each key a quota, so their
+    /// utilization counts in quotas of one key and may exceed 1.
+    pub utilization: f64,
+    /// Denied requests as a fraction of all decisions in the interval, 0
+    /// without any
+    pub denied_ratio: f64,
+    /// Callers waiting for capacity when the sample was taken, see
+    /// [`GovernorHandle::waiting`](crate::GovernorHandle::waiting)
+    pub waiting: usize,
+    /// Start of the interval
+    pub window_start: SystemTime,
+    /// End of the interval
+    pub window_end: SystemTime,
+}
+
+/// Destination for pressure samples
+///
+/// Implemented for closures taking a [`Pressure`].
+pub trait PressureSink: Send + Sync + 'static {
+    /// Receive the sample of one policy for one finished interval
+    fn publish(&self, pressure: Pressure);
+}
+
+impl<F> PressureSink for F
+where
+    F: Fn(Pressure) + Send + Sync + 'static,
+{
+    fn publish(&self, pressure: Pressure) {
+        self(pressure)
+    }
+}
+
+/// A watched policy and its decision totals at the last sample
+struct Watched {
+    policy: Weak<GovernorPolicy>,
+    allowed: u64,
+    denied: u64,
+}
+
+/// Periodically publishes the [`Pressure`] of watched policies
+pub struct PressureExporter {
+    interval: Duration,
+    sink: Box<dyn PressureSink>,
+    watched: Vec<Watched>,
+    sampled: Instant,
+    sampled_at: SystemTime,
+}
+
+impl PressureExporter {
+    /// Publish samples to `sink`, every 10 seconds by default
+    pub fn new(sink: impl PressureSink) -> Self {
+        PressureExporter {
+            interval: DEFAULT_INTERVAL,
+            sink: Box::new(sink),
+            watched: Vec::new(),
+            sampled: Instant::now(),
+            sampled_at: SystemTime::now(),
+        }
+    }
+
+    /// Sample every `interval`
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Also sample `policy`, for as long as it lives
+    pub fn watch(mut self, policy: &Arc<GovernorPolicy>) -> Self {
+        let (allowed, denied) = policy.telemetry_ref().counts().totals();
+        self.watched.push(Watched {
+            policy: Arc::downgrade(policy),
+            allowed,
+            denied,
+        });
+        self
+    }
+
+    /// Publish the pressure of every watched policy since the last sample
+    ///
+    /// Called by the task spawned through [`start`](Self::start); call it
+    /// directly where there is no runtime. Policies that were dropped are
+    /// no longer watched.
+    pub fn sample(&mut self) {
+        let now = Instant::now();
+        let window_end = SystemTime::now();
+        let elapsed = now.duration_since(self.sampled);
+        let window_start = std::mem::replace(&mut self.sampled_at, window_end);
+        self.sampled = now;
+
+        self.watched.retain_mut(|watched| {
+            let Some(policy) = watched.policy.upgrade() else {
+                return false;
+            };
+            let (allowed, denied) = policy.telemetry_ref().counts().totals();
+            let allowed_delta = allowed - std::mem::replace(&mut watched.allowed, allowed);
+            let denied_delta = denied - std::mem::replace(&mut watched.denied, denied);
+            let capacity =
+                elapsed.as_secs_f64() / policy.quota().replenish_interval().as_secs_f64();
+            let decisions = allowed_delta + denied_delta;
+            self.sink.publish(Pressure {
+                policy: policy.name().to_owned(),
+                utilization: match capacity > 0.0 {
+                    true => allowed_delta as f64 / capacity,
+                    false => 0.0,
+                },
+                denied_ratio: match decisions {
+                    0 => 0.0,
+                    _ => denied_delta as f64 / decisions as f64,
+                },
+                waiting: policy.handle_ref().waiting(),
+                window_start,
+                window_end,
+            });
+            true
+        });
+    }
+
+    /// Sample on `runtime` every interval, until every watched policy is
+    /// dropped or the runtime shuts down
+    pub fn start(mut self, runtime: impl Runtime) {
+        let runtime: Arc<dyn Runtime> = Arc::new(runtime);
+        let timer = runtime.clone();
+        let mut shutdown = runtime.shutdown();
+        runtime.spawn(Box::pin(async move {
+            loop {
+                let running = runtime::tick(&*timer, self.interval, &mut shutdown).await;
+                self.sample();
+                if !running || self.watched.is_empty() {
+                    return;
+                }
+            }
+        }));
+    }
+}
+
+impl std::fmt::Debug for PressureExporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PressureExporter")
+            .field("interval", &self.interval)
+            .field("watched", &self.watched.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pressure_sample() {
+        let policy = Arc::new(
+            GovernorPolicy::builder()
+                .per_minute(60)
+                .burst_size(4)
+                .name("api")
+                .build(),
+        );
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let sink = samples.clone();
+        let mut exporter =
+            PressureExporter::new(move |pressure| sink.lock().unwrap().push(pressure))
+                .watch(&policy);
+
+        let limiter = crate::Limiter::new(policy.clone());
+        for _ in 0..6 {
+            let _ = limiter.check().await;
+        }
+        exporter.sample();
+        let pressure = samples.lock().unwrap().pop().unwrap();
+        assert_eq!(pressure.policy, "api");
+        // a few milliseconds admit a fraction of a request, four went through
+        assert!(pressure.utilization > 1.0);
+        assert!((pressure.denied_ratio - 2.0 / 6.0).abs() < 1e-9);
+        assert_eq!(pressure.waiting, 0);
+
+        drop((limiter, policy));
+        exporter.sample();
+        assert!(samples.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+//! Passing the rate limit decision on to upstream services
+//!
+//! Behind a proxy, upstream services may want to apply secondary policies or
+//! log consistently with the edge, without knowing who the client was.
+//! [`PropagateRateLimitLayer`] adds the outcome of the limit layer in front of
+//! it as request headers: a hash of the key in [`X_RATELIMIT_CLIENT_KEY`] and
+//! the quota left in [`X_RATELIMIT_REMAINING`] and [`X_RATELIMIT_LIMIT`].
+//! Mount it behind the limit layer:
+//!
+//! ```ignore
+//! (
+//!     LimitLayer::new(policy),
+//!     PropagateRateLimitLayer::new().salt(secret),
+//! )
+//! ```
+//!
+//! Copies of these headers sent by clients are always removed, so upstreams
+//! can trust them.
+
+use std::hash::Hasher;
+use std::sync::Arc;
+
+use rama_core::error::BoxError;
+use rama_core::{Context, Layer, Service};
+use rama_http::{HeaderName, HeaderValue, Request};
+
+use crate::stable_hash::stable_hasher;
+use crate::{GovernorGuard, RateLimitInfo};
+
+/// The `X-RateLimit-Client-Key` header with the hashed key of a request
+pub const X_RATELIMIT_CLIENT_KEY: HeaderName = HeaderName::from_static("x-ratelimit-client-key");
+
+/// The `X-RateLimit-Remaining` header with the requests left for the key
+pub const X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+
+/// The `X-RateLimit-Limit` header with the burst size of the quota
+pub const X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+
+/// Layer adding the rate limit decision of requests to their headers
+#[derive(Debug, Clone, Default)]
+pub struct PropagateRateLimitLayer {
+    salt: Arc<[u8]>,
+}
+
+impl PropagateRateLimitLayer {
+    /// Propagate keys hashed without a salt
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hash keys with `salt`
+    ///
+    /// Without one, keys drawn from a small space, like IPv4 addresses, can
+    /// be recovered from their hash by trying them all.
+    pub fn salt(mut self, salt: impl AsRef<[u8]>) -> Self {
+        self.salt = salt.as_ref().into();
+        self
+    }
+}
+
+impl<S> Layer<S> for PropagateRateLimitLayer {
+    type Service = PropagateRateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PropagateRateLimitService {
+            inner,
+            salt: self.salt.clone(),
+        }
+    }
+}
+
+/// Service created by [`PropagateRateLimitLayer`]
+#[derive(Debug, Clone)]
+pub struct PropagateRateLimitService<S> {
+    inner: S,
+    salt: Arc<[u8]>,
+}
+
+impl<S> PropagateRateLimitService<S> {
+    fn hash(&self, key: &str) -> String {
+        let mut hasher = stable_hasher();
+        hasher.write(&self.salt);
+        hasher.write(key.as_bytes());
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+impl<S, State, Body> Service<State, Request<Body>> for PropagateRateLimitService<S>
+where
+    S: Service<State, Request<Body>, Error: Into<BoxError>>,
+    State: Clone + Send + Sync + 'static,
+    Body: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+
+    async fn serve(
+        &self,
+        ctx: Context<State>,
+        mut req: Request<Body>,
+    ) -> Result<Self::Response, Self::Error> {
+        let headers = req.headers_mut();
+        for name in [
+            X_RATELIMIT_CLIENT_KEY,
+            X_RATELIMIT_REMAINING,
+            X_RATELIMIT_LIMIT,
+        ] {
+            headers.remove(name);
+        }
+        let guard = ctx.get::<GovernorGuard>();
+        if let Some(key) = guard.and_then(GovernorGuard::key) {
+            let hash = HeaderValue::from_str(&self.hash(key)).expect("hex is a valid header");
+            headers.insert(X_RATELIMIT_CLIENT_KEY, hash);
+        }
+        if let Some(remaining) = guard.and_then(GovernorGuard::remaining) {
+            headers.insert(X_RATELIMIT_REMAINING, remaining.into());
+        }
+        if let Some(info) = ctx.get::<RateLimitInfo>() {
+            headers.insert(X_RATELIMIT_LIMIT, info.limit.into());
+        }
+        self.inner.serve(ctx, req).await.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+    use rama_core::service::service_fn;
+    use rama_http::HeaderMap;
+
+    use super::*;
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_propagate_decision() {
+        let policy = GovernorPolicy::builder()
+            .per_second(1)
+            .burst_size(3)
+            .build();
+        let service = PropagateRateLimitLayer::new()
+            .salt("pepper")
+            .layer(service_fn(async |req: Request<()>| {
+                Ok::<_, Infallible>(req.headers().clone())
+            }));
+        let request = Request::builder()
+            .header(X_RATELIMIT_REMAINING, "1000")
+            .body(())
+            .unwrap();
+
+        let result = policy.check(Context::<()>::default(), request).await;
+        assert!(matches!(result.output, PolicyOutput::Ready(_)));
+        let headers: HeaderMap = service.serve(result.ctx, result.request).await.unwrap();
+        let key = headers[X_RATELIMIT_CLIENT_KEY].to_str().unwrap();
+        assert_eq!(key.len(), 16);
+        assert_ne!(key, "default");
+        assert_eq!(headers[X_RATELIMIT_REMAINING], "2");
+        assert_eq!(headers[X_RATELIMIT_LIMIT], "3");
+
+        // nothing to propagate without a decision, and no spoofed values
+        let request = Request::builder()
+            .header(X_RATELIMIT_REMAINING, "1000")
+            .body(())
+            .unwrap();
+        let headers = service.serve(Context::default(), request).await.unwrap();
+        assert!(headers.is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+//! Quota arithmetic for capacity planning
+//!
+//! Business quotas are usually stated per month or per day, while GCRA is
+//! configured with a replenish interval and a burst. [`QuotaExt`] translates
+//! between the two, and the same accessors are available on a built
+//! [`GovernorPolicy`] for its current quota.
+
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use governor::Quota;
+
+use crate::GovernorPolicy;
+
+/// Length of a month in [`QuotaExt::from_monthly`]
+const MONTH: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Length of the period a [`QuotaExt::from_monthly`] burst may use up at once
+const BURST_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Capacity planning helpers for [`Quota`]
+pub trait QuotaExt: Sized {
+    /// Spread `count` requests evenly over a 30 day month
+    ///
+    /// The burst is an hour worth of the steady-state rate, at least 1, so a
+    /// client may use up to an hour of its allowance at once.
+    ///
+    /// # Panics
+    ///
+    /// If `count` is zero.
+    fn from_monthly(count: u64) -> Self;
+
+    /// Steady-state requests per second, ignoring the burst
+    fn effective_rps(&self) -> f64;
+
+    /// Time it takes until `burst` cells spent at once are available again
+    fn time_to_drain(&self, burst: u32) -> Duration;
+}
+
+impl QuotaExt for Quota {
+    fn from_monthly(count: u64) -> Self {
+        assert!(count > 0, "Rate limit count must be non-zero");
+        let period = (MONTH.as_nanos() / count as u128).max(1) as u64;
+        let burst = (count / (MONTH.as_secs() / BURST_PERIOD.as_secs())).clamp(1, u32::MAX as u64);
+        Quota::with_period(Duration::from_nanos(period))
+            .expect("period is non-zero")
+            .allow_burst(NonZeroU32::new(burst as u32).expect("burst is non-zero"))
+    }
+
+    fn effective_rps(&self) -> f64 {
+        1.0 / self.replenish_interval().as_secs_f64()
+    }
+
+    fn time_to_drain(&self, burst: u32) -> Duration {
+        self.replenish_interval() * burst
+    }
+}
+
+impl GovernorPolicy {
+    /// The quota currently enforced, including changes made through the handle
+    pub fn quota(&self) -> Quota {
+        match self {
+            GovernorPolicy::Direct(policy) => policy.limiter.load().quota(),
+            GovernorPolicy::Keyed(policy) => policy.quota(),
+            GovernorPolicy::Store(policy) => **policy.quota.load(),
+        }
+    }
+
+    /// Steady-state requests per second of the current quota
+    pub fn effective_rps(&self) -> f64 {
+        self.quota().effective_rps()
+    }
+
+    /// Time it takes until `burst` cells spent at once are available again
+    pub fn time_to_drain(&self, burst: u32) -> Duration {
+        self.quota().time_to_drain(burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_monthly_quota() {
+        let quota = Quota::from_monthly(2_592_000);
+        assert_eq!(quota.replenish_interval(), Duration::from_secs(1));
+        assert_eq!(quota.burst_size().get(), 3600);
+        assert_eq!(quota.effective_rps(), 1.0);
+
+        let policy = GovernorPolicy::builder().quota(quota).build();
+        assert_eq!(policy.time_to_drain(10), Duration::from_secs(10));
+        assert_eq!(Quota::from_monthly(1).burst_size().get(), 1);
+    }
+}
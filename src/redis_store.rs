@@ -0,0 +1,229 @@
+//! Redis backed rate limit store
+//!
+//! GCRA state lives in Redis and is updated atomically by a Lua script, using
+//! the Redis server clock so instances with skewed clocks still agree.
+//!
+//! With [`RedisStore::batching`], checks arriving close together are sent as
+//! one pipeline instead of one round trip each.
+
+use std::sync::{Arc, Mutex};
+
+use redis::Script;
+use redis::aio::ConnectionManager;
+use tokio::sync::oneshot;
+
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture, gcra_micros};
+use governor::Quota;
+use std::time::Duration;
+
+/// GCRA on a single key. Times are in microseconds.
+///
+/// Returns `{1, 0}` when the cells were consumed, `{0, retry_after}` otherwise.
+const GCRA_SCRIPT: &str = r#"
+local t = tonumber(ARGV[1])
+local tau = tonumber(ARGV[2])
+local n = tonumber(ARGV[3])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local tat = tonumber(redis.call('GET', KEYS[1])) or now
+local new_tat = math.max(tat, now) + n * t
+local allow_at = new_tat - tau
+if allow_at > now then
+    return {0, allow_at - now}
+end
+redis.call('SET', KEYS[1], new_tat, 'PX', math.ceil((new_tat - now) / 1000) + 1)
+return {1, 0}
+"#;
+
+fn decision(allowed: u8, retry_after: u64) -> StoreDecision {
+    if allowed == 1 {
+        StoreDecision::Allowed
+    } else {
+        StoreDecision::Denied {
+            retry_after: Duration::from_micros(retry_after),
+        }
+    }
+}
+
+/// A check waiting for the next pipeline
+struct Pending {
+    key: String,
+    t: u64,
+    tau: u64,
+    n: u32,
+    tx: oneshot::Sender<Result<StoreDecision, StoreError>>,
+}
+
+/// Checks collected for the next pipeline
+struct Batcher {
+    max_size: usize,
+    window: Duration,
+    queue: Mutex<Vec<Pending>>,
+}
+
+/// A [`RateLimitStore`] keeping GCRA state in Redis
+#[derive(Clone)]
+pub struct RedisStore {
+    conn: ConnectionManager,
+    script: Script,
+    prefix: String,
+    batcher: Option<Arc<Batcher>>,
+}
+
+impl RedisStore {
+    /// Connect to the Redis server at `url`
+    pub async fn connect(url: &str) -> Result<Self, StoreError> {
+        let client = redis::Client::open(url).map_err(StoreError::new)?;
+        let conn = client
+            .get_connection_manager()
+            .await
+            .map_err(StoreError::new)?;
+        Ok(Self::new(conn))
+    }
+
+    /// Use an existing connection
+    pub fn new(conn: ConnectionManager) -> Self {
+        RedisStore {
+            conn,
+            script: Script::new(GCRA_SCRIPT),
+            prefix: "governor:".to_owned(),
+            batcher: None,
+        }
+    }
+
+    /// Send concurrent checks as pipelines of up to `max_size` commands
+    ///
+    /// A pipeline goes out when it is full, or `window` after its first check
+    /// arrived, so `window` is the most latency batching adds to a check.
+    /// Under high concurrency this saves most round trips; at low traffic it
+    /// only adds latency, so keep the window small, e.g. a millisecond.
+    pub fn batching(mut self, max_size: usize, window: Duration) -> Self {
+        self.batcher = Some(Arc::new(Batcher {
+            max_size: max_size.max(1),
+            window,
+            queue: Mutex::new(Vec::new()),
+        }));
+        self
+    }
+
+    /// Send the queued checks as one pipeline and hand out the results
+    async fn flush(mut self, batch: Vec<Pending>) {
+        if batch.is_empty() {
+            return;
+        }
+        let mut pipe = redis::pipe();
+        for check in &batch {
+            pipe.cmd("EVALSHA")
+                .arg(self.script.get_hash())
+                .arg(1)
+                .arg(&check.key)
+                .arg(check.t)
+                .arg(check.tau)
+                .arg(check.n);
+        }
+        let mut result = pipe.query_async::<Vec<(u8, u64)>>(&mut self.conn).await;
+        // like `Script::invoke_async`, load the script once the server lost it
+        if matches!(&result, Err(err) if err.kind() == redis::ErrorKind::NoScriptError) {
+            result = match self
+                .script
+                .prepare_invoke()
+                .load_async(&mut self.conn)
+                .await
+            {
+                Ok(_) => pipe.query_async(&mut self.conn).await,
+                Err(err) => Err(err),
+            };
+        }
+        match result {
+            Ok(results) => {
+                for (check, (allowed, retry_after)) in batch.into_iter().zip(results) {
+                    let _ = check.tx.send(Ok(decision(allowed, retry_after)));
+                }
+            }
+            Err(err) => {
+                let err = err.to_string();
+                for check in batch {
+                    let _ = check.tx.send(Err(StoreError::new(err.clone())));
+                }
+            }
+        }
+    }
+
+    async fn check_batched(
+        &self,
+        batcher: &Batcher,
+        key: String,
+        quota: Quota,
+        n: u32,
+    ) -> Result<StoreDecision, StoreError> {
+        let (t, tau) = gcra_micros(&quota);
+        let (tx, rx) = oneshot::channel();
+        let (first, full) = {
+            let mut queue = batcher.queue.lock().unwrap();
+            queue.push(Pending { key, t, tau, n, tx });
+            let full = queue.len() >= batcher.max_size;
+            (queue.len() == 1, full.then(|| std::mem::take(&mut *queue)))
+        };
+        // flushes run as tasks so a cancelled check can't strand the others
+        if let Some(batch) = full {
+            tokio::spawn(self.clone().flush(batch));
+        } else if first {
+            let store = self.clone();
+            let window = batcher.window;
+            tokio::spawn(async move {
+                tokio::time::sleep(window).await;
+                let batch = match &store.batcher {
+                    Some(batcher) => std::mem::take(&mut *batcher.queue.lock().unwrap()),
+                    None => Vec::new(),
+                };
+                store.flush(batch).await;
+            });
+        }
+        rx.await
+            .unwrap_or_else(|_| Err(StoreError::new("pipelined check was dropped")))
+    }
+
+    /// Prefix prepended to every key (default `governor:`)
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+impl std::fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("prefix", &self.prefix)
+            .field("batching", &self.batcher.is_some())
+            .finish()
+    }
+}
+
+impl RateLimitStore for RedisStore {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        Box::pin(async move {
+            if let Some(batcher) = &self.batcher {
+                let key = format!("{}{}", self.prefix, key);
+                return self.check_batched(batcher, key, quota, n).await;
+            }
+            let (t, tau) = gcra_micros(&quota);
+            let mut conn = self.conn.clone();
+            let (allowed, retry_after): (u8, u64) = self
+                .script
+                .key(format!("{}{}", self.prefix, key))
+                .arg(t)
+                .arg(tau)
+                .arg(n)
+                .invoke_async(&mut conn)
+                .await
+                .map_err(StoreError::new)?;
+
+            Ok(decision(allowed, retry_after))
+        })
+    }
+}
@@ -0,0 +1,135 @@
+//! Named policy registry
+//!
+//! Policies registered under a name, like "public-api" or "login", can be
+//! looked up from anywhere in the application. Layers, the admin API and
+//! config reload paths then share one policy, and so one limiter state,
+//! instead of each building their own with the same quota.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+use crate::GovernorPolicy;
+
+/// Policies shared by name
+///
+/// Clones refer to the same registry. Use [`PolicyRegistry::global`] for a
+/// process wide registry, or create one with [`PolicyRegistry::new`] and pass it
+/// around.
+#[derive(Clone, Default)]
+pub struct PolicyRegistry {
+    policies: Arc<DashMap<String, Arc<GovernorPolicy>>>,
+}
+
+static GLOBAL: Lazy<PolicyRegistry> = Lazy::new(PolicyRegistry::new);
+
+impl PolicyRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The process wide registry
+    pub fn global() -> &'static PolicyRegistry {
+        &GLOBAL
+    }
+
+    /// Register `policy` under `name`, returning the policy it replaces
+    ///
+    /// Holders of the replaced policy keep using it until they look it up again.
+    pub fn register(
+        &self,
+        name: impl Into<String>,
+        policy: GovernorPolicy,
+    ) -> Option<Arc<GovernorPolicy>> {
+        self.policies.insert(name.into(), Arc::new(policy))
+    }
+
+    /// The policy registered under `name`, building and registering it if there is none
+    ///
+    /// `build` runs at most once per name, so concurrent callers share the
+    /// same policy.
+    pub fn get_or_register(
+        &self,
+        name: &str,
+        build: impl FnOnce() -> GovernorPolicy,
+    ) -> Arc<GovernorPolicy> {
+        if let Some(policy) = self.get(name) {
+            return policy;
+        }
+        self.policies
+            .entry(name.to_owned())
+            .or_insert_with(|| Arc::new(build()))
+            .clone()
+    }
+
+    /// The policy registered under `name`
+    pub fn get(&self, name: &str) -> Option<Arc<GovernorPolicy>> {
+        self.policies.get(name).map(|policy| policy.clone())
+    }
+
+    /// Remove the policy registered under `name`
+    pub fn remove(&self, name: &str) -> Option<Arc<GovernorPolicy>> {
+        self.policies.remove(name).map(|(_, policy)| policy)
+    }
+
+    /// Names of all registered policies
+    pub fn names(&self) -> Vec<String> {
+        self.policies
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// All registered policies, e.g. for `governor_status_service`
+    pub fn policies(&self) -> Vec<Arc<GovernorPolicy>> {
+        self.policies
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+
+    /// Number of registered policies
+    pub fn len(&self) -> usize {
+        self.policies.len()
+    }
+
+    /// Whether no policies are registered
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+}
+
+impl std::fmt::Debug for PolicyRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PolicyRegistry")
+            .field("policies", &self.names())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_policy_registry() {
+        let registry = PolicyRegistry::new();
+        let login = registry.get_or_register("login", || {
+            GovernorPolicy::builder()
+                .per_minute(5)
+                .name("login")
+                .build()
+        });
+        let again = registry.clone().get_or_register("login", || unreachable!());
+        assert!(Arc::ptr_eq(&login, &again));
+
+        let replaced = registry.register("login", GovernorPolicy::builder().per_minute(10).build());
+        assert!(Arc::ptr_eq(&replaced.unwrap(), &login));
+        let status = registry.get("login").unwrap().status();
+        assert_eq!(status.replenish_interval_ms, 6000);
+        assert_eq!(registry.names(), ["login"]);
+        assert!(registry.remove("login").is_some() && registry.is_empty());
+    }
+}
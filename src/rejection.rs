@@ -0,0 +1,307 @@
+//! HTTP responses for rejected requests
+//!
+//! A policy answers rejected requests with the [`RejectionResponse`] set through
+//! [`GovernorPolicyBuilder::rejection_response`](crate::GovernorPolicyBuilder::rejection_response),
+//! 429 with a plain text body by default. The response travels with the error
+//! as [`Rejected`], so a single error mapping layer can render it for requests
+//! rejected by any policy:
+//!
+//! ```ignore
+//! MapResultLayer::new(|result: Result<Response, BoxError>| match result {
+//!     Err(err) => match err.downcast::<GovernorError>() {
+//!         Ok(err) => Ok(err.into_response()),
+//!         Err(err) => Err(err),
+//!     },
+//!     ok => ok,
+//! })
+//! ```
+//!
+//! Header values and the body are templates: `{policy}` is replaced by the
+//! policy name and `{retry_after}` by the seconds until a retry may succeed,
+//! rounded up (0 if unknown). Which retry-after is advertised is set through
+//! [`RejectionResponse::retry_after`]; jittering it keeps a fleet of clients
+//! limited at the same moment from retrying at the same moment, too.
+//!
+//! Policies decide before the request body is read: `LimitLayer` hands the
+//! request to the inner service only once it was let through, so a rejected
+//! body is never buffered. Left unread, it may still be drained by the server
+//! to reuse the connection; [`RejectionResponse::close_above`] closes the
+//! connection instead when the declared body is large.
+
+use std::any::Any;
+use std::sync::Arc;
+use std::time::Duration;
+
+use governor::Jitter;
+use rama_http::{Body, HeaderName, HeaderValue, Response, StatusCode, header};
+
+use crate::exempt::{declared_length, http_request};
+
+/// How the retry-after advertised to rejected clients is derived
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RetryAfter {
+    /// The time until a retry may succeed (default)
+    #[default]
+    Exact,
+    /// The time until a retry may succeed, rounded up to a multiple of the step
+    RoundUp(Duration),
+    /// Always the given duration, also when the time is unknown
+    Fixed(Duration),
+    /// The time until a retry may succeed plus a random extra of up to the given duration
+    Jittered(Duration),
+}
+
+impl RetryAfter {
+    fn advertise(self, retry_after: Option<Duration>) -> Option<Duration> {
+        match self {
+            RetryAfter::Exact => retry_after,
+            RetryAfter::RoundUp(step) => retry_after.map(|d| {
+                let step = step.as_nanos().max(1);
+                let steps = d.as_nanos().div_ceil(step);
+                Duration::from_nanos((steps * step).min(u64::MAX as u128) as u64)
+            }),
+            RetryAfter::Fixed(d) => Some(d),
+            RetryAfter::Jittered(spread) => retry_after.map(|d| Jitter::up_to(spread) + d),
+        }
+    }
+}
+
+/// Status, headers and body sent for requests rejected by a policy
+#[derive(Debug, Clone)]
+pub struct RejectionResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, String)>,
+    body: String,
+    retry_after: RetryAfter,
+    close_above: Option<u64>,
+}
+
+impl Default for RejectionResponse {
+    fn default() -> Self {
+        RejectionResponse {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            headers: Vec::new(),
+            body: "rate limit exceeded".to_owned(),
+            retry_after: RetryAfter::default(),
+            close_above: None,
+        }
+    }
+}
+
+impl RejectionResponse {
+    /// 429 with body "rate limit exceeded" and no extra headers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `status` instead of 429, e.g. 503 behind proxies that retry on 429
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Add a header whose value is rendered from `template`
+    pub fn header(mut self, name: HeaderName, template: impl Into<String>) -> Self {
+        self.headers.push((name, template.into()));
+        self
+    }
+
+    /// Render the body from `template`
+    pub fn body(mut self, template: impl Into<String>) -> Self {
+        self.body = template.into();
+        self
+    }
+
+    /// Derive the advertised retry-after through `strategy`
+    pub fn retry_after(mut self, strategy: RetryAfter) -> Self {
+        self.retry_after = strategy;
+        self
+    }
+
+    /// Close the connection after rejecting a request that declares a body
+    /// of more than `content_length` bytes
+    ///
+    /// The response carries `Connection: close`, so the server sends it right
+    /// away and closes the connection rather than reading the body to keep
+    /// the connection alive. HTTP/2 servers reset the stream of a request
+    /// answered before its body was read. Only applies to HTTP requests with
+    /// rama's default body type.
+    pub fn close_above(mut self, content_length: u64) -> Self {
+        self.close_above = Some(content_length);
+        self
+    }
+
+    fn render(&self, policy: &str, retry_after: Option<Duration>) -> Response {
+        let retry_after = retry_after
+            .map_or(0, |d| d.as_secs() + u64::from(d.subsec_nanos() > 0))
+            .to_string();
+        let fill = |template: &str| {
+            template
+                .replace("{policy}", policy)
+                .replace("{retry_after}", &retry_after)
+        };
+
+        let mut response = Response::new(Body::from(fill(&self.body)));
+        *response.status_mut() = self.status;
+        for (name, template) in &self.headers {
+            match HeaderValue::try_from(fill(template)) {
+                Ok(value) => {
+                    response.headers_mut().append(name.clone(), value);
+                }
+                Err(_) => tracing::warn!("Skipping invalid rejection header: {}", name),
+            }
+        }
+        response
+    }
+}
+
+/// Context extension marking a request let through over its limit
+///
+/// Inserted by policies in mirror mode, see
+/// [`GovernorPolicyBuilder::mirror`](crate::GovernorPolicyBuilder::mirror).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitExceeded {
+    /// Time after which the request would have been allowed, if known
+    pub retry_after: Option<Duration>,
+}
+
+/// A request rejected by a policy
+#[derive(Debug, Clone)]
+pub struct Rejected {
+    policy: Arc<str>,
+    retry_after: Option<Duration>,
+    response: Arc<RejectionResponse>,
+    close: bool,
+}
+
+impl Rejected {
+    pub(crate) fn new(
+        policy: Arc<str>,
+        retry_after: Option<Duration>,
+        response: Arc<RejectionResponse>,
+    ) -> Self {
+        Rejected {
+            policy,
+            retry_after: response.retry_after.advertise(retry_after),
+            response,
+            close: false,
+        }
+    }
+
+    /// Note the rejected `request`, closing the connection if it declares a
+    /// body above the limit of the response
+    pub(crate) fn of_request(&mut self, request: &dyn Any) {
+        let (Some(limit), Some(request)) = (self.response.close_above, http_request(request))
+        else {
+            return;
+        };
+        self.close = declared_length(request).is_some_and(|len| len > limit);
+    }
+
+    /// Whether the response closes the connection, see
+    /// [`RejectionResponse::close_above`]
+    pub fn closes_connection(&self) -> bool {
+        self.close
+    }
+
+    /// Name of the rejecting policy
+    pub fn policy(&self) -> &str {
+        &self.policy
+    }
+
+    /// Time after which the client is told to retry, if known
+    ///
+    /// Derived through the [`RetryAfter`] strategy of the policy. Unknown for
+    /// denylisted keys and lockdown clamps unless the strategy is fixed.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    /// The response configured for the rejecting policy
+    pub fn to_response(&self) -> Response {
+        let mut response = self.response.render(&self.policy, self.retry_after);
+        if self.close {
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejection_response() {
+        let response = RejectionResponse::new()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(HeaderName::from_static("x-limited-by"), "{policy}")
+            .header(HeaderName::from_static("retry-after"), "{retry_after}")
+            .header(HeaderName::from_static("x-bad"), "\n")
+            .body("slow down, retry in {retry_after}s");
+        let rejected = Rejected::new(
+            Arc::from("login"),
+            Some(Duration::from_millis(1500)),
+            Arc::new(response),
+        );
+
+        let response = rejected.to_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["x-limited-by"], "login");
+        assert_eq!(response.headers()["retry-after"], "2");
+        assert!(!response.headers().contains_key("x-bad"));
+    }
+
+    #[test]
+    fn test_retry_after_strategies() {
+        let wait = Duration::from_millis(1500);
+        let step = RetryAfter::RoundUp(Duration::from_secs(10));
+        assert_eq!(step.advertise(Some(wait)), Some(Duration::from_secs(10)));
+        let fixed = RetryAfter::Fixed(Duration::from_secs(30));
+        assert_eq!(fixed.advertise(None), Some(Duration::from_secs(30)));
+
+        let jittered = RetryAfter::Jittered(Duration::from_secs(5))
+            .advertise(Some(wait))
+            .unwrap();
+        assert!(jittered >= wait && jittered <= Duration::from_millis(6500));
+    }
+
+    #[tokio::test]
+    async fn test_reject_before_body_read() {
+        use rama_core::Context;
+        use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+        use rama_http::Request;
+        use rama_http::dep::http_body_util::BodyExt;
+
+        use crate::{GovernorError, GovernorPolicy};
+
+        let policy = GovernorPolicy::builder()
+            .per_minute(1)
+            .rejection_response(RejectionResponse::new().close_above(1024))
+            .build();
+        let upload = |len: usize| {
+            Request::builder()
+                .header(header::CONTENT_LENGTH, len)
+                .body(Body::from(vec![0; len]))
+                .unwrap()
+        };
+
+        policy.check(Context::<()>::default(), upload(16)).await;
+        let result = policy
+            .check(Context::<()>::default(), upload(1 << 20))
+            .await;
+        let PolicyOutput::Abort(GovernorError::RateLimited(rejected)) = result.output else {
+            panic!("expected a rejection");
+        };
+        assert!(rejected.closes_connection());
+        assert_eq!(
+            rejected.to_response().headers()[header::CONNECTION],
+            "close"
+        );
+        // the body comes back untouched
+        let body = result.request.into_body().collect().await.unwrap();
+        assert_eq!(body.to_bytes().len(), 1 << 20);
+    }
+}
@@ -0,0 +1,262 @@
+//! Async runtime abstraction for background tasks
+//!
+//! Garbage collection, schedules, usage accounting and persistence run as
+//! background tasks. They only need to spawn futures and sleep, which the
+//! [`Runtime`] trait provides, so policies work on executors other than tokio.
+//!
+//! A runtime may also signal the shutdown of the application, on which the
+//! tasks stop, flushing usage records and persisted state one last time.
+//! [`GracefulRuntime`] ties them to rama's graceful shutdown this way:
+//!
+//! ```ignore
+//! let shutdown = Shutdown::default();
+//! let policy = GovernorPolicy::builder()
+//!     .per_second(10)
+//!     .runtime(GracefulRuntime::new(shutdown.guard()))
+//!     .build_with_keyer(|key: &str| key.to_owned());
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+#[cfg(feature = "tokio")]
+use rama_core::graceful::ShutdownGuard;
+
+/// Boxed future used by [`Runtime`]
+pub type RuntimeFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Executor and timer used to drive the background tasks of a policy
+///
+/// With the `tokio` feature (on by default) policies use [`TokioRuntime`]
+/// unless configured otherwise through
+/// [`GovernorPolicyBuilder::runtime`](crate::GovernorPolicyBuilder::runtime).
+pub trait Runtime: Send + Sync + 'static {
+    /// Run `task` in the background
+    fn spawn(&self, task: RuntimeFuture);
+
+    /// A future completing after `duration`
+    fn sleep(&self, duration: Duration) -> RuntimeFuture;
+
+    /// Whether tasks can be spawned from the current context
+    ///
+    /// Builders start background tasks right away if this returns true.
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    /// A future completing once the application starts shutting down
+    ///
+    /// Background tasks stop when it completes. Runtimes without a notion of
+    /// shutdown return `None`, and their tasks run until the policy is dropped.
+    fn shutdown(&self) -> Option<RuntimeFuture> {
+        None
+    }
+}
+
+impl<R: Runtime + ?Sized> Runtime for Arc<R> {
+    fn spawn(&self, task: RuntimeFuture) {
+        (**self).spawn(task)
+    }
+
+    fn sleep(&self, duration: Duration) -> RuntimeFuture {
+        (**self).sleep(duration)
+    }
+
+    fn is_available(&self) -> bool {
+        (**self).is_available()
+    }
+
+    fn shutdown(&self) -> Option<RuntimeFuture> {
+        (**self).shutdown()
+    }
+}
+
+/// [`Runtime`] spawning onto the tokio runtime of the caller
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+#[cfg(feature = "tokio")]
+impl Runtime for TokioRuntime {
+    fn spawn(&self, task: RuntimeFuture) {
+        tokio::spawn(task);
+    }
+
+    fn sleep(&self, duration: Duration) -> RuntimeFuture {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn is_available(&self) -> bool {
+        tokio::runtime::Handle::try_current().is_ok()
+    }
+}
+
+/// [`Runtime`] running background tasks under rama's graceful shutdown
+///
+/// Tasks are spawned through the [`ShutdownGuard`], so a graceful shutdown
+/// waits for them, and stop once shutdown starts instead of lingering as
+/// detached tokio tasks. Timers are tokio's.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone)]
+pub struct GracefulRuntime {
+    guard: ShutdownGuard,
+}
+
+#[cfg(feature = "tokio")]
+impl GracefulRuntime {
+    /// Run background tasks under `guard`, e.g. from `Shutdown::guard`
+    pub fn new(guard: ShutdownGuard) -> Self {
+        GracefulRuntime { guard }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Runtime for GracefulRuntime {
+    fn spawn(&self, task: RuntimeFuture) {
+        self.guard.spawn_task(task);
+    }
+
+    fn sleep(&self, duration: Duration) -> RuntimeFuture {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn is_available(&self) -> bool {
+        tokio::runtime::Handle::try_current().is_ok()
+    }
+
+    fn shutdown(&self) -> Option<RuntimeFuture> {
+        // a weak guard, so waiting for the signal doesn't hold up the shutdown
+        Some(Box::pin(self.guard.clone_weak().into_cancelled()))
+    }
+}
+
+/// Sleep for `duration`, returning false instead once `shutdown` completes
+///
+/// `shutdown` is the future returned by [`Runtime::shutdown`], taken once
+/// per task; tasks return when this returns false.
+pub(crate) async fn tick(
+    runtime: &dyn Runtime,
+    duration: Duration,
+    shutdown: &mut Option<RuntimeFuture>,
+) -> bool {
+    let mut sleep = runtime.sleep(duration);
+    std::future::poll_fn(|cx| {
+        if let Some(shutdown) = shutdown {
+            if shutdown.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(false);
+            }
+        }
+        sleep.as_mut().poll(cx).map(|()| true)
+    })
+    .await
+}
+
+/// Run `future` to completion unless `duration` elapses first
+pub(crate) async fn timeout<F: Future>(
+    runtime: &dyn Runtime,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    let mut future = std::pin::pin!(future);
+    let mut sleep = runtime.sleep(duration);
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        sleep.as_mut().poll(cx).map(|()| None)
+    })
+    .await
+}
+
+/// The runtime used when none is configured
+pub(crate) fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    #[cfg(feature = "tokio")]
+    return Some(Arc::new(TokioRuntime));
+    #[cfg(not(feature = "tokio"))]
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GovernorPolicy;
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::Policy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Records spawned tasks instead of running them
+    #[derive(Default)]
+    struct RecordingRuntime {
+        spawned: AtomicUsize,
+    }
+
+    impl Runtime for RecordingRuntime {
+        fn spawn(&self, _task: RuntimeFuture) {
+            self.spawned.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn sleep(&self, _duration: Duration) -> RuntimeFuture {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[test]
+    fn test_custom_runtime() {
+        let runtime = Arc::new(RecordingRuntime::default());
+        let policy = GovernorPolicy::builder()
+            .per_second(1)
+            .runtime(runtime.clone())
+            .build_with_keyer(|key: &str| key.to_owned());
+
+        // GC started at build time without any tokio runtime around
+        assert_eq!(runtime.spawned.load(Ordering::Relaxed), 1);
+        policy.start_background_tasks();
+        assert_eq!(runtime.spawned.load(Ordering::Relaxed), 1);
+    }
+
+    /// Tokio runtime shutting down when notified
+    struct ShutdownRuntime {
+        shutdown: Arc<tokio::sync::Notify>,
+    }
+
+    impl Runtime for ShutdownRuntime {
+        fn spawn(&self, task: RuntimeFuture) {
+            tokio::spawn(task);
+        }
+
+        fn sleep(&self, duration: Duration) -> RuntimeFuture {
+            Box::pin(tokio::time::sleep(duration))
+        }
+
+        fn shutdown(&self) -> Option<RuntimeFuture> {
+            let shutdown = self.shutdown.clone();
+            Some(Box::pin(async move { shutdown.notified().await }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_shutdown() {
+        let shutdown = Arc::new(tokio::sync::Notify::new());
+        let (sink, mut records) = tokio::sync::mpsc::channel(1);
+        let policy = GovernorPolicy::builder()
+            .per_second(1)
+            .usage_accounting(Duration::from_secs(3600), sink)
+            .runtime(ShutdownRuntime {
+                shutdown: shutdown.clone(),
+            })
+            .build();
+        policy.check(Context::default(), ()).await;
+
+        // the accounting task flushes the open window right away
+        shutdown.notify_one();
+        let records = tokio::time::timeout(Duration::from_secs(1), records.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(records[0].count, 1);
+        drop(policy);
+    }
+}
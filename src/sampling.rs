@@ -0,0 +1,156 @@
+//! Sampling of denial log lines
+//!
+//! Under attack, logging every rejected request floods the logs. A sampled
+//! policy logs only some denials and periodically emits a summary event with
+//! the number of denials and suppressed lines since the previous summary.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+/// Which denials of a policy are logged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DenialLogSampling {
+    /// Log every denial (default)
+    #[default]
+    All,
+    /// Log one in every `n` denials
+    OneIn(u64),
+    /// Log at most `n` denials per second for each key
+    ///
+    /// Up to 10 000 keys are tracked between two summaries; denials of
+    /// further keys share one budget of `n` per second.
+    PerKeyPerSecond(u32),
+}
+
+/// Interval between summary events of sampled policies
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keys tracked by [`DenialLogSampling::PerKeyPerSecond`] between summaries
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+pub(crate) struct DenialSampler {
+    sampling: DenialLogSampling,
+    start: Instant,
+    denials: AtomicU64,
+    suppressed: AtomicU64,
+    /// Seconds since `start` at which the last summary was emitted
+    last_summary: AtomicU64,
+    /// Logged lines per key within the current second
+    per_key: DashMap<String, (u64, u32)>,
+    /// Keys added to `per_key` since the last summary, roughly its length
+    tracked: AtomicUsize,
+    /// Logged lines of keys past the cap within the current second
+    overflow: Mutex<(u64, u32)>,
+}
+
+impl DenialSampler {
+    pub(crate) fn new(sampling: DenialLogSampling) -> Self {
+        DenialSampler {
+            sampling,
+            start: Instant::now(),
+            denials: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+            last_summary: AtomicU64::new(0),
+            per_key: DashMap::new(),
+            tracked: AtomicUsize::new(0),
+            overflow: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Count a denial for `key`, returning whether it should be logged
+    pub(crate) fn sample(&self, policy: &str, key: &str) -> bool {
+        if self.sampling == DenialLogSampling::All {
+            return true;
+        }
+
+        let now = self.start.elapsed().as_secs();
+        let seen = self.denials.fetch_add(1, Ordering::Relaxed);
+        let log = match self.sampling {
+            DenialLogSampling::All => true,
+            DenialLogSampling::OneIn(n) => seen % n.max(1) == 0,
+            DenialLogSampling::PerKeyPerSecond(n) => {
+                let lines = match self.per_key.get_mut(key) {
+                    Some(mut entry) => count_line(&mut entry, now),
+                    None if self.tracked.load(Ordering::Relaxed) < MAX_TRACKED_KEYS => {
+                        self.tracked.fetch_add(1, Ordering::Relaxed);
+                        let mut entry = self.per_key.entry(key.to_owned()).or_insert((now, 0));
+                        count_line(&mut entry, now)
+                    }
+                    // an attack spread over many keys doesn't grow the map
+                    None => count_line(&mut self.overflow.lock().unwrap(), now),
+                };
+                lines <= n
+            }
+        };
+        if !log {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.summarize_if_due(policy, now);
+        log
+    }
+
+    fn summarize_if_due(&self, policy: &str, now: u64) {
+        let last = self.last_summary.load(Ordering::Relaxed);
+        if now < last + SUMMARY_INTERVAL.as_secs()
+            || self
+                .last_summary
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+
+        let denials = self.denials.swap(0, Ordering::Relaxed);
+        let suppressed = self.suppressed.swap(0, Ordering::Relaxed);
+        self.per_key.retain(|_, (second, _)| *second == now);
+        self.tracked.store(self.per_key.len(), Ordering::Relaxed);
+        tracing::info!(
+            policy,
+            denials,
+            suppressed,
+            "Rate limit denials since last summary"
+        );
+    }
+}
+
+/// Count a logged line in `entry`, the second and lines of a key, returning
+/// the lines within the second `now`
+fn count_line(entry: &mut (u64, u32), now: u64) -> u32 {
+    if entry.0 != now {
+        *entry = (now, 0);
+    }
+    entry.1 += 1;
+    entry.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denial_sampling() {
+        let sampler = DenialSampler::new(DenialLogSampling::OneIn(10));
+        let logged = (0..100).filter(|_| sampler.sample("api", "alice")).count();
+        assert_eq!(logged, 10);
+
+        let sampler = DenialSampler::new(DenialLogSampling::PerKeyPerSecond(2));
+        let logged = (0..10)
+            .filter(|_| sampler.sample("api", "alice") | sampler.sample("api", "bob"))
+            .count();
+        assert_eq!(logged, 2);
+        assert_eq!(sampler.suppressed.load(Ordering::Relaxed), 16);
+
+        // keys past the cap share one budget
+        let sampler = DenialSampler::new(DenialLogSampling::PerKeyPerSecond(1));
+        for key in 0..MAX_TRACKED_KEYS {
+            assert!(sampler.sample("api", &key.to_string()));
+        }
+        assert!(sampler.sample("api", "alice"));
+        assert!(!sampler.sample("api", "bob"));
+        assert_eq!(sampler.per_key.len(), MAX_TRACKED_KEYS);
+    }
+}
@@ -0,0 +1,377 @@
+//! Time-of-day quota schedules
+//!
+//! A schedule is a list of `(cron expression, quota)` pairs. Whenever the
+//! current minute matches one of the expressions, that quota becomes the
+//! active one and stays active until another entry fires. All times are UTC.
+
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use arc_swap::ArcSwap;
+use governor::Quota;
+use thiserror::Error;
+
+use crate::runtime::{self, Runtime};
+
+/// Error returned when a cron expression cannot be parsed
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CronError {
+    /// The expression does not consist of exactly five fields
+    #[error("expected 5 cron fields, found {0}")]
+    FieldCount(usize),
+    /// A field contains something that is not a number, range, step or `*`
+    #[error("invalid value {value:?} in {field} field")]
+    InvalidValue {
+        /// Name of the offending field
+        field: &'static str,
+        /// The raw value that failed to parse
+        value: String,
+    },
+    /// A value is outside of the range allowed for its field
+    #[error("value {value} out of range for {field} field ({min}-{max})")]
+    OutOfRange {
+        /// Name of the offending field
+        field: &'static str,
+        /// The value that was out of range
+        value: u32,
+        /// Smallest allowed value
+        min: u32,
+        /// Largest allowed value
+        max: u32,
+    },
+}
+
+/// A standard five field cron expression (`minute hour day-of-month month day-of-week`)
+///
+/// Each field supports `*`, single values, ranges (`1-5`), steps (`*/15`, `8-18/2`)
+/// and comma separated lists of those. Day-of-week accepts both `0` and `7` for Sunday.
+/// As in classic cron, when both day fields are restricted a day matches if either does;
+/// a field starting with `*`, like `*/2`, doesn't count as restricted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    dom_restricted: bool,
+    dow_restricted: bool,
+}
+
+impl CronExpr {
+    /// Returns true if the given unix minute (minutes since the epoch, UTC) matches
+    pub fn matches_minute(&self, unix_minute: i64) -> bool {
+        let minute_of_day = unix_minute.rem_euclid(1440);
+        bit(self.minutes, minute_of_day % 60)
+            && bit(self.hours, minute_of_day / 60)
+            && self.matches_day(unix_minute.div_euclid(1440))
+    }
+
+    /// Whether the day `days` after the epoch matches the day and month fields
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4).rem_euclid(7);
+        if !bit(self.months, month) {
+            return false;
+        }
+
+        let dom = bit(self.days_of_month, day);
+        let dow = bit(self.days_of_week, weekday);
+        match (self.dom_restricted, self.dow_restricted) {
+            (true, true) => dom || dow,
+            (true, false) => dom,
+            (false, true) => dow,
+            (false, false) => true,
+        }
+    }
+
+    /// The last matching minute of the day `days` after the epoch, up to
+    /// minute `until` of that day
+    fn last_match_on_day(&self, days: i64, until: i64) -> Option<i64> {
+        if !self.matches_day(days) {
+            return None;
+        }
+        let (last_hour, last_minute) = (until / 60, until % 60);
+        (0..=last_hour)
+            .rev()
+            .filter(|hour| bit(self.hours, *hour))
+            .find_map(|hour| {
+                let limit = if hour == last_hour { last_minute } else { 59 };
+                highest_bit(self.minutes, limit).map(|minute| hour * 60 + minute)
+            })
+    }
+}
+
+impl FromStr for CronExpr {
+    type Err = CronError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(CronError::FieldCount(fields.len()));
+        }
+
+        let mut days_of_week = parse_field(fields[4], "day-of-week", 0, 7)?;
+        // fold 7 (Sunday) onto 0
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week & !(1 << 7)) | 1;
+        }
+
+        Ok(CronExpr {
+            minutes: parse_field(fields[0], "minute", 0, 59)?,
+            hours: parse_field(fields[1], "hour", 0, 23)?,
+            days_of_month: parse_field(fields[2], "day-of-month", 1, 31)?,
+            months: parse_field(fields[3], "month", 1, 12)?,
+            days_of_week,
+            // as in Vixie cron, `*/2` restricts no more than `*` does
+            dom_restricted: !fields[2].starts_with('*'),
+            dow_restricted: !fields[4].starts_with('*'),
+        })
+    }
+}
+
+fn bit(mask: u64, n: i64) -> bool {
+    mask & (1 << n) != 0
+}
+
+/// The highest bit set in `mask` at or below bit `n`
+fn highest_bit(mask: u64, n: i64) -> Option<i64> {
+    let masked = mask & (u64::MAX >> (63 - n));
+    (masked != 0).then(|| 63 - i64::from(masked.leading_zeros()))
+}
+
+fn parse_field(field: &str, name: &'static str, min: u32, max: u32) -> Result<u64, CronError> {
+    let invalid = || CronError::InvalidValue {
+        field: name,
+        value: field.to_owned(),
+    };
+    let number = |s: &str| -> Result<u32, CronError> {
+        let value: u32 = s.parse().map_err(|_| invalid())?;
+        if value < min || value > max {
+            return Err(CronError::OutOfRange {
+                field: name,
+                value,
+                min,
+                max,
+            });
+        }
+        Ok(value)
+    };
+
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| invalid())?;
+                if step == 0 {
+                    return Err(invalid());
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (number(start)?, number(end)?)
+        } else {
+            let value = number(range)?;
+            // `5/10` means "from 5 to the end in steps of 10"
+            (value, if step > 1 { max } else { value })
+        };
+        if start > end {
+            return Err(invalid());
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+/// Converts days since the unix epoch into a (year, month, day) civil date.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// How far back we look when resolving the active entry at startup.
+const LOOKBACK_DAYS: i64 = 366;
+
+/// An ordered list of cron expressions and the quota they activate
+#[derive(Debug, Clone)]
+pub(crate) struct Schedule {
+    entries: Vec<(CronExpr, Quota)>,
+}
+
+impl Schedule {
+    pub(crate) fn new(entries: Vec<(CronExpr, Quota)>) -> Self {
+        Schedule { entries }
+    }
+
+    /// The entry firing at exactly this minute. Later entries win ties.
+    fn firing_at(&self, unix_minute: i64) -> Option<usize> {
+        self.entries
+            .iter()
+            .rposition(|(expr, _)| expr.matches_minute(unix_minute))
+    }
+
+    /// The entry that most recently fired at or before this minute
+    ///
+    /// Looks back a day at a time, so this costs at most a few hundred day
+    /// matches per entry rather than one match per minute of the year.
+    pub(crate) fn active_at(&self, unix_minute: i64) -> Option<usize> {
+        let today = unix_minute.div_euclid(1440);
+        (0..=LOOKBACK_DAYS).find_map(|back| {
+            let until = if back == 0 {
+                unix_minute.rem_euclid(1440)
+            } else {
+                1439
+            };
+            self.entries
+                .iter()
+                .enumerate()
+                .filter_map(|(index, (expr, _))| {
+                    Some((expr.last_match_on_day(today - back, until)?, index))
+                })
+                // later entries win ties
+                .max()
+                .map(|(_, index)| index)
+        })
+    }
+
+    pub(crate) fn quota(&self, index: usize) -> Quota {
+        self.entries[index].1
+    }
+}
+
+pub(crate) fn now_unix_minute() -> i64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    (secs / 60) as i64
+}
+
+fn until_next_minute() -> Duration {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let into_minute = Duration::new(since_epoch.as_secs() % 60, since_epoch.subsec_nanos());
+    Duration::from_secs(60) - into_minute
+}
+
+/// Spawn the task that swaps the live limiter whenever a schedule entry fires.
+///
+/// The task holds only a weak reference to the limiter and exits once the
+/// owning policy has been dropped.
+pub(crate) fn spawn_scheduler<L>(
+    runtime: &Arc<dyn Runtime>,
+    schedule: Arc<Schedule>,
+    limiter: Weak<ArcSwap<L>>,
+    build: fn(&L, Quota) -> L,
+) where
+    L: Send + Sync + 'static,
+{
+    let timer = runtime.clone();
+    let mut shutdown = runtime.shutdown();
+    runtime.spawn(Box::pin(async move {
+        // the limiter last built from the schedule, to tell whether it was
+        // swapped since, e.g. by `GovernorHandle::set_quota`
+        let Some(live) = limiter.upgrade() else {
+            return;
+        };
+        let mut current = schedule.active_at(now_unix_minute());
+        let mut applied = Arc::downgrade(&live.load_full());
+        drop(live);
+        while runtime::tick(&*timer, until_next_minute(), &mut shutdown).await {
+            let Some(limiter) = limiter.upgrade() else {
+                return;
+            };
+            let Some(index) = schedule.firing_at(now_unix_minute()) else {
+                continue;
+            };
+            // rebuilding empties the limiter, so only when the quota changes
+            let swapped = !Weak::ptr_eq(&applied, &Arc::downgrade(&limiter.load_full()));
+            if current != Some(index) || swapped {
+                let quota = schedule.quota(index);
+                tracing::info!("Switching to scheduled quota {:?}", quota);
+                let built = Arc::new(build(&limiter.load(), quota));
+                applied = Arc::downgrade(&built);
+                limiter.store(built);
+                current = Some(index);
+            }
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU32;
+
+    // 2025-03-10 is a Monday
+    const MONDAY_MIDNIGHT: i64 = 20_157 * 1440;
+
+    #[test]
+    fn test_cron_matching() {
+        let business: CronExpr = "0 9 * * 1-5".parse().unwrap();
+        assert!(business.matches_minute(MONDAY_MIDNIGHT + 9 * 60));
+        assert!(!business.matches_minute(MONDAY_MIDNIGHT + 9 * 60 + 1));
+        // Sunday
+        assert!(!business.matches_minute(MONDAY_MIDNIGHT - 1440 + 9 * 60));
+
+        let every_quarter: CronExpr = "*/15 * * * *".parse().unwrap();
+        assert!(every_quarter.matches_minute(MONDAY_MIDNIGHT + 45));
+        assert!(!every_quarter.matches_minute(MONDAY_MIDNIGHT + 50));
+
+        assert_eq!(
+            "* * *".parse::<CronExpr>().unwrap_err(),
+            CronError::FieldCount(3)
+        );
+        // `*/2` doesn't restrict the days of the month, so only Mondays match
+        let mondays: CronExpr = "0 9 */2 * 1".parse().unwrap();
+        assert!(mondays.matches_minute(MONDAY_MIDNIGHT + 9 * 60));
+        assert!(!mondays.matches_minute(MONDAY_MIDNIGHT + 1440 + 9 * 60));
+
+        assert!(matches!(
+            "60 * * * *".parse::<CronExpr>(),
+            Err(CronError::OutOfRange { value: 60, .. })
+        ));
+    }
+
+    #[test]
+    fn test_schedule_active_entry() {
+        let quota = |n| Quota::per_second(NonZeroU32::new(n).unwrap());
+        let schedule = Schedule::new(vec![
+            ("0 9 * * 1-5".parse().unwrap(), quota(100)),
+            ("0 18 * * *".parse().unwrap(), quota(10)),
+        ]);
+
+        // Monday morning, before 9: last fired entry is Sunday 18:00
+        assert_eq!(schedule.active_at(MONDAY_MIDNIGHT + 8 * 60), Some(1));
+        assert_eq!(schedule.active_at(MONDAY_MIDNIGHT + 12 * 60), Some(0));
+        assert_eq!(schedule.active_at(MONDAY_MIDNIGHT + 20 * 60), Some(1));
+        assert_eq!(schedule.active_at(MONDAY_MIDNIGHT + 18 * 60), Some(1));
+
+        // entries that fired months ago are still found, but not beyond a year
+        let yearly = Schedule::new(vec![("30 6 1 1 *".parse().unwrap(), quota(1))]);
+        assert_eq!(yearly.active_at(MONDAY_MIDNIGHT), Some(0));
+        let leap_day = Schedule::new(vec![("0 0 29 2 *".parse().unwrap(), quota(1))]);
+        assert_eq!(leap_day.active_at(MONDAY_MIDNIGHT), None);
+    }
+}
@@ -0,0 +1,158 @@
+//! Abuse scores selecting among quota tiers
+//!
+//! No single signal tells abuse apart: a datacenter ASN, a headless TLS
+//! fingerprint or a history of denials each misfire on their own. A
+//! [`ScoreProvider`] supplied by the application combines whatever signals
+//! it has into one score, e.g. from a [`TlsFingerprint`](crate::TlsFingerprint)
+//! and a [`GeoResolver`](crate::GeoResolver) ASN in the context and the
+//! [`key_stats`](crate::GovernorHandle::key_stats) of the client. A
+//! [`ScoreSelector`] maps the score to a [`ScoreTier`] by its thresholds and
+//! checks the request against the policy of that tier:
+//!
+//! ```ignore
+//! let selector = ScoreSelector::new(my_scorer, normal_policy)
+//!     .tier(ScoreTier::Trusted, generous_policy)
+//!     .tier(ScoreTier::Suspicious, strict_policy)
+//!     .thresholds(0.2, 0.8);
+//! let policy = SelectingPolicy::new(selector);
+//! ```
+
+use std::collections::HashMap;
+
+use rama_core::Context;
+
+use crate::{GovernorPolicy, PolicySelector};
+
+/// Scores up to this are trusted unless configured otherwise
+const DEFAULT_TRUSTED_UP_TO: f64 = 0.2;
+
+/// Scores from this on are suspicious unless configured otherwise
+const DEFAULT_SUSPICIOUS_FROM: f64 = 0.7;
+
+/// Rates how likely a request is abusive, from 0 (surely not) to 1 (surely)
+pub trait ScoreProvider<State, Request>: Send + Sync + 'static {
+    /// The abuse score of `req`
+    fn score(&self, ctx: &Context<State>, req: &Request) -> f64;
+}
+
+impl<State, Request, F> ScoreProvider<State, Request> for F
+where
+    F: Fn(&Context<State>, &Request) -> f64 + Send + Sync + 'static,
+{
+    fn score(&self, ctx: &Context<State>, req: &Request) -> f64 {
+        self(ctx, req)
+    }
+}
+
+/// Quota tier of a request, by its abuse score
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScoreTier {
+    /// Scores up to the trusted threshold
+    Trusted,
+    /// Scores between the thresholds
+    Normal,
+    /// Scores from the suspicious threshold on, and scores that aren't numbers
+    Suspicious,
+}
+
+/// Selects the policy by the tier of the abuse score of a request
+///
+/// Tiers without a policy of their own use the normal policy.
+pub struct ScoreSelector<P> {
+    provider: P,
+    trusted_up_to: f64,
+    suspicious_from: f64,
+    policies: HashMap<ScoreTier, GovernorPolicy>,
+    normal: GovernorPolicy,
+}
+
+impl<P> ScoreSelector<P> {
+    /// Score requests with `provider`, checking them with `normal` unless
+    /// configured otherwise
+    pub fn new(provider: P, normal: GovernorPolicy) -> Self {
+        ScoreSelector {
+            provider,
+            trusted_up_to: DEFAULT_TRUSTED_UP_TO,
+            suspicious_from: DEFAULT_SUSPICIOUS_FROM,
+            policies: HashMap::new(),
+            normal,
+        }
+    }
+
+    /// Use `policy` for requests of `tier`
+    pub fn tier(mut self, tier: ScoreTier, policy: GovernorPolicy) -> Self {
+        self.policies.insert(tier, policy);
+        self
+    }
+
+    /// Trust scores up to `trusted_up_to` and suspect scores from
+    /// `suspicious_from` on, 0.2 and 0.7 by default
+    ///
+    /// # Panics
+    ///
+    /// Panics if `trusted_up_to` isn't below `suspicious_from`.
+    pub fn thresholds(mut self, trusted_up_to: f64, suspicious_from: f64) -> Self {
+        assert!(
+            trusted_up_to < suspicious_from,
+            "Trusted threshold must be below the suspicious one"
+        );
+        self.trusted_up_to = trusted_up_to;
+        self.suspicious_from = suspicious_from;
+        self
+    }
+
+    /// The tier of a request with abuse score `score`
+    pub fn tier_of(&self, score: f64) -> ScoreTier {
+        if score <= self.trusted_up_to {
+            ScoreTier::Trusted
+        } else if score < self.suspicious_from {
+            ScoreTier::Normal
+        } else {
+            ScoreTier::Suspicious
+        }
+    }
+}
+
+impl<P> std::fmt::Debug for ScoreSelector<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScoreSelector")
+            .field("trusted_up_to", &self.trusted_up_to)
+            .field("suspicious_from", &self.suspicious_from)
+            .field("tiers", &self.policies.keys())
+            .finish()
+    }
+}
+
+impl<P, State, Request> PolicySelector<State, Request> for ScoreSelector<P>
+where
+    P: ScoreProvider<State, Request>,
+{
+    fn select(&self, ctx: &Context<State>, req: &Request) -> &GovernorPolicy {
+        let score = self.provider.score(ctx, req);
+        let tier = self.tier_of(score);
+        tracing::trace!(score, ?tier, "Scored request");
+        self.policies.get(&tier).unwrap_or(&self.normal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score_selector() {
+        let per_second = |n| GovernorPolicy::builder().per_second(n).build();
+        let selector = ScoreSelector::new(|_: &Context<()>, score: &f64| *score, per_second(10))
+            .tier(ScoreTier::Trusted, per_second(100))
+            .tier(ScoreTier::Suspicious, per_second(1))
+            .thresholds(0.1, 0.9);
+
+        assert_eq!(selector.tier_of(f64::NAN), ScoreTier::Suspicious);
+        let ctx = Context::default();
+        let interval = |score: f64| selector.select(&ctx, &score).status().replenish_interval_ms;
+        assert_eq!(
+            [0.0, 0.1, 0.5, 0.9, 1.0].map(interval),
+            [10, 10, 100, 1000, 1000]
+        );
+    }
+}
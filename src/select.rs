@@ -0,0 +1,114 @@
+//! Programmatic per-request policy selection
+//!
+//! An alternative to rama's matcher vectors when the choice of policy depends
+//! on something computed earlier in the stack, like an auth scope stored in
+//! the [`Context`]. Implement [`PolicySelector`] and wrap it in a
+//! [`SelectingPolicy`] to use it with `LimitLayer`.
+
+use std::fmt;
+
+use rama_core::Context;
+use rama_core::layer::limit::policy::{Policy, PolicyResult};
+
+use crate::{Explanation, GovernorError, GovernorGuard, GovernorPolicy};
+
+/// Chooses the policy that checks a request
+pub trait PolicySelector<State, Request>: Send + Sync + 'static {
+    /// The policy for `req`
+    fn select(&self, ctx: &Context<State>, req: &Request) -> &GovernorPolicy;
+}
+
+/// Policy delegating every check to the policy chosen by a [`PolicySelector`]
+#[derive(Clone)]
+pub struct SelectingPolicy<S> {
+    selector: S,
+}
+
+impl<S> SelectingPolicy<S> {
+    /// Check requests with the policies chosen by `selector`
+    pub fn new(selector: S) -> Self {
+        SelectingPolicy { selector }
+    }
+
+    /// The wrapped selector
+    pub fn selector(&self) -> &S {
+        &self.selector
+    }
+
+    /// Explain the decision of the selected policy on `request` without
+    /// counting it, see [`GovernorPolicy::explain`]
+    pub fn explain<State, Request: 'static>(
+        &self,
+        ctx: &Context<State>,
+        request: &Request,
+    ) -> Explanation
+    where
+        S: PolicySelector<State, Request>,
+    {
+        self.selector.select(ctx, request).explain(ctx, request)
+    }
+}
+
+impl<S> fmt::Debug for SelectingPolicy<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SelectingPolicy").finish_non_exhaustive()
+    }
+}
+
+impl<S, State, Request> Policy<State, Request> for SelectingPolicy<S>
+where
+    S: PolicySelector<State, Request>,
+    State: Clone + Send + Sync + 'static,
+    Request: Send + Sync + 'static,
+{
+    type Guard = GovernorGuard;
+    type Error = GovernorError;
+
+    async fn check(
+        &self,
+        ctx: Context<State>,
+        request: Request,
+    ) -> PolicyResult<State, Request, Self::Guard, Self::Error> {
+        let policy = self.selector.select(&ctx, &request);
+        policy.check(ctx, request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rama_core::layer::limit::policy::PolicyOutput;
+
+    /// Admins get a generous limit, everybody else a strict one
+    struct ByScope {
+        admin: GovernorPolicy,
+        user: GovernorPolicy,
+    }
+
+    impl PolicySelector<(), &'static str> for ByScope {
+        fn select(&self, _ctx: &Context<()>, scope: &&'static str) -> &GovernorPolicy {
+            match *scope {
+                "admin" => &self.admin,
+                _ => &self.user,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_selecting_policy() {
+        let policy = SelectingPolicy::new(ByScope {
+            admin: GovernorPolicy::builder()
+                .per_second(10)
+                .burst_size(5)
+                .build(),
+            user: GovernorPolicy::builder().per_minute(1).build(),
+        });
+
+        let mut admitted = 0;
+        for scope in ["user", "user", "admin", "admin", "admin"] {
+            let result = policy.check(Context::default(), scope).await;
+            admitted += matches!(result.output, PolicyOutput::Ready(_)) as usize;
+        }
+        assert_eq!(admitted, 4);
+    }
+}
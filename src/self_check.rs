@@ -0,0 +1,225 @@
+//! Startup self-test of a built policy
+//!
+//! [`GovernorPolicy::self_check`] exercises what a policy depends on before it
+//! sees real traffic, so a deployment with an unreachable store or a broken
+//! clock fails at startup rather than failing open or closed on the first
+//! request.
+
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use governor::Quota;
+use governor::clock::Clock;
+use serde::Serialize;
+
+use crate::clock::LimiterClock;
+use crate::runtime::Runtime;
+use crate::{GovernorPolicy, StoreDecision};
+
+/// Key a store is probed with, kept apart from real keys by its prefix
+const PROBE_KEY: &str = "__governor_self_check";
+
+/// How long the limiter clock is given to advance
+const CLOCK_PROBE: Duration = Duration::from_millis(1);
+
+/// 2024-01-01T00:00:00Z; a system clock before this is certainly wrong
+const EARLIEST_PLAUSIBLE_UNIX_SECS: u64 = 1_704_067_200;
+
+/// What a [`SelfCheckItem`] checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelfCheckKind {
+    /// The system time is plausible and, on a runtime, the limiter clock advances
+    Clock,
+    /// Background tasks have a runtime to run on
+    Runtime,
+    /// A write and read round trip through the store
+    Store,
+}
+
+/// Outcome of a single check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "outcome", content = "detail")]
+pub enum SelfCheckOutcome {
+    /// The check succeeded
+    Passed,
+    /// The check doesn't apply to this kind of policy
+    Skipped,
+    /// The check failed for the given reason
+    Failed(String),
+}
+
+/// A single check of a [`SelfCheckReport`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SelfCheckItem {
+    /// What was checked
+    pub kind: SelfCheckKind,
+    /// How it went
+    #[serde(flatten)]
+    pub outcome: SelfCheckOutcome,
+    /// How long the check took
+    pub elapsed: Duration,
+}
+
+/// Result of [`GovernorPolicy::self_check`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SelfCheckReport {
+    /// Name of the checked policy
+    pub policy: String,
+    /// All checks, in the order they ran
+    pub items: Vec<SelfCheckItem>,
+}
+
+impl SelfCheckReport {
+    /// Returns true if no check failed
+    pub fn is_ok(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// The checks that failed
+    pub fn failures(&self) -> impl Iterator<Item = &SelfCheckItem> {
+        self.items
+            .iter()
+            .filter(|item| matches!(item.outcome, SelfCheckOutcome::Failed(_)))
+    }
+}
+
+impl fmt::Display for SelfCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "self check of policy {:?}:", self.policy)?;
+        for item in &self.items {
+            write!(f, "\n  {:?}: ", item.kind)?;
+            match &item.outcome {
+                SelfCheckOutcome::Passed => write!(f, "passed in {:?}", item.elapsed)?,
+                SelfCheckOutcome::Skipped => write!(f, "skipped")?,
+                SelfCheckOutcome::Failed(reason) => write!(f, "FAILED: {reason}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the clocks, waiting on the timer of `runtime` for the limiter clock
+/// to advance; without a runtime only the system time is checked
+async fn check_clock(runtime: Option<&Arc<dyn Runtime>>) -> SelfCheckOutcome {
+    if let Some(runtime) = runtime.filter(|runtime| runtime.is_available()) {
+        let clock = LimiterClock::default();
+        let before = clock.now();
+        runtime.sleep(CLOCK_PROBE).await;
+        if clock.now() <= before {
+            return SelfCheckOutcome::Failed("limiter clock does not advance".to_owned());
+        }
+    }
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(now) if now.as_secs() >= EARLIEST_PLAUSIBLE_UNIX_SECS => SelfCheckOutcome::Passed,
+        _ => SelfCheckOutcome::Failed(
+            "system time is before 2024, schedules would misfire".to_owned(),
+        ),
+    }
+}
+
+impl GovernorPolicy {
+    /// Exercise the clock, runtime and store of this policy
+    ///
+    /// Store backed policies consume one cell of a probe key under a quota of
+    /// their own; the limits of real keys are not touched. Meant to run once
+    /// at startup, e.g. failing the deployment unless the report
+    /// [`is_ok`](SelfCheckReport::is_ok).
+    pub async fn self_check(&self) -> SelfCheckReport {
+        let mut items = Vec::with_capacity(3);
+        let start = Instant::now();
+        let outcome = check_clock(self.runtime()).await;
+        items.push(SelfCheckItem {
+            kind: SelfCheckKind::Clock,
+            outcome,
+            elapsed: start.elapsed(),
+        });
+
+        let start = Instant::now();
+        let outcome = match self.runtime() {
+            Some(runtime) if runtime.is_available() => SelfCheckOutcome::Passed,
+            Some(_) => SelfCheckOutcome::Failed(
+                "runtime not available here, background tasks may not be running".to_owned(),
+            ),
+            None => SelfCheckOutcome::Failed(
+                "no runtime configured, background tasks such as GC will not run".to_owned(),
+            ),
+        };
+        items.push(SelfCheckItem {
+            kind: SelfCheckKind::Runtime,
+            outcome,
+            elapsed: start.elapsed(),
+        });
+
+        let start = Instant::now();
+        let outcome = match self {
+            GovernorPolicy::Store(policy) => {
+                let quota = Quota::per_second(NonZeroU32::MAX);
+                match policy.store.check_n(PROBE_KEY, quota, 1).await {
+                    Ok(StoreDecision::Allowed) => SelfCheckOutcome::Passed,
+                    Ok(StoreDecision::Denied { .. }) => SelfCheckOutcome::Failed(
+                        "store denied a probe well within its quota".to_owned(),
+                    ),
+                    Err(err) => SelfCheckOutcome::Failed(err.to_string()),
+                }
+            }
+            GovernorPolicy::Direct(_) | GovernorPolicy::Keyed(_) => SelfCheckOutcome::Skipped,
+        };
+        items.push(SelfCheckItem {
+            kind: SelfCheckKind::Store,
+            outcome,
+            elapsed: start.elapsed(),
+        });
+
+        let report = SelfCheckReport {
+            policy: self.name().to_owned(),
+            items,
+        };
+        if report.is_ok() {
+            tracing::debug!("{}", report);
+        } else {
+            tracing::error!("{}", report);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RateLimitStore, StoreError, StoreFuture};
+
+    struct Unreachable;
+
+    impl RateLimitStore for Unreachable {
+        fn check_n<'a>(
+            &'a self,
+            _key: &'a str,
+            _quota: Quota,
+            _n: u32,
+        ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+            Box::pin(async { Err(StoreError::new("connection refused")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_self_check() {
+        let report = GovernorPolicy::builder()
+            .per_second(1)
+            .build()
+            .self_check()
+            .await;
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.items[2].outcome, SelfCheckOutcome::Skipped);
+
+        let report = GovernorPolicy::builder()
+            .per_second(1)
+            .build_with_store(Unreachable)
+            .self_check()
+            .await;
+        let failures: Vec<_> = report.failures().map(|item| item.kind).collect();
+        assert_eq!(failures, [SelfCheckKind::Store]);
+    }
+}
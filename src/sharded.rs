@@ -0,0 +1,283 @@
+//! Sharded in-memory rate limit store
+//!
+//! A single concurrent map shows lock contention on machines with many cores.
+//! [`ShardedStore`] splits keys over independent shards by key hash, each with
+//! its own lock and its own garbage collection, so checks for different keys
+//! rarely touch the same lock.
+//!
+//! On very large stores, a full [`gc`](ShardedStore::gc) still keeps a core
+//! busy for long. [`sweep`](ShardedStore::sweep) instead collects shards in
+//! turn until a [`SweepBudget`] is used up, continuing where the previous
+//! sweep stopped, and [`sweep_every`](ShardedStore::sweep_every) runs such
+//! sweeps in the background.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use governor::Quota;
+
+use crate::runtime::{self, Runtime};
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture};
+
+/// Collect a shard's replenished keys every this many inserts into it
+const GC_EVERY: u64 = 4096;
+
+#[derive(Default)]
+struct Shard {
+    /// Theoretical arrival time per key, in nanoseconds since the store was created
+    tats: HashMap<String, u64>,
+    inserts: u64,
+}
+
+impl Shard {
+    fn gc(&mut self, now: u64) -> usize {
+        let before = self.tats.len();
+        self.tats.retain(|_, tat| *tat > now);
+        before - self.tats.len()
+    }
+}
+
+/// How much work a single [`ShardedStore::sweep`] may do
+///
+/// A sweep stops after the shard during which a limit was reached, and
+/// always collects at least one shard. Without limits it collects all shards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SweepBudget {
+    max_keys: Option<usize>,
+    max_time: Option<Duration>,
+}
+
+impl SweepBudget {
+    /// A budget collecting all shards
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// Stop once `keys` keys have been looked at
+    pub fn max_keys(mut self, keys: usize) -> Self {
+        self.max_keys = Some(keys);
+        self
+    }
+
+    /// Stop once the sweep has taken `time`
+    pub fn max_time(mut self, time: Duration) -> Self {
+        self.max_time = Some(time);
+        self
+    }
+}
+
+/// A [`RateLimitStore`] keeping GCRA state in memory, split over shards
+pub struct ShardedStore {
+    shards: Box<[Mutex<Shard>]>,
+    hasher: RandomState,
+    start: Instant,
+    /// Shard the next sweep starts at
+    cursor: AtomicUsize,
+}
+
+impl ShardedStore {
+    /// Create a store with four shards per available core
+    pub fn new() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_shards(cores * 4)
+    }
+
+    /// Create a store with `shards` shards, rounded up to a power of two
+    pub fn with_shards(shards: usize) -> Self {
+        let shards = shards.max(1).next_power_of_two();
+        ShardedStore {
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            start: Instant::now(),
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of shards
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Number of keys currently tracked
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| lock(shard).tats.len()).sum()
+    }
+
+    /// Whether no keys are tracked
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget keys that are fully replenished, returning how many were removed
+    ///
+    /// Shards are locked one at a time, so concurrent checks are only held up
+    /// for the shard being collected. Shards also collect themselves as they grow.
+    pub fn gc(&self) -> usize {
+        let now = self.now();
+        self.shards.iter().map(|shard| lock(shard).gc(now)).sum()
+    }
+
+    /// Collect shards in turn, starting after the last shard the previous
+    /// sweep collected, until `budget` is used up or every shard was
+    /// collected once, returning how many keys were removed
+    pub fn sweep(&self, budget: SweepBudget) -> usize {
+        let started = Instant::now();
+        let now = self.now();
+        let first = self.cursor.load(Ordering::Relaxed);
+        let (mut examined, mut removed) = (0, 0);
+        for i in 0..self.shards.len() {
+            let index = (first + i) & (self.shards.len() - 1);
+            let mut shard = lock(&self.shards[index]);
+            examined += shard.tats.len();
+            removed += shard.gc(now);
+            drop(shard);
+            self.cursor.store(index + 1, Ordering::Relaxed);
+
+            let keys_spent = budget.max_keys.is_some_and(|max| examined >= max);
+            let time_spent = budget.max_time.is_some_and(|max| started.elapsed() >= max);
+            if keys_spent || time_spent {
+                break;
+            }
+        }
+        removed
+    }
+
+    /// Sweep the store within `budget` every `interval` on `runtime`, until
+    /// the store is dropped or the runtime shuts down
+    pub fn sweep_every(
+        self: &Arc<Self>,
+        runtime: impl Runtime,
+        interval: Duration,
+        budget: SweepBudget,
+    ) {
+        let store = Arc::downgrade(self);
+        let runtime = Arc::new(runtime);
+        let timer = runtime.clone();
+        let mut shutdown = runtime.shutdown();
+        runtime.spawn(Box::pin(async move {
+            while runtime::tick(&*timer, interval, &mut shutdown).await {
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                let removed = store.sweep(budget);
+                tracing::trace!("Sweep removed {} replenished keys", removed);
+            }
+        }));
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash & (self.shards.len() - 1)]
+    }
+
+    pub(crate) fn check(&self, key: &str, quota: Quota, n: u32) -> StoreDecision {
+        let t = (quota.replenish_interval().as_nanos() as u64).max(1);
+        let tau = t * u64::from(quota.burst_size().get());
+        let now = self.now();
+
+        let mut shard = lock(self.shard(key));
+        let tat = shard.tats.get(key).copied().unwrap_or(now);
+        let new_tat = tat.max(now) + u64::from(n) * t;
+        let allow_at = new_tat.saturating_sub(tau);
+        if allow_at > now {
+            return StoreDecision::Denied {
+                retry_after: Duration::from_nanos(allow_at - now),
+            };
+        }
+
+        match shard.tats.get_mut(key) {
+            Some(tat) => *tat = new_tat,
+            None => {
+                shard.tats.insert(key.to_owned(), new_tat);
+                shard.inserts += 1;
+                if shard.inserts % GC_EVERY == 0 {
+                    shard.gc(now);
+                }
+            }
+        }
+        StoreDecision::Allowed
+    }
+}
+
+impl Default for ShardedStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for ShardedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardedStore")
+            .field("shards", &self.shards.len())
+            .finish()
+    }
+}
+
+/// A panic while holding a shard can't leave its map inconsistent, so ignore poisoning
+fn lock(shard: &Mutex<Shard>) -> std::sync::MutexGuard<'_, Shard> {
+    shard
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl RateLimitStore for ShardedStore {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        let decision = self.check(key, quota, n);
+        Box::pin(async move { Ok(decision) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU32;
+
+    #[tokio::test]
+    async fn test_sharded_store() {
+        let store = ShardedStore::with_shards(6);
+        assert_eq!(store.shard_count(), 8);
+
+        let quota =
+            Quota::per_hour(NonZeroU32::new(1).unwrap()).allow_burst(NonZeroU32::new(2).unwrap());
+        for key in ["alice", "bob"] {
+            assert_eq!(
+                store.check_n(key, quota, 2).await.unwrap(),
+                StoreDecision::Allowed
+            );
+            assert!(matches!(
+                store.check_n(key, quota, 1).await.unwrap(),
+                StoreDecision::Denied { .. }
+            ));
+        }
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.gc(), 0);
+    }
+
+    #[test]
+    fn test_budgeted_sweep() {
+        let store = ShardedStore::with_shards(4);
+        let quota = Quota::with_period(Duration::from_millis(1)).unwrap();
+        for i in 0..64 {
+            store.check(&format!("key-{i}"), quota, 1);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+
+        // one shard per sweep, continuing round-robin
+        let budget = SweepBudget::unlimited().max_keys(1);
+        let removed: usize = (0..4).map(|_| store.sweep(budget)).sum();
+        assert_eq!(removed, 64);
+        assert!(store.is_empty());
+    }
+}
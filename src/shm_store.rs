@@ -0,0 +1,330 @@
+//! Shared memory backed rate limit store
+//!
+//! Prefork and multi-process deployments run several workers on one host,
+//! each with its own memory. [`SharedMemoryStore`] keeps GCRA state in a
+//! memory mapped file, e.g. under `/dev/shm`, updated with atomic
+//! compare-and-swap, so all workers enforce one host-level limit without a
+//! network round trip to Redis.
+//!
+//! The file holds a fixed size open addressing table of key hashes and
+//! theoretical arrival times. Keys are identified by a 64-bit hash, so two
+//! keys whose hashes collide share a bucket. Slots of keys whose bucket is
+//! full again are reused, and a key finding no free slot within a few probes
+//! fails the check with a [`StoreError`]. Times are read from the system
+//! clock, which all processes on the host share.
+//!
+//! The arrival time word of a slot also holds a generation, odd while the
+//! slot changes owner, so an update racing with a reclaim fails its
+//! compare-and-swap and looks the key up again instead of charging the new
+//! owner.
+
+use std::fs::OpenOptions;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use governor::Quota;
+use memmap2::MmapMut;
+
+use crate::stable_hash::stable_hasher;
+use crate::store::{RateLimitStore, StoreDecision, StoreError, StoreFuture, gcra_micros};
+
+/// Marks a file initialized by this store, and the version of its layout
+const MAGIC: u64 = u64::from_be_bytes(*b"govshm02");
+
+/// Words before the first slot: the magic and the number of slots
+const HEADER_WORDS: usize = 2;
+
+/// Words per slot: the key hash and the theoretical arrival time
+const SLOT_WORDS: usize = 2;
+
+/// Slots probed for a key before giving up
+const MAX_PROBES: usize = 16;
+
+/// Bits of an arrival time word holding the time, in microseconds since the
+/// Unix epoch, enough until 2112; the bits above hold the generation
+const TIME_BITS: u32 = 52;
+
+/// Mask of the time in an arrival time word
+const TIME_MASK: u64 = (1 << TIME_BITS) - 1;
+
+/// One generation in an arrival time word
+const GENERATION: u64 = 1 << TIME_BITS;
+
+/// Reads of a slot changing owner before it is skipped, in case the process
+/// reclaiming it died halfway
+const MAX_SPINS: usize = 1 << 10;
+
+/// A [`RateLimitStore`] shared by the processes mapping the same file
+pub struct SharedMemoryStore {
+    map: MmapMut,
+    slots: usize,
+}
+
+impl SharedMemoryStore {
+    /// Map the table at `path` with room for `slots` keys, creating it if missing
+    ///
+    /// Every process must open the same path with the same number of slots.
+    /// The file outlives the processes; remove it to start over.
+    pub fn open(path: impl AsRef<Path>, slots: usize) -> io::Result<Self> {
+        if slots == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shared memory store needs at least one slot",
+            ));
+        }
+        let len = (HEADER_WORDS + slots * SLOT_WORDS) * size_of::<u64>();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let existing = file.metadata()?.len();
+        if existing == 0 {
+            file.set_len(len as u64)?;
+        } else if existing != len as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("shared memory table has {existing} bytes, expected {len}"),
+            ));
+        }
+        // SAFETY: the mapping is only accessed through atomics, so writes of
+        // other processes can't tear or invalidate what this one reads
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        let store = SharedMemoryStore { map, slots };
+
+        let magic = store.word(0);
+        match magic.compare_exchange(0, MAGIC, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => store.word(1).store(slots as u64, Ordering::Release),
+            Err(MAGIC) => {}
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a shared memory rate limit table",
+                ));
+            }
+        }
+        Ok(store)
+    }
+
+    /// The word at `index` of the mapping
+    fn word(&self, index: usize) -> &AtomicU64 {
+        assert!(index < HEADER_WORDS + self.slots * SLOT_WORDS);
+        // SAFETY: in bounds, and the page aligned mapping keeps words aligned
+        unsafe { &*self.map.as_ptr().cast::<AtomicU64>().add(index) }
+    }
+
+    /// The key hash and arrival time words of slot `index`
+    fn slot(&self, index: usize) -> (&AtomicU64, &AtomicU64) {
+        let first = HEADER_WORDS + index * SLOT_WORDS;
+        (self.word(first), self.word(first + 1))
+    }
+
+    /// The arrival time word of the slot of `hash` and its value, claiming a
+    /// slot if needed
+    fn find(&self, hash: u64, now: u64) -> Option<(&AtomicU64, u64)> {
+        let start = (hash % self.slots as u64) as usize;
+        let probes =
+            || (0..MAX_PROBES.min(self.slots)).map(|probe| self.slot((start + probe) % self.slots));
+        for (owner, tat) in probes() {
+            // the owner is read after the generation it belongs to
+            let Some(word) = settled(tat) else { continue };
+            if owner.load(Ordering::Acquire) == hash {
+                return Some((tat, word));
+            }
+        }
+        for (owner, tat) in probes() {
+            while let Some(word) = settled(tat) {
+                let current = owner.load(Ordering::Acquire);
+                if current == hash {
+                    return Some((tat, word));
+                }
+                // free, or its key's bucket is full again and its state worthless
+                if current != 0 && word & TIME_MASK > now {
+                    break;
+                }
+                // an odd generation locks out updates while the owner changes
+                let locked = word.wrapping_add(GENERATION);
+                if tat
+                    .compare_exchange(word, locked, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+                {
+                    owner.store(hash, Ordering::Release);
+                    let claimed = locked.wrapping_add(GENERATION) & !TIME_MASK;
+                    tat.store(claimed, Ordering::Release);
+                    return Some((tat, claimed));
+                }
+            }
+        }
+        None
+    }
+
+    /// Consume `n` cells for the key hashed to `hash`
+    fn check_hash(&self, hash: u64, quota: &Quota, n: u32) -> Result<StoreDecision, StoreError> {
+        let (t, tau) = gcra_micros(quota);
+        let now = now_micros();
+        'find: loop {
+            let Some((slot, mut word)) = self.find(hash, now) else {
+                return Err(StoreError::new(io::Error::other(
+                    "shared memory table is full",
+                )));
+            };
+            loop {
+                let new_tat = ((word & TIME_MASK).max(now) + u64::from(n) * t).min(TIME_MASK);
+                let allow_at = new_tat.saturating_sub(tau);
+                if allow_at > now {
+                    return Ok(StoreDecision::Denied {
+                        retry_after: Duration::from_micros(allow_at - now),
+                    });
+                }
+                let new_word = word & !TIME_MASK | new_tat;
+                match slot.compare_exchange_weak(
+                    word,
+                    new_word,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return Ok(StoreDecision::Allowed),
+                    // the slot changed owner since it was found
+                    Err(current) if current & !TIME_MASK != word & !TIME_MASK => continue 'find,
+                    Err(current) => word = current,
+                }
+            }
+        }
+    }
+}
+
+/// The arrival time word `tat` once its slot isn't changing owner, or `None`
+/// if it keeps changing
+fn settled(tat: &AtomicU64) -> Option<u64> {
+    for _ in 0..MAX_SPINS {
+        let word = tat.load(Ordering::Acquire);
+        if (word >> TIME_BITS) % 2 == 0 {
+            return Some(word);
+        }
+        std::hint::spin_loop();
+    }
+    None
+}
+
+/// Microseconds since the Unix epoch, the clock shared by all processes
+fn now_micros() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_micros().min(TIME_MASK.into()) as u64
+}
+
+/// Hash of `key`, the same in every process
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = stable_hasher();
+    hasher.write(key.as_bytes());
+    // zero marks free slots
+    hasher.finish().max(1)
+}
+
+impl std::fmt::Debug for SharedMemoryStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedMemoryStore")
+            .field("slots", &self.slots)
+            .finish()
+    }
+}
+
+impl RateLimitStore for SharedMemoryStore {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        Box::pin(std::future::ready(self.check_hash(
+            key_hash(key),
+            &quota,
+            n,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_processes_share_quota() {
+        let path = std::env::temp_dir().join(format!("governor-shm-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // each worker process maps the file on its own
+        let first = SharedMemoryStore::open(&path, 64).unwrap();
+        let second = SharedMemoryStore::open(&path, 64).unwrap();
+        assert!(SharedMemoryStore::open(&path, 32).is_err());
+
+        let quota = Quota::per_minute(2.try_into().unwrap());
+        let check = async |store: &SharedMemoryStore, key| store.check_n(key, quota, 1).await;
+        assert_eq!(
+            check(&first, "alice").await.unwrap(),
+            StoreDecision::Allowed
+        );
+        assert_eq!(
+            check(&second, "alice").await.unwrap(),
+            StoreDecision::Allowed
+        );
+        let StoreDecision::Denied { retry_after } = check(&first, "alice").await.unwrap() else {
+            panic!("Expected the shared quota to be used up");
+        };
+        assert!(retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30));
+        assert_eq!(check(&second, "bob").await.unwrap(), StoreDecision::Allowed);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reclaims_race_with_updates() {
+        let path = std::env::temp_dir().join(format!("governor-shm-race-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let keys = ["alice", "bob", "carol", "dave"].map(key_hash);
+
+        // a worker finds the slot of alice, and bob takes it over before the update
+        let store = SharedMemoryStore::open(&path, 1).unwrap();
+        let now = now_micros();
+        let (slot, word) = store.find(keys[0], now).unwrap();
+        store.find(keys[1], now).unwrap();
+        let stale = slot.compare_exchange(word, word + 1, Ordering::AcqRel, Ordering::Acquire);
+        assert!(stale.is_err());
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+
+        // four keys taking turns in two slots, reclaimed as they fill again
+        let store = SharedMemoryStore::open(&path, 2).unwrap();
+        let period = Duration::from_millis(2);
+        let quota = Quota::with_period(period).unwrap();
+        let allowed = keys.map(|_| std::sync::atomic::AtomicU32::new(0));
+
+        let start = std::time::Instant::now();
+        std::thread::scope(|scope| {
+            for worker in 0..8 {
+                let (store, keys, allowed) = (&store, &keys, &allowed);
+                scope.spawn(move || {
+                    let mut i = worker;
+                    while start.elapsed() < Duration::from_millis(100) {
+                        let key = i % keys.len();
+                        if let Ok(StoreDecision::Allowed) = store.check_hash(keys[key], &quota, 1) {
+                            allowed[key].fetch_add(1, Ordering::Relaxed);
+                        }
+                        i += 1;
+                    }
+                });
+            }
+        });
+        // an update landing in a reclaimed slot would let its key in again early
+        let most = start.elapsed().as_micros() / period.as_micros() + 1;
+        for allowed in &allowed {
+            assert!(u128::from(allowed.load(Ordering::Relaxed)) <= most);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+//! Closing connections that trickle in their request head
+//!
+//! Slowloris clients open many connections and send each request head a
+//! byte at a time, just fast enough to dodge idle timeouts, tying up a
+//! connection slot per client at next to no cost. [`HeadReadLayer`] wraps
+//! the stream of each connection and fails its reads once the head arrives
+//! slower than a minimum number of bytes per interval, so the server closes
+//! the connection. Mount it on the transport, before the HTTP server:
+//!
+//! ```ignore
+//! TcpListener::bind("0.0.0.0:8080").await?.serve(
+//!     HeadReadLayer::new(256, Duration::from_secs(1))
+//!         .layer(HttpServer::auto(executor).service(app)),
+//! );
+//! ```
+//!
+//! Only the head of the first request is watched: after it, the connection
+//! may idle between keep-alive requests and bodies may be slow on purpose.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use rama_core::error::BoxError;
+use rama_core::{Context, Layer, Service};
+use rama_net::stream::Stream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// End of an HTTP/1 request head
+const HEAD_END: &[u8] = b"\r\n\r\n";
+
+/// Layer closing connections whose request head arrives too slowly
+#[derive(Debug, Clone, Copy)]
+pub struct HeadReadLayer {
+    min_bytes: usize,
+    interval: Duration,
+}
+
+impl HeadReadLayer {
+    /// Require at least `min_bytes` of the request head every `interval`
+    ///
+    /// The first interval starts when the connection is accepted, so clients
+    /// that connect and send nothing are closed after one interval too.
+    pub fn new(min_bytes: usize, interval: Duration) -> Self {
+        HeadReadLayer {
+            min_bytes,
+            interval,
+        }
+    }
+}
+
+impl<S> Layer<S> for HeadReadLayer {
+    type Service = HeadReadService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HeadReadService {
+            inner,
+            min_bytes: self.min_bytes,
+            interval: self.interval,
+        }
+    }
+}
+
+/// Service created by [`HeadReadLayer`]
+#[derive(Debug, Clone)]
+pub struct HeadReadService<S> {
+    inner: S,
+    min_bytes: usize,
+    interval: Duration,
+}
+
+impl<S, State, IO> Service<State, IO> for HeadReadService<S>
+where
+    S: Service<State, HeadReadStream<IO>, Error: Into<BoxError>>,
+    State: Clone + Send + Sync + 'static,
+    IO: Stream + Unpin,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+
+    async fn serve(&self, ctx: Context<State>, stream: IO) -> Result<Self::Response, Self::Error> {
+        let stream = HeadReadStream::new(stream, self.min_bytes, self.interval);
+        self.inner.serve(ctx, stream).await.map_err(Into::into)
+    }
+}
+
+/// Progress of the request head
+struct HeadProgress {
+    min_bytes: usize,
+    interval: Duration,
+    /// Bytes of the head read in the current interval
+    read: usize,
+    deadline: Pin<Box<Sleep>>,
+    /// How many bytes of [`HEAD_END`] the bytes read so far end with
+    matched: usize,
+}
+
+impl HeadProgress {
+    /// Count `bytes` read, returning true once the head is complete
+    fn observe(&mut self, bytes: &[u8]) -> bool {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.matched = match byte == HEAD_END[self.matched] {
+                true => self.matched + 1,
+                false => usize::from(byte == HEAD_END[0]),
+            };
+            if self.matched == HEAD_END.len() {
+                self.read += i + 1;
+                return true;
+            }
+        }
+        self.read += bytes.len();
+        false
+    }
+
+    /// Fail once an interval passed with too few bytes of the head
+    fn poll_deadline(&mut self, cx: &mut TaskContext<'_>) -> io::Result<()> {
+        while self.deadline.as_mut().poll(cx).is_ready() {
+            if self.read < self.min_bytes {
+                tracing::debug!("Closing connection sending its request head too slowly");
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "request head sent too slowly",
+                ));
+            }
+            self.read = 0;
+            let next = self.deadline.deadline() + self.interval;
+            self.deadline.as_mut().reset(next);
+        }
+        Ok(())
+    }
+}
+
+/// Stream failing reads once the request head arrives too slowly
+pub struct HeadReadStream<IO> {
+    inner: IO,
+    /// Gone once the head is complete
+    head: Option<HeadProgress>,
+}
+
+impl<IO> HeadReadStream<IO> {
+    fn new(inner: IO, min_bytes: usize, interval: Duration) -> Self {
+        HeadReadStream {
+            inner,
+            head: Some(HeadProgress {
+                min_bytes,
+                interval,
+                read: 0,
+                deadline: Box::pin(tokio::time::sleep_until(Instant::now() + interval)),
+                matched: 0,
+            }),
+        }
+    }
+
+    /// The wrapped stream
+    pub fn get_ref(&self) -> &IO {
+        &self.inner
+    }
+}
+
+impl<IO> std::fmt::Debug for HeadReadStream<IO> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeadReadStream")
+            .field("head_complete", &self.head.is_none())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for HeadReadStream<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let Some(head) = &mut this.head else {
+            return result;
+        };
+        if let Poll::Ready(Ok(())) = &result {
+            if head.observe(&buf.filled()[filled..]) {
+                this.head = None;
+                return result;
+            }
+        }
+        // also registers the deadline, so a stalled read is woken up for it
+        head.poll_deadline(cx)?;
+        result
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for HeadReadStream<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_slow_head_is_cut_off() {
+        let interval = Duration::from_millis(50);
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = HeadReadStream::new(server, 8, interval);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(server.read(&mut buf).await.unwrap(), 16);
+        // a byte per interval is too slow
+        let trickle = tokio::spawn(async move {
+            for byte in b"Host: x\r\n\r\n" {
+                client.write_all(&[*byte]).await.unwrap();
+                tokio::time::sleep(interval).await;
+            }
+        });
+        let mut err = None;
+        while err.is_none() {
+            err = server.read(&mut buf).await.err();
+        }
+        assert_eq!(err.unwrap().kind(), io::ErrorKind::TimedOut);
+        trickle.abort();
+
+        // once the head is in, the connection may idle
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = HeadReadStream::new(server, 8, interval);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 27);
+        tokio::time::sleep(interval * 3).await;
+        client.write_all(b"body").await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 4);
+    }
+}
@@ -0,0 +1,130 @@
+//! Serializable snapshots of keyed limiter state
+//!
+//! Snapshots let deploys and restarts carry over each key's quota usage,
+//! instead of handing every client a fresh burst.
+
+use std::time::{Duration, SystemTime};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::GovernorKey;
+use crate::keyed::KeyedLimiter;
+
+/// Error returned when exporting or importing limiter state
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    /// Snapshots are only available for keyed policies
+    #[error("state snapshots are only supported for keyed policies")]
+    NotKeyed,
+    /// The policy was built with keys that don't serialize, see
+    /// [`build_with_serde_keyer`](crate::GovernorPolicyBuilder::build_with_serde_keyer)
+    #[error("state snapshots need a policy built with serializable keys")]
+    NotSerializable,
+    /// A key could not be converted from or to its serialized form
+    #[error("invalid key in snapshot: {0}")]
+    InvalidKey(#[from] serde_json::Error),
+}
+
+/// Point-in-time copy of the state of a keyed policy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    /// When the snapshot was taken
+    pub taken_at: SystemTime,
+    /// Keys whose bucket was not fully replenished at `taken_at`
+    pub entries: Vec<SnapshotEntry>,
+}
+
+/// State of a single key within a [`StateSnapshot`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    /// The key, in its serialized form
+    pub key: serde_json::Value,
+    /// How far the key's theoretical arrival time (GCRA) lay beyond `taken_at`
+    pub tat_offset: Duration,
+}
+
+impl StateSnapshot {
+    /// Offset of an entry adjusted for the time that passed since the snapshot
+    /// was taken, or `None` if the key has fully replenished in the meantime
+    pub(crate) fn remaining(&self, entry: &SnapshotEntry) -> Option<Duration> {
+        let elapsed = SystemTime::now()
+            .duration_since(self.taken_at)
+            .unwrap_or_default();
+        entry
+            .tat_offset
+            .checked_sub(elapsed)
+            .filter(|offset| !offset.is_zero())
+    }
+}
+
+/// Converts keys of a policy from and to their serialized form
+///
+/// Captured when the policy is built, so only the keys of policies that use
+/// snapshots need to implement serde.
+pub(crate) struct KeyCodec<K> {
+    encode: fn(&K) -> serde_json::Result<serde_json::Value>,
+    decode: fn(serde_json::Value) -> serde_json::Result<K>,
+}
+
+impl<K: Serialize + DeserializeOwned> KeyCodec<K> {
+    pub(crate) fn serde() -> Self {
+        KeyCodec {
+            encode: |key| serde_json::to_value(key),
+            decode: serde_json::from_value,
+        }
+    }
+}
+
+impl<K> KeyCodec<K> {
+    pub(crate) fn encode(&self, key: &K) -> serde_json::Result<serde_json::Value> {
+        (self.encode)(key)
+    }
+
+    pub(crate) fn decode(&self, key: serde_json::Value) -> serde_json::Result<K> {
+        (self.decode)(key)
+    }
+}
+
+impl<K> Clone for KeyCodec<K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K> Copy for KeyCodec<K> {}
+
+pub(crate) fn export<K: GovernorKey>(
+    limiter: &KeyedLimiter<K>,
+    codec: KeyCodec<K>,
+) -> Result<StateSnapshot, SnapshotError> {
+    let taken_at = SystemTime::now();
+    let entries = limiter
+        .export()
+        .into_iter()
+        .map(|(key, tat_offset)| {
+            Ok(SnapshotEntry {
+                key: codec.encode(&key)?,
+                tat_offset,
+            })
+        })
+        .collect::<Result<_, SnapshotError>>()?;
+    Ok(StateSnapshot { taken_at, entries })
+}
+
+pub(crate) fn import<K: GovernorKey>(
+    limiter: &KeyedLimiter<K>,
+    codec: KeyCodec<K>,
+    snapshot: &StateSnapshot,
+) -> Result<usize, SnapshotError> {
+    let mut imported = 0;
+    for entry in &snapshot.entries {
+        let key = codec.decode(entry.key.clone())?;
+        if let Some(offset) = snapshot.remaining(entry) {
+            limiter.import(key, offset);
+            imported += 1;
+        }
+    }
+    Ok(imported)
+}
@@ -0,0 +1,33 @@
+//! Hashes that stay the same across builds
+//!
+//! `std`'s `DefaultHasher` is only specified to be stable within one build:
+//! its algorithm and keys may change with any Rust release. Hashes that leave
+//! the process, like keys derived from requests and sent to a shared store or
+//! header values other instances compare, come from [`stable_hasher`]
+//! instead, SipHash-1-3 with fixed keys. Changing the keys below changes every
+//! such key and header, so treat them as part of the wire format.
+
+use siphasher::sip::SipHasher13;
+
+/// Keys of the hasher, version 1
+const KEY: [u8; 16] = *b"rama-x-governor1";
+
+/// A hasher giving the same hashes in every process and build
+pub(crate) fn stable_hasher() -> SipHasher13 {
+    SipHasher13::new_with_key(&KEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::Hasher;
+
+    use super::*;
+
+    #[test]
+    fn test_stable_hash_is_pinned() {
+        let mut hasher = stable_hasher();
+        hasher.write(b"203.0.113.7");
+        // a different value breaks keys shared with running instances
+        assert_eq!(hasher.finish(), 12110825338595736066);
+    }
+}
@@ -0,0 +1,308 @@
+//! Serving stale responses to rate limited pollers
+//!
+//! Aggressive but benign pollers, like dashboards left open in many tabs,
+//! are better served a slightly stale answer than a 429 they show as an
+//! error. [`StaleResponseLayer`] keeps the last good response of a few
+//! resources and serves it to rate limited `GET` requests for them, with
+//! `Age` and `Warning: 110` headers marking it stale. Mount it in front of
+//! the limit layer, so it sees the rejections:
+//!
+//! ```ignore
+//! (
+//!     StaleResponseLayer::new(128).max_age(Duration::from_secs(60)),
+//!     LimitLayer::new(policy),
+//! )
+//! ```
+//!
+//! Only successful responses with a `Content-Length` of at most a limit are
+//! kept, and like `CoalesceLayer` it never keeps
+//! responses to requests with credentials, setting cookies, or marked
+//! `private` or `no-store`.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use rama_core::error::BoxError;
+use rama_core::{Context, Layer, Service};
+use rama_http::dep::http_body_util::BodyExt;
+use rama_http::{
+    Body, HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Version, header,
+};
+
+use crate::GovernorError;
+
+/// Longest a response is served stale unless configured otherwise
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// Largest response body kept unless configured otherwise
+pub(crate) const DEFAULT_MAX_BODY_LEN: u64 = 64 * 1024;
+
+/// The `Warning` of stale responses, see RFC 7234
+const STALE_WARNING: HeaderValue = HeaderValue::from_static("110 - \"Response is Stale\"");
+
+/// A buffered response that can be served more than once
+#[derive(Debug)]
+pub(crate) struct CachedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl CachedResponse {
+    /// Buffer the body of `response`
+    pub(crate) async fn read(response: Response) -> Result<Self, BoxError> {
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await?.to_bytes();
+        Ok(CachedResponse {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+            body: body.to_vec(),
+        })
+    }
+
+    pub(crate) fn to_response(&self) -> Response {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.version_mut() = self.version;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+
+    /// Whether the response may be served to clients other than the requester
+    pub(crate) fn is_shareable(&self) -> bool {
+        !self.headers.contains_key(header::SET_COOKIE)
+            && !self
+                .headers
+                .get_all(header::CACHE_CONTROL)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|directive| {
+                    let directive = directive.trim();
+                    directive.eq_ignore_ascii_case("private")
+                        || directive.eq_ignore_ascii_case("no-store")
+                })
+    }
+}
+
+/// Whether `response` is successful with a `Content-Length` of at most
+/// `max_body_len`, so it is worth buffering to serve again
+pub(crate) fn is_keepable(response: &Response, max_body_len: u64) -> bool {
+    response.status().is_success()
+        && response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+            .is_some_and(|len| len <= max_body_len)
+}
+
+/// The resource `req` is for, if its response may be shared
+///
+/// That is its host, path and query, ignoring a trailing slash. Only `GET`
+/// requests without credentials have one.
+pub(crate) fn resource<ReqBody>(req: &Request<ReqBody>) -> Option<String> {
+    let headers = req.headers();
+    if req.method() != Method::GET
+        || headers.contains_key(header::AUTHORIZATION)
+        || headers.contains_key(header::COOKIE)
+    {
+        return None;
+    }
+    let host = match req.uri().authority() {
+        Some(authority) => authority.as_str(),
+        None => headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or_default(),
+    };
+    let path = req.uri().path();
+    let path = path.strip_suffix('/').filter(|path| !path.is_empty());
+    let path = path.unwrap_or(req.uri().path());
+    let query = req.uri().query().unwrap_or_default();
+    Some(format!("{}{}?{}", host.to_ascii_lowercase(), path, query))
+}
+
+/// Layer serving the last good response to rate limited `GET` requests
+#[derive(Debug, Clone)]
+pub struct StaleResponseLayer {
+    capacity: usize,
+    max_age: Duration,
+    max_body_len: u64,
+}
+
+impl StaleResponseLayer {
+    /// Keep the last good response of up to `capacity` resources
+    pub fn new(capacity: usize) -> Self {
+        StaleResponseLayer {
+            capacity,
+            max_age: DEFAULT_MAX_AGE,
+            max_body_len: DEFAULT_MAX_BODY_LEN,
+        }
+    }
+
+    /// Serve responses at most `max_age` old, five minutes by default
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Keep responses with bodies of at most `max_body_len` bytes, 64 KiB by default
+    pub fn max_body_len(mut self, max_body_len: u64) -> Self {
+        self.max_body_len = max_body_len;
+        self
+    }
+}
+
+impl<S> Layer<S> for StaleResponseLayer {
+    type Service = StaleResponseService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        StaleResponseService {
+            inner,
+            layer: self.clone(),
+            responses: Arc::default(),
+        }
+    }
+}
+
+/// Service created by [`StaleResponseLayer`]
+///
+/// Clones share their responses.
+#[derive(Debug, Clone)]
+pub struct StaleResponseService<S> {
+    inner: S,
+    layer: StaleResponseLayer,
+    responses: Arc<DashMap<String, (Arc<CachedResponse>, Instant)>>,
+}
+
+impl<S> StaleResponseService<S> {
+    /// Keep `response` as the last good response of `resource`
+    fn store(&self, resource: String, response: CachedResponse) {
+        if self.layer.capacity == 0 {
+            return;
+        }
+        if self.responses.len() >= self.layer.capacity && !self.responses.contains_key(&resource) {
+            let oldest = self
+                .responses
+                .iter()
+                .min_by_key(|entry| entry.value().1)
+                .map(|entry| entry.key().clone());
+            if let Some(oldest) = oldest {
+                self.responses.remove(&oldest);
+            }
+        }
+        self.responses
+            .insert(resource, (Arc::new(response), Instant::now()));
+    }
+
+    /// The last good response of `resource` marked stale, if it isn't too old
+    fn stale(&self, resource: &str) -> Option<Response> {
+        let (cached, stored) = self.responses.get(resource)?.value().clone();
+        let age = stored.elapsed();
+        if age > self.layer.max_age {
+            return None;
+        }
+        let mut response = cached.to_response();
+        let headers = response.headers_mut();
+        headers.insert(header::AGE, age.as_secs().into());
+        headers.append(header::WARNING, STALE_WARNING);
+        Some(response)
+    }
+}
+
+impl<S, State, ReqBody> Service<State, Request<ReqBody>> for StaleResponseService<S>
+where
+    S: Service<State, Request<ReqBody>, Response = Response, Error: Into<BoxError>>,
+    State: Clone + Send + Sync + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = Response;
+    type Error = BoxError;
+
+    async fn serve(
+        &self,
+        ctx: Context<State>,
+        req: Request<ReqBody>,
+    ) -> Result<Self::Response, Self::Error> {
+        let Some(resource) = resource(&req) else {
+            return self.inner.serve(ctx, req).await.map_err(Into::into);
+        };
+        match self.inner.serve(ctx, req).await.map_err(Into::into) {
+            Ok(response) if is_keepable(&response, self.layer.max_body_len) => {
+                let response = CachedResponse::read(response).await?;
+                let served = response.to_response();
+                if response.is_shareable() {
+                    self.store(resource, response);
+                }
+                Ok(served)
+            }
+            Err(err) if matches!(err.downcast_ref(), Some(GovernorError::RateLimited(_))) => {
+                match self.stale(&resource) {
+                    Some(response) => {
+                        tracing::debug!("Serving stale response to limited request: {}", resource);
+                        Ok(response)
+                    }
+                    None => Err(err),
+                }
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+    use rama_core::service::service_fn;
+
+    use super::*;
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_stale_response_on_rejection() {
+        let limited = Arc::new(AtomicBool::new(false));
+        let limiting = limited.clone();
+        let policy = Arc::new(GovernorPolicy::builder().per_minute(1).build());
+        let service = StaleResponseLayer::new(4).layer(service_fn(move |req: Request<()>| {
+            let limited = limiting.load(Ordering::Relaxed);
+            let policy = policy.clone();
+            async move {
+                if limited {
+                    let result = policy.check(Context::<()>::default(), ()).await;
+                    if let PolicyOutput::Abort(err) = result.output {
+                        return Err(BoxError::from(err));
+                    }
+                }
+                let body = format!("report of {}", req.uri().path());
+                let response = Response::builder()
+                    .header(header::CONTENT_LENGTH, body.len())
+                    .body(Body::from(body))
+                    .unwrap();
+                Ok(response)
+            }
+        }));
+        let poll = async |path: &str| {
+            let req = Request::builder().uri(path).body(()).unwrap();
+            service.serve(Context::default(), req).await
+        };
+
+        poll("/reports/1").await.unwrap();
+        limited.store(true, Ordering::Relaxed);
+        // uses up the quota
+        poll("/reports/2").await.unwrap();
+
+        let response = poll("/reports/1/").await.unwrap();
+        assert_eq!(response.headers()[header::AGE], "0");
+        assert_eq!(response.headers()[header::WARNING], STALE_WARNING);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "report of /reports/1");
+
+        let err = poll("/reports/3").await.unwrap_err();
+        assert!(err.downcast_ref::<GovernorError>().is_some());
+    }
+}
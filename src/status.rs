@@ -0,0 +1,150 @@
+//! Introspection of built policies
+//!
+//! [`GovernorPolicy::status`](crate::GovernorPolicy::status) reports the
+//! configuration and recent decisions of a policy. With the `http` feature,
+//! [`governor_status_service`] serves the status of a set of policies as JSON,
+//! e.g. mounted under `/internal/rate-limits`.
+
+use serde::Serialize;
+
+/// Kind of state a policy keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyKind {
+    /// One global state
+    Direct,
+    /// One in-memory state per key
+    Keyed,
+    /// State kept in a [`RateLimitStore`](crate::RateLimitStore)
+    Store,
+}
+
+/// Configuration and recent decisions of a policy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PolicyStatus {
+    /// Name set through [`GovernorPolicyBuilder::name`](crate::GovernorPolicyBuilder::name)
+    pub name: String,
+    /// Kind of state the policy keeps
+    pub kind: PolicyKind,
+    /// Interval in which one cell is replenished, in milliseconds
+    pub replenish_interval_ms: u64,
+    /// Maximum number of cells
+    pub burst_size: u32,
+    /// Whether limits are enforced, see [`GovernorHandle::disable`](crate::GovernorHandle::disable)
+    pub enabled: bool,
+    /// Whether a lockdown clamp is active
+    pub locked_down: bool,
+    /// Number of keys tracked in memory, for keyed policies
+    pub tracked_keys: Option<usize>,
+    /// Requests allowed within the last full minute
+    pub allowed_last_minute: u64,
+    /// Requests denied within the last full minute
+    pub denied_last_minute: u64,
+}
+
+#[cfg(feature = "http")]
+pub use service::{GovernorStatusService, governor_status_service};
+
+#[cfg(feature = "http")]
+mod service {
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    use rama_core::{Context, Service};
+    use rama_http::{Body, Request, Response, header};
+    use serde_json::json;
+
+    use crate::GovernorPolicy;
+
+    /// Service answering every request with the status of its policies as JSON
+    #[derive(Debug, Clone)]
+    pub struct GovernorStatusService {
+        policies: Arc<Vec<Arc<GovernorPolicy>>>,
+        include_keys: bool,
+    }
+
+    /// Serve the status of `policies` as JSON
+    ///
+    /// Mount it behind whatever access control the application uses for
+    /// internal endpoints, especially with [`GovernorStatusService::include_keys`].
+    pub fn governor_status_service(
+        policies: impl IntoIterator<Item = Arc<GovernorPolicy>>,
+    ) -> GovernorStatusService {
+        GovernorStatusService {
+            policies: Arc::new(policies.into_iter().collect()),
+            include_keys: false,
+        }
+    }
+
+    impl GovernorStatusService {
+        /// Also list the state of every tracked key of keyed policies
+        pub fn include_keys(mut self, include: bool) -> Self {
+            self.include_keys = include;
+            self
+        }
+
+        fn render(&self) -> serde_json::Value {
+            let policies: Vec<_> = self
+                .policies
+                .iter()
+                .map(|policy| {
+                    let mut status = json!(policy.status());
+                    if self.include_keys {
+                        if let Ok(snapshot) = policy.export_state() {
+                            status["keys"] = json!(snapshot.entries);
+                        }
+                    }
+                    status
+                })
+                .collect();
+            json!({ "policies": policies })
+        }
+    }
+
+    impl<State, ReqBody> Service<State, Request<ReqBody>> for GovernorStatusService
+    where
+        State: Clone + Send + Sync + 'static,
+        ReqBody: Send + 'static,
+    {
+        type Response = Response;
+        type Error = Infallible;
+
+        async fn serve(
+            &self,
+            _ctx: Context<State>,
+            _req: Request<ReqBody>,
+        ) -> Result<Self::Response, Self::Error> {
+            let mut response = Response::new(Body::from(self.render().to_string()));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("application/json"),
+            );
+            Ok(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GovernorPolicy;
+    use rama_core::Context;
+    use rama_core::layer::limit::policy::Policy;
+
+    #[tokio::test]
+    async fn test_policy_status() {
+        let policy = GovernorPolicy::builder()
+            .per_second(2)
+            .burst_size(5)
+            .name("api")
+            .build_with_keyer(|key: &str| key.to_owned());
+        let _ = policy.check(Context::default(), ()).await;
+
+        let status = policy.status();
+        assert_eq!(status.name, "api");
+        assert_eq!(status.kind, PolicyKind::Keyed);
+        assert_eq!((status.replenish_interval_ms, status.burst_size), (500, 5));
+        assert!(status.enabled && !status.locked_down);
+        assert_eq!(status.tracked_keys, Some(1));
+    }
+}
@@ -0,0 +1,127 @@
+//! Pluggable rate limit state stores
+//!
+//! In-process policies keep their state in memory. A [`RateLimitStore`] moves
+//! that state elsewhere (e.g. Redis) so that several instances can enforce one
+//! shared limit. Stores are used through [`GovernorPolicyBuilder::build_with_store`](crate::GovernorPolicyBuilder::build_with_store).
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use governor::Quota;
+
+/// Boxed future returned by [`RateLimitStore`] methods
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Decision of a store for a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreDecision {
+    /// The cells were consumed
+    Allowed,
+    /// The key is over its quota
+    Denied {
+        /// Earliest time after which the same check could succeed
+        retry_after: Duration,
+    },
+}
+
+/// Error raised by a store that could not reach a decision
+#[derive(Debug)]
+pub struct StoreError {
+    source: Box<dyn StdError + Send + Sync>,
+}
+
+impl StoreError {
+    /// Wrap the underlying backend error
+    pub fn new(source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        StoreError {
+            source: source.into(),
+        }
+    }
+}
+
+/// The store didn't answer within the configured store timeout
+#[derive(Debug)]
+struct Timeout(Duration);
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no answer within {:?}", self.0)
+    }
+}
+
+impl StdError for Timeout {}
+
+impl StoreError {
+    pub(crate) fn timeout(after: Duration) -> Self {
+        StoreError::new(Timeout(after))
+    }
+
+    /// Returns true if the store didn't answer in time, see
+    /// [`GovernorPolicyBuilder::store_timeout`](crate::GovernorPolicyBuilder::store_timeout)
+    pub fn is_timeout(&self) -> bool {
+        self.source.is::<Timeout>()
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limit store error: {}", self.source)
+    }
+}
+
+impl StdError for StoreError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// What to do with a request when the store fails to answer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Let the request through (default)
+    #[default]
+    Open,
+    /// Reject the request
+    Closed,
+}
+
+/// A backend holding GCRA state for string keys
+///
+/// A check of `n` cells for a key succeeds if the key's bucket, configured by
+/// `quota`, can accommodate all of them right now, in which case they are
+/// consumed. Implementations follow governor's GCRA semantics where the
+/// backend allows it, and document where they deviate.
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Try to consume `n` cells of `quota` for `key`
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>>;
+}
+
+impl<S: RateLimitStore + ?Sized> RateLimitStore for std::sync::Arc<S> {
+    fn check_n<'a>(
+        &'a self,
+        key: &'a str,
+        quota: Quota,
+        n: u32,
+    ) -> StoreFuture<'a, Result<StoreDecision, StoreError>> {
+        (**self).check_n(key, quota, n)
+    }
+}
+
+/// GCRA parameters in microseconds, as used by remote stores
+#[cfg_attr(
+    not(any(feature = "redis", feature = "postgres", feature = "shm")),
+    allow(dead_code)
+)]
+pub(crate) fn gcra_micros(quota: &Quota) -> (u64, u64) {
+    let t = (quota.replenish_interval().as_micros() as u64).max(1);
+    let tau = t * u64::from(quota.burst_size().get());
+    (t, tau)
+}
@@ -0,0 +1,84 @@
+//! Synchronization shared between requests and background tasks
+//!
+//! Policy state is owned per instance: the limiter sits behind an `Arc` held
+//! by the policy, and background tasks only keep a `Weak` to it, so they exit
+//! on their next tick once the policy is dropped. The only cross-request
+//! coordination left is starting those tasks, which [`StartOnce`] does with a
+//! single atomic instead of a lock.
+//!
+//! Under `--cfg loom` the atomics come from loom, and the interleavings of
+//! concurrent starts are model checked:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//! ```
+
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Runs a start routine at most once, without blocking concurrent callers
+#[derive(Debug, Default)]
+pub(crate) struct StartOnce {
+    started: AtomicBool,
+}
+
+impl StartOnce {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `start` unless a previous call already did, returning whether
+    /// this call ran it
+    ///
+    /// Callers losing the race return right away rather than waiting for
+    /// the winner to finish starting.
+    pub(crate) fn call(&self, start: impl FnOnce()) -> bool {
+        let won = self
+            .started
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if won {
+            start();
+        }
+        won
+    }
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::sync::atomic::AtomicUsize;
+    use loom::thread;
+
+    #[test]
+    fn loom_start_once() {
+        loom::model(|| {
+            let once = Arc::new(StartOnce::new());
+            let spawned = Arc::new(AtomicUsize::new(0));
+
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let once = once.clone();
+                    let spawned = spawned.clone();
+                    thread::spawn(move || {
+                        once.call(|| {
+                            spawned.fetch_add(1, Ordering::Relaxed);
+                        })
+                    })
+                })
+                .collect();
+            let won = threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .filter(|won| *won)
+                .count();
+
+            assert_eq!(won, 1);
+            assert_eq!(spawned.load(Ordering::Relaxed), 1);
+            assert!(!once.call(|| panic!("started twice")));
+        });
+    }
+}
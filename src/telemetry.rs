@@ -0,0 +1,225 @@
+//! Per-policy decision telemetry
+//!
+//! Every decision of a policy is reported here, tagged with the policy name
+//! set through [`GovernorPolicyBuilder::name`](crate::GovernorPolicyBuilder::name).
+//! Exporters are enabled through cargo features.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::GovernorError;
+use crate::audit::{AuditEvent, Auditor, DenyReason};
+use crate::rejection::{Rejected, RejectionResponse};
+use crate::sampling::{DenialLogSampling, DenialSampler};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsHandles;
+#[cfg(feature = "otel")]
+use crate::otel::OtelInstruments;
+
+/// Name of policies built without one
+pub(crate) const DEFAULT_POLICY_NAME: &str = "governor";
+
+/// Decision telemetry of a single policy
+pub struct Telemetry {
+    name: Arc<str>,
+    /// Response carried by the errors of rejected requests
+    rejection: Arc<RejectionResponse>,
+    sampler: DenialSampler,
+    counts: DecisionCounts,
+    audit: Option<Arc<Auditor>>,
+    #[cfg(feature = "otel")]
+    otel: OtelInstruments,
+    #[cfg(feature = "metrics")]
+    metrics: MetricsHandles,
+}
+
+impl Telemetry {
+    pub(crate) fn new(
+        name: Option<String>,
+        sampling: DenialLogSampling,
+        rejection: RejectionResponse,
+        audit: Option<Arc<Auditor>>,
+    ) -> Self {
+        let name: Arc<str> = name.as_deref().unwrap_or(DEFAULT_POLICY_NAME).into();
+        Telemetry {
+            #[cfg(feature = "otel")]
+            otel: OtelInstruments::new(),
+            #[cfg(feature = "metrics")]
+            metrics: MetricsHandles::new(&name),
+            name,
+            rejection: Arc::new(rejection),
+            sampler: DenialSampler::new(sampling),
+            counts: DecisionCounts::new(),
+            audit,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the denial of a request for `key` should be logged
+    pub(crate) fn sample_denial(&self, key: &str) -> bool {
+        self.sampler.sample(&self.name, key)
+    }
+
+    pub(crate) fn counts(&self) -> &DecisionCounts {
+        &self.counts
+    }
+
+    /// A request was let through
+    pub(crate) fn allowed(&self) {
+        self.counts.record(true);
+        #[cfg(feature = "otel")]
+        self.otel.record(&self.name, true, None);
+        #[cfg(feature = "metrics")]
+        self.metrics.record(true, None);
+    }
+
+    /// A request was rejected, retrying may succeed after `retry_after` if known
+    pub(crate) fn denied(&self, retry_after: Option<Duration>) {
+        self.counts.record(false);
+        #[cfg(feature = "otel")]
+        self.otel.record(&self.name, false, retry_after);
+        #[cfg(feature = "metrics")]
+        self.metrics.record(false, retry_after);
+        #[cfg(not(any(feature = "otel", feature = "metrics")))]
+        let _ = retry_after;
+    }
+
+    /// Report the rejection of a request for `key` to the audit sink, if any
+    pub(crate) fn audit_denial(
+        &self,
+        key: &str,
+        reason: DenyReason,
+        retry_after: Option<Duration>,
+    ) {
+        if let Some(audit) = &self.audit {
+            audit.record(
+                Some(key),
+                AuditEvent::Denied {
+                    reason,
+                    retry_after,
+                },
+            );
+        }
+    }
+
+    /// Gauge reporting the number of tracked keys from background tasks
+    pub(crate) fn tracked_keys_gauge(&self) -> TrackedKeysGauge {
+        TrackedKeysGauge {
+            #[cfg(feature = "metrics")]
+            gauge: crate::metrics::tracked_keys(&self.name),
+        }
+    }
+
+    /// The store of the policy didn't answer in time
+    pub(crate) fn store_timeout(&self) {
+        #[cfg(feature = "metrics")]
+        self.metrics.record_store_timeout();
+    }
+
+    /// Record a rejection and build the error returned for it
+    pub(crate) fn rejected(&self, retry_after: Option<Duration>) -> GovernorError {
+        self.denied(retry_after);
+        GovernorError::RateLimited(Rejected::new(
+            self.name.clone(),
+            retry_after,
+            self.rejection.clone(),
+        ))
+    }
+}
+
+impl std::fmt::Debug for Telemetry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Telemetry")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// Number of keys a policy tracks in memory, reported after each GC run
+#[derive(Clone)]
+pub(crate) struct TrackedKeysGauge {
+    #[cfg(feature = "metrics")]
+    gauge: metrics::Gauge,
+}
+
+impl TrackedKeysGauge {
+    pub(crate) fn record(&self, keys: usize) {
+        tracing::trace!("{} keys tracked", keys);
+        #[cfg(feature = "metrics")]
+        self.gauge.set(keys as f64);
+    }
+}
+
+/// Decision counters for the current and the previous minute
+///
+/// Minutes roll over without locking, so a few decisions right at the
+/// boundary may be attributed to the wrong minute.
+pub(crate) struct DecisionCounts {
+    start: Instant,
+    /// Minutes since `start` of the current bucket
+    minute: AtomicU64,
+    allowed: [AtomicU64; 2],
+    denied: [AtomicU64; 2],
+    /// Allowed and denied requests since `start`
+    totals: [AtomicU64; 2],
+}
+
+impl DecisionCounts {
+    fn new() -> Self {
+        DecisionCounts {
+            start: Instant::now(),
+            minute: AtomicU64::new(0),
+            allowed: Default::default(),
+            denied: Default::default(),
+            totals: Default::default(),
+        }
+    }
+
+    fn roll(&self) -> u64 {
+        let minute = self.start.elapsed().as_secs() / 60;
+        let current = self.minute.load(Ordering::Relaxed);
+        if current != minute
+            && self
+                .minute
+                .compare_exchange(current, minute, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let stale = if minute > current + 1 { 0..2 } else { 0..1 };
+            for offset in stale {
+                let bucket = ((minute + offset) % 2) as usize;
+                self.allowed[bucket].store(0, Ordering::Relaxed);
+                self.denied[bucket].store(0, Ordering::Relaxed);
+            }
+        }
+        minute
+    }
+
+    fn record(&self, allowed: bool) {
+        let bucket = (self.roll() % 2) as usize;
+        let counter = if allowed { &self.allowed } else { &self.denied };
+        counter[bucket].fetch_add(1, Ordering::Relaxed);
+        self.totals[usize::from(!allowed)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Allowed and denied requests since the policy was built
+    pub(crate) fn totals(&self) -> (u64, u64) {
+        (
+            self.totals[0].load(Ordering::Relaxed),
+            self.totals[1].load(Ordering::Relaxed),
+        )
+    }
+
+    /// Allowed and denied requests within the last full minute
+    pub(crate) fn last_minute(&self) -> (u64, u64) {
+        let bucket = ((self.roll() + 1) % 2) as usize;
+        (
+            self.allowed[bucket].load(Ordering::Relaxed),
+            self.denied[bucket].load(Ordering::Relaxed),
+        )
+    }
+}
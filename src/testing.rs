@@ -0,0 +1,393 @@
+//! Deterministic simulation of quotas
+//!
+//! A [`Simulator`] replays a scripted arrival pattern against a quota on a
+//! fake clock and returns the resulting [`Timeline`] of decisions, so quota
+//! configurations can be unit tested in CI without sleeps or servers:
+//!
+//! ```ignore
+//! let timeline = Simulator::new(Quota::per_second(NonZeroU32::new(10).unwrap()))
+//!     .run(testing::uniform("client", Duration::from_millis(20), 500));
+//! assert_allows_at_most!(timeline, 20, per Duration::from_secs(1));
+//! ```
+//!
+//! The simulator applies the same algorithms as built policies, but not
+//! their runtime controls, stores or telemetry.
+//!
+//! A [`Scenario`] pins the exact decisions for an arrival pattern in a JSON
+//! file, so a semantic change across crate upgrades fails a test instead of
+//! going unnoticed. [`golden_scenarios`] are the scenarios this crate itself
+//! is tested against, one set per [`Algorithm`].
+//!
+//! [`ChaosPolicy`] injects faults into a live policy instead, to test how an
+//! application copes with denials, slow checks and store failures.
+
+use std::fmt;
+use std::time::Duration;
+
+use governor::Quota;
+use governor::clock::FakeRelativeClock;
+use governor::middleware::NoOpMiddleware;
+use governor::nanos::Nanos;
+use governor::state::RateLimiter;
+use governor::state::keyed::DefaultKeyedStateStore;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub use crate::chaos::ChaosPolicy;
+use crate::fixed_window::FixedWindowStore;
+use crate::{Algorithm, PolicyParseError, StoreDecision};
+
+/// Scenario files shipped with the crate, one per algorithm
+const GOLDEN_SCENARIOS: [&str; 2] = [
+    include_str!("../scenarios/gcra.json"),
+    include_str!("../scenarios/fixed_window.json"),
+];
+
+/// A request arriving at a point of simulated time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arrival {
+    /// Time since the start of the simulation
+    pub at: Duration,
+    /// Key the request is counted under
+    pub key: String,
+}
+
+/// `count` requests for `key`, one every `interval`, starting at zero
+pub fn uniform(key: &str, interval: Duration, count: u32) -> Vec<Arrival> {
+    (0..count)
+        .map(|i| Arrival {
+            at: interval * i,
+            key: key.to_owned(),
+        })
+        .collect()
+}
+
+/// `count` requests for `key`, all arriving `at` the same time
+pub fn burst(key: &str, at: Duration, count: u32) -> Vec<Arrival> {
+    (0..count)
+        .map(|_| Arrival {
+            at,
+            key: key.to_owned(),
+        })
+        .collect()
+}
+
+/// Decision on one simulated request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The request
+    pub arrival: Arrival,
+    /// Whether the request was let through
+    pub allowed: bool,
+}
+
+/// Decisions of a simulation, in order of arrival
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timeline {
+    /// All decisions
+    pub events: Vec<Event>,
+}
+
+impl Timeline {
+    /// Number of requests let through
+    pub fn allowed(&self) -> usize {
+        self.events.iter().filter(|event| event.allowed).count()
+    }
+
+    /// Number of requests rejected
+    pub fn denied(&self) -> usize {
+        self.events.len() - self.allowed()
+    }
+
+    /// Number of requests let through in `from..to`
+    pub fn allowed_between(&self, from: Duration, to: Duration) -> usize {
+        self.events
+            .iter()
+            .filter(|event| event.allowed && (from..to).contains(&event.arrival.at))
+            .count()
+    }
+
+    /// Largest number of requests let through in any sliding `window`
+    pub fn max_allowed_in(&self, window: Duration) -> usize {
+        let allowed: Vec<_> = self
+            .events
+            .iter()
+            .filter(|event| event.allowed)
+            .map(|event| event.arrival.at)
+            .collect();
+        let mut max = 0;
+        let mut start = 0;
+        for (end, at) in allowed.iter().enumerate() {
+            while *at - allowed[start] >= window {
+                start += 1;
+            }
+            max = max.max(end + 1 - start);
+        }
+        max
+    }
+
+    /// Only the decisions on requests for `key`
+    pub fn for_key(&self, key: &str) -> Timeline {
+        Timeline {
+            events: self
+                .events
+                .iter()
+                .filter(|event| event.arrival.key == key)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Replays arrival patterns against a quota on a fake clock
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    quota: Quota,
+    algorithm: Algorithm,
+}
+
+impl Simulator {
+    /// Simulate `quota` with GCRA
+    pub fn new(quota: Quota) -> Self {
+        Simulator {
+            quota,
+            algorithm: Algorithm::default(),
+        }
+    }
+
+    /// Simulate `algorithm` instead of GCRA
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Decide on every arrival, in order of time
+    pub fn run(&self, arrivals: impl IntoIterator<Item = Arrival>) -> Timeline {
+        let mut arrivals: Vec<_> = arrivals.into_iter().collect();
+        arrivals.sort_by_key(|arrival| arrival.at);
+
+        let mut decide: Box<dyn FnMut(&Arrival) -> bool> = match self.algorithm {
+            Algorithm::Gcra => {
+                let clock = FakeRelativeClock::default();
+                let limiter: RateLimiter<
+                    _,
+                    DefaultKeyedStateStore<String>,
+                    _,
+                    NoOpMiddleware<Nanos>,
+                > = RateLimiter::new(self.quota, DefaultKeyedStateStore::default(), &clock);
+                let mut now = Duration::ZERO;
+                Box::new(move |arrival| {
+                    clock.advance(arrival.at - now);
+                    now = arrival.at;
+                    limiter.check_key(&arrival.key).is_ok()
+                })
+            }
+            Algorithm::FixedWindow => {
+                let store = FixedWindowStore::new(str::to_owned);
+                let quota = self.quota;
+                Box::new(move |arrival| {
+                    let decision = store.check_at(&arrival.key, quota, 1, arrival.at);
+                    decision == StoreDecision::Allowed
+                })
+            }
+        };
+
+        let events = arrivals
+            .into_iter()
+            .map(|arrival| Event {
+                allowed: decide(&arrival),
+                arrival,
+            })
+            .collect();
+        Timeline { events }
+    }
+}
+
+/// One request of a [`Scenario`] and the decision expected for it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    /// Milliseconds since the start of the scenario
+    pub at_ms: u64,
+    /// Key the request is counted under, `default` if not given
+    #[serde(default = "default_key")]
+    pub key: String,
+    /// Whether the request must be let through
+    pub allowed: bool,
+}
+
+fn default_key() -> String {
+    "default".to_owned()
+}
+
+/// An arrival pattern with the decisions expected for it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scenario {
+    /// What the scenario shows
+    pub name: String,
+    /// Rate like `10r/s`, see [`PolicySpec`](crate::PolicySpec)
+    pub rate: String,
+    /// Burst size, the rate's own count if not given
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// Algorithm, GCRA if not given
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    /// The requests, in order of time
+    pub steps: Vec<Step>,
+}
+
+/// A step whose decision differs from the expected one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Name of the scenario
+    pub scenario: String,
+    /// The step, with the expected decision
+    pub step: Step,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "scenario {:?}: request for {:?} at {}ms should have been {}",
+            self.scenario,
+            self.step.key,
+            self.step.at_ms,
+            if self.step.allowed {
+                "allowed"
+            } else {
+                "denied"
+            }
+        )
+    }
+}
+
+/// Error running a [`Scenario`]
+#[derive(Debug, Error)]
+pub enum ScenarioError {
+    /// The rate or burst of the scenario is invalid
+    #[error("invalid scenario: {0}")]
+    Invalid(#[from] PolicyParseError),
+    /// Decisions differ from the expected ones
+    #[error("{} decisions differ, first: {}", .0.len(), .0[0])]
+    Mismatch(Vec<Mismatch>),
+}
+
+impl Scenario {
+    /// Parse a scenario file, a JSON array of scenarios
+    pub fn from_json(json: &str) -> Result<Vec<Scenario>, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// Replay the steps in a [`Simulator`], failing on any unexpected decision
+    pub fn run(&self) -> Result<(), ScenarioError> {
+        let mut quota = crate::dsl::parse_rate(&self.rate)?;
+        if let Some(burst) = self.burst {
+            let burst =
+                std::num::NonZeroU32::new(burst).ok_or_else(|| PolicyParseError::InvalidValue {
+                    option: "burst".to_owned(),
+                    value: burst.to_string(),
+                })?;
+            quota = quota.allow_burst(burst);
+        }
+
+        let mut steps = self.steps.clone();
+        steps.sort_by_key(|step| step.at_ms);
+        let timeline = Simulator::new(quota)
+            .algorithm(self.algorithm)
+            .run(steps.iter().map(|step| Arrival {
+                at: Duration::from_millis(step.at_ms),
+                key: step.key.clone(),
+            }));
+        let mismatches: Vec<_> = steps
+            .into_iter()
+            .zip(timeline.events)
+            .filter(|(step, event)| step.allowed != event.allowed)
+            .map(|(step, _)| Mismatch {
+                scenario: self.name.clone(),
+                step,
+            })
+            .collect();
+        match mismatches.is_empty() {
+            true => Ok(()),
+            false => Err(ScenarioError::Mismatch(mismatches)),
+        }
+    }
+}
+
+/// The scenarios shipped with this crate, covering every [`Algorithm`]
+pub fn golden_scenarios() -> Vec<Scenario> {
+    GOLDEN_SCENARIOS
+        .iter()
+        .flat_map(|json| Scenario::from_json(json).expect("valid golden scenario file"))
+        .collect()
+}
+
+/// Assert that a [`Timeline`](crate::testing::Timeline) lets at most `max`
+/// requests through in any sliding window of the given length
+///
+/// ```ignore
+/// assert_allows_at_most!(timeline, 20, per Duration::from_secs(1));
+/// ```
+#[macro_export]
+macro_rules! assert_allows_at_most {
+    ($timeline:expr, $max:expr, per $window:expr) => {{
+        let timeline: &$crate::testing::Timeline = &$timeline;
+        let window: ::std::time::Duration = $window;
+        let allowed = timeline.max_allowed_in(window);
+        assert!(
+            allowed <= $max,
+            "allowed {} requests within {:?}, expected at most {}",
+            allowed,
+            window,
+            $max
+        );
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU32;
+
+    #[test]
+    fn test_simulated_quota() {
+        let quota = Quota::per_second(NonZeroU32::new(10).unwrap());
+        let arrivals = uniform("client", Duration::from_millis(10), 300);
+
+        let gcra = Simulator::new(quota).run(arrivals.clone());
+        assert_allows_at_most!(gcra, 20, per Duration::from_secs(1));
+        assert_eq!(
+            gcra.allowed_between(Duration::ZERO, Duration::from_millis(10)),
+            1
+        );
+
+        // fixed windows allow up to twice the quota around a window edge
+        let fixed =
+            Simulator::new(quota)
+                .algorithm(Algorithm::FixedWindow)
+                .run(arrivals.into_iter().map(|mut arrival| {
+                    arrival.at += Duration::from_millis(500);
+                    arrival
+                }));
+        assert_eq!(fixed.allowed(), 40);
+        assert_eq!(fixed.max_allowed_in(Duration::from_secs(1)), 20);
+    }
+
+    #[test]
+    fn test_golden_scenarios() {
+        let scenarios = golden_scenarios();
+        for algorithm in [Algorithm::Gcra, Algorithm::FixedWindow] {
+            assert!(
+                scenarios
+                    .iter()
+                    .any(|scenario| scenario.algorithm == algorithm)
+            );
+        }
+        for scenario in scenarios {
+            if let Err(err) = scenario.run() {
+                panic!("{err}");
+            }
+        }
+    }
+}
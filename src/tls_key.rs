@@ -0,0 +1,156 @@
+//! Keying requests by the TLS fingerprint of their client
+//!
+//! Scrapers rotating addresses and `User-Agent`s rarely rotate their TLS
+//! stack, so the client hello they send stays recognizable. JA3 and JA4
+//! summarize a client hello in a short fingerprint, and
+//! [`TlsFingerprintKey`] keys on the [`TlsFingerprint`] stored in the request
+//! context, so one client keeps one bucket across addresses and agents.
+//!
+//! The rama TLS layer this crate builds against doesn't expose client hello
+//! fingerprints, so the TLS acceptor stores one: either a JA4 fingerprint
+//! computed by the TLS library, or a JA3 one built from the client hello
+//! fields through [`TlsFingerprint::ja3`]:
+//!
+//! ```ignore
+//! let fingerprint = TlsFingerprint::ja3(
+//!     hello.legacy_version(),
+//!     hello.cipher_suites(),
+//!     hello.extension_types(),
+//!     hello.supported_groups(),
+//!     hello.ec_point_formats(),
+//! );
+//! ctx.insert(fingerprint);
+//! ```
+//!
+//! Like [`UserAgentFingerprintKey`](crate::UserAgentFingerprintKey), all
+//! clients of one browser build share a fingerprint; key on it for traffic
+//! already suspected of automation.
+
+use std::fmt::{self, Write};
+use std::hash::Hasher;
+
+use rama_core::Context;
+
+use crate::KeyExtractor;
+use crate::stable_hash::stable_hasher;
+
+/// Fingerprint of the TLS client hello of a connection, stored as a context
+/// extension by the TLS acceptor
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TlsFingerprint {
+    /// A JA3 string, e.g. `771,4865-4866,0-23-65281,29-23,0`
+    Ja3(String),
+    /// A JA4 fingerprint, e.g. `t13d1516h2_8daaf6152771_b186095e22b6`
+    Ja4(String),
+}
+
+impl TlsFingerprint {
+    /// The JA3 fingerprint of a client hello with the given fields
+    ///
+    /// GREASE values, which clients pick at random, are left out as JA3
+    /// specifies.
+    pub fn ja3(
+        version: u16,
+        ciphers: &[u16],
+        extensions: &[u16],
+        curves: &[u16],
+        point_formats: &[u8],
+    ) -> Self {
+        let mut ja3 = version.to_string();
+        for list in [ciphers, extensions, curves] {
+            ja3.push(',');
+            join(&mut ja3, list.iter().filter(|value| !is_grease(**value)));
+        }
+        ja3.push(',');
+        join(&mut ja3, point_formats.iter());
+        TlsFingerprint::Ja3(ja3)
+    }
+}
+
+impl fmt::Display for TlsFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsFingerprint::Ja3(ja3) => write!(f, "ja3:{ja3}"),
+            TlsFingerprint::Ja4(ja4) => write!(f, "ja4:{ja4}"),
+        }
+    }
+}
+
+/// GREASE values reserved by RFC 8701, like `0x0a0a` and `0xfafa`
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Append `values` to `out`, separated by dashes
+fn join<T: fmt::Display>(out: &mut String, values: impl Iterator<Item = T>) {
+    for (i, value) in values.enumerate() {
+        if i > 0 {
+            out.push('-');
+        }
+        // writing into a String cannot fail
+        let _ = write!(out, "{value}");
+    }
+}
+
+/// Keys requests by the [`TlsFingerprint`] in their context
+///
+/// JA4 fingerprints are short and used as they are, e.g.
+/// `ja4:t13d1516h2_8daaf6152771_b186095e22b6`. JA3 strings run to hundreds
+/// of bytes and are hashed, e.g. `ja3:3f2c9a0d1b7e4c55`; the hash is not the
+/// MD5 digest JA3 tools print. Requests without a fingerprint, e.g. over
+/// plain HTTP, have no key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TlsFingerprintKey;
+
+impl<State, Request> KeyExtractor<State, Request> for TlsFingerprintKey {
+    fn extract(&self, ctx: &Context<State>, _req: &Request) -> Option<String> {
+        match ctx.get::<TlsFingerprint>()? {
+            TlsFingerprint::Ja3(ja3) => {
+                let mut hasher = stable_hasher();
+                hasher.write(ja3.as_bytes());
+                Some(format!("ja3:{:016x}", hasher.finish()))
+            }
+            ja4 => Some(ja4.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_fingerprint_key() {
+        let hello = |grease: u16| {
+            TlsFingerprint::ja3(
+                771,
+                &[grease, 4865, 4866],
+                &[grease, 0, 23, 65281],
+                &[grease, 29, 23],
+                &[0],
+            )
+        };
+        assert_eq!(
+            hello(0x0a0a),
+            TlsFingerprint::Ja3("771,4865-4866,0-23-65281,29-23,0".to_owned())
+        );
+
+        let key = |fingerprint: Option<TlsFingerprint>| {
+            let mut ctx = Context::<()>::default();
+            if let Some(fingerprint) = fingerprint {
+                ctx.insert(fingerprint);
+            }
+            TlsFingerprintKey.extract(&ctx, &())
+        };
+        // random GREASE values don't split a client into several keys
+        let ja3 = key(Some(hello(0x1a1a))).unwrap();
+        assert!(ja3.starts_with("ja3:"));
+        assert_eq!(Some(ja3), key(Some(hello(0xfafa))));
+        let ja4 = TlsFingerprint::Ja4("t13d1516h2_8daaf6152771_b186095e22b6".to_owned());
+        assert_eq!(
+            key(Some(ja4)).unwrap(),
+            "ja4:t13d1516h2_8daaf6152771_b186095e22b6"
+        );
+        assert_eq!(key(None), None);
+    }
+}
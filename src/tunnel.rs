@@ -0,0 +1,284 @@
+//! Rate limiting CONNECT and SOCKS5 tunnels
+//!
+//! A forwarding proxy sees a tunnel request once, then relays opaque bytes,
+//! so limits have to be enforced where the tunnel is set up rather than per
+//! HTTP request. [`TunnelPolicy`] limits how often each authenticated user
+//! may open tunnels and how often tunnels to each destination are opened,
+//! and optionally caps the bandwidth of every tunnel:
+//!
+//! ```ignore
+//! let tunnels = TunnelPolicy::new()
+//!     .per_user(GovernorPolicy::builder().per_minute(60).burst_size(10).build_with_keyer(|key: &str| key.to_owned()))
+//!     .per_destination(GovernorPolicy::builder().per_second(50).burst_size(100).build_with_keyer(|key: &str| key.to_owned()))
+//!     .bandwidth(1 << 20);
+//!
+//! // in the CONNECT or SOCKS5 handler, once the user is authenticated
+//! let tunnel = tunnels.establish(Some(&username), "example.com:443").await?;
+//! let mut client = tunnel.throttle(client);
+//! tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::{GovernorError, GovernorPolicy};
+
+/// Key of tunnels opened without an authenticated user
+pub const ANONYMOUS_USER: &str = "anonymous";
+
+/// Limits on opening and using proxy tunnels
+#[derive(Debug, Clone, Default)]
+pub struct TunnelPolicy {
+    per_user: Option<Arc<GovernorPolicy>>,
+    per_destination: Option<Arc<GovernorPolicy>>,
+    bandwidth: Option<NonZeroU32>,
+}
+
+impl TunnelPolicy {
+    /// A policy that doesn't limit tunnels yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the tunnels each user opens with `policy`, keyed by user name
+    ///
+    /// Tunnels opened without authentication share the [`ANONYMOUS_USER`] key.
+    pub fn per_user(mut self, policy: impl Into<Arc<GovernorPolicy>>) -> Self {
+        self.per_user = Some(policy.into());
+        self
+    }
+
+    /// Limit the tunnels opened to each destination with `policy`, keyed by
+    /// the lowercased `host:port`
+    pub fn per_destination(mut self, policy: impl Into<Arc<GovernorPolicy>>) -> Self {
+        self.per_destination = Some(policy.into());
+        self
+    }
+
+    /// Cap each direction of every tunnel to `bytes_per_second`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_second` is zero.
+    pub fn bandwidth(mut self, bytes_per_second: u32) -> Self {
+        self.bandwidth =
+            Some(NonZeroU32::new(bytes_per_second).expect("Bandwidth must be non-zero"));
+        self
+    }
+
+    /// Check the opening of a tunnel by `user` to `destination`
+    ///
+    /// The user is checked first, so a tunnel rejected for its destination
+    /// still counts against the user, as a retry would.
+    pub async fn establish(
+        &self,
+        user: Option<&str>,
+        destination: &str,
+    ) -> Result<Tunnel, GovernorError> {
+        if let Some(policy) = &self.per_user {
+            policy.decide(user.unwrap_or(ANONYMOUS_USER)).await?;
+        }
+        if let Some(policy) = &self.per_destination {
+            policy.decide(&destination.to_ascii_lowercase()).await?;
+        }
+        Ok(Tunnel {
+            bandwidth: self.bandwidth,
+        })
+    }
+}
+
+/// An admitted tunnel
+#[derive(Debug, Clone, Copy)]
+pub struct Tunnel {
+    bandwidth: Option<NonZeroU32>,
+}
+
+impl Tunnel {
+    /// Wrap one side of the tunnel in the bandwidth cap of its policy
+    ///
+    /// Wrapping one side is enough, as relaying copies every byte through
+    /// it. Without a cap the stream is passed through as is.
+    pub fn throttle<IO>(&self, stream: IO) -> ThrottledStream<IO> {
+        ThrottledStream {
+            inner: stream,
+            read: self.bandwidth.map(Bandwidth::new),
+            write: self.bandwidth.map(Bandwidth::new),
+        }
+    }
+}
+
+/// Byte budget of one direction, refilled at a fixed rate
+///
+/// Transfers are charged after the fact and the next one waits until the
+/// budget is paid off, so bursts are bounded by the buffer size of the relay.
+struct Bandwidth {
+    bytes_per_second: f64,
+    /// Bytes that may be transferred now, negative while in debt
+    budget: f64,
+    updated: Instant,
+    wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl Bandwidth {
+    fn new(bytes_per_second: NonZeroU32) -> Self {
+        let bytes_per_second = f64::from(bytes_per_second.get());
+        Bandwidth {
+            bytes_per_second,
+            budget: bytes_per_second,
+            updated: Instant::now(),
+            wait: None,
+        }
+    }
+
+    /// Ready once the budget allows another transfer
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<()> {
+        loop {
+            let now = Instant::now();
+            let refill = now.duration_since(self.updated).as_secs_f64() * self.bytes_per_second;
+            self.budget = (self.budget + refill).min(self.bytes_per_second);
+            self.updated = now;
+            if self.budget > 0.0 {
+                self.wait = None;
+                return Poll::Ready(());
+            }
+            let until = now + Duration::from_secs_f64(-self.budget / self.bytes_per_second);
+            let wait = self
+                .wait
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(until)));
+            wait.as_mut().reset(until);
+            if wait.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn charge(&mut self, bytes: usize) {
+        self.budget -= bytes as f64;
+    }
+}
+
+/// Stream relaying at most the bandwidth of its [`Tunnel`]
+pub struct ThrottledStream<IO> {
+    inner: IO,
+    read: Option<Bandwidth>,
+    write: Option<Bandwidth>,
+}
+
+impl<IO> ThrottledStream<IO> {
+    /// The wrapped stream
+    pub fn get_ref(&self) -> &IO {
+        &self.inner
+    }
+}
+
+impl<IO> std::fmt::Debug for ThrottledStream<IO> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThrottledStream")
+            .field(
+                "bytes_per_second",
+                &self.read.as_ref().map(|read| read.bytes_per_second),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for ThrottledStream<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Some(bandwidth) = &mut this.read {
+            std::task::ready!(bandwidth.poll_ready(cx));
+        }
+        let filled = buf.filled().len();
+        let result = std::task::ready!(Pin::new(&mut this.inner).poll_read(cx, buf));
+        if let Some(bandwidth) = &mut this.read {
+            bandwidth.charge(buf.filled().len() - filled);
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Some(bandwidth) = &mut this.write {
+            std::task::ready!(bandwidth.poll_ready(cx));
+        }
+        let written = std::task::ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let Some(bandwidth) = &mut this.write {
+            bandwidth.charge(written);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tunnel_limits() {
+        let keyed = |burst| {
+            GovernorPolicy::builder()
+                .per_minute(1)
+                .burst_size(burst)
+                .build_with_keyer(|key: &str| key.to_owned())
+        };
+        let tunnels = TunnelPolicy::new()
+            .per_user(keyed(2))
+            .per_destination(keyed(1))
+            .bandwidth(1000);
+
+        tunnels
+            .establish(Some("alice"), "Example.com:443")
+            .await
+            .unwrap();
+        assert!(
+            tunnels
+                .establish(Some("bob"), "example.com:443")
+                .await
+                .is_err()
+        );
+        // the rejected attempt counted against alice too
+        let tunnel = tunnels.establish(Some("alice"), "other:22").await.unwrap();
+        assert!(tunnels.establish(Some("alice"), "third:22").await.is_err());
+
+        // the first second of budget passes at once, the rest at the cap
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut server = tunnel.throttle(server);
+        client.write_all(&[0; 1500]).await.unwrap();
+        let mut buf = [0; 4096];
+        let start = Instant::now();
+        assert_eq!(server.read(&mut buf[..1000]).await.unwrap(), 1000);
+        assert_eq!(server.read(&mut buf).await.unwrap(), 500);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        client.write_all(&[0; 10]).await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 10);
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
@@ -0,0 +1,159 @@
+//! Keying requests by the fingerprint of their client software
+//!
+//! Headless automation rotating through proxy addresses gets a fresh bucket
+//! per address from [`ClientIpKey`](crate::ClientIpKey). What stays the same
+//! across its requests is the client software: its `User-Agent` and the
+//! shape of the requests it sends. [`UserAgentFingerprintKey`] hashes these
+//! into a key, so all requests of one client build share a bucket wherever
+//! they come from.
+//!
+//! The fingerprint is taken from the request headers, not from a `UserAgent`
+//! a rama user agent layer may have put in the context. That value is parsed
+//! into a device and browser profile and drops the exact agent string and the
+//! header order, which are what tell one automation build from another; it
+//! also lives in rama's `ua` module, which this crate doesn't depend on. Read
+//! from the headers, the key is the same whether or not such a layer ran.
+//!
+//! Every installation of a popular browser version shares its fingerprint,
+//! so key on it only for traffic already classified as automation, e.g.
+//! through a [`ClassSelector`](crate::ClassSelector), or with a quota meant
+//! for a whole client population.
+
+use std::hash::Hasher;
+
+use rama_core::Context;
+use rama_http::{Request, header};
+
+use crate::KeyExtractor;
+use crate::stable_hash::stable_hasher;
+
+/// Headers whose values are part of the fingerprint
+const FINGERPRINT_HEADERS: [header::HeaderName; 3] = [
+    header::ACCEPT,
+    header::ACCEPT_ENCODING,
+    header::ACCEPT_LANGUAGE,
+];
+
+/// Keys HTTP requests by a fingerprint of their client software
+///
+/// The fingerprint covers the `User-Agent` with versions cut to their major
+/// number, the order of the header names and the values of the `Accept`
+/// headers. Keys look like `ua:3f2c9a0d1b7e4c55`.
+#[derive(Debug, Clone, Copy)]
+pub struct UserAgentFingerprintKey {
+    user_agent: bool,
+}
+
+impl UserAgentFingerprintKey {
+    /// Fingerprint the `User-Agent` and the shape of requests
+    pub fn new() -> Self {
+        UserAgentFingerprintKey { user_agent: true }
+    }
+
+    /// Leave the `User-Agent` out, for clients that rotate it on every request
+    pub fn ignore_user_agent(mut self) -> Self {
+        self.user_agent = false;
+        self
+    }
+}
+
+impl Default for UserAgentFingerprintKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<State, Body> KeyExtractor<State, Request<Body>> for UserAgentFingerprintKey {
+    fn extract(&self, _ctx: &Context<State>, req: &Request<Body>) -> Option<String> {
+        let headers = req.headers();
+        let mut hasher = stable_hasher();
+        if self.user_agent {
+            let agent = headers.get(header::USER_AGENT)?.to_str().ok()?;
+            hasher.write(major_versions(agent).as_bytes());
+        }
+        for name in headers.keys() {
+            hasher.write(name.as_str().as_bytes());
+            hasher.write_u8(b'\n');
+        }
+        for name in &FINGERPRINT_HEADERS {
+            for value in headers.get_all(name) {
+                hasher.write(value.as_bytes());
+            }
+            hasher.write_u8(b'\n');
+        }
+        Some(format!("ua:{:016x}", hasher.finish()))
+    }
+}
+
+/// `agent` with dotted version numbers cut to their major number, so
+/// `Chrome/124.0.6367.91` reads `Chrome/124`
+fn major_versions(agent: &str) -> String {
+    let mut out = String::with_capacity(agent.len());
+    let mut chars = agent.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if !c.is_ascii_digit() || chars.peek().is_some_and(char::is_ascii_digit) {
+            continue;
+        }
+        // skip the `.minor.patch` groups following the major number
+        while chars.peek() == Some(&'.') {
+            let mut rest = chars.clone();
+            rest.next();
+            if !rest.peek().is_some_and(char::is_ascii_digit) {
+                break;
+            }
+            chars = rest;
+            while chars.peek().is_some_and(char::is_ascii_digit) {
+                chars.next();
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_agent_fingerprint() {
+        let request = |agent: &str, language: &str| {
+            Request::builder()
+                .header(header::USER_AGENT, agent)
+                .header(header::ACCEPT, "*/*")
+                .header(header::ACCEPT_LANGUAGE, language)
+                .body(())
+                .unwrap()
+        };
+        let key = |extractor: UserAgentFingerprintKey, req: &Request<()>| {
+            extractor.extract(&Context::<()>::default(), req).unwrap()
+        };
+        let fingerprint = UserAgentFingerprintKey::new();
+
+        assert_eq!(
+            major_versions("Mozilla/5.0 HeadlessChrome/124.0.6367.91 Safari/537.36"),
+            "Mozilla/5 HeadlessChrome/124 Safari/537"
+        );
+        // patch releases of one build share a key, other builds don't
+        let headless = key(fingerprint, &request("HeadlessChrome/124.0.1", "en"));
+        assert!(headless.starts_with("ua:"));
+        assert_eq!(
+            headless,
+            key(fingerprint, &request("HeadlessChrome/124.0.2", "en"))
+        );
+        assert_ne!(
+            headless,
+            key(fingerprint, &request("HeadlessChrome/125.0.1", "en"))
+        );
+        assert_ne!(
+            headless,
+            key(fingerprint, &request("HeadlessChrome/124.0.1", "de"))
+        );
+        // rotating agents are caught by the shape of their requests alone
+        let shape = fingerprint.ignore_user_agent();
+        assert_eq!(
+            key(shape, &request("curl/8.5.0", "en")),
+            key(shape, &request("Wget/1.21", "en"))
+        );
+    }
+}
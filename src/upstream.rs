@@ -0,0 +1,97 @@
+//! Quotas per upstream in proxy deployments
+//!
+//! A fragile backend needs protection from the aggregate traffic a proxy
+//! sends it, whichever clients that traffic comes from. [`UpstreamKey`] keys
+//! requests by their upstream, and [`upstream_policy`] limits each upstream
+//! to its own quota, with overrides for the ones that need them:
+//!
+//! ```ignore
+//! let policy = upstream_policy(
+//!     NamespaceQuotas::new(Quota::per_second(nonzero!(500u32)))
+//!         .with_override("legacy-billing:8080", Quota::per_second(nonzero!(20u32))),
+//! );
+//! ```
+//!
+//! Forward proxies know the upstream from the request target. Reverse proxies
+//! pick it while routing, and should store it as an [`Upstream`] context
+//! extension before the limit layer.
+
+use rama_core::Context;
+use rama_http::{Request, header};
+
+use crate::{KeyExtractor, NamespaceQuotas, NamespacedPolicy};
+
+/// Context extension naming the upstream a request is routed to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Upstream(pub String);
+
+/// Keys requests by their upstream
+///
+/// That is the [`Upstream`] extension if there is one, or else the authority
+/// of the request target: the URI authority of absolute-form and `CONNECT`
+/// requests, or the `Host` header. Authorities are lowercased, so the same
+/// upstream isn't counted under two keys.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UpstreamKey;
+
+impl<State, Body> KeyExtractor<State, Request<Body>> for UpstreamKey {
+    fn extract(&self, ctx: &Context<State>, req: &Request<Body>) -> Option<String> {
+        if let Some(upstream) = ctx.get::<Upstream>() {
+            return Some(upstream.0.clone());
+        }
+        let authority = match req.uri().authority() {
+            Some(authority) => authority.as_str(),
+            None => req.headers().get(header::HOST)?.to_str().ok()?,
+        };
+        Some(authority.to_ascii_lowercase())
+    }
+}
+
+/// Limit the requests to each upstream to its quota in `quotas`
+///
+/// Every upstream is limited on its own, see [`NamespacedPolicy`] to
+/// inspect or reset them.
+pub fn upstream_policy(
+    quotas: impl Into<NamespaceQuotas>,
+) -> NamespacedPolicy<UpstreamKey, UpstreamKey> {
+    NamespacedPolicy::new(quotas, UpstreamKey, UpstreamKey).name("upstream")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use governor::Quota;
+    use rama_core::layer::limit::policy::{Policy, PolicyOutput};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_upstream_policy() {
+        let per_minute = |n| Quota::per_minute(NonZeroU32::new(n).unwrap());
+        let policy = upstream_policy(
+            NamespaceQuotas::new(per_minute(2)).with_override("fragile:8080", per_minute(1)),
+        );
+        let request = |target: &str| Request::builder().uri(target).body(()).unwrap();
+
+        let mut admitted = Vec::new();
+        for target in [
+            "http://fragile:8080/a",
+            "http://FRAGILE:8080/b",
+            "http://sturdy/a",
+            "http://sturdy/b",
+        ] {
+            let result = policy
+                .check(Context::<()>::default(), request(target))
+                .await;
+            admitted.push(matches!(result.output, PolicyOutput::Ready(_)));
+        }
+        assert_eq!(admitted, [true, false, true, true]);
+
+        // routed requests count against the upstream they are routed to
+        let mut ctx = Context::<()>::default();
+        ctx.insert(Upstream("fragile:8080".to_owned()));
+        let result = policy.check(ctx, request("/c")).await;
+        assert!(matches!(result.output, PolicyOutput::Abort(_)));
+    }
+}
@@ -0,0 +1,215 @@
+//! Respecting the rate limits of upstreams in proxy deployments
+//!
+//! When an upstream limits a client, a proxy that keeps passing the client's
+//! requests on only has them rejected again, at the cost of the upstream.
+//! [`UpstreamBackoffLayer`] inspects the responses of the proxied service and,
+//! when an upstream says it limited a request, backs off its key in a policy
+//! through [`GovernorHandle::back_off`], so the edge sheds the key's requests
+//! until the upstream's retry time has passed. Mount it behind the limit
+//! layer of the policy:
+//!
+//! ```ignore
+//! (
+//!     LimitLayer::new(policy.clone()),
+//!     UpstreamBackoffLayer::new(policy.handle()),
+//! )
+//! ```
+//!
+//! An upstream limited a request if it responded with `429 Too Many Requests`,
+//! or with a `RateLimit-Remaining` or `X-RateLimit-Remaining` of zero. The
+//! back-off lasts for its `Retry-After`, or else its `RateLimit-Reset` or
+//! `X-RateLimit-Reset`, in seconds.
+
+use std::time::Duration;
+
+use rama_core::error::BoxError;
+use rama_core::{Context, Layer, Service};
+use rama_http::{HeaderMap, HeaderName, Request, Response, StatusCode, header};
+
+use crate::{GovernorGuard, GovernorHandle, KeyExtractor, UpstreamKey};
+
+/// Back-off when the upstream doesn't say how long to wait
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound of back-offs unless configured otherwise
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+const REMAINING: [HeaderName; 2] = [
+    HeaderName::from_static("ratelimit-remaining"),
+    HeaderName::from_static("x-ratelimit-remaining"),
+];
+
+const RESET: [HeaderName; 3] = [
+    header::RETRY_AFTER,
+    HeaderName::from_static("ratelimit-reset"),
+    HeaderName::from_static("x-ratelimit-reset"),
+];
+
+/// Layer backing off keys an upstream limited in a policy
+#[derive(Debug, Clone)]
+pub struct UpstreamBackoffLayer {
+    handle: GovernorHandle,
+    per_upstream: bool,
+    default_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl UpstreamBackoffLayer {
+    /// Back off the keys of limited requests in the policy of `handle`
+    ///
+    /// Requests are backed off under the key the policy counted them under,
+    /// taken from their [`GovernorGuard`].
+    pub fn new(handle: GovernorHandle) -> Self {
+        UpstreamBackoffLayer {
+            handle,
+            per_upstream: false,
+            default_backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+
+    /// Back off the upstream instead of the client, under its [`UpstreamKey`]
+    ///
+    /// For policies keyed by upstream, like
+    /// `ExtractingPolicy::new(policy, UpstreamKey)`, so an upstream limiting
+    /// some clients is spared by all of them.
+    pub fn per_upstream(mut self) -> Self {
+        self.per_upstream = true;
+        self
+    }
+
+    /// Back off for `backoff` when the upstream gives no retry time
+    pub fn default_backoff(mut self, backoff: Duration) -> Self {
+        self.default_backoff = backoff;
+        self
+    }
+
+    /// Back off for at most `backoff`, whatever the upstream asks for
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// How long the upstream asks to back off for, if it limited the request
+    fn backoff(&self, status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+        let exhausted = REMAINING
+            .iter()
+            .any(|name| header_secs(headers, name) == Some(0));
+        if status != StatusCode::TOO_MANY_REQUESTS && !exhausted {
+            return None;
+        }
+        let backoff = RESET
+            .iter()
+            .find_map(|name| header_secs(headers, name))
+            .map_or(self.default_backoff, Duration::from_secs);
+        Some(backoff.min(self.max_backoff))
+    }
+}
+
+/// Value of the header `name` as a whole number, e.g. delay seconds
+fn header_secs(headers: &HeaderMap, name: &HeaderName) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+impl<S> Layer<S> for UpstreamBackoffLayer {
+    type Service = UpstreamBackoffService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        UpstreamBackoffService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service created by [`UpstreamBackoffLayer`]
+#[derive(Debug, Clone)]
+pub struct UpstreamBackoffService<S> {
+    inner: S,
+    layer: UpstreamBackoffLayer,
+}
+
+impl<S, State, ReqBody, ResBody> Service<State, Request<ReqBody>> for UpstreamBackoffService<S>
+where
+    S: Service<State, Request<ReqBody>, Response = Response<ResBody>, Error: Into<BoxError>>,
+    State: Clone + Send + Sync + 'static,
+    ReqBody: Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = BoxError;
+
+    async fn serve(
+        &self,
+        ctx: Context<State>,
+        req: Request<ReqBody>,
+    ) -> Result<Self::Response, Self::Error> {
+        let key = match self.layer.per_upstream {
+            true => UpstreamKey.extract(&ctx, &req),
+            false => ctx
+                .get::<GovernorGuard>()
+                .and_then(|guard| guard.key().map(str::to_owned)),
+        };
+        let response = self.inner.serve(ctx, req).await.map_err(Into::into)?;
+        if let Some(key) = key {
+            if let Some(backoff) = self.layer.backoff(response.status(), response.headers()) {
+                self.layer.handle.back_off(&key, backoff);
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use rama_core::layer::limit::policy::PolicyOutput;
+    use rama_core::service::service_fn;
+
+    use super::*;
+    use crate::GovernorPolicy;
+
+    #[tokio::test]
+    async fn test_back_off_limited_clients() {
+        let policy = GovernorPolicy::builder()
+            .per_second(100)
+            .build_with_keyer(|key: &str| key.to_owned());
+        let upstream = UpstreamBackoffLayer::new(policy.handle()).layer(service_fn(
+            async |req: Request<&'static str>| {
+                let status = match *req.body() {
+                    "spammer" => StatusCode::TOO_MANY_REQUESTS,
+                    _ => StatusCode::OK,
+                };
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .header(header::RETRY_AFTER, "30")
+                        .body(())
+                        .unwrap(),
+                )
+            },
+        ));
+        let proxy = async |client: &'static str| {
+            let ctx = Context::<()>::default();
+            let result = policy
+                .check_with_key(ctx, Request::new(client), client)
+                .await;
+            match result.output {
+                PolicyOutput::Ready(_) => {
+                    upstream.serve(result.ctx, result.request).await.unwrap();
+                    true
+                }
+                _ => false,
+            }
+        };
+
+        assert!(proxy("spammer").await);
+        assert!(!proxy("spammer").await);
+        assert!(proxy("polite").await);
+        assert!(proxy("polite").await);
+
+        policy.handle().reset_key("spammer");
+        assert!(proxy("spammer").await);
+    }
+}
@@ -0,0 +1,142 @@
+//! Per-API-version quotas
+//!
+//! A [`VersionSelector`] reads the API version of each request and checks it
+//! against the policy configured for that version. Lowering the quota of a
+//! deprecated version over time nudges its remaining clients to migrate.
+
+use std::collections::HashMap;
+
+use rama_core::Context;
+use rama_http::{HeaderName, Request, header};
+
+use crate::{GovernorPolicy, PolicySelector};
+
+/// Where the API version of a request is read from
+///
+/// Versions are compared as `v` followed by the version, e.g. "v2", so
+/// `version=2` in a header and a `/v2/` path select the same policy.
+#[derive(Debug, Clone)]
+pub enum VersionSource {
+    /// The first path segment, if it looks like `v1`, `v2`, ...
+    PathPrefix,
+    /// The value of a custom header, e.g. `Api-Version: 2`
+    Header(HeaderName),
+    /// A `version` parameter (`application/json; version=2`) or a vendor
+    /// suffix (`application/vnd.example.v2+json`) in the `Accept` header
+    Accept,
+}
+
+impl VersionSource {
+    fn version<Body>(&self, req: &Request<Body>) -> Option<String> {
+        match self {
+            VersionSource::PathPrefix => {
+                let segment = req.uri().path().trim_start_matches('/').split('/').next()?;
+                normalize(segment.strip_prefix(['v', 'V'])?)
+            }
+            VersionSource::Header(name) => {
+                let value = req.headers().get(name)?.to_str().ok()?.trim();
+                normalize(value.strip_prefix(['v', 'V']).unwrap_or(value))
+            }
+            VersionSource::Accept => {
+                let accept = req.headers().get(header::ACCEPT)?.to_str().ok()?;
+                accept.split([',', ';']).find_map(|part| {
+                    let part = part.trim();
+                    if let Some(version) = part.strip_prefix("version=") {
+                        return normalize(version.trim_matches('"'));
+                    }
+                    let vendor = part.split('+').next()?;
+                    normalize(vendor.rsplit_once(".v")?.1)
+                })
+            }
+        }
+    }
+}
+
+/// `v` followed by `version`, if it is a dotted number like "2" or "1.1"
+fn normalize(version: &str) -> Option<String> {
+    let valid = !version.is_empty()
+        && version
+            .split('.')
+            .all(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    valid.then(|| format!("v{version}"))
+}
+
+/// Selects the policy by the API version of the request
+///
+/// Requests without a recognized version, or for versions without a policy of
+/// their own, use the default policy.
+pub struct VersionSelector {
+    source: VersionSource,
+    policies: HashMap<String, GovernorPolicy>,
+    default: GovernorPolicy,
+}
+
+impl VersionSelector {
+    /// Read versions from `source`, using `default` unless configured otherwise
+    pub fn new(source: VersionSource, default: GovernorPolicy) -> Self {
+        VersionSelector {
+            source,
+            policies: HashMap::new(),
+            default,
+        }
+    }
+
+    /// Use `policy` for requests to `version`, e.g. "v1" or "1"
+    ///
+    /// # Panics
+    ///
+    /// Panics if `version` is not a dotted number, optionally prefixed with `v`.
+    pub fn version(mut self, version: &str, policy: GovernorPolicy) -> Self {
+        let version = normalize(version.strip_prefix(['v', 'V']).unwrap_or(version))
+            .unwrap_or_else(|| panic!("invalid API version {version:?}"));
+        self.policies.insert(version, policy);
+        self
+    }
+}
+
+impl std::fmt::Debug for VersionSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VersionSelector")
+            .field("source", &self.source)
+            .field("versions", &self.policies.keys())
+            .finish()
+    }
+}
+
+impl<State, Body> PolicySelector<State, Request<Body>> for VersionSelector {
+    fn select(&self, _ctx: &Context<State>, req: &Request<Body>) -> &GovernorPolicy {
+        self.source
+            .version(req)
+            .and_then(|version| self.policies.get(&version))
+            .unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rama_http::Body;
+
+    #[test]
+    fn test_version_sources() {
+        let req = |uri: &str, accept: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(header::ACCEPT, accept)
+                .header("api-version", "2")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let path = VersionSource::PathPrefix;
+        let custom = VersionSource::Header(HeaderName::from_static("api-version"));
+        let accept = VersionSource::Accept;
+
+        assert_eq!(path.version(&req("/v1/users", "*/*")).unwrap(), "v1");
+        assert_eq!(path.version(&req("/users/v1", "*/*")), None);
+        assert_eq!(custom.version(&req("/", "*/*")).unwrap(), "v2");
+        let vendor = req("/", "application/vnd.example.v3+json");
+        assert_eq!(accept.version(&vendor).unwrap(), "v3");
+        let param = req("/", "application/json; version=1.1");
+        assert_eq!(accept.version(&param).unwrap(), "v1.1");
+    }
+}
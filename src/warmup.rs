@@ -0,0 +1,98 @@
+//! Gradual ramp-up of the quota after startup
+//!
+//! A policy built with [`warmup`](crate::GovernorPolicyBuilder::warmup)
+//! starts at a tenth of its quota and raises it in equal steps until the
+//! full quota applies at the end of the warm-up period. A new instance
+//! joining a pool so doesn't hit cold caches and upstreams at full rate.
+//!
+//! Each step replaces the quota like
+//! [`GovernorHandle::set_quota`](crate::GovernorHandle::set_quota) does, so
+//! a quota set by hand during the warm-up only lasts until the next step.
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+
+use governor::Quota;
+
+use crate::handle::HandleTarget;
+use crate::runtime::{self, Runtime};
+use crate::sync::StartOnce;
+
+/// Steps from the initial to the full quota
+const STEPS: u32 = 10;
+
+/// Ramp of a policy from a fraction of its quota to the full quota
+pub(crate) struct Warmup {
+    quota: Quota,
+    period: Duration,
+    started: StartOnce,
+}
+
+impl Warmup {
+    pub(crate) fn new(quota: Quota, period: Duration) -> Self {
+        Warmup {
+            quota,
+            period,
+            started: StartOnce::new(),
+        }
+    }
+
+    /// The quota to start from
+    pub(crate) fn initial_quota(&self) -> Quota {
+        step_quota(self.quota, 1)
+    }
+
+    /// Spawn the task raising the quota of `target` step by step, once
+    pub(crate) fn start_if_needed(
+        &self,
+        runtime: &Arc<dyn Runtime>,
+        target: Arc<dyn HandleTarget>,
+    ) {
+        self.started.call(|| {
+            let (quota, interval) = (self.quota, self.period / STEPS);
+            let timer = runtime.clone();
+            let mut shutdown = runtime.shutdown();
+            runtime.spawn(Box::pin(async move {
+                for step in 2..=STEPS {
+                    if !runtime::tick(&*timer, interval, &mut shutdown).await {
+                        return;
+                    }
+                    target.set_quota(step_quota(quota, step));
+                }
+                tracing::info!("Warm-up finished at quota {:?}", quota);
+            }));
+        });
+    }
+}
+
+/// `step` tenths of `quota`, by rate and burst size
+fn step_quota(quota: Quota, step: u32) -> Quota {
+    if step >= STEPS {
+        return quota;
+    }
+    let burst = quota.burst_size().get() * step / STEPS;
+    let period = quota.replenish_interval() * STEPS / step;
+    Quota::with_period(period)
+        .unwrap_or(quota)
+        .allow_burst(NonZeroU32::new(burst).unwrap_or(NonZeroU32::MIN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warmup_steps() {
+        let quota = Quota::per_second(NonZeroU32::new(100).unwrap());
+        let first = step_quota(quota, 1);
+        assert_eq!(first.replenish_interval(), Duration::from_millis(100));
+        assert_eq!(first.burst_size().get(), 10);
+        assert_eq!(step_quota(quota, 5).burst_size().get(), 50);
+        assert_eq!(step_quota(quota, STEPS), quota);
+
+        // tiny bursts keep at least one cell
+        let quota = Quota::per_minute(NonZeroU32::new(3).unwrap());
+        assert_eq!(step_quota(quota, 1).burst_size().get(), 1);
+    }
+}
@@ -0,0 +1,62 @@
+//! Checking tracked client addresses must not allocate
+//!
+//! Lives in its own test binary, as counting allocations takes over the
+//! global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::net::IpAddr;
+
+use rama_x_governor::{GovernorPolicy, Limiter};
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Allocations made by the current thread so far
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_check_ip_does_not_allocate() {
+    let limiter = Limiter::new(
+        GovernorPolicy::builder()
+            .per_minute(10)
+            .burst_size(10)
+            .build_with_ip_keys(),
+    );
+    let clients: [IpAddr; 2] = [
+        "203.0.113.7".parse().unwrap(),
+        "2001:db8::7".parse().unwrap(),
+    ];
+    for ip in clients {
+        limiter.check_ip(ip).await.unwrap();
+    }
+
+    let before = allocations();
+    for ip in clients {
+        // allowed, then rejected
+        for _ in 0..20 {
+            let _ = limiter.check_ip(ip).await;
+        }
+    }
+    assert_eq!(allocations() - before, 0);
+    assert!(limiter.check_ip(clients[0]).await.is_err());
+}